@@ -0,0 +1,390 @@
+pub mod dmc;
+pub mod envelope;
+pub mod expansion;
+pub mod framecounter;
+pub mod length;
+pub mod mixer;
+pub mod noise;
+pub mod pulse;
+pub mod triangle;
+
+use crate::apu::dmc::DMC;
+use crate::apu::framecounter::{FrameClock, FrameCounter};
+use crate::apu::mixer::{Channel, Mixer};
+use crate::apu::noise::Noise;
+use crate::apu::pulse::Pulse;
+use crate::apu::triangle::Triangle;
+use crate::audio::AudioSink;
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use crate::wav::{AudioRecorder, RECORDING_RATE};
+use std::io;
+use std::path::Path;
+
+// Audio Processing Unit, registers live at 0x4000-0x4017
+// 0x4000-0x4003 Pulse 1
+// 0x4004-0x4007 Pulse 2
+// 0x4008-0x400B Triangle
+// 0x400C-0x400F Noise
+// 0x4010-0x4013 DMC
+// 0x4015 Channel enable and status
+// 0x4017 Frame counter
+pub struct APU {
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: DMC,
+    pub frame_counter: FrameCounter,
+    pub mixer: Mixer,
+    pub expansion: f32, // Output of the cartridge's sound chip, updated by its owner every cycle
+    pub sink: AudioSink, // Resamples the output for the frontend
+    recorder: Option<AudioRecorder>,
+    recording_error: Option<io::Error>, // Ended the recording early, stop_recording returns it
+    capture: Option<AudioSink>,         // Audio for video recording, at a fixed rate
+    cpu_clock_hz: f64,
+    pub cycles: usize, // Total amount of cpu cycles the APU has run
+}
+
+impl APU {
+    pub fn new(region: Region) -> Self {
+        APU {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(region),
+            dmc: DMC::new(region),
+            frame_counter: FrameCounter::new(region),
+            mixer: Mixer::new(),
+            expansion: 0.0,
+            sink: AudioSink::new(region.cpu_clock_hz()),
+            recorder: None,
+            recording_error: None,
+            capture: None,
+            cpu_clock_hz: region.cpu_clock_hz(),
+            cycles: 0,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr - 0x4000, data),
+            0x4004..=0x4007 => self.pulse2.write(addr - 0x4004, data),
+            0x4008..=0x400B => self.triangle.write(addr - 0x4008, data),
+            0x400C..=0x400F => self.noise.write(addr - 0x400C, data),
+            0x4010..=0x4013 => self.dmc.write(addr - 0x4010, data),
+            0x4015 => {
+                // ---D NT21 enables each channel, disabling clears its length counter
+                self.pulse1.length.set_enabled(data & 0b0001 != 0);
+                self.pulse2.length.set_enabled(data & 0b0010 != 0);
+                self.triangle.length.set_enabled(data & 0b0100 != 0);
+                self.noise.length.set_enabled(data & 0b1000 != 0);
+                self.dmc.set_enabled(data & 0b1_0000 != 0);
+            }
+            0x4017 => {
+                let clock = self.frame_counter.write(data);
+                self.clock_frame(clock);
+            }
+            _ => {}
+        }
+    }
+
+    // 0x4015 read
+    // IF-D NT21, reading clears the frame interrupt flag
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        if self.pulse1.length.is_active() {
+            status |= 0b0000_0001;
+        }
+        if self.pulse2.length.is_active() {
+            status |= 0b0000_0010;
+        }
+        if self.triangle.length.is_active() {
+            status |= 0b0000_0100;
+        }
+        if self.noise.length.is_active() {
+            status |= 0b0000_1000;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= 0b0001_0000;
+        }
+        if self.frame_counter.irq {
+            status |= 0b0100_0000;
+        }
+        if self.dmc.irq {
+            status |= 0b1000_0000;
+        }
+        self.frame_counter.irq = false;
+        status
+    }
+
+    // Runs the APU for one CPU cycle
+    pub fn tick(&mut self) {
+        self.cycles += 1;
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        // Pulse timers run at half the CPU clock
+        if self.cycles & 1 == 0 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        let clock = self.frame_counter.tick();
+        self.clock_frame(clock);
+        let output = self.output();
+        self.sink.push(output);
+        if let Some(capture) = self.capture.as_mut() {
+            capture.push(output);
+        }
+
+        let stems = match &self.recorder {
+            Some(recorder) if recorder.has_stems() => self.channel_outputs(),
+            Some(_) => [0.0; 6],
+            None => return,
+        };
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(err) = recorder.push(output, &stems) {
+                self.recording_error = Some(err);
+                self.recorder = None;
+            }
+        }
+    }
+
+    // Starts writing the mixed output to a WAV file, stems adds one file per channel
+    pub fn start_recording(&mut self, path: &Path, stems: bool) -> io::Result<()> {
+        self.stop_recording()?;
+        self.recorder = Some(AudioRecorder::start(path, self.cpu_clock_hz, stems)?);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        if let Some(err) = self.recording_error.take() {
+            return Err(err);
+        }
+        match self.recorder.take() {
+            Some(recorder) => recorder.stop(),
+            None => Ok(()),
+        }
+    }
+
+    // Stays true after a write error until stop_recording reports it
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some() || self.recording_error.is_some()
+    }
+
+    // Collects the output at RECORDING_RATE until stop_capture, for video recording
+    pub fn start_capture(&mut self) {
+        let mut capture = AudioSink::new(self.cpu_clock_hz);
+        capture.set_output_rate(RECORDING_RATE);
+        self.capture = Some(capture);
+    }
+
+    pub fn stop_capture(&mut self) {
+        self.capture = None;
+    }
+
+    // Samples since the last call
+    pub fn take_capture(&mut self) -> Vec<f32> {
+        self.capture
+            .as_mut()
+            .map_or(Vec::new(), |capture| capture.take_samples())
+    }
+
+    fn clock_frame(&mut self, clock: FrameClock) {
+        if clock.quarter {
+            self.pulse1.envelope.clock();
+            self.pulse2.envelope.clock();
+            self.noise.envelope.clock();
+            self.triangle.clock_linear();
+        }
+        if clock.half {
+            self.pulse1.length.clock();
+            self.pulse2.length.clock();
+            self.triangle.length.clock();
+            self.noise.length.clock();
+            self.pulse1.clock_sweep();
+            self.pulse2.clock_sweep();
+        }
+    }
+
+    // The APU holds the CPU's IRQ line low while an interrupt is pending
+    pub fn irq(&self) -> bool {
+        self.frame_counter.irq || self.dmc.irq
+    }
+
+    // Each channel on its own, indexed by Channel
+    fn channel_outputs(&self) -> [f32; 6] {
+        let levels = [
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+        ];
+        let mut outputs = [0.0; 6];
+        for (channel, level) in Channel::ALL.iter().zip(levels.iter()) {
+            outputs[*channel as usize] = self.mixer.channel_output(*channel, *level as f32);
+        }
+        outputs[Channel::EXPANSION as usize] = self.expansion;
+        outputs
+    }
+
+    // Current output level in the range 0.0 to 1.0
+    pub fn output(&self) -> f32 {
+        self.mixer.mix(
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+            self.expansion,
+        )
+    }
+}
+
+// The mixer's settings and the audio output aren't part of the machine
+impl Savestate for APU {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.component(&self.pulse1);
+        w.component(&self.pulse2);
+        w.component(&self.triangle);
+        w.component(&self.noise);
+        w.component(&self.dmc);
+        w.component(&self.frame_counter);
+        w.f32(self.expansion);
+        w.usize(self.cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.component(&mut self.pulse1);
+        r.component(&mut self.pulse2);
+        r.component(&mut self.triangle);
+        r.component(&mut self.noise);
+        r.component(&mut self.dmc);
+        r.component(&mut self.frame_counter);
+        self.expansion = r.f32();
+        self.cycles = r.usize();
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_length_counter_status() {
+        let mut apu = APU::new(Region::NTSC);
+        apu.write_register(0x4015, 0b0000_0001);
+        apu.write_register(0x4003, 0b0000_1000); // Length index 1 = 254
+        assert_eq!(apu.read_status() & 0b1, 1);
+
+        apu.write_register(0x4015, 0);
+        assert_eq!(apu.read_status() & 0b1, 0);
+
+        // Loading while disabled has no effect
+        apu.write_register(0x4003, 0b0000_1000);
+        assert_eq!(apu.read_status() & 0b1, 0);
+    }
+
+    #[test]
+    fn test_length_counter_half_frame() {
+        let mut apu = APU::new(Region::NTSC);
+        apu.write_register(0x4015, 0b0000_1000);
+        apu.write_register(0x4017, 0b0100_0000);
+        apu.write_register(0x400F, 0b0001_1000); // Length index 3 = 2
+        for _ in 0..29830 {
+            apu.tick();
+        }
+        assert_eq!(apu.read_status() & 0b1000, 0);
+    }
+
+    #[test]
+    fn test_dmc_dma_and_irq() {
+        let mut apu = APU::new(Region::NTSC);
+        apu.write_register(0x4010, 0b1000_1111); // IRQ on, fastest rate
+        apu.write_register(0x4012, 0x01); // 0xC040
+        apu.write_register(0x4013, 0x00); // 1 byte
+        apu.write_register(0x4015, 0b0001_0000);
+        assert_eq!(apu.read_status() & 0b0001_0000, 0b0001_0000);
+
+        assert_eq!(apu.dmc.dma_request(), Some(0xC040));
+        apu.dmc.load_sample(0xFF);
+        assert_eq!(apu.dmc.dma_request(), None);
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & 0b1001_0000, 0b1000_0000);
+
+        // Writing 0x4015 acknowledges the interrupt
+        apu.write_register(0x4015, 0);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = APU::new(Region::NTSC);
+        apu.write_register(0x4017, 0);
+        for _ in 0..29827 {
+            apu.tick();
+        }
+        assert!(!apu.irq());
+        apu.tick();
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & 0b0100_0000, 0b0100_0000);
+        assert!(!apu.irq());
+
+        // 5-step mode never raises the interrupt
+        apu.write_register(0x4017, 0b1000_0000);
+        for _ in 0..40000 {
+            apu.tick();
+        }
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_recording_error() {
+        let path = Path::new("/dev/full");
+        if !path.exists() {
+            return;
+        }
+        let mut apu = APU::new(Region::NTSC);
+        apu.start_recording(path, false).unwrap();
+        // A few buffers worth of samples
+        for _ in 0..1_000_000 {
+            apu.tick();
+        }
+        // The write failed while running and is kept for stop_recording
+        assert!(apu.recorder.is_none());
+        assert!(apu.is_recording());
+        assert!(apu.stop_recording().is_err());
+        assert!(!apu.is_recording());
+        assert!(apu.stop_recording().is_ok());
+    }
+
+    // Noise, DMC and frame IRQ outputs over 30000 cycles with the slowest noise period and fastest DMC rate
+    fn timing_trace(region: Region) -> Vec<(u8, u8, bool)> {
+        let mut apu = APU::new(region);
+        apu.write_register(0x400C, 0b0011_1111); // Constant volume 15
+        apu.write_register(0x400E, 0x0F);
+        apu.write_register(0x4010, 0b0100_1111); // Looping
+        apu.write_register(0x4013, 0x01);
+        apu.write_register(0x4015, 0b0001_1000);
+        apu.write_register(0x400F, 0b0000_1000);
+        apu.write_register(0x4017, 0);
+        let mut trace = Vec::new();
+        for _ in 0..30000 {
+            if apu.dmc.dma_request().is_some() {
+                apu.dmc.load_sample(0xF0);
+            }
+            apu.tick();
+            trace.push((apu.noise.output(), apu.dmc.output(), apu.frame_counter.irq));
+        }
+        trace
+    }
+
+    #[test]
+    fn test_dendy_tables() {
+        // Dendy runs the NTSC noise periods, DMC rates and frame sequence at its own clock
+        let dendy = timing_trace(Region::DENDY);
+        assert_eq!(dendy, timing_trace(Region::NTSC));
+        assert_ne!(dendy, timing_trace(Region::PAL));
+    }
+}
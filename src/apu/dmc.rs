@@ -0,0 +1,201 @@
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// Timer periods in CPU cycles
+const NTSC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const PAL_RATES: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+// Delta modulation channel, 0x4010-0x4013
+// Plays 1-bit delta encoded samples fetched from CPU memory with DMA, each bit moves the output level by 2
+pub struct DMC {
+    rates: &'static [u16; 16],
+    pub irq_enabled: bool,
+    pub irq: bool,
+    looping: bool,
+    timer_period: u16,
+    timer: u16,
+
+    // Memory reader
+    sample_addr: u16,
+    sample_length: u16,
+    current_addr: u16,
+    pub bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+
+    // Output unit
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+    level: u8,
+}
+
+impl DMC {
+    pub fn new(region: Region) -> Self {
+        let rates = match region {
+            Region::NTSC | Region::DENDY => &NTSC_RATES,
+            Region::PAL => &PAL_RATES,
+        };
+        DMC {
+            rates,
+            irq_enabled: false,
+            irq: false,
+            looping: false,
+            timer_period: rates[0],
+            timer: rates[0],
+            sample_addr: 0xC000,
+            sample_length: 1,
+            current_addr: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+            level: 0,
+        }
+    }
+
+    // reg is the register offset 0-3
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            // IL-- RRRR
+            0 => {
+                self.irq_enabled = data & 0b1000_0000 != 0;
+                self.looping = data & 0b0100_0000 != 0;
+                self.timer_period = self.rates[(data & 0b1111) as usize];
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            // -DDD DDDD loads the output level directly
+            1 => self.level = data & 0b0111_1111,
+            // Sample address = 0xC000 + A * 64
+            2 => self.sample_addr = 0xC000 | ((data as u16) << 6),
+            // Sample length = L * 16 + 1
+            3 => self.sample_length = ((data as u16) << 4) | 1,
+            _ => panic!("DMC: Unknown register {}", reg),
+        }
+    }
+
+    // Bit 4 of 0x4015, also acknowledges the DMC interrupt
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // Address the memory reader wants to fetch, the bus performs the DMA and stalls the CPU
+    pub fn dma_request(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_addr)
+        } else {
+            None
+        }
+    }
+
+    // Result of the DMA requested by dma_request
+    pub fn load_sample(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        // Address wraps around to 0x8000 instead of 0x0000
+        self.current_addr = if self.current_addr == 0xFFFF {
+            0x8000
+        } else {
+            self.current_addr + 1
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle, the rate table is in CPU cycles
+    pub fn clock_timer(&mut self) {
+        if self.timer > 1 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period;
+
+        if !self.silence {
+            if self.shift & 1 == 1 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+
+        // Start a new output cycle with the next byte from the sample buffer
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift = sample;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+}
+
+impl Savestate for DMC {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.irq_enabled);
+        w.bool(self.irq);
+        w.bool(self.looping);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+        w.u16(self.sample_addr);
+        w.u16(self.sample_length);
+        w.u16(self.current_addr);
+        w.u16(self.bytes_remaining);
+        w.bool(self.sample_buffer.is_some());
+        w.u8(self.sample_buffer.unwrap_or(0));
+        w.u8(self.shift);
+        w.u8(self.bits_remaining);
+        w.bool(self.silence);
+        w.u8(self.level);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.irq_enabled = r.bool();
+        self.irq = r.bool();
+        self.looping = r.bool();
+        self.timer_period = r.u16();
+        self.timer = r.u16();
+        self.sample_addr = r.u16();
+        self.sample_length = r.u16();
+        self.current_addr = r.u16();
+        self.bytes_remaining = r.u16();
+        let buffered = r.bool();
+        let sample = r.u8();
+        self.sample_buffer = buffered.then_some(sample);
+        self.shift = r.u8();
+        self.bits_remaining = r.u8();
+        self.silence = r.bool();
+        self.level = r.u8();
+    }
+}
@@ -0,0 +1,88 @@
+// Volume envelope shared by the pulse and noise channels
+// Either outputs a constant volume or a sawtooth that decays from 15 to 0, clocked by the quarter frame
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+pub struct Envelope {
+    start: bool,
+    divider: u8,
+    decay: u8,
+    pub looping: bool, // Same bit as the length counter halt flag
+    constant: bool,
+    volume: u8, // Constant volume or the divider period
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            start: false,
+            divider: 0,
+            decay: 0,
+            looping: false,
+            constant: false,
+            volume: 0,
+        }
+    }
+
+    // --LC VVVV
+    pub fn write(&mut self, data: u8) {
+        self.looping = data & 0b0010_0000 != 0;
+        self.constant = data & 0b0001_0000 != 0;
+        self.volume = data & 0b1111;
+    }
+
+    // Writing the length counter load register restarts the envelope
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Envelope::new()
+    }
+}
+
+impl Savestate for Envelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.start);
+        w.u8(self.divider);
+        w.u8(self.decay);
+        w.bool(self.looping);
+        w.bool(self.constant);
+        w.u8(self.volume);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.start = r.bool();
+        self.divider = r.u8();
+        self.decay = r.u8();
+        self.looping = r.bool();
+        self.constant = r.bool();
+        self.volume = r.u8();
+    }
+}
@@ -0,0 +1,30 @@
+pub mod fds;
+pub mod mmc5;
+pub mod n163;
+pub mod sunsoft5b;
+pub mod vrc6;
+pub mod vrc7;
+
+// Sound chips on Famicom cartridges(and the Disk System), mixed in through the cartridge port
+// Each chip is clocked every CPU cycle by whatever owns it(a mapper or the NSF player) and its
+// output is already scaled against the 2A03, so it can be added straight onto the mixer's output
+
+use crate::savestate::Savestate;
+
+// One 2A03 pulse at full volume through the pulse table, the reference for every chip's level
+pub const APU_PULSE_LEVEL: f32 = 95.52 / (8128.0 / 15.0 + 100.0);
+
+pub trait ExpansionAudio: Savestate {
+    // addr is the CPU address as the chip sees it, mappers with swapped address lines fix them up first
+    fn write(&mut self, addr: u16, data: u8);
+
+    // Only some chips have readable registers, None leaves the read to the mapper
+    fn read(&mut self, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    // Runs the chip for one CPU cycle
+    fn clock(&mut self);
+
+    fn output(&self) -> f32;
+}
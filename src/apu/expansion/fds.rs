@@ -0,0 +1,348 @@
+use crate::apu::expansion::{ExpansionAudio, APU_PULSE_LEVEL};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// Famicom Disk System, a single wavetable channel with frequency modulation
+// 0x4040-0x407F Wavetable, 64 6-bit samples, only writable while 0x4089 bit 7 is set
+// 0x4080 Volume envelope          0x4082-0x4083 Wave frequency
+// 0x4084 Modulation envelope      0x4085 Modulation counter
+// 0x4086-0x4087 Modulation frequency
+// 0x4088 Modulation table, each write appends a 3-bit entry
+// 0x4089 Wavetable write enable and master volume
+// 0x408A Envelope speed
+// 0x4090 and 0x4092 read back the volume and modulation gain
+// At full volume and master volume the wave is about 2.4 times as loud as a 2A03 pulse
+const LEVEL: f32 = APU_PULSE_LEVEL * 2.4 / (63.0 * 32.0);
+const MASTER_VOLUME: [f32; 4] = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0];
+const MOD_ADJUST: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1]; // Entry 4 resets the counter instead
+const LOWPASS: f32 = 0.4; // Rough match for the RC filter on the output, about 2kHz
+
+struct FDSEnvelope {
+    direct: bool, // Sets the gain straight from the speed bits instead of ramping
+    increase: bool,
+    speed: u8,
+    gain: u8,
+    timer: u32,
+}
+
+impl FDSEnvelope {
+    fn new() -> Self {
+        FDSEnvelope {
+            direct: true,
+            increase: false,
+            speed: 0,
+            gain: 0,
+            timer: 0,
+        }
+    }
+
+    // MDSS SSSS
+    fn write(&mut self, data: u8) {
+        self.direct = data & 0b1000_0000 != 0;
+        self.increase = data & 0b0100_0000 != 0;
+        self.speed = data & 0b11_1111;
+        if self.direct {
+            self.gain = self.speed;
+        }
+        self.timer = 0;
+    }
+
+    // Ticks every 8 * master speed * (speed + 1) CPU cycles
+    fn clock(&mut self, master_speed: u8) {
+        if self.direct || master_speed == 0 {
+            return;
+        }
+        self.timer += 1;
+        if self.timer < 8 * master_speed as u32 * (self.speed as u32 + 1) {
+            return;
+        }
+        self.timer = 0;
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+}
+
+pub struct FDSAudio {
+    wave: [u8; 64],
+    wave_write: bool,
+    wave_halt: bool,
+    wave_pos: u8,
+    wave_accumulator: u32,
+    wave_freq: u16,
+    master_volume: u8,
+    last_sample: u8, // The output holds while the wavetable is writable
+
+    volume: FDSEnvelope,
+    modulation: FDSEnvelope,
+    envelopes_halted: bool,
+    envelope_speed: u8,
+
+    mod_table: [u8; 64],
+    mod_pos: u8,
+    mod_counter: i8, // 7-bit signed
+    mod_freq: u16,
+    mod_halt: bool,
+    mod_accumulator: u32,
+
+    filtered: f32,
+}
+
+impl FDSAudio {
+    pub fn new() -> Self {
+        FDSAudio {
+            wave: [0; 64],
+            wave_write: false,
+            wave_halt: true,
+            wave_pos: 0,
+            wave_accumulator: 0,
+            wave_freq: 0,
+            master_volume: 0,
+            last_sample: 0,
+            volume: FDSEnvelope::new(),
+            modulation: FDSEnvelope::new(),
+            envelopes_halted: false,
+            envelope_speed: 0xE8,
+            mod_table: [0; 64],
+            mod_pos: 0,
+            mod_counter: 0,
+            mod_freq: 0,
+            mod_halt: true,
+            mod_accumulator: 0,
+            filtered: 0.0,
+        }
+    }
+
+    // The wave's pitch bent by the modulation unit
+    fn modulated_freq(&self) -> u32 {
+        if self.mod_halt {
+            return self.wave_freq as u32;
+        }
+        let mut temp = self.mod_counter as i32 * self.modulation.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if self.mod_counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        temp *= self.wave_freq as i32;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        (self.wave_freq as i32 + temp).max(0) as u32
+    }
+
+    fn clock_modulation(&mut self) {
+        if self.mod_halt || self.mod_freq == 0 {
+            return;
+        }
+        self.mod_accumulator += self.mod_freq as u32;
+        if self.mod_accumulator < 0x10000 {
+            return;
+        }
+        self.mod_accumulator &= 0xFFFF;
+        let entry = self.mod_table[self.mod_pos as usize];
+        self.mod_pos = (self.mod_pos + 1) & 0x3F;
+        self.mod_counter = if entry == 4 {
+            0
+        } else {
+            // Wraps within 7 bits
+            let counter = self.mod_counter as i16 + MOD_ADJUST[entry as usize] as i16;
+            (((counter + 64) & 0x7F) - 64) as i8
+        };
+    }
+
+    fn clock_wave(&mut self) {
+        if self.wave_halt || self.wave_write {
+            return;
+        }
+        self.wave_accumulator += self.modulated_freq();
+        if self.wave_accumulator >= 0x10000 {
+            self.wave_accumulator &= 0xFFFF;
+            self.wave_pos = (self.wave_pos + 1) & 0x3F;
+        }
+    }
+}
+
+impl Default for FDSAudio {
+    fn default() -> Self {
+        FDSAudio::new()
+    }
+}
+
+impl ExpansionAudio for FDSAudio {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4040..=0x407F if self.wave_write => {
+                self.wave[(addr - 0x4040) as usize] = data & 0b11_1111;
+            }
+            0x4080 => self.volume.write(data),
+            0x4082 => self.wave_freq = (self.wave_freq & 0xF00) | data as u16,
+            // HE-- FFFF, halting the wave resets its position
+            0x4083 => {
+                self.wave_freq = (self.wave_freq & 0xFF) | ((data as u16 & 0x0F) << 8);
+                self.wave_halt = data & 0b1000_0000 != 0;
+                self.envelopes_halted = data & 0b0100_0000 != 0;
+                if self.wave_halt {
+                    self.wave_pos = 0;
+                    self.wave_accumulator = 0;
+                }
+            }
+            0x4084 => self.modulation.write(data),
+            // -CCC CCCC
+            0x4085 => self.mod_counter = (((data & 0x7F) as i8) << 1) >> 1,
+            0x4086 => self.mod_freq = (self.mod_freq & 0xF00) | data as u16,
+            // H--- FFFF, the modulation table can only be written while halted
+            0x4087 => {
+                self.mod_freq = (self.mod_freq & 0xFF) | ((data as u16 & 0x0F) << 8);
+                self.mod_halt = data & 0b1000_0000 != 0;
+                if self.mod_halt {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // Each entry is stored twice
+            0x4088 if self.mod_halt => {
+                self.mod_table[self.mod_pos as usize] = data & 0b111;
+                self.mod_table[(self.mod_pos as usize + 1) & 0x3F] = data & 0b111;
+                self.mod_pos = (self.mod_pos + 2) & 0x3F;
+            }
+            // W--- --VV
+            0x4089 => {
+                self.wave_write = data & 0b1000_0000 != 0;
+                self.master_volume = data & 0b11;
+            }
+            0x408A => self.envelope_speed = data,
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4040..=0x407F => Some(self.wave[(addr - 0x4040) as usize]),
+            0x4090 => Some(self.volume.gain),
+            0x4092 => Some(self.modulation.gain),
+            _ => None,
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.envelopes_halted && !self.wave_halt {
+            self.volume.clock(self.envelope_speed);
+            self.modulation.clock(self.envelope_speed);
+        }
+        self.clock_modulation();
+        self.clock_wave();
+        if !self.wave_write {
+            self.last_sample = self.wave[self.wave_pos as usize];
+        }
+
+        let gain = self.volume.gain.min(32) as f32;
+        let level = self.last_sample as f32 * gain * MASTER_VOLUME[self.master_volume as usize];
+        self.filtered += (level - self.filtered) * LOWPASS;
+    }
+
+    fn output(&self) -> f32 {
+        self.filtered * LEVEL
+    }
+}
+
+impl Savestate for FDSEnvelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.direct);
+        w.bool(self.increase);
+        w.u8(self.speed);
+        w.u8(self.gain);
+        w.u32(self.timer);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.direct = r.bool();
+        self.increase = r.bool();
+        self.speed = r.u8();
+        self.gain = r.u8();
+        self.timer = r.u32();
+    }
+}
+
+impl Savestate for FDSAudio {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.wave);
+        w.bool(self.wave_write);
+        w.bool(self.wave_halt);
+        w.u8(self.wave_pos);
+        w.u32(self.wave_accumulator);
+        w.u16(self.wave_freq);
+        w.u8(self.master_volume);
+        w.u8(self.last_sample);
+        w.component(&self.volume);
+        w.component(&self.modulation);
+        w.bool(self.envelopes_halted);
+        w.u8(self.envelope_speed);
+        w.bytes(&self.mod_table);
+        w.u8(self.mod_pos);
+        w.u8(self.mod_counter as u8);
+        w.u16(self.mod_freq);
+        w.bool(self.mod_halt);
+        w.u32(self.mod_accumulator);
+        w.f32(self.filtered);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes_into(&mut self.wave);
+        self.wave_write = r.bool();
+        self.wave_halt = r.bool();
+        self.wave_pos = r.u8();
+        self.wave_accumulator = r.u32();
+        self.wave_freq = r.u16();
+        self.master_volume = r.u8();
+        self.last_sample = r.u8();
+        r.component(&mut self.volume);
+        r.component(&mut self.modulation);
+        self.envelopes_halted = r.bool();
+        self.envelope_speed = r.u8();
+        r.bytes_into(&mut self.mod_table);
+        self.mod_pos = r.u8();
+        self.mod_counter = r.u8() as i8;
+        self.mod_freq = r.u16();
+        self.mod_halt = r.bool();
+        self.mod_accumulator = r.u32();
+        self.filtered = r.f32();
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_fds_wave_write_and_pitch() {
+        let mut fds = FDSAudio::new();
+        fds.write(0x4089, 0x80);
+        for i in 0..64 {
+            fds.write(0x4040 + i, i as u8);
+        }
+        fds.write(0x4089, 0x00);
+        assert_eq!(fds.read(0x4045), Some(5));
+
+        // Writes are ignored once the wavetable is locked
+        fds.write(0x4040, 63);
+        assert_eq!(fds.read(0x4040), Some(0));
+
+        // A frequency of 0x800 steps the wave every 32 cycles
+        fds.write(0x4080, 0x80 | 32);
+        fds.write(0x4082, 0x00);
+        fds.write(0x4083, 0x08);
+        for _ in 0..160 {
+            fds.clock();
+        }
+        assert_eq!(fds.wave_pos, 5);
+        assert!(fds.output() > 0.0);
+    }
+}
@@ -0,0 +1,248 @@
+use crate::apu::envelope::Envelope;
+use crate::apu::expansion::{ExpansionAudio, APU_PULSE_LEVEL};
+use crate::apu::length::LengthCounter;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// Nintendo MMC5, two pulse channels like the 2A03's(without sweep) and an 8-bit PCM channel
+// 0x5000-0x5003 Pulse 1
+// 0x5004-0x5007 Pulse 2
+// 0x5010 PCM mode and IRQ
+// 0x5011 PCM level
+// 0x5015 Channel enable and status
+// The pulses are as loud as the 2A03's, PCM at full scale is about as loud as the DMC at full scale
+const PULSE_LEVEL: f32 = APU_PULSE_LEVEL / 15.0;
+const PCM_LEVEL: f32 = 0.0022;
+const FRAME_PERIOD: u16 = 7457; // The envelopes and length counters are clocked at a fixed 240Hz
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+struct MMC5Pulse {
+    duty: u8,
+    duty_pos: u8,
+    timer_period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+}
+
+impl MMC5Pulse {
+    fn new() -> Self {
+        MMC5Pulse {
+            duty: 0,
+            duty_pos: 0,
+            timer_period: 0,
+            timer: 0,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+        }
+    }
+
+    // Same layout as 0x4000-0x4003, the sweep register does nothing
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            2 => self.timer_period = (self.timer_period & 0x700) | data as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0xFF) | ((data as u16 & 0b111) << 8);
+                self.length.load(data);
+                self.envelope.restart();
+                self.duty_pos = 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_pos = (self.duty_pos + 1) & 0b111;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Unlike the 2A03 there is no sweep unit, so low periods aren't muted
+    fn output(&self) -> u8 {
+        if DUTY_TABLE[self.duty as usize][self.duty_pos as usize] == 0 || !self.length.is_active() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+pub struct MMC5Audio {
+    pulse1: MMC5Pulse,
+    pulse2: MMC5Pulse,
+    pcm: u8,
+    pcm_read_mode: bool, // Reading mode samples reads from 0x8000-0xBFFF, which the mapper handles
+    pcm_irq_enabled: bool,
+    pcm_irq: bool,
+    frame_timer: u16,
+    cycles: usize,
+}
+
+impl MMC5Audio {
+    pub fn new() -> Self {
+        MMC5Audio {
+            pulse1: MMC5Pulse::new(),
+            pulse2: MMC5Pulse::new(),
+            pcm: 0,
+            pcm_read_mode: false,
+            pcm_irq_enabled: false,
+            pcm_irq: false,
+            frame_timer: FRAME_PERIOD,
+            cycles: 0,
+        }
+    }
+
+    // In reading mode every CPU read from 0x8000-0xBFFF is latched as the PCM level, 0 raises the IRQ
+    pub fn pcm_read(&mut self, data: u8) {
+        if !self.pcm_read_mode {
+            return;
+        }
+        if data == 0 {
+            self.pcm_irq = true;
+        } else {
+            self.pcm = data;
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.pcm_irq && self.pcm_irq_enabled
+    }
+
+    // 0x5010 without acknowledging the IRQ
+    pub fn status(&self) -> u8 {
+        if self.irq() {
+            0b1000_0000
+        } else {
+            0
+        }
+    }
+}
+
+impl Default for MMC5Audio {
+    fn default() -> Self {
+        MMC5Audio::new()
+    }
+}
+
+impl ExpansionAudio for MMC5Audio {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5003 => self.pulse1.write(addr - 0x5000, data),
+            0x5004..=0x5007 => self.pulse2.write(addr - 0x5004, data),
+            // I--- ---M
+            0x5010 => {
+                self.pcm_irq_enabled = data & 0b1000_0000 != 0;
+                self.pcm_read_mode = data & 1 != 0;
+            }
+            // Writing 0 has no effect in writing mode
+            0x5011 if !self.pcm_read_mode && data != 0 => self.pcm = data,
+            // ---- --21
+            0x5015 => {
+                self.pulse1.length.set_enabled(data & 0b01 != 0);
+                self.pulse2.length.set_enabled(data & 0b10 != 0);
+            }
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            // I--- ---- reading acknowledges the IRQ
+            0x5010 => {
+                let status = self.status();
+                self.pcm_irq = false;
+                Some(status)
+            }
+            // ---- --21 length counter status
+            0x5015 => {
+                let mut status = 0;
+                if self.pulse1.length.is_active() {
+                    status |= 0b01;
+                }
+                if self.pulse2.length.is_active() {
+                    status |= 0b10;
+                }
+                Some(status)
+            }
+            _ => None,
+        }
+    }
+
+    fn clock(&mut self) {
+        self.cycles += 1;
+        if self.cycles & 1 == 0 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.frame_timer -= 1;
+        if self.frame_timer == 0 {
+            self.frame_timer = FRAME_PERIOD;
+            for pulse in [&mut self.pulse1, &mut self.pulse2] {
+                pulse.envelope.clock();
+                pulse.length.clock();
+            }
+        }
+    }
+
+    fn output(&self) -> f32 {
+        (self.pulse1.output() + self.pulse2.output()) as f32 * PULSE_LEVEL
+            + self.pcm as f32 * PCM_LEVEL
+    }
+}
+
+impl Savestate for MMC5Pulse {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.duty);
+        w.u8(self.duty_pos);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+        w.component(&self.envelope);
+        w.component(&self.length);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.duty = r.u8();
+        self.duty_pos = r.u8();
+        self.timer_period = r.u16();
+        self.timer = r.u16();
+        r.component(&mut self.envelope);
+        r.component(&mut self.length);
+    }
+}
+
+impl Savestate for MMC5Audio {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.component(&self.pulse1);
+        w.component(&self.pulse2);
+        w.u8(self.pcm);
+        w.bool(self.pcm_read_mode);
+        w.bool(self.pcm_irq_enabled);
+        w.bool(self.pcm_irq);
+        w.u16(self.frame_timer);
+        w.usize(self.cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.component(&mut self.pulse1);
+        r.component(&mut self.pulse2);
+        self.pcm = r.u8();
+        self.pcm_read_mode = r.bool();
+        self.pcm_irq_enabled = r.bool();
+        self.pcm_irq = r.bool();
+        self.frame_timer = r.u16();
+        self.cycles = r.usize();
+    }
+}
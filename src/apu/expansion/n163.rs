@@ -0,0 +1,189 @@
+use crate::apu::expansion::{ExpansionAudio, APU_PULSE_LEVEL};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// Namco 163, up to 8 wavetable channels stored in 128 bytes of internal RAM
+// 0xF800 RAM address, bit 7 auto-increments it on every access
+// 0x4800 RAM data
+// Each channel's registers live at the top of the RAM, channel 7 at 0x78-0x7F and channel 0 at 0x40-0x47
+// 0x78 Frequency low      0x79 Phase low
+// 0x7A Frequency middle   0x7B Phase middle
+// 0x7C LLLL LLFF, wave length 256 - L * 4 and frequency high
+// 0x7D Phase high         0x7E Wave address
+// 0x7F -CCC VVVV, enabled channel count(0x7F only) and volume
+// The chip updates one channel every 15 CPU cycles and only outputs that channel, so the more channels are
+// enabled the lower each one's pitch. The multiplexed output is averaged instead of reproducing the whine
+// A single channel at full swing is about twice as loud as a 2A03 pulse
+const LEVEL: f32 = APU_PULSE_LEVEL * 2.0 / 105.0;
+const CYCLES_PER_CHANNEL: u8 = 15;
+
+pub struct N163Audio {
+    ram: [u8; 128],
+    addr: u8,
+    auto_increment: bool,
+    outputs: [i16; 8], // Last output of each channel, (sample - 8) * volume
+    current: u8,       // Channel being updated
+    timer: u8,
+    pub disabled: bool, // Set through the mapper's 0xE000 register
+}
+
+impl N163Audio {
+    pub fn new() -> Self {
+        N163Audio {
+            ram: [0; 128],
+            addr: 0,
+            auto_increment: false,
+            outputs: [0; 8],
+            current: 7,
+            timer: CYCLES_PER_CHANNEL,
+            disabled: false,
+        }
+    }
+
+    // Channels count down from 7, with 1 to 8 enabled
+    fn enabled_channels(&self) -> u8 {
+        ((self.ram[0x7F] >> 4) & 0b111) + 1
+    }
+
+    // 0x4800 without moving the address on
+    pub fn peek(&self) -> u8 {
+        self.ram[self.addr as usize]
+    }
+
+    fn next_addr(&mut self) {
+        if self.auto_increment {
+            self.addr = (self.addr + 1) & 0x7F;
+        }
+    }
+
+    // Samples are 4 bits, packed low nibble first
+    fn sample(&self, index: u8) -> u8 {
+        let byte = self.ram[(index >> 1) as usize & 0x7F];
+        if index & 1 == 0 {
+            byte & 0x0F
+        } else {
+            byte >> 4
+        }
+    }
+
+    fn update_channel(&mut self, channel: u8) {
+        let base = 0x40 + channel as usize * 8;
+        let freq = self.ram[base] as u32
+            | (self.ram[base + 2] as u32) << 8
+            | (self.ram[base + 4] as u32 & 0b11) << 16;
+        let mut phase = self.ram[base + 1] as u32
+            | (self.ram[base + 3] as u32) << 8
+            | (self.ram[base + 5] as u32) << 16;
+        let length = 256 - (self.ram[base + 4] as u32 & 0xFC);
+
+        phase = (phase + freq) % (length << 16);
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+
+        let index = ((phase >> 16) as u8).wrapping_add(self.ram[base + 6]);
+        let volume = (self.ram[base + 7] & 0x0F) as i16;
+        self.outputs[channel as usize] = (self.sample(index) as i16 - 8) * volume;
+    }
+}
+
+impl Default for N163Audio {
+    fn default() -> Self {
+        N163Audio::new()
+    }
+}
+
+impl ExpansionAudio for N163Audio {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4800..=0x4FFF => {
+                self.ram[self.addr as usize] = data;
+                self.next_addr();
+            }
+            0xF800..=0xFFFF => {
+                self.addr = data & 0x7F;
+                self.auto_increment = data & 0b1000_0000 != 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800..=0x4FFF => {
+                let data = self.ram[self.addr as usize];
+                self.next_addr();
+                Some(data)
+            }
+            _ => None,
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.disabled {
+            return;
+        }
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = CYCLES_PER_CHANNEL;
+        let channel = self.current;
+        self.update_channel(channel);
+        let lowest = 8 - self.enabled_channels();
+        self.current = if channel <= lowest { 7 } else { channel - 1 };
+    }
+
+    fn output(&self) -> f32 {
+        if self.disabled {
+            return 0.0;
+        }
+        let count = self.enabled_channels();
+        let first = 8 - count as usize;
+        let sum: i16 = self.outputs[first..].iter().sum();
+        sum as f32 / count as f32 * LEVEL
+    }
+}
+
+impl Savestate for N163Audio {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.u8(self.addr);
+        w.bool(self.auto_increment);
+        for output in self.outputs {
+            w.u16(output as u16);
+        }
+        w.u8(self.current);
+        w.u8(self.timer);
+        w.bool(self.disabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes_into(&mut self.ram);
+        self.addr = r.u8();
+        self.auto_increment = r.bool();
+        for output in self.outputs.iter_mut() {
+            *output = r.u16() as i16;
+        }
+        self.current = r.u8();
+        self.timer = r.u8();
+        self.disabled = r.bool();
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_n163_ram_port() {
+        let mut n163 = N163Audio::new();
+        n163.write(0xF800, 0x80 | 0x10);
+        n163.write(0x4800, 0xAB);
+        n163.write(0x4800, 0xCD);
+        n163.write(0xF800, 0x80 | 0x10);
+        assert_eq!(n163.read(0x4800), Some(0xAB));
+        assert_eq!(n163.read(0x4800), Some(0xCD));
+        assert_eq!(n163.sample(0x20), 0x0B);
+        assert_eq!(n163.sample(0x21), 0x0A);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::apu::expansion::{ExpansionAudio, APU_PULSE_LEVEL};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// Sunsoft 5B, a YM2149(AY-3-8910 compatible) inside the FME-7 mapper
+// 0xC000 Register select
+// 0xE000 Register write
+// 0x00-0x05 Tone period of channels A, B and C, 12 bits each
+// 0x06 Noise period
+// 0x07 --CB Acba, noise(upper) and tone(lower) disable for each channel
+// 0x08-0x0A ---E VVVV, envelope mode and volume for each channel
+// 0x0B-0x0C Envelope period
+// 0x0D Envelope shape, writing restarts the envelope
+// Volume is logarithmic, 3dB per step of the register and 1.5dB per step of the envelope
+// A channel at full volume is about as loud as two 2A03 pulses
+const LEVEL: f32 = APU_PULSE_LEVEL * 2.0;
+const CLOCK_DIVIDER: u8 = 16; // The tone, noise and envelope units step every 16 CPU cycles
+
+pub struct Sunsoft5BAudio {
+    regs: [u8; 16],
+    selected: u8,
+    levels: [f32; 32],
+
+    tone_timers: [u16; 3],
+    tone_outputs: [bool; 3],
+    noise_timer: u8,
+    noise_lfsr: u32, // 17 bit LFSR
+    envelope_timer: u16,
+    envelope_step: u8, // 0-31, counting from loud to quiet before the shape is applied
+    envelope_holding: bool,
+    envelope_flip: bool, // Alternating shapes flip direction every cycle
+    divider: u8,
+}
+
+impl Sunsoft5BAudio {
+    pub fn new() -> Self {
+        // Level 0 is silent, every step above it is 1.5dB louder up to 31
+        let mut levels = [0.0; 32];
+        for (i, level) in levels.iter_mut().enumerate().skip(1) {
+            *level = 10f32.powf(-1.5 * (31 - i) as f32 / 20.0);
+        }
+        Sunsoft5BAudio {
+            regs: [0; 16],
+            selected: 0,
+            levels,
+            tone_timers: [0; 3],
+            tone_outputs: [false; 3],
+            noise_timer: 0,
+            noise_lfsr: 1,
+            envelope_timer: 0,
+            envelope_step: 0,
+            envelope_holding: false,
+            envelope_flip: false,
+            divider: CLOCK_DIVIDER,
+        }
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        (self.regs[channel * 2] as u16 | (self.regs[channel * 2 + 1] as u16 & 0x0F) << 8).max(1)
+    }
+
+    fn envelope_period(&self) -> u16 {
+        (self.regs[0x0B] as u16 | (self.regs[0x0C] as u16) << 8).max(1)
+    }
+
+    fn restart_envelope(&mut self) {
+        self.envelope_timer = 0;
+        self.envelope_step = 0;
+        self.envelope_holding = false;
+        self.envelope_flip = false;
+    }
+
+    // CAtH, continue, attack, alternate and hold
+    fn envelope_level(&self) -> u8 {
+        let shape = self.regs[0x0D];
+        let attack = shape & 0b0100 != 0;
+        if self.envelope_holding {
+            let continues = shape & 0b1000 != 0;
+            let alternate = shape & 0b0010 != 0;
+            // Shapes without continue drop to 0 after one cycle, the rest hold their last level
+            // unless alternate flips it
+            return match (continues, attack ^ alternate) {
+                (false, _) => 0,
+                (true, true) => 31,
+                (true, false) => 0,
+            };
+        }
+        let rising = attack ^ self.envelope_flip;
+        if rising {
+            self.envelope_step
+        } else {
+            31 - self.envelope_step
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        self.envelope_timer += 1;
+        if self.envelope_timer < self.envelope_period() {
+            return;
+        }
+        self.envelope_timer = 0;
+        if self.envelope_step < 31 {
+            self.envelope_step += 1;
+            return;
+        }
+        let shape = self.regs[0x0D];
+        let continues = shape & 0b1000 != 0;
+        let hold = shape & 0b0001 != 0;
+        if !continues || hold {
+            self.envelope_holding = true;
+        } else {
+            self.envelope_step = 0;
+            if shape & 0b0010 != 0 {
+                self.envelope_flip = !self.envelope_flip;
+            }
+        }
+    }
+
+    fn channel_output(&self, channel: usize) -> f32 {
+        let mixer = self.regs[0x07];
+        let tone = self.tone_outputs[channel] || mixer & (1 << channel) != 0;
+        let noise = self.noise_lfsr & 1 != 0 || mixer & (0b1000 << channel) != 0;
+        if !(tone && noise) {
+            return 0.0;
+        }
+        let volume = self.regs[0x08 + channel];
+        let level = if volume & 0b1_0000 != 0 {
+            self.envelope_level()
+        } else if volume & 0x0F == 0 {
+            0
+        } else {
+            (volume & 0x0F) * 2 + 1
+        };
+        self.levels[level as usize]
+    }
+}
+
+impl Default for Sunsoft5BAudio {
+    fn default() -> Self {
+        Sunsoft5BAudio::new()
+    }
+}
+
+impl ExpansionAudio for Sunsoft5BAudio {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr & 0xE000 {
+            0xC000 => self.selected = data & 0x0F,
+            0xE000 => {
+                self.regs[self.selected as usize] = data;
+                if self.selected == 0x0D {
+                    self.restart_envelope();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        self.divider -= 1;
+        if self.divider > 0 {
+            return;
+        }
+        self.divider = CLOCK_DIVIDER;
+
+        for channel in 0..3 {
+            self.tone_timers[channel] += 1;
+            if self.tone_timers[channel] >= self.tone_period(channel) {
+                self.tone_timers[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+
+        // The noise runs at half the tone rate
+        self.noise_timer += 1;
+        if self.noise_timer >= (self.regs[0x06] & 0x1F).max(1) * 2 {
+            self.noise_timer = 0;
+            let feedback = (self.noise_lfsr ^ (self.noise_lfsr >> 3)) & 1;
+            self.noise_lfsr = (self.noise_lfsr >> 1) | (feedback << 16);
+        }
+
+        self.clock_envelope();
+    }
+
+    fn output(&self) -> f32 {
+        (0..3)
+            .map(|channel| self.channel_output(channel))
+            .sum::<f32>()
+            * LEVEL
+    }
+}
+
+// levels is a constant table
+impl Savestate for Sunsoft5BAudio {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.regs);
+        w.u8(self.selected);
+        for (timer, output) in self.tone_timers.iter().zip(self.tone_outputs) {
+            w.u16(*timer);
+            w.bool(output);
+        }
+        w.u8(self.noise_timer);
+        w.u32(self.noise_lfsr);
+        w.u16(self.envelope_timer);
+        w.u8(self.envelope_step);
+        w.bool(self.envelope_holding);
+        w.bool(self.envelope_flip);
+        w.u8(self.divider);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes_into(&mut self.regs);
+        self.selected = r.u8();
+        for (timer, output) in self
+            .tone_timers
+            .iter_mut()
+            .zip(self.tone_outputs.iter_mut())
+        {
+            *timer = r.u16();
+            *output = r.bool();
+        }
+        self.noise_timer = r.u8();
+        self.noise_lfsr = r.u32();
+        self.envelope_timer = r.u16();
+        self.envelope_step = r.u8();
+        self.envelope_holding = r.bool();
+        self.envelope_flip = r.bool();
+        self.divider = r.u8();
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_5b_tone_period() {
+        let mut chip = Sunsoft5BAudio::new();
+        chip.write(0xC000, 0x00);
+        chip.write(0xE000, 4);
+        chip.write(0xC000, 0x07);
+        chip.write(0xE000, 0b0011_1110); // Tone A only
+        chip.write(0xC000, 0x08);
+        chip.write(0xE000, 0x0F);
+
+        // A period of 4 toggles every 64 CPU cycles
+        let mut toggles = 0;
+        let mut last = chip.output();
+        for _ in 0..640 {
+            chip.clock();
+            let output = chip.output();
+            if output != last {
+                toggles += 1;
+                last = output;
+            }
+        }
+        assert_eq!(toggles, 10);
+        assert!((chip.levels[31] - 1.0).abs() < 1e-6);
+    }
+}
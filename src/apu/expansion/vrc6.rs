@@ -0,0 +1,281 @@
+use crate::apu::expansion::{ExpansionAudio, APU_PULSE_LEVEL};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// Konami VRC6, two pulse channels with 8 duty cycles and a sawtooth
+// 0x9000-0x9002 Pulse 1
+// 0xA000-0xA002 Pulse 2
+// 0xB000-0xB002 Sawtooth
+// 0x9003 Frequency scaling and halt
+// The channels are summed linearly, a full volume pulse is about as loud as a 2A03 pulse
+const LEVEL: f32 = APU_PULSE_LEVEL / 15.0;
+
+struct VRC6Pulse {
+    mode: bool, // Ignores the duty cycle and outputs the volume constantly
+    duty: u8,
+    volume: u8,
+    period: u16,
+    timer: u16,
+    step: u8, // Counts down from 15, the output is high while step <= duty
+    enabled: bool,
+}
+
+impl VRC6Pulse {
+    fn new() -> Self {
+        VRC6Pulse {
+            mode: false,
+            duty: 0,
+            volume: 0,
+            period: 0,
+            timer: 0,
+            step: 15,
+            enabled: false,
+        }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            // MDDD VVVV
+            0 => {
+                self.mode = data & 0b1000_0000 != 0;
+                self.duty = (data >> 4) & 0b111;
+                self.volume = data & 0b1111;
+            }
+            1 => self.period = (self.period & 0xF00) | data as u16,
+            // E--- PPPP, disabling resets the duty cycle
+            2 => {
+                self.period = (self.period & 0xFF) | ((data as u16 & 0b1111) << 8);
+                self.enabled = data & 0b1000_0000 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0b1111;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.mode || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+struct VRC6Saw {
+    rate: u8, // Added to the accumulator every other step
+    accumulator: u8,
+    period: u16,
+    timer: u16,
+    step: u8, // 14 steps per cycle of the saw
+    enabled: bool,
+}
+
+impl VRC6Saw {
+    fn new() -> Self {
+        VRC6Saw {
+            rate: 0,
+            accumulator: 0,
+            period: 0,
+            timer: 0,
+            step: 0,
+            enabled: false,
+        }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            // --AA AAAA
+            0 => self.rate = data & 0b11_1111,
+            1 => self.period = (self.period & 0xF00) | data as u16,
+            // E--- PPPP
+            2 => {
+                self.period = (self.period & 0xFF) | ((data as u16 & 0b1111) << 8);
+                self.enabled = data & 0b1000_0000 != 0;
+                if !self.enabled {
+                    self.accumulator = 0;
+                    self.step = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step & 1 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    // Top 5 bits of the accumulator
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+pub struct VRC6Audio {
+    pulse1: VRC6Pulse,
+    pulse2: VRC6Pulse,
+    saw: VRC6Saw,
+    halt: bool,
+    shift: u8, // Frequency scaling from 0x9003, the periods are shifted right by 4 or 8
+}
+
+impl VRC6Audio {
+    pub fn new() -> Self {
+        VRC6Audio {
+            pulse1: VRC6Pulse::new(),
+            pulse2: VRC6Pulse::new(),
+            saw: VRC6Saw::new(),
+            halt: false,
+            shift: 0,
+        }
+    }
+}
+
+impl Default for VRC6Audio {
+    fn default() -> Self {
+        VRC6Audio::new()
+    }
+}
+
+impl ExpansionAudio for VRC6Audio {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x9000..=0x9002 => self.pulse1.write(addr - 0x9000, data),
+            0xA000..=0xA002 => self.pulse2.write(addr - 0xA000, data),
+            0xB000..=0xB002 => self.saw.write(addr - 0xB000, data),
+            // ---- -ABH, B takes priority over A
+            0x9003 => {
+                self.halt = data & 0b001 != 0;
+                self.shift = if data & 0b100 != 0 {
+                    8
+                } else if data & 0b010 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.halt {
+            return;
+        }
+        self.pulse1.clock(self.shift);
+        self.pulse2.clock(self.shift);
+        self.saw.clock(self.shift);
+    }
+
+    fn output(&self) -> f32 {
+        (self.pulse1.output() + self.pulse2.output() + self.saw.output()) as f32 * LEVEL
+    }
+}
+
+impl Savestate for VRC6Pulse {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.mode);
+        w.u8(self.duty);
+        w.u8(self.volume);
+        w.u16(self.period);
+        w.u16(self.timer);
+        w.u8(self.step);
+        w.bool(self.enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.mode = r.bool();
+        self.duty = r.u8();
+        self.volume = r.u8();
+        self.period = r.u16();
+        self.timer = r.u16();
+        self.step = r.u8();
+        self.enabled = r.bool();
+    }
+}
+
+impl Savestate for VRC6Saw {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.rate);
+        w.u8(self.accumulator);
+        w.u16(self.period);
+        w.u16(self.timer);
+        w.u8(self.step);
+        w.bool(self.enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.rate = r.u8();
+        self.accumulator = r.u8();
+        self.period = r.u16();
+        self.timer = r.u16();
+        self.step = r.u8();
+        self.enabled = r.bool();
+    }
+}
+
+impl Savestate for VRC6Audio {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.component(&self.pulse1);
+        w.component(&self.pulse2);
+        w.component(&self.saw);
+        w.bool(self.halt);
+        w.u8(self.shift);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.component(&mut self.pulse1);
+        r.component(&mut self.pulse2);
+        r.component(&mut self.saw);
+        self.halt = r.bool();
+        self.shift = r.u8();
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_vrc6_saw_ramp() {
+        let mut vrc6 = VRC6Audio::new();
+        vrc6.write(0xB000, 42);
+        vrc6.write(0xB001, 0);
+        vrc6.write(0xB002, 0x80);
+        // With a period of 0 every clock is a step, the accumulator grows every other step
+        let mut levels = Vec::new();
+        for _ in 0..14 {
+            vrc6.clock();
+            levels.push(vrc6.saw.output());
+        }
+        assert_eq!(levels[..4], [0, 5, 5, 10]);
+        assert_eq!(*levels.iter().max().unwrap(), 252 >> 3);
+        assert_eq!(levels[13], 0);
+    }
+}
@@ -0,0 +1,516 @@
+use crate::apu::expansion::{ExpansionAudio, APU_PULSE_LEVEL};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use std::f32::consts::TAU;
+
+// Konami VRC7, a cut down YM2413(OPLL) with 6 two operator FM channels
+// 0x9010 Register select
+// 0x9030 Register write
+// 0x00-0x07 Custom instrument
+// 0x10-0x15 Frequency low 8 bits
+// 0x20-0x25 --SK BBBF, sustain, key on, block(octave) and frequency high bit
+// 0x30-0x35 IIII VVVV, instrument(0 is the custom one) and volume(3dB attenuation steps)
+// This isn't a bit exact OPLL, the operators are calculated in floating point from the documented rates and
+// levels, which is close enough to sound right
+// A channel at full volume swings about as far as a 2A03 pulse
+const LEVEL: f32 = APU_PULSE_LEVEL / 2.0;
+const SAMPLE_CYCLES: u8 = 36; // The OPLL makes a sample every 72 clocks of its 3.58MHz crystal
+const SAMPLE_RATE: f32 = 3_579_545.0 / 72.0;
+const MAX_ATTENUATION: f32 = 48.0; // dB, the envelope generator's range
+const MOD_DEPTH: f32 = 2.0; // Cycles of phase shift a full scale modulator puts on the carrier
+const VIBRATO_RATE: f32 = 6.4; // Hz
+const VIBRATO_CENTS: f32 = 13.75;
+const TREMOLO_RATE: f32 = 3.7; // Hz
+const TREMOLO_DB: f32 = 4.8;
+
+// Frequency multipliers selected by MULT, 0 is half
+const MULTIPLIERS: [f32; 16] = [
+    0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0,
+];
+
+// Key scale level attenuation in dB at block 7, indexed by the top 4 bits of the frequency
+const KSL_TABLE: [f32; 16] = [
+    0.0, 9.0, 12.0, 13.875, 15.0, 16.125, 16.875, 17.625, 18.0, 18.75, 19.125, 19.5, 19.875, 20.25,
+    20.625, 21.0,
+];
+
+// Built in instruments 1-15, dumped from the chip
+// Modulator/carrier flags, modulator level, wave and feedback, attack/decay, sustain/release
+const PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum EnvelopeStage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+// One half of an instrument, byte 0 describes the modulator and byte 1 the carrier
+#[derive(Clone, Copy)]
+struct OperatorPatch {
+    tremolo: bool,
+    vibrato: bool,
+    sustained: bool, // Holds at the sustain level instead of carrying on decaying
+    key_scale_rate: bool,
+    multiplier: f32,
+    key_scale_level: u8,
+    rectified: bool, // Half sine wave
+    attack: u8,
+    decay: u8,
+    sustain_level: f32,
+    release: u8,
+}
+
+impl OperatorPatch {
+    fn new(patch: &[u8; 8], carrier: bool) -> Self {
+        let i = carrier as usize;
+        let flags = patch[i];
+        OperatorPatch {
+            tremolo: flags & 0b1000_0000 != 0,
+            vibrato: flags & 0b0100_0000 != 0,
+            sustained: flags & 0b0010_0000 != 0,
+            key_scale_rate: flags & 0b0001_0000 != 0,
+            multiplier: MULTIPLIERS[(flags & 0x0F) as usize],
+            key_scale_level: patch[2 + i] >> 6,
+            rectified: patch[3] & if carrier { 0b1_0000 } else { 0b1000 } != 0,
+            attack: patch[4 + i] >> 4,
+            decay: patch[4 + i] & 0x0F,
+            sustain_level: (patch[6 + i] >> 4) as f32 * 3.0,
+            release: patch[6 + i] & 0x0F,
+        }
+    }
+}
+
+struct Operator {
+    phase: f32, // In cycles
+    attenuation: f32,
+    stage: EnvelopeStage,
+}
+
+impl Operator {
+    fn new() -> Self {
+        Operator {
+            phase: 0.0,
+            attenuation: MAX_ATTENUATION,
+            stage: EnvelopeStage::Off,
+        }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.stage = EnvelopeStage::Attack;
+    }
+
+    fn key_off(&mut self) {
+        if self.stage != EnvelopeStage::Off {
+            self.stage = EnvelopeStage::Release;
+        }
+    }
+
+    // rate is the effective 0-63 rate, 4 times the register plus the key scaling
+    fn decay_step(rate: u8) -> f32 {
+        if rate < 4 {
+            return 0.0;
+        }
+        // Rate 4 takes about 39 seconds to fall 96dB, each step of 4 doubles the speed
+        96.0 / (39.28 * SAMPLE_RATE) * 2f32.powf((rate - 4) as f32 / 4.0)
+    }
+
+    fn clock_envelope(&mut self, patch: &OperatorPatch, key_scale: u8, channel_sustain: bool) {
+        let rate = |register: u8| {
+            if register == 0 {
+                0
+            } else {
+                (register * 4 + key_scale).min(63)
+            }
+        };
+        match self.stage {
+            EnvelopeStage::Attack => {
+                let attack = rate(patch.attack);
+                if attack >= 60 {
+                    self.attenuation = 0.0;
+                } else if attack >= 4 {
+                    // Exponential attack, rate 4 takes about 2.8 seconds
+                    let speed = 8.0 * 2f32.powf((attack - 4) as f32 / 4.0) / (2.826 * SAMPLE_RATE);
+                    self.attenuation *= 1.0 - speed.min(1.0);
+                }
+                if self.attenuation < 0.05 {
+                    self.attenuation = 0.0;
+                    self.stage = EnvelopeStage::Decay;
+                }
+            }
+            EnvelopeStage::Decay => {
+                self.attenuation += Operator::decay_step(rate(patch.decay));
+                if self.attenuation >= patch.sustain_level {
+                    self.attenuation = patch.sustain_level;
+                    self.stage = EnvelopeStage::Sustain;
+                }
+            }
+            EnvelopeStage::Sustain => {
+                // Percussive sounds keep fading with the release rate while the key is held
+                if !patch.sustained {
+                    self.attenuation += Operator::decay_step(rate(patch.release));
+                }
+            }
+            EnvelopeStage::Release => {
+                let release = if channel_sustain {
+                    5
+                } else if patch.sustained {
+                    patch.release
+                } else {
+                    7
+                };
+                self.attenuation += Operator::decay_step(rate(release));
+            }
+            EnvelopeStage::Off => {}
+        }
+        if self.attenuation >= MAX_ATTENUATION {
+            self.attenuation = MAX_ATTENUATION;
+            if self.stage != EnvelopeStage::Attack {
+                self.stage = EnvelopeStage::Off;
+            }
+        }
+    }
+
+    // phase_offset is in cycles and attenuation in dB, on top of the envelope
+    fn output(&self, patch: &OperatorPatch, phase_offset: f32, attenuation: f32) -> f32 {
+        if self.stage == EnvelopeStage::Off {
+            return 0.0;
+        }
+        let mut wave = (TAU * (self.phase + phase_offset)).sin();
+        if patch.rectified && wave < 0.0 {
+            wave = 0.0;
+        }
+        wave * 10f32.powf(-(self.attenuation + attenuation) / 20.0)
+    }
+}
+
+struct FMChannel {
+    fnum: u16, // 9 bits
+    block: u8,
+    sustain: bool,
+    key: bool,
+    instrument: u8,
+    volume: u8,
+    modulator: Operator,
+    carrier: Operator,
+    feedback: [f32; 2], // Last two modulator outputs
+    output: f32,
+}
+
+impl FMChannel {
+    fn new() -> Self {
+        FMChannel {
+            fnum: 0,
+            block: 0,
+            sustain: false,
+            key: false,
+            instrument: 0,
+            volume: 0,
+            modulator: Operator::new(),
+            carrier: Operator::new(),
+            feedback: [0.0; 2],
+            output: 0.0,
+        }
+    }
+
+    fn set_key(&mut self, key: bool) {
+        if key && !self.key {
+            self.modulator.key_on();
+            self.carrier.key_on();
+        } else if !key && self.key {
+            self.modulator.key_off();
+            self.carrier.key_off();
+        }
+        self.key = key;
+    }
+
+    fn key_scale_rate(&self, patch: &OperatorPatch) -> u8 {
+        let ksr = (self.block << 1) | (self.fnum >> 8) as u8;
+        if patch.key_scale_rate {
+            ksr
+        } else {
+            ksr >> 2
+        }
+    }
+
+    // Higher notes are quieter by 1.5, 3 or 6dB per octave
+    fn key_scale_level(&self, patch: &OperatorPatch) -> f32 {
+        let scale = [0.0, 0.5, 1.0, 2.0][patch.key_scale_level as usize];
+        let level = KSL_TABLE[(self.fnum >> 5) as usize] - 6.0 * (7 - self.block) as f32;
+        level.max(0.0) * scale
+    }
+
+    fn clock(&mut self, patch: &[u8; 8], vibrato: f32, tremolo: f32) {
+        let modulator = OperatorPatch::new(patch, false);
+        let carrier = OperatorPatch::new(patch, true);
+
+        // Phase increment in cycles per sample before the multiplier
+        let base = self.fnum as f32 * 2f32.powi(self.block as i32 - 1) / (1 << 18) as f32 * vibrato;
+        for (operator, op_patch) in [
+            (&mut self.modulator, &modulator),
+            (&mut self.carrier, &carrier),
+        ] {
+            let step = if op_patch.vibrato {
+                base
+            } else {
+                base / vibrato
+            };
+            operator.phase = (operator.phase + step * op_patch.multiplier).fract();
+        }
+        let ksr = self.key_scale_rate(&modulator);
+        self.modulator.clock_envelope(&modulator, ksr, self.sustain);
+        let ksr = self.key_scale_rate(&carrier);
+        self.carrier.clock_envelope(&carrier, ksr, self.sustain);
+
+        let feedback = patch[3] & 0b111;
+        let feedback_phase = if feedback == 0 {
+            0.0
+        } else {
+            (self.feedback[0] + self.feedback[1]) / 2.0 * 2f32.powi(feedback as i32 - 1) / 32.0
+        };
+        let mod_level = (patch[2] & 0b11_1111) as f32 * 0.75
+            + self.key_scale_level(&modulator)
+            + if modulator.tremolo { tremolo } else { 0.0 };
+        let mod_out = self.modulator.output(&modulator, feedback_phase, mod_level);
+        self.feedback = [self.feedback[1], mod_out];
+
+        let car_level = self.volume as f32 * 3.0
+            + self.key_scale_level(&carrier)
+            + if carrier.tremolo { tremolo } else { 0.0 };
+        self.output = self
+            .carrier
+            .output(&carrier, mod_out * MOD_DEPTH, car_level);
+    }
+}
+
+pub struct VRC7Audio {
+    selected: u8,
+    custom: [u8; 8],
+    channels: [FMChannel; 6],
+    timer: u8,
+    lfo_time: f32,      // Seconds, drives vibrato and tremolo
+    pub silenced: bool, // Set through the mapper's 0xE000 register, also resets the chip
+}
+
+impl VRC7Audio {
+    pub fn new() -> Self {
+        VRC7Audio {
+            selected: 0,
+            custom: [0; 8],
+            channels: std::array::from_fn(|_| FMChannel::new()),
+            timer: SAMPLE_CYCLES,
+            lfo_time: 0.0,
+            silenced: false,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.custom = [0; 8];
+        self.channels = std::array::from_fn(|_| FMChannel::new());
+    }
+
+    fn write_register(&mut self, reg: u8, data: u8) {
+        match reg {
+            0x00..=0x07 => self.custom[reg as usize] = data,
+            0x10..=0x15 => {
+                let channel = &mut self.channels[(reg - 0x10) as usize];
+                channel.fnum = (channel.fnum & 0x100) | data as u16;
+            }
+            0x20..=0x25 => {
+                let channel = &mut self.channels[(reg - 0x20) as usize];
+                channel.fnum = (channel.fnum & 0xFF) | ((data as u16 & 1) << 8);
+                channel.block = (data >> 1) & 0b111;
+                channel.sustain = data & 0b10_0000 != 0;
+                channel.set_key(data & 0b1_0000 != 0);
+            }
+            0x30..=0x35 => {
+                let channel = &mut self.channels[(reg - 0x30) as usize];
+                channel.instrument = data >> 4;
+                channel.volume = data & 0x0F;
+            }
+            _ => {}
+        }
+    }
+
+    fn patch(&self, instrument: u8) -> [u8; 8] {
+        if instrument == 0 {
+            self.custom
+        } else {
+            PATCHES[instrument as usize - 1]
+        }
+    }
+}
+
+impl Default for VRC7Audio {
+    fn default() -> Self {
+        VRC7Audio::new()
+    }
+}
+
+impl ExpansionAudio for VRC7Audio {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x9010 => self.selected = data,
+            0x9030 => self.write_register(self.selected, data),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = SAMPLE_CYCLES;
+
+        self.lfo_time += 1.0 / SAMPLE_RATE;
+        let vibrato =
+            2f32.powf(VIBRATO_CENTS / 1200.0 * (TAU * VIBRATO_RATE * self.lfo_time).sin());
+        let tremolo = TREMOLO_DB * (1.0 - (TAU * TREMOLO_RATE * self.lfo_time).cos()) / 2.0;
+        for i in 0..self.channels.len() {
+            let patch = self.patch(self.channels[i].instrument);
+            self.channels[i].clock(&patch, vibrato, tremolo);
+        }
+    }
+
+    fn output(&self) -> f32 {
+        if self.silenced {
+            return 0.0;
+        }
+        self.channels
+            .iter()
+            .map(|channel| channel.output)
+            .sum::<f32>()
+            * LEVEL
+    }
+}
+
+impl Savestate for Operator {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.f32(self.phase);
+        w.f32(self.attenuation);
+        w.u8(match self.stage {
+            EnvelopeStage::Attack => 0,
+            EnvelopeStage::Decay => 1,
+            EnvelopeStage::Sustain => 2,
+            EnvelopeStage::Release => 3,
+            EnvelopeStage::Off => 4,
+        });
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.phase = r.f32();
+        self.attenuation = r.f32();
+        self.stage = match r.u8() {
+            0 => EnvelopeStage::Attack,
+            1 => EnvelopeStage::Decay,
+            2 => EnvelopeStage::Sustain,
+            3 => EnvelopeStage::Release,
+            _ => EnvelopeStage::Off,
+        };
+    }
+}
+
+impl Savestate for FMChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.fnum);
+        w.u8(self.block);
+        w.bool(self.sustain);
+        w.bool(self.key);
+        w.u8(self.instrument);
+        w.u8(self.volume);
+        w.component(&self.modulator);
+        w.component(&self.carrier);
+        w.f32(self.feedback[0]);
+        w.f32(self.feedback[1]);
+        w.f32(self.output);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.fnum = r.u16();
+        self.block = r.u8();
+        self.sustain = r.bool();
+        self.key = r.bool();
+        self.instrument = r.u8();
+        self.volume = r.u8();
+        r.component(&mut self.modulator);
+        r.component(&mut self.carrier);
+        self.feedback = [r.f32(), r.f32()];
+        self.output = r.f32();
+    }
+}
+
+impl Savestate for VRC7Audio {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.selected);
+        w.bytes(&self.custom);
+        for channel in self.channels.iter() {
+            w.component(channel);
+        }
+        w.u8(self.timer);
+        w.f32(self.lfo_time);
+        w.bool(self.silenced);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.selected = r.u8();
+        r.bytes_into(&mut self.custom);
+        for channel in self.channels.iter_mut() {
+            r.component(channel);
+        }
+        self.timer = r.u8();
+        self.lfo_time = r.f32();
+        self.silenced = r.bool();
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn write(vrc7: &mut VRC7Audio, reg: u8, data: u8) {
+        vrc7.write(0x9010, reg);
+        vrc7.write(0x9030, data);
+    }
+
+    #[test]
+    fn test_vrc7_key_on_and_release() {
+        let mut vrc7 = VRC7Audio::new();
+        write(&mut vrc7, 0x10, 0x20);
+        write(&mut vrc7, 0x30, 0x30); // Instrument 3, full volume
+        write(&mut vrc7, 0x20, 0b1_0000 | (4 << 1) | 1);
+
+        let mut peak: f32 = 0.0;
+        for _ in 0..SAMPLE_CYCLES as usize * 2000 {
+            vrc7.clock();
+            peak = peak.max(vrc7.output().abs());
+        }
+        assert!(peak > 0.0 && peak <= LEVEL);
+
+        // Key off releases the note until it's silent
+        write(&mut vrc7, 0x20, (4 << 1) | 1);
+        for _ in 0..SAMPLE_CYCLES as usize * 50000 {
+            vrc7.clock();
+        }
+        assert_eq!(vrc7.channels[0].carrier.stage, EnvelopeStage::Off);
+        assert_eq!(vrc7.output(), 0.0);
+    }
+}
@@ -0,0 +1,106 @@
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// CPU cycles at which each step of the sequence happens, the last entry is the length of the sequence
+const NTSC_4_STEP: [usize; 5] = [7457, 14913, 22371, 29829, 29830];
+const NTSC_5_STEP: [usize; 6] = [7457, 14913, 22371, 29829, 37281, 37282];
+const PAL_4_STEP: [usize; 5] = [8313, 16627, 24939, 33253, 33254];
+const PAL_5_STEP: [usize; 6] = [8313, 16627, 24939, 33253, 41565, 41566];
+
+// Clocks generated by the frame counter for a single CPU cycle
+#[derive(Default)]
+pub struct FrameClock {
+    pub quarter: bool, // Envelopes and the triangle's linear counter
+    pub half: bool,    // Length counters and sweep units
+}
+
+// Frame sequencer at 0x4017
+// 4-step mode raises an IRQ at the end of every sequence unless inhibited, 5-step mode never does
+pub struct FrameCounter {
+    four_step: &'static [usize; 5],
+    five_step: &'static [usize; 6],
+    pub five_step_mode: bool,
+    irq_inhibit: bool,
+    pub irq: bool,
+    cycle: usize,
+}
+
+impl FrameCounter {
+    pub fn new(region: Region) -> Self {
+        let (four_step, five_step) = match region {
+            Region::NTSC | Region::DENDY => (&NTSC_4_STEP, &NTSC_5_STEP),
+            Region::PAL => (&PAL_4_STEP, &PAL_5_STEP),
+        };
+        FrameCounter {
+            four_step,
+            five_step,
+            five_step_mode: false,
+            irq_inhibit: false,
+            irq: false,
+            cycle: 0,
+        }
+    }
+
+    // MI-- ----
+    // Resets the sequence, 5-step mode clocks all units immediately
+    pub fn write(&mut self, data: u8) -> FrameClock {
+        self.five_step_mode = data & 0b1000_0000 != 0;
+        self.irq_inhibit = data & 0b0100_0000 != 0;
+        if self.irq_inhibit {
+            self.irq = false;
+        }
+        self.cycle = 0;
+        FrameClock {
+            quarter: self.five_step_mode,
+            half: self.five_step_mode,
+        }
+    }
+
+    pub fn tick(&mut self) -> FrameClock {
+        self.cycle += 1;
+        let mut clock = FrameClock::default();
+        if self.five_step_mode {
+            let steps = self.five_step;
+            if self.cycle == steps[0] || self.cycle == steps[2] {
+                clock.quarter = true;
+            } else if self.cycle == steps[1] || self.cycle == steps[4] {
+                clock.quarter = true;
+                clock.half = true;
+            }
+            if self.cycle >= steps[5] {
+                self.cycle = 0;
+            }
+        } else {
+            let steps = self.four_step;
+            if self.cycle == steps[0] || self.cycle == steps[2] {
+                clock.quarter = true;
+            } else if self.cycle == steps[1] || self.cycle == steps[3] {
+                clock.quarter = true;
+                clock.half = true;
+            }
+            if self.cycle >= steps[3] - 1 && !self.irq_inhibit {
+                self.irq = true;
+            }
+            if self.cycle >= steps[4] {
+                self.cycle = 0;
+            }
+        }
+        clock
+    }
+}
+
+impl Savestate for FrameCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.five_step_mode);
+        w.bool(self.irq_inhibit);
+        w.bool(self.irq);
+        w.usize(self.cycle);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.five_step_mode = r.bool();
+        self.irq_inhibit = r.bool();
+        self.irq = r.bool();
+        self.cycle = r.usize();
+    }
+}
@@ -0,0 +1,68 @@
+// Length counter, silences a channel once it counts down to 0
+// Clocked by the half frame unless halted
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+pub struct LengthCounter {
+    pub counter: u8,
+    pub halt: bool,
+    enabled: bool, // Controlled through 0x4015
+}
+
+impl LengthCounter {
+    pub fn new() -> Self {
+        LengthCounter {
+            counter: 0,
+            halt: false,
+            enabled: false,
+        }
+    }
+
+    // Upper 5 bits of the channel's last register index into the length table
+    pub fn load(&mut self, data: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(data >> 3) as usize];
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+impl Default for LengthCounter {
+    fn default() -> Self {
+        LengthCounter::new()
+    }
+}
+
+impl Savestate for LengthCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.counter);
+        w.bool(self.halt);
+        w.bool(self.enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.counter = r.u8();
+        self.halt = r.bool();
+        self.enabled = r.bool();
+    }
+}
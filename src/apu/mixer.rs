@@ -0,0 +1,173 @@
+// Non-linear mixer of the 2A03's channels
+// The pulse channels and the triangle/noise/DMC(TND) group each go through their own resistor network,
+// so their outputs don't add up linearly. The official lookup tables approximate both networks:
+// pulse_table[n] = 95.52 / (8128.0 / n + 100)       n = pulse1 + pulse2
+// tnd_table[n] = 163.67 / (24329.0 / n + 100)       n = 3 * triangle + 2 * noise + dmc
+// Each channel can also be muted, soloed or scaled before it reaches the tables
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Channel {
+    PULSE1,
+    PULSE2,
+    TRIANGLE,
+    NOISE,
+    DMC,
+    EXPANSION, // Audio from the cartridge's sound chip
+}
+
+impl Channel {
+    pub const ALL: [Channel; 6] = [
+        Channel::PULSE1,
+        Channel::PULSE2,
+        Channel::TRIANGLE,
+        Channel::NOISE,
+        Channel::DMC,
+        Channel::EXPANSION,
+    ];
+}
+
+pub struct Mixer {
+    pulse_table: [f32; 31],
+    tnd_table: [f32; 203],
+    volume: [f32; 6],
+    muted: [bool; 6],
+    solo: Option<Channel>,
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        let mut pulse_table = [0.0; 31];
+        for (n, entry) in pulse_table.iter_mut().enumerate().skip(1) {
+            *entry = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+        let mut tnd_table = [0.0; 203];
+        for (n, entry) in tnd_table.iter_mut().enumerate().skip(1) {
+            *entry = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+        Mixer {
+            pulse_table,
+            tnd_table,
+            volume: [1.0; 6],
+            muted: [false; 6],
+            solo: None,
+        }
+    }
+
+    pub fn set_volume(&mut self, channel: Channel, volume: f32) {
+        self.volume[channel as usize] = volume.max(0.0);
+    }
+
+    pub fn volume(&self, channel: Channel) -> f32 {
+        self.volume[channel as usize]
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    // Only the soloed channel is heard, None goes back to every unmuted channel
+    pub fn set_solo(&mut self, channel: Option<Channel>) {
+        self.solo = channel;
+    }
+
+    pub fn solo(&self) -> Option<Channel> {
+        self.solo
+    }
+
+    // Scale applied to a channel after mute, solo and volume
+    pub fn gain(&self, channel: Channel) -> f32 {
+        let audible = match self.solo {
+            Some(solo) => solo == channel,
+            None => !self.muted[channel as usize],
+        };
+        if audible {
+            self.volume[channel as usize]
+        } else {
+            0.0
+        }
+    }
+
+    // Scaled channels land between table entries, so the lookup is interpolated
+    fn lookup(table: &[f32], index: f32) -> f32 {
+        let index = index.clamp(0.0, (table.len() - 1) as f32);
+        let low = index as usize;
+        let high = (low + 1).min(table.len() - 1);
+        let frac = index - low as f32;
+        table[low] + (table[high] - table[low]) * frac
+    }
+
+    // A single channel through its own network, ignoring mute, solo and volume(used for stems)
+    pub fn channel_output(&self, channel: Channel, level: f32) -> f32 {
+        match channel {
+            Channel::PULSE1 | Channel::PULSE2 => Mixer::lookup(&self.pulse_table, level),
+            Channel::TRIANGLE => Mixer::lookup(&self.tnd_table, 3.0 * level),
+            Channel::NOISE => Mixer::lookup(&self.tnd_table, 2.0 * level),
+            Channel::DMC => Mixer::lookup(&self.tnd_table, level),
+            Channel::EXPANSION => level,
+        }
+    }
+
+    // Channel levels are the raw DAC values(0-15 for pulse, triangle and noise, 0-127 for DMC)
+    // expansion is already mixed to the same scale as the output
+    pub fn mix(
+        &self,
+        pulse1: u8,
+        pulse2: u8,
+        triangle: u8,
+        noise: u8,
+        dmc: u8,
+        expansion: f32,
+    ) -> f32 {
+        let pulse =
+            pulse1 as f32 * self.gain(Channel::PULSE1) + pulse2 as f32 * self.gain(Channel::PULSE2);
+        let tnd = 3.0 * triangle as f32 * self.gain(Channel::TRIANGLE)
+            + 2.0 * noise as f32 * self.gain(Channel::NOISE)
+            + dmc as f32 * self.gain(Channel::DMC);
+        Mixer::lookup(&self.pulse_table, pulse)
+            + Mixer::lookup(&self.tnd_table, tnd)
+            + expansion * self.gain(Channel::EXPANSION)
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Mixer::new()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_mixer_tables() {
+        let mixer = Mixer::new();
+        assert_eq!(mixer.mix(0, 0, 0, 0, 0, 0.0), 0.0);
+        // Two full pulses are less than twice one full pulse
+        let one = mixer.mix(15, 0, 0, 0, 0, 0.0);
+        let two = mixer.mix(15, 15, 0, 0, 0, 0.0);
+        assert!(two < one * 2.0);
+        assert!((two - 95.52 / (8128.0 / 30.0 + 100.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_mute_solo_volume() {
+        let mut mixer = Mixer::new();
+        mixer.set_muted(Channel::PULSE1, true);
+        assert_eq!(mixer.mix(15, 0, 0, 0, 0, 0.0), 0.0);
+
+        mixer.set_solo(Some(Channel::NOISE));
+        assert_eq!(mixer.mix(0, 15, 15, 0, 0, 0.0), 0.0);
+        assert!(mixer.mix(0, 15, 15, 15, 0, 0.0) > 0.0);
+
+        mixer.set_solo(None);
+        mixer.set_volume(Channel::PULSE2, 0.5);
+        let half = mixer.mix(0, 15, 0, 0, 0, 0.0);
+        let full = Mixer::new();
+        assert!(half > full.mix(0, 7, 0, 0, 0, 0.0) && half < full.mix(0, 8, 0, 0, 0, 0.0));
+    }
+}
@@ -0,0 +1,104 @@
+use crate::apu::envelope::Envelope;
+use crate::apu::length::LengthCounter;
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// Timer periods in CPU cycles
+const NTSC_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PAL_PERIODS: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+// Pseudo-random noise channel, 0x400C-0x400F
+// A 15 bit linear feedback shift register, mode 1 taps bit 6 instead of bit 1 for a short metallic loop
+pub struct Noise {
+    periods: &'static [u16; 16],
+    mode: bool,
+    timer_period: u16,
+    timer: u16,
+    shift: u16,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Noise {
+    pub fn new(region: Region) -> Self {
+        Noise {
+            periods: match region {
+                Region::NTSC | Region::DENDY => &NTSC_PERIODS,
+                Region::PAL => &PAL_PERIODS,
+            },
+            mode: false,
+            timer_period: NTSC_PERIODS[0],
+            timer: 0,
+            shift: 1, // Loaded with 1 on power up
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+        }
+    }
+
+    // reg is the register offset 0-3
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            // --LC VVVV
+            0 => {
+                self.length.halt = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            1 => {} // Unused
+            // M--- PPPP
+            2 => {
+                self.mode = data & 0b1000_0000 != 0;
+                self.timer_period = self.periods[(data & 0b1111) as usize];
+            }
+            // LLLL L---
+            3 => {
+                self.length.load(data);
+                self.envelope.restart();
+            }
+            _ => panic!("Noise: Unknown register {}", reg),
+        }
+    }
+
+    // Clocked every CPU cycle, the period table is already in CPU cycles
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift & 1) ^ ((self.shift >> tap) & 1);
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.shift & 1 == 1 || !self.length.is_active() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl Savestate for Noise {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.mode);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+        w.u16(self.shift);
+        w.component(&self.envelope);
+        w.component(&self.length);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.mode = r.bool();
+        self.timer_period = r.u16();
+        self.timer = r.u16();
+        self.shift = r.u16();
+        r.component(&mut self.envelope);
+        r.component(&mut self.length);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::apu::envelope::Envelope;
+use crate::apu::length::LengthCounter;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+    [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+    [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
+];
+
+// Square wave channel, 0x4000-0x4003 for pulse 1 and 0x4004-0x4007 for pulse 2
+pub struct Pulse {
+    ones_complement: bool, // Pulse 1 negates its sweep with one's complement, pulse 2 with two's complement
+    duty: u8,
+    duty_pos: u8,
+    timer_period: u16,
+    timer: u16,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Self {
+        Pulse {
+            ones_complement,
+            duty: 0,
+            duty_pos: 0,
+            timer_period: 0,
+            timer: 0,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_reload: false,
+            sweep_divider: 0,
+        }
+    }
+
+    // reg is the register offset 0-3
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            // DDLC VVVV
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            // EPPP NSSS
+            1 => {
+                self.sweep_enabled = data & 0b1000_0000 != 0;
+                self.sweep_period = (data >> 4) & 0b111;
+                self.sweep_negate = data & 0b1000 != 0;
+                self.sweep_shift = data & 0b111;
+                self.sweep_reload = true;
+            }
+            // Timer low
+            2 => self.timer_period = (self.timer_period & 0x700) | data as u16,
+            // LLLL LHHH
+            3 => {
+                self.timer_period = (self.timer_period & 0xFF) | ((data as u16 & 0b111) << 8);
+                self.length.load(data);
+                self.envelope.restart();
+                self.duty_pos = 0;
+            }
+            _ => panic!("Pulse: Unknown register {}", reg),
+        }
+    }
+
+    // Clocked every APU cycle(every other CPU cycle)
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_pos = (self.duty_pos + 1) & 0b111;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let negated = self.timer_period.saturating_sub(change);
+            if self.ones_complement {
+                negated.saturating_sub(1)
+            } else {
+                negated
+            }
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    // The sweep unit mutes the channel even when disabled
+    fn is_muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    // Clocked by the half frame
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.is_muted()
+        {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if DUTY_TABLE[self.duty as usize][self.duty_pos as usize] == 0
+            || !self.length.is_active()
+            || self.is_muted()
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl Savestate for Pulse {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.duty);
+        w.u8(self.duty_pos);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+        w.component(&self.envelope);
+        w.component(&self.length);
+        w.bool(self.sweep_enabled);
+        w.u8(self.sweep_period);
+        w.bool(self.sweep_negate);
+        w.u8(self.sweep_shift);
+        w.bool(self.sweep_reload);
+        w.u8(self.sweep_divider);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.duty = r.u8();
+        self.duty_pos = r.u8();
+        self.timer_period = r.u16();
+        self.timer = r.u16();
+        r.component(&mut self.envelope);
+        r.component(&mut self.length);
+        self.sweep_enabled = r.bool();
+        self.sweep_period = r.u8();
+        self.sweep_negate = r.bool();
+        self.sweep_shift = r.u8();
+        self.sweep_reload = r.bool();
+        self.sweep_divider = r.u8();
+    }
+}
@@ -0,0 +1,115 @@
+use crate::apu::length::LengthCounter;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+// Triangle wave channel, 0x4008-0x400B
+// Has no volume control, the linear counter gives finer grained note lengths than the length counter
+pub struct Triangle {
+    control: bool, // Also halts the length counter
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    timer_period: u16,
+    timer: u16,
+    sequence_pos: u8,
+    pub length: LengthCounter,
+}
+
+impl Triangle {
+    pub fn new() -> Self {
+        Triangle {
+            control: false,
+            linear_reload_value: 0,
+            linear_counter: 0,
+            linear_reload: false,
+            timer_period: 0,
+            timer: 0,
+            sequence_pos: 0,
+            length: LengthCounter::new(),
+        }
+    }
+
+    // reg is the register offset 0-3
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            // CRRR RRRR
+            0 => {
+                self.control = data & 0b1000_0000 != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = data & 0b0111_1111;
+            }
+            1 => {} // Unused
+            2 => self.timer_period = (self.timer_period & 0x700) | data as u16,
+            // LLLL LHHH
+            3 => {
+                self.timer_period = (self.timer_period & 0xFF) | ((data as u16 & 0b111) << 8);
+                self.length.load(data);
+                self.linear_reload = true;
+            }
+            _ => panic!("Triangle: Unknown register {}", reg),
+        }
+    }
+
+    // Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.linear_counter > 0 && self.length.is_active() {
+                self.sequence_pos = (self.sequence_pos + 1) & 0b11111;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Clocked by the quarter frame
+    pub fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    // The sequencer holds its last value when silenced instead of dropping to 0
+    pub fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.sequence_pos as usize]
+    }
+}
+
+impl Default for Triangle {
+    fn default() -> Self {
+        Triangle::new()
+    }
+}
+
+impl Savestate for Triangle {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.control);
+        w.u8(self.linear_reload_value);
+        w.u8(self.linear_counter);
+        w.bool(self.linear_reload);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+        w.u8(self.sequence_pos);
+        w.component(&self.length);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.control = r.bool();
+        self.linear_reload_value = r.u8();
+        self.linear_counter = r.u8();
+        self.linear_reload = r.bool();
+        self.timer_period = r.u16();
+        self.timer = r.u16();
+        self.sequence_pos = r.u8();
+        r.component(&mut self.length);
+    }
+}
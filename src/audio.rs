@@ -0,0 +1,218 @@
+// Turns the APU's output(one sample every CPU cycle) into samples at the host's audio rate
+// 1. Averages groups of DECIMATION samples(box filter) down to ~224 kHz
+// 2. Runs the NES's analog filter chain: high-pass 90 Hz, high-pass 440 Hz, low-pass 14 kHz
+// 3. Resamples to the output rate with a windowed-sinc(band-limited) interpolator
+// The resample ratio can be nudged by the frontend to keep its audio buffer at a steady level
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+const DECIMATION: usize = 8;
+const HALF_TAPS: usize = 16; // Kernel covers 2 * HALF_TAPS intermediate samples
+const PHASES: usize = 256; // Sub-sample positions the kernel is precomputed for
+const MAX_RATE_ADJUST: f64 = 0.005; // Dynamic rate control never changes pitch by more than 0.5%
+
+enum FilterKind {
+    HighPass,
+    LowPass,
+}
+
+// First order RC filter
+struct Filter {
+    kind: FilterKind,
+    alpha: f32,
+    prev_in: f32,
+    prev_out: f32,
+}
+
+impl Filter {
+    fn new(kind: FilterKind, sample_rate: f64, cutoff: f64) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        let alpha = match kind {
+            FilterKind::HighPass => rc / (rc + dt),
+            FilterKind::LowPass => dt / (rc + dt),
+        };
+        Filter {
+            kind,
+            alpha: alpha as f32,
+            prev_in: 0.0,
+            prev_out: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = match self.kind {
+            FilterKind::HighPass => self.alpha * (self.prev_out + input - self.prev_in),
+            FilterKind::LowPass => self.prev_out + self.alpha * (input - self.prev_out),
+        };
+        self.prev_in = input;
+        self.prev_out = output;
+        output
+    }
+}
+
+pub struct AudioSink {
+    input_rate: f64,          // Rate after decimation
+    output_rate: Option<u32>, // No samples are produced until a rate is set
+    filters: Vec<Filter>,
+    accumulator: f32,
+    accumulated: usize,
+    history: VecDeque<f32>,
+    kernel: Vec<[f32; 2 * HALF_TAPS]>,
+    step: f64, // Intermediate samples per output sample
+    rate_adjust: f64,
+    time: f64, // Position of the next output sample, relative to the oldest sample in history
+    samples: Vec<f32>,
+}
+
+impl AudioSink {
+    pub fn new(cpu_clock_hz: f64) -> Self {
+        let input_rate = cpu_clock_hz / DECIMATION as f64;
+        AudioSink {
+            input_rate,
+            output_rate: None,
+            filters: vec![
+                Filter::new(FilterKind::HighPass, input_rate, 90.0),
+                Filter::new(FilterKind::HighPass, input_rate, 440.0),
+                Filter::new(FilterKind::LowPass, input_rate, 14000.0),
+            ],
+            accumulator: 0.0,
+            accumulated: 0,
+            history: VecDeque::with_capacity(2 * HALF_TAPS + 1),
+            kernel: Vec::new(),
+            step: 1.0,
+            rate_adjust: 1.0,
+            time: (HALF_TAPS - 1) as f64,
+            samples: Vec::new(),
+        }
+    }
+
+    // Enables output at the given sample rate, eg. 44100 or 48000
+    pub fn set_output_rate(&mut self, rate: u32) {
+        self.output_rate = Some(rate);
+        self.step = self.input_rate / rate as f64;
+        self.kernel = AudioSink::build_kernel(self.step);
+    }
+
+    pub fn output_rate(&self) -> Option<u32> {
+        self.output_rate
+    }
+
+    // Blackman windowed sinc, with its cutoff just below the output's nyquist frequency
+    // Row p holds the weights for an output sample p / PHASES of the way between two input samples
+    fn build_kernel(step: f64) -> Vec<[f32; 2 * HALF_TAPS]> {
+        let cutoff = 0.45 / step;
+        let half = HALF_TAPS as f64;
+        (0..=PHASES)
+            .map(|phase| {
+                let mut row = [0.0; 2 * HALF_TAPS];
+                let t = half - 1.0 + phase as f64 / PHASES as f64;
+                for (k, weight) in row.iter_mut().enumerate() {
+                    let x = t - k as f64;
+                    let sinc = if x == 0.0 {
+                        2.0 * cutoff
+                    } else {
+                        (2.0 * PI * cutoff * x).sin() / (PI * x)
+                    };
+                    let window =
+                        0.42 + 0.5 * (PI * x / half).cos() + 0.08 * (2.0 * PI * x / half).cos();
+                    *weight = (sinc * window) as f32;
+                }
+                // Normalize so a constant input produces the same constant output
+                let sum: f32 = row.iter().sum();
+                row.iter_mut().for_each(|weight| *weight /= sum);
+                row
+            })
+            .collect()
+    }
+
+    // Adjusts the resample ratio from how full the frontend's buffer is
+    // A fuller buffer than the target produces slightly fewer samples and vice versa
+    pub fn adjust_for_buffer(&mut self, queued: usize, target: usize) {
+        if target == 0 {
+            return;
+        }
+        let error = (queued as f64 - target as f64) / target as f64;
+        self.rate_adjust = 1.0 + (error * MAX_RATE_ADJUST).clamp(-MAX_RATE_ADJUST, MAX_RATE_ADJUST);
+    }
+
+    // Takes one sample from the APU, called every CPU cycle
+    pub fn push(&mut self, sample: f32) {
+        if self.output_rate.is_none() {
+            return;
+        }
+        self.accumulator += sample;
+        self.accumulated += 1;
+        if self.accumulated < DECIMATION {
+            return;
+        }
+        let mut filtered = self.accumulator / DECIMATION as f32;
+        self.accumulator = 0.0;
+        self.accumulated = 0;
+        for filter in self.filters.iter_mut() {
+            filtered = filter.process(filtered);
+        }
+        self.resample(filtered);
+    }
+
+    fn resample(&mut self, sample: f32) {
+        self.history.push_back(sample);
+        if self.history.len() > 2 * HALF_TAPS {
+            self.history.pop_front();
+            self.time -= 1.0;
+        }
+        if self.history.len() < 2 * HALF_TAPS {
+            return;
+        }
+        while self.time < HALF_TAPS as f64 {
+            let frac = self.time - (HALF_TAPS - 1) as f64;
+            let row = &self.kernel[(frac.max(0.0) * PHASES as f64) as usize];
+            let output = self
+                .history
+                .iter()
+                .zip(row.iter())
+                .map(|(sample, weight)| sample * weight)
+                .sum();
+            self.samples.push(output);
+            self.time += self.step * self.rate_adjust;
+        }
+    }
+
+    // Samples produced since the last call, at the output rate
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_output_rate() {
+        let mut sink = AudioSink::new(1_789_773.0);
+        sink.push(1.0); // Disabled until a rate is set
+        assert!(sink.take_samples().is_empty());
+
+        sink.set_output_rate(48000);
+        for _ in 0..1_789_773 {
+            sink.push(0.5);
+        }
+        let samples = sink.take_samples();
+        assert!((samples.len() as i64 - 48000).abs() < 10);
+        // The high-pass filters remove the DC offset
+        assert!(samples.last().unwrap().abs() < 0.01);
+    }
+
+    #[test]
+    fn test_rate_adjust() {
+        let mut sink = AudioSink::new(1_789_773.0);
+        sink.set_output_rate(48000);
+        sink.adjust_for_buffer(8000, 4000); // Buffer too full, produce less
+        for _ in 0..1_789_773 {
+            sink.push(0.0);
+        }
+        let samples = sink.take_samples();
+        assert!(samples.len() < 47800 && samples.len() > 47700);
+    }
+}
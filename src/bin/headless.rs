@@ -0,0 +1,357 @@
+// Runs a ROM or NSF tune without a window
+// Usage: headless <rom> --frames N [--region ntsc|pal|dendy] [--wav out.wav] [--stems]
+//                       [--movie FILE] [--frame-hashes] [--screenshot N FILE]... [--screenshot-scale S]
+//                       [--video out.avi]
+//        headless <nsf> [--track N | --all-tracks] [--seconds S] [--wav out.wav] [--stems]
+// NSF tracks play for their NSFe time and fade, or --seconds, with --all-tracks writing out_01.wav, out_02.wav...
+// A movie runs to its end unless --frames is given, --frame-hashes prints a hash of every frame so
+// replays can be compared. --screenshot saves frame N as a PNG and can be given more than once
+// --video records every frame and the sound to an uncompressed AVI
+use nes::apu::mixer::Channel;
+use nes::apu::APU;
+use nes::bus::Bus;
+use nes::cpu::CPU;
+use nes::frame::Frame;
+use nes::gamedb::GameDb;
+use nes::input::InputPorts;
+use nes::movie::{self, Movie, MovieMode};
+use nes::nsf::{NSFDriver, NSF};
+use nes::ppu::PPU;
+use nes::region::Region;
+use nes::render;
+use nes::rom::{Mirroring, Rom};
+use nes::savestate;
+use nes::video::VideoRecorder;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Duration;
+
+struct Options {
+    rom: String,
+    frames: Option<usize>,
+    region: Option<Region>,
+    wav: Option<PathBuf>,
+    stems: bool,
+    track: Option<u8>, // 1 based like the players
+    all_tracks: bool,
+    seconds: Option<f64>,
+    movie: Option<PathBuf>,
+    frame_hashes: bool,
+    screenshots: Vec<(usize, PathBuf)>, // Frame and where it's saved
+    screenshot_scale: usize,
+    video: Option<PathBuf>,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: headless <rom> --frames N [--region ntsc|pal|dendy] [--wav out.wav] [--stems]"
+    );
+    eprintln!("                      [--movie FILE] [--frame-hashes] [--screenshot N FILE]...");
+    eprintln!("                      [--screenshot-scale S] [--video out.avi]");
+    eprintln!(
+        "       headless <nsf> [--track N | --all-tracks] [--seconds S] [--wav out.wav] [--stems]"
+    );
+    exit(2);
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        rom: String::new(),
+        frames: None,
+        region: None,
+        wav: None,
+        stems: false,
+        track: None,
+        all_tracks: false,
+        seconds: None,
+        movie: None,
+        frame_hashes: false,
+        screenshots: Vec::new(),
+        screenshot_scale: 1,
+        video: None,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => {
+                options.frames = match args.next().and_then(|n| n.parse().ok()) {
+                    Some(frames) => Some(frames),
+                    None => usage(),
+                }
+            }
+            "--region" => {
+                options.region = match args.next().and_then(|name| Region::from_name(&name)) {
+                    Some(region) => Some(region),
+                    None => usage(),
+                }
+            }
+            "--wav" => options.wav = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "--stems" => options.stems = true,
+            "--track" => {
+                options.track = match args.next().and_then(|n| n.parse().ok()) {
+                    Some(track) if track > 0 => Some(track),
+                    _ => usage(),
+                }
+            }
+            "--all-tracks" => options.all_tracks = true,
+            "--movie" => options.movie = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "--frame-hashes" => options.frame_hashes = true,
+            "--video" => options.video = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "--screenshot" => {
+                let frame = args.next().and_then(|n| n.parse().ok());
+                match (frame, args.next()) {
+                    (Some(frame), Some(path)) => options.screenshots.push((frame, path.into())),
+                    _ => usage(),
+                }
+            }
+            "--screenshot-scale" => {
+                options.screenshot_scale = match args.next().and_then(|n| n.parse().ok()) {
+                    Some(scale) if scale > 0 => scale,
+                    _ => usage(),
+                }
+            }
+            "--seconds" => {
+                options.seconds = match args.next().and_then(|n| n.parse().ok()) {
+                    // Negative, NaN, infinite and huge lengths aren't a Duration
+                    Some(seconds) if Duration::try_from_secs_f64(seconds).is_ok() => Some(seconds),
+                    _ => usage(),
+                }
+            }
+            _ if arg.starts_with("--") || !options.rom.is_empty() => usage(),
+            _ => options.rom = arg,
+        }
+    }
+    if options.rom.is_empty() {
+        usage();
+    }
+    options
+}
+
+fn start_recording(apu: &mut APU, path: &Path, stems: bool) {
+    if let Err(err) = apu.start_recording(path, stems) {
+        eprintln!("Could not record to {}: {}", path.display(), err);
+        exit(1);
+    }
+}
+
+fn stop_recording(apu: &mut APU) {
+    if let Err(err) = apu.stop_recording() {
+        eprintln!("Could not finish recording: {}", err);
+        exit(1);
+    }
+}
+
+fn run_rom(options: &Options, game_bytes: &[u8]) {
+    let mut rom = Rom::new(&game_bytes.to_vec()).unwrap_or_else(|err| {
+        eprintln!("Could not load {}: {}", options.rom, err);
+        exit(1);
+    });
+    // NES 2.0 timing, then the game database, then --region
+    let (gamedb, warnings) = GameDb::load_default();
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    gamedb.apply(&mut rom);
+    if let Some(region) = options.region {
+        rom.region = region;
+    }
+
+    let frame_hashes = options.frame_hashes;
+    let screenshots = options.screenshots.clone();
+    let screenshot_scale = options.screenshot_scale;
+    let region = rom.region;
+    let mut video = options.video.as_ref().map(|path| {
+        VideoRecorder::start(path, region).unwrap_or_else(|err| {
+            eprintln!("Could not record to {}: {}", path.display(), err);
+            exit(1);
+        })
+    });
+    let recorder = &mut video;
+    let mut frame = Frame::new();
+    let mut bus = Bus::new(rom, move |ppu: &PPU, apu: &mut APU, _: &mut InputPorts| {
+        let shots: Vec<&PathBuf> = screenshots
+            .iter()
+            .filter(|(n, _)| *n == ppu.frame)
+            .map(|(_, path)| path)
+            .collect();
+        if !frame_hashes && shots.is_empty() && recorder.is_none() {
+            return;
+        }
+        render::render(ppu, &mut frame);
+        if let Some(recorder) = recorder.as_mut() {
+            if let Err(err) = recorder.record(&frame, &apu.take_capture()) {
+                eprintln!("Could not write the video: {}", err);
+                exit(1);
+            }
+        }
+        if frame_hashes {
+            let hash = savestate::checksum(&[&frame.data]);
+            println!("frame {} {:08x}", ppu.frame, hash);
+        }
+        for path in shots {
+            if let Err(err) = frame.save_png(path, screenshot_scale) {
+                eprintln!("Could not write {}: {}", path.display(), err);
+                exit(1);
+            }
+        }
+    })
+    .unwrap_or_else(|err| {
+        eprintln!("Could not load {}: {}", options.rom, err);
+        exit(1);
+    });
+    if let Some(path) = &options.wav {
+        start_recording(&mut bus.apu, path, options.stems);
+    }
+    if options.video.is_some() {
+        bus.apu.start_capture();
+    }
+
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    if let Some(path) = &options.movie {
+        let result = std::fs::read_to_string(path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| Movie::parse(&text))
+            .and_then(|movie| movie::play(&mut cpu, movie, true));
+        if let Err(err) = result {
+            eprintln!("Could not play {}: {}", path.display(), err);
+            exit(1);
+        }
+    }
+
+    let frames = match (options.frames, &options.movie) {
+        (Some(frames), _) => frames,
+        (None, Some(_)) => usize::MAX,
+        (None, None) => options
+            .screenshots
+            .iter()
+            .map(|(frame, _)| *frame)
+            .fold(60, usize::max),
+    };
+    cpu.run_with_callback(|cpu| {
+        let movie_over = cpu
+            .bus
+            .input()
+            .movie()
+            .is_some_and(|movie| movie.mode() == MovieMode::Finished);
+        if cpu.bus.ppu.frame >= frames || movie_over {
+            cpu.halted = true;
+        }
+    });
+    stop_recording(&mut cpu.bus.apu);
+    // The bus holds on to the recorder until the CPU is gone
+    drop(cpu);
+    if let Some(recorder) = video {
+        if let Err(err) = recorder.finish() {
+            eprintln!("Could not finish the video: {}", err);
+            exit(1);
+        }
+    }
+}
+
+// Plays one track into path, fading every channel out over the track's fade time
+fn render_track(nsf: &NSF, region: Region, track: u8, length: Duration, path: &Path, stems: bool) {
+    let mut bus = Bus::with_mapper(
+        nsf.mapper(),
+        vec![0; 0x2000],
+        Mirroring::HORIZONTAL,
+        region,
+        |_: &PPU, _: &mut APU, _: &mut InputPorts| {},
+    );
+    start_recording(&mut bus.apu, path, stems);
+
+    let fade = nsf.track_fade(track).as_secs_f64();
+    let total = length.as_secs_f64() + fade;
+    let mut cpu = CPU::new(bus);
+    let mut driver = NSFDriver::new(nsf, region);
+    driver.start_track(&mut cpu, track);
+    let start = cpu.bus.cycles;
+    cpu.run_with_callback(|cpu| {
+        driver.step(cpu);
+        let elapsed = (cpu.bus.cycles - start) as f64 / region.cpu_clock_hz();
+        if elapsed >= total {
+            cpu.halted = true;
+        } else if fade > 0.0 && elapsed > total - fade {
+            let volume = ((total - elapsed) / fade) as f32;
+            for channel in Channel::ALL {
+                cpu.bus.apu.mixer.set_volume(channel, volume);
+            }
+        }
+    });
+    stop_recording(&mut cpu.bus.apu);
+}
+
+fn track_path(path: &Path, track: u8) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}_{:02}.wav", stem, track + 1))
+}
+
+fn run_nsf(options: &Options, game_bytes: &[u8]) {
+    let nsf = NSF::new(game_bytes).unwrap_or_else(|err| {
+        eprintln!("Could not load {}: {}", options.rom, err);
+        exit(1);
+    });
+    let region = options.region.unwrap_or(nsf.region);
+
+    println!("Title:     {}", nsf.title);
+    println!("Artist:    {}", nsf.artist);
+    println!("Copyright: {}", nsf.copyright);
+    if !nsf.ripper.is_empty() {
+        println!("Ripper:    {}", nsf.ripper);
+    }
+    println!("Chips:     {:?}", nsf.expansion);
+    for track in 0..nsf.total_tracks {
+        let length = nsf.track_duration(track).as_secs();
+        println!(
+            "{:3}. {} ({}:{:02})",
+            track + 1,
+            nsf.track_name(track),
+            length / 60,
+            length % 60
+        );
+    }
+
+    let Some(path) = &options.wav else {
+        return;
+    };
+    let tracks: Vec<u8> = if options.all_tracks {
+        (0..nsf.total_tracks).collect()
+    } else {
+        let track = options
+            .track
+            .map(|track| track - 1)
+            .unwrap_or(nsf.starting_track);
+        if track >= nsf.total_tracks {
+            eprintln!("{} only has {} tracks", options.rom, nsf.total_tracks);
+            exit(1);
+        }
+        vec![track]
+    };
+    for track in tracks {
+        let length = match options.seconds {
+            Some(seconds) => Duration::from_secs_f64(seconds),
+            None => nsf.track_duration(track),
+        };
+        let path = if options.all_tracks {
+            track_path(path, track)
+        } else {
+            path.clone()
+        };
+        println!("Rendering track {} to {}", track + 1, path.display());
+        render_track(&nsf, region, track, length, &path, options.stems);
+    }
+}
+
+fn main() {
+    let options = parse_args();
+
+    let game_bytes = std::fs::read(&options.rom).unwrap_or_else(|err| {
+        eprintln!("Could not read {}: {}", options.rom, err);
+        exit(1);
+    });
+    if NSF::is_nsf(&game_bytes) {
+        run_nsf(&options, &game_bytes);
+    } else {
+        run_rom(&options, &game_bytes);
+    }
+}
@@ -1,66 +1,166 @@
-use core::panic;
-
-use crate::controller::Controller;
+use crate::apu::APU;
 use crate::cpu::Mem;
+use crate::input::InputPorts;
+use crate::mapper::{self, Mapper};
 use crate::ppu::PPU;
-use crate::rom::Rom;
+use crate::region::Region;
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{self, Savestate, StateReader, StateWriter};
 
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
-    prg_rom: Vec<u8>,
+    mapper: Box<dyn Mapper>,
     pub ppu: PPU,
+    pub apu: APU,
     pub cycles: usize, // Contains total amount of cpu cycles
-    gameloop_callback: Box<dyn FnMut(&PPU, &mut Controller) + 'call>, // Box, pointer to heap ddata is managed by the box
-    controller1: Controller,
+    pub checksum: u32, // Of the ROM, save states are only loaded into the game they came from
+    gameloop_callback: Box<dyn FnMut(&PPU, &mut APU, &mut InputPorts) + 'call>, // Box, pointer to heap ddata is managed by the box
+    input: InputPorts, // Controller ports at 0x4016/0x4017 and the Famicom expansion port
+    port_read: Option<usize>, // Controller port the current instruction read from
+    ppu_dot_remainder: u16, // Fraction of a PPU dot left over for regions that don't run at a whole ratio(PAL)
 }
 
 impl<'a> Bus<'a> {
-    pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Bus<'call>
+    // Fails for mappers that aren't supported
+    pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Result<Bus<'call>, String>
+    where
+        F: FnMut(&PPU, &mut APU, &mut InputPorts) + 'call,
+    {
+        let checksum = savestate::checksum(&[&rom.prg_rom, &rom.chr_rom]);
+        let mapper = mapper::new(rom.mapper, rom.prg_rom)?;
+        let mut bus = Bus::with_mapper(
+            mapper,
+            rom.chr_rom,
+            rom.screen_mirroring,
+            rom.region,
+            gameloop_callback,
+        );
+        bus.checksum = checksum;
+        Ok(bus)
+    }
+
+    // For programs that don't come from an iNES file, like NSF tunes
+    pub fn with_mapper<'call, F>(
+        mapper: Box<dyn Mapper>,
+        chr_rom: Vec<u8>,
+        mirroring: Mirroring,
+        region: Region,
+        gameloop_callback: F,
+    ) -> Bus<'call>
     where
-        F: FnMut(&PPU, &mut Controller) + 'call,
+        F: FnMut(&PPU, &mut APU, &mut InputPorts) + 'call,
     {
-        let ppu = PPU::new(rom.chr_rom, rom.screen_mirroring);
-        Bus {
+        let mut ppu = PPU::new(chr_rom, mirroring);
+        ppu.region = region;
+        let mut bus = Bus {
             cpu_vram: [0; 2048],
-            prg_rom: rom.prg_rom,
+            mapper: mapper,
             ppu: ppu,
+            apu: APU::new(region),
             cycles: 7, // Starting with 7 clock cycles
+            checksum: 0,
             gameloop_callback: Box::from(gameloop_callback),
-            controller1: Controller::new(),
-        }
+            input: InputPorts::new(),
+            port_read: None,
+            ppu_dot_remainder: 0,
+        };
+        bus.sync_mapper();
+        bus
     }
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            //mirror 16 kb for addressible space
-            addr = addr % 0x4000;
+
+    // Hands the mapper's mirroring and CHR banks to the PPU
+    fn sync_mapper(&mut self) {
+        if let Some(mirroring) = self.mapper.mirroring() {
+            self.ppu.set_mirroring(mirroring);
+        }
+        let tall_sprites = self.ppu.ctrl.tall_sprites();
+        if let Some(banks) = self.mapper.chr_banks(tall_sprites) {
+            self.ppu.chr_banks = banks;
         }
-        // println!("chosen addr {:04X} with values {:04X}!", addr, self.prg_rom[addr as usize]);
-        self.prg_rom[addr as usize]
     }
-
     // Counting ticks
     pub fn tick(&mut self, cycles: u8) {
-        self.cycles += cycles as usize;
-        let new_frame = self.ppu.tick(cycles * 3);
+        let mut remaining = cycles as usize;
+        while remaining > 0 {
+            remaining -= 1;
+            self.tick_cycle();
+            // DMC sample fetches halt the CPU while the bus keeps running
+            if let Some(addr) = self.apu.dmc.dma_request() {
+                // Halting on a controller read makes the CPU repeat it, clocking the port an extra time
+                if remaining == 0 {
+                    if let Some(port) = self.port_read {
+                        self.input.read(port);
+                    }
+                }
+                let sample = self.mem_read(addr);
+                self.apu.dmc.load_sample(sample);
+                remaining += DMC_DMA_STALL;
+            }
+        }
+        self.port_read = None;
+    }
+
+    fn tick_cycle(&mut self) {
+        self.cycles += 1;
+        // NTSC runs 3 PPU dots per CPU cycle, PAL runs 3.2
+        let dots = self.ppu.region.ppu_dots_per_5_cycles() + self.ppu_dot_remainder;
+        self.ppu_dot_remainder = dots % 5;
+        let scanline = self.ppu.scanline;
+        let new_frame = self.ppu.tick((dots / 5) as u8);
+        if self.ppu.scanline != scanline {
+            let rendering = self.ppu.mask.is_rendering();
+            self.mapper.scanline(self.ppu.scanline, rendering);
+        }
+        self.mapper.clock();
+        if let Some(audio) = self.mapper.audio() {
+            audio.clock();
+            self.apu.expansion = audio.output();
+        }
+        self.apu.tick();
         if new_frame {
-            (self.gameloop_callback)(&self.ppu, &mut self.controller1);
+            self.input.next_frame();
+            (self.gameloop_callback)(&self.ppu, &mut self.apu, &mut self.input);
+        }
+    }
+
+    // Calls the frame callback again without running the PPU, after a save state loads while paused
+    pub fn redraw(&mut self) {
+        (self.gameloop_callback)(&self.ppu, &mut self.apu, &mut self.input);
+    }
+
+    pub fn input(&mut self) -> &mut InputPorts {
+        &mut self.input
+    }
+
+    // Reads RAM and the cartridge without side effects for the tracer
+    // The PPU, APU and controller registers change when read, so they show as FF like in the nestest log
+    pub fn peek(&mut self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0b00000111_11111111) as usize],
+            CARTRIDGE..=CARTRIDGE_END => self.mapper.peek(addr),
+            _ => 0xFF,
         }
     }
 
     // Polling for NMI Interrupt
     pub fn poll_nmi_status(&mut self) -> Option<u8> {
-        self.ppu.nmi_interrupt.take()
+        self.ppu.poll_nmi()
+    }
+
+    // IRQ is level triggered, it stays asserted until the source is acknowledged
+    pub fn poll_irq_status(&self) -> bool {
+        self.apu.irq() || self.mapper.irq()
     }
 }
 
+const DMC_DMA_STALL: usize = 4; // CPU cycles lost for every DMC sample fetch
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF; // 0x800- 0x1FFF mirrors of 0000-07FF
                                      // const PPU_REGISTERS: u16 = 0x2000; // 0x2000- 0x2007 NES PPU Registers(Communication with PPU)
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF; // Mirrors of above for every 8 bytes
-                                               // Cartride ROM and mapper registers
-const PROGRAM_RAM: u16 = 0x8000;
-const PROGRAM_RAM_END: u16 = 0xFFFF;
+const CARTRIDGE: u16 = 0x4020; // Cartridge RAM, ROM and mapper registers
+const CARTRIDGE_END: u16 = 0xFFFF;
+const CONTROLLER_OPEN_BUS: u8 = 0x40; // Undriven bits of 0x4016/0x4017 keep the high byte of the address
 
 impl Mem for Bus<'_> {
     // Used for the CPU
@@ -70,7 +170,11 @@ impl Mem for Bus<'_> {
                 let mirror_down_addr = addr & 0b00000111_11111111;
                 self.cpu_vram[mirror_down_addr as usize]
             }
-            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 | 0x4014 => {
+            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 => {
+                // Write-only PPU registers return the PPU's decaying I/O latch
+                self.ppu.read_open_bus()
+            }
+            0x4014 => {
                 // panic!("Attempt to read from write-only PPU address {:x}", addr);
                 0
             }
@@ -82,18 +186,19 @@ impl Mem for Bus<'_> {
                 let mirror_down_addr = addr & 0x2007;
                 self.mem_read(mirror_down_addr)
             }
-            0x4000..=0x4015 => {
-                // Ignoring APU
+            0x4015 => self.apu.read_status(),
+            0x4000..=0x4013 => {
+                // APU registers are write-only
                 0
             }
 
-            0x4016 => self.controller1.read(),
-
-            0x4017 => {
-                // Controller 2
-                0
+            0x4016 | 0x4017 => {
+                let port = (addr - 0x4016) as usize;
+                self.port_read = Some(port);
+                self.input.set_beam(self.ppu.scanline, self.ppu.cycles);
+                CONTROLLER_OPEN_BUS | self.input.read(port)
             }
-            PROGRAM_RAM..=PROGRAM_RAM_END => self.read_prg_rom(addr),
+            CARTRIDGE..=CARTRIDGE_END => self.mapper.read(addr),
             _ => {
                 println!("Ignoring mem access at 0x{:4X}", addr);
                 0
@@ -102,6 +207,10 @@ impl Mem for Bus<'_> {
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        if (0x2000..=0x2007).contains(&addr) {
+            // Every PPU register write fills the PPU's I/O latch
+            self.ppu.write_open_bus(data);
+        }
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b11111111111;
@@ -110,10 +219,13 @@ impl Mem for Bus<'_> {
             // PPU Control
             0x2000 => {
                 self.ppu.write_to_ctrl(data);
+                self.sync_mapper();
             }
             // PPUMask Rendering
             0x2001 => self.ppu.write_to_mask(data),
-            0x2002 => panic!("Attempt to write to PPU status register PPUSTATUS"),
+            0x2002 => {
+                // PPUSTATUS is read-only, the write only reaches the I/O latch
+            }
             0x2003 => self.ppu.write_to_oam_addr(data),
             0x2004 => self.ppu.write_to_oam_data(data),
             0x2005 => self.ppu.write_to_ppuscroll(data),
@@ -127,8 +239,9 @@ impl Mem for Bus<'_> {
                 let mirror_addr = addr & 0b00100000_00000111;
                 self.mem_write(mirror_addr, data);
             }
-            PROGRAM_RAM..=PROGRAM_RAM_END => {
-                panic!("Attempt to write to program CHR ROM space {:x}!", addr);
+            CARTRIDGE..=CARTRIDGE_END => {
+                self.mapper.write(addr, data);
+                self.sync_mapper();
             }
 
             0x4014 => {
@@ -142,15 +255,17 @@ impl Mem for Bus<'_> {
             }
 
             0x4000..=0x4013 | 0x4015 => {
-                //ignore APU
+                self.apu.write_register(addr, data);
             }
 
             0x4016 => {
-                self.controller1.write(data);
+                // Every port shares the strobe line
+                self.input.write(data);
             }
 
             0x4017 => {
-                // ignore controller 2
+                // Frame counter, controller 2 has no write register
+                self.apu.write_register(addr, data);
             }
             _ => {
                 println!("Ignoring mem write-access at {}", addr);
@@ -158,3 +273,186 @@ impl Mem for Bus<'_> {
         }
     }
 }
+
+// States are taken between instructions, when port_read is always clear
+impl Savestate for Bus<'_> {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.cpu_vram);
+        w.usize(self.cycles);
+        w.u16(self.ppu_dot_remainder);
+        w.component(&self.ppu);
+        w.component(&self.apu);
+        w.component(self.mapper.as_ref());
+        w.component(&self.input);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes_into(&mut self.cpu_vram);
+        self.cycles = r.usize();
+        self.ppu_dot_remainder = r.u16();
+        r.component(&mut self.ppu);
+        r.component(&mut self.apu);
+        r.component(self.mapper.as_mut());
+        r.component(&mut self.input);
+        self.port_read = None;
+        self.sync_mapper();
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::controller::ControllerButton;
+
+    fn mapper_bus(mapper: u8) -> Bus<'static> {
+        // Every 1 KB page of CHR is filled with its page number
+        let chr = (0..0x8000).map(|i| (i / 0x400) as u8).collect();
+        Bus::with_mapper(
+            mapper::new(mapper, vec![0; 0x8000]).unwrap(),
+            chr,
+            Mirroring::HORIZONTAL,
+            Region::NTSC,
+            |_: &PPU, _: &mut APU, _: &mut InputPorts| {},
+        )
+    }
+
+    fn test_bus() -> Bus<'static> {
+        mapper_bus(0)
+    }
+
+    #[test]
+    fn test_second_controller() {
+        let mut bus = test_bus();
+        bus.input.set_button(0, ControllerButton::A, true);
+        bus.input.set_button(1, ControllerButton::B, true);
+
+        // One strobe latches both ports
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        let player1: Vec<u8> = (0..2).map(|_| bus.mem_read(0x4016)).collect();
+        let player2: Vec<u8> = (0..2).map(|_| bus.mem_read(0x4017)).collect();
+        assert_eq!(player1, vec![0x41, 0x40]);
+        assert_eq!(player2, vec![0x40, 0x41]);
+    }
+
+    #[test]
+    fn test_chr_banks() {
+        let mut bus = mapper_bus(69);
+        assert_eq!(bus.ppu.read_chr(0x1C00, false), 7);
+        bus.mem_write(0x8000, 0x7); // FME-7 command 7, CHR bank for 0x1C00
+        bus.mem_write(0xA000, 0x1A);
+        assert_eq!(bus.ppu.read_chr(0x1C00, false), 0x1A);
+        assert_eq!(bus.ppu.read_chr(0x1C00, true), 0x1A);
+
+        // MMC5 banks sprites and background apart with 8x16 sprites
+        let mut bus = mapper_bus(5);
+        bus.mem_write(0x5101, 1); // 4 KB banks
+        bus.mem_write(0x5127, 3);
+        bus.mem_write(0x512B, 5);
+        assert_eq!(bus.ppu.read_chr(0x1000, true), 20); // Last written set
+        bus.mem_write(0x2000, 0b0010_0000);
+        assert_eq!(bus.ppu.read_chr(0x1000, true), 12);
+        assert_eq!(bus.ppu.read_chr(0x0400, false), 21);
+    }
+
+    #[test]
+    fn test_fme7_irq_counter() {
+        let mut bus = mapper_bus(69);
+        bus.mem_write(0x8000, 0xE);
+        bus.mem_write(0xA000, 10);
+        bus.mem_write(0x8000, 0xD);
+        bus.mem_write(0xA000, 0x81);
+        bus.tick(10);
+        assert!(!bus.poll_irq_status());
+        bus.tick(1);
+        assert!(bus.poll_irq_status());
+        bus.mem_write(0xA000, 0x81); // Acknowledge
+        assert!(!bus.poll_irq_status());
+    }
+
+    #[test]
+    fn test_mmc5_scanline_irq() {
+        let mut bus = mapper_bus(5);
+        bus.mem_write(0x4017, 0x40); // No frame counter IRQ
+        bus.mem_write(0x2001, 0b0001_1000);
+        bus.mem_write(0x5203, 20);
+        bus.mem_write(0x5204, 0x80);
+        // The bus starts part way into scanline 0, so the first frame counts one line short
+        while bus.ppu.scanline != 241 {
+            bus.tick(1);
+        }
+        assert_eq!(bus.mem_read(0x5204), 0x80);
+        assert!(!bus.poll_irq_status());
+        while !bus.poll_irq_status() {
+            bus.tick(1);
+        }
+        assert_eq!(bus.ppu.scanline, 20);
+        assert_eq!(bus.mem_read(0x5204), 0xC0);
+        assert!(!bus.poll_irq_status());
+
+        // Not raised with rendering off
+        bus.mem_write(0x2001, 0);
+        for _ in 0..30000 {
+            bus.tick(1);
+            assert!(!bus.poll_irq_status());
+        }
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut bus = mapper_bus(5);
+        bus.mem_write(0x4017, 0x40); // No frame counter IRQ
+        bus.mem_write(0x2001, 0b0001_1000);
+        bus.mem_write(0x5203, 20);
+        bus.mem_write(0x5204, 0x80);
+        while !bus.poll_irq_status() {
+            bus.tick(1);
+        }
+        // Peeking doesn't acknowledge the MMC5 scanline IRQ
+        assert_eq!(bus.peek(0x5204), 0xC0);
+        assert!(bus.poll_irq_status());
+
+        // Or move the N163 sound RAM address on
+        let mut bus = mapper_bus(19);
+        bus.mem_write(0xF800, 0x80); // Address 0 with auto increment
+        bus.mem_write(0x4800, 0x12);
+        bus.mem_write(0x4800, 0x34);
+        bus.mem_write(0xF800, 0x80);
+        assert_eq!(bus.peek(0x4800), 0x12);
+        assert_eq!(bus.mem_read(0x4800), 0x12);
+        assert_eq!(bus.peek(0x4800), 0x34);
+        assert_eq!(bus.peek(0x2002), 0xFF);
+    }
+
+    #[test]
+    fn test_unsupported_mapper() {
+        assert!(mapper::new(4, vec![0; 0x8000]).is_err());
+        assert!(mapper::new(0, Vec::new()).is_err());
+        // PRG smaller than a bank still has a last bank
+        let mut vrc6 = mapper::new(24, vec![0x42; 0x1000]).unwrap();
+        assert_eq!(vrc6.read(0xFFFC), 0x42);
+    }
+
+    #[test]
+    fn test_nrom_rom_write() {
+        let mut bus = test_bus();
+        bus.mem_write(0x8000, 0x42); // Ignored, NROM has no registers
+        assert_eq!(bus.mem_read(0x8000), 0);
+    }
+
+    #[test]
+    fn test_dmc_dma_double_read() {
+        let mut bus = test_bus();
+        bus.input.set_button(0, ControllerButton::B, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+
+        // Start a sample so a DMA lands on the last cycle of the read
+        bus.mem_write(0x4013, 1);
+        bus.mem_write(0x4015, 0b0001_0000);
+        assert_eq!(bus.mem_read(0x4016), 0x40); // A
+        bus.tick(1);
+        // B was clocked out by the repeated read
+        assert_eq!(bus.mem_read(0x4016), 0x40); // Select
+    }
+}
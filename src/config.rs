@@ -0,0 +1,768 @@
+// Settings file for the SDL frontend, INI style with every entry optional
+// Lives at $XDG_CONFIG_HOME/nexie/config.ini, falling back to ~/.config/nexie/config.ini
+//
+// [video]
+// scale = 3
+// palette = /path/to/palette.pal   64 RGB triples, "default" is the built in palette
+// screenshot_scale = 1             Screenshots are 256x240 times this
+//
+// [audio]
+// mute = false
+// volume = 1.0                     0.0-1.0 on top of the mixer's channel volumes
+// sample_rate = 48000              Only read on start
+// latency = 4                      Frames of audio kept queued, only read on start
+//
+// [player1] to [player4]
+// a = Z                            Key names, several keys are separated by commas
+// up = Up, Keypad 8
+// turbo_a = C                      turbo_ before a button name makes it a turbo key
+//
+// [gamepad]
+// a = B                            Game controller buttons for A, B, Select, Start and turbo A/B
+// turbo_a = Y
+// deadzone = 0.3                   How far the left stick moves before it counts as the d-pad
+//
+// [speed]
+// fast_forward = 4                 Times normal speed while the fast_forward hotkey is held, 0 is uncapped
+// slow_motion = 2                  Slow motion runs at 1/N speed
+//
+// [rewind]
+// interval = 2                     Frames between snapshots, holding the rewind hotkey goes back
+//                                  this many frames for every frame shown. Only read on start
+// memory = 64                      Megabytes kept for snapshots, 0 turns rewinding off
+//
+// [input]
+// turbo_rate = 2                   Frames between each press and release of a turbo button
+//
+// [macros]
+// M = down+b*2, none*3, a*10       Key = a macro script(see input::macros), player 1 unless
+// N = 2: start                     it starts with the player number and a colon
+//
+// [hotkeys]
+// pause = Pause
+//
+// [powerpad]
+// 1 = 1                            Keys for Power Pad buttons 1-12, only used with a Power Pad plugged in
+//
+// Lines starting with # or ; are comments. Anything that can't be used is reported as a warning
+// and the default is kept.
+use crate::apu::mixer::Channel;
+use crate::controller::ControllerButton;
+use crate::input::macros::Macro;
+use crate::palette::{self, SYSTEM_PALLETE};
+use sdl2::controller::Button;
+use sdl2::keyboard::Keycode;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Hotkey {
+    Pause,
+    FrameAdvance, // Runs one frame and pauses
+    FastForward,  // While held
+    SlowMotion,   // Toggles
+    FrameCounter, // Shows the frame number and speed in the title bar
+    SaveState,    // To the selected slot
+    LoadState,
+    NextSlot,
+    PreviousSlot,
+    Rewind,        // While held
+    RecordMovie,   // Starts recording from the current state or stops the movie
+    MovieReadOnly, // Toggles, loading a state while read+write rerecords from there
+    Screenshot,    // To <game>-001.png, <game>-002.png... in the working directory
+    RecordVideo,   // Starts or stops recording to video-<unix time>.avi
+    ReloadConfig,
+    Port2Device,    // Cycles the device in port 2
+    Adapter,        // Cycles the four player adapters
+    Record,         // Audio recording, Shift adds stems
+    RecordMacro,    // Starts or stops recording player 1
+    PlayMacro,      // Plays the last recording on player 1
+    Mixer(Channel), // Mute, Shift solos and Ctrl steps the volume
+}
+
+const HOTKEY_NAMES: [(&str, Hotkey); 26] = [
+    ("pause", Hotkey::Pause),
+    ("frame_advance", Hotkey::FrameAdvance),
+    ("fast_forward", Hotkey::FastForward),
+    ("slow_motion", Hotkey::SlowMotion),
+    ("frame_counter", Hotkey::FrameCounter),
+    ("save_state", Hotkey::SaveState),
+    ("load_state", Hotkey::LoadState),
+    ("next_slot", Hotkey::NextSlot),
+    ("previous_slot", Hotkey::PreviousSlot),
+    ("rewind", Hotkey::Rewind),
+    ("record_movie", Hotkey::RecordMovie),
+    ("movie_read_only", Hotkey::MovieReadOnly),
+    ("screenshot", Hotkey::Screenshot),
+    ("record_video", Hotkey::RecordVideo),
+    ("reload_config", Hotkey::ReloadConfig),
+    ("port2_device", Hotkey::Port2Device),
+    ("adapter", Hotkey::Adapter),
+    ("record", Hotkey::Record),
+    ("record_macro", Hotkey::RecordMacro),
+    ("play_macro", Hotkey::PlayMacro),
+    ("pulse1", Hotkey::Mixer(Channel::PULSE1)),
+    ("pulse2", Hotkey::Mixer(Channel::PULSE2)),
+    ("triangle", Hotkey::Mixer(Channel::TRIANGLE)),
+    ("noise", Hotkey::Mixer(Channel::NOISE)),
+    ("dmc", Hotkey::Mixer(Channel::DMC)),
+    ("expansion", Hotkey::Mixer(Channel::EXPANSION)),
+];
+
+const BUTTON_NAMES: [(&str, ControllerButton); 8] = [
+    ("a", ControllerButton::A),
+    ("b", ControllerButton::B),
+    ("select", ControllerButton::SELECT),
+    ("start", ControllerButton::START),
+    ("up", ControllerButton::UP),
+    ("down", ControllerButton::DOWN),
+    ("left", ControllerButton::LEFT),
+    ("right", ControllerButton::RIGHT),
+];
+
+// SDL's names for the game controller buttons, the d-pad always steers
+const PAD_BUTTON_NAMES: [(&str, Button); 11] = [
+    ("a", Button::A),
+    ("b", Button::B),
+    ("x", Button::X),
+    ("y", Button::Y),
+    ("back", Button::Back),
+    ("guide", Button::Guide),
+    ("start", Button::Start),
+    ("leftstick", Button::LeftStick),
+    ("rightstick", Button::RightStick),
+    ("leftshoulder", Button::LeftShoulder),
+    ("rightshoulder", Button::RightShoulder),
+];
+
+// Names for the keys that aren't a single letter, digit or symbol, matching SDL's key names
+const KEY_NAMES: [(&str, Keycode); 35] = [
+    ("return", Keycode::Return),
+    ("enter", Keycode::Return),
+    ("space", Keycode::Space),
+    ("tab", Keycode::Tab),
+    ("backspace", Keycode::Backspace),
+    ("escape", Keycode::Escape),
+    ("up", Keycode::Up),
+    ("down", Keycode::Down),
+    ("left", Keycode::Left),
+    ("right", Keycode::Right),
+    ("pause", Keycode::Pause),
+    ("insert", Keycode::Insert),
+    ("delete", Keycode::Delete),
+    ("home", Keycode::Home),
+    ("end", Keycode::End),
+    ("pageup", Keycode::PageUp),
+    ("pagedown", Keycode::PageDown),
+    ("printscreen", Keycode::PrintScreen),
+    ("left shift", Keycode::LShift),
+    ("right shift", Keycode::RShift),
+    ("left ctrl", Keycode::LCtrl),
+    ("right ctrl", Keycode::RCtrl),
+    ("left alt", Keycode::LAlt),
+    ("right alt", Keycode::RAlt),
+    ("keypad 0", Keycode::Kp0),
+    ("keypad 1", Keycode::Kp1),
+    ("keypad 2", Keycode::Kp2),
+    ("keypad 3", Keycode::Kp3),
+    ("keypad 4", Keycode::Kp4),
+    ("keypad 5", Keycode::Kp5),
+    ("keypad 6", Keycode::Kp6),
+    ("keypad 7", Keycode::Kp7),
+    ("keypad 8", Keycode::Kp8),
+    ("keypad 9", Keycode::Kp9),
+    ("keypad enter", Keycode::KpEnter),
+];
+
+pub fn key_from_name(name: &str) -> Option<Keycode> {
+    let name = name.trim().to_lowercase();
+    if let Some((_, key)) = KEY_NAMES.iter().find(|(key_name, _)| *key_name == name) {
+        return Some(*key);
+    }
+    // Printable keys use their character as the key code
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        if c.is_ascii_graphic() {
+            return Keycode::from_i32(c as i32);
+        }
+    }
+    // F1-F12 follow each other
+    if let Some(n) = name.strip_prefix('f').and_then(|n| n.parse::<i32>().ok()) {
+        if (1..=12).contains(&n) {
+            return Keycode::from_i32(Keycode::F1 as i32 + n - 1);
+        }
+    }
+    None
+}
+
+pub struct Config {
+    pub scale: u32,
+    pub screenshot_scale: usize,
+    pub palette: [(u8, u8, u8); 64],
+    pub mute: bool,
+    pub volume: f32,
+    pub sample_rate: i32,
+    pub latency: u32,
+    pub bindings: HashMap<Keycode, (usize, ControllerButton)>, // Key to player and button
+    pub turbo_bindings: HashMap<Keycode, (usize, ControllerButton)>,
+    pub hotkeys: HashMap<Keycode, Hotkey>,
+    pub macros: HashMap<Keycode, (usize, Macro)>,
+    pub powerpad: HashMap<Keycode, usize>, // Key to Power Pad button 1-12
+    pub pad_buttons: HashMap<Button, ControllerButton>,
+    pub pad_turbo: HashMap<Button, ControllerButton>,
+    pub deadzone: f32,          // Fraction of the left stick's travel
+    pub turbo_rate: u32,        // Frames per toggle
+    pub fast_forward: u32,      // Times normal speed, 0 runs uncapped
+    pub slow_motion: u32,       // Fraction of normal speed
+    pub rewind_interval: usize, // Frames between snapshots
+    pub rewind_memory: usize,   // Megabytes, 0 is off
+}
+
+impl Config {
+    // Player 1 on the arrows with turbo A and B on C and V, player 2 on WASD, players 3 and 4
+    // on IJKL and the keypad, the Power Pad on the number row
+    pub fn new() -> Self {
+        let players = [
+            ["Z", "X", "Space", "Return", "Up", "Down", "Left", "Right"],
+            ["H", "G", "Q", "E", "W", "S", "A", "D"],
+            ["O", "U", "Y", "P", "I", "K", "J", "L"],
+            [
+                "Keypad 3", "Keypad 1", "Keypad 7", "Keypad 9", "Keypad 8", "Keypad 5", "Keypad 4",
+                "Keypad 6",
+            ],
+        ];
+        let mut bindings = HashMap::new();
+        for (player, keys) in players.iter().enumerate() {
+            for (key, (_, button)) in keys.iter().zip(BUTTON_NAMES.iter()) {
+                bindings.insert(key_from_name(key).unwrap(), (player, *button));
+            }
+        }
+        let turbo_bindings = [
+            (Keycode::C, (0, ControllerButton::A)),
+            (Keycode::V, (0, ControllerButton::B)),
+        ]
+        .into_iter()
+        .collect();
+
+        let powerpad = ["1", "2", "3", "4", "5", "6", "7", "8", "9", "0", "-", "["]
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (key_from_name(key).unwrap(), i + 1))
+            .collect();
+
+        let hotkeys = [
+            ("Pause", Hotkey::Pause),
+            ("\\", Hotkey::FrameAdvance),
+            ("Tab", Hotkey::FastForward),
+            ("`", Hotkey::SlowMotion),
+            ("Backspace", Hotkey::FrameCounter),
+            ("Insert", Hotkey::SaveState),
+            ("Home", Hotkey::LoadState),
+            ("PageUp", Hotkey::NextSlot),
+            ("PageDown", Hotkey::PreviousSlot),
+            ("Delete", Hotkey::Rewind),
+            ("End", Hotkey::RecordMovie),
+            ("=", Hotkey::MovieReadOnly),
+            ("PrintScreen", Hotkey::Screenshot),
+            ("]", Hotkey::RecordVideo),
+            ("F10", Hotkey::ReloadConfig),
+            ("F7", Hotkey::Port2Device),
+            ("F8", Hotkey::Adapter),
+            ("F9", Hotkey::Record),
+            ("F11", Hotkey::RecordMacro),
+            ("F12", Hotkey::PlayMacro),
+            ("F1", Hotkey::Mixer(Channel::PULSE1)),
+            ("F2", Hotkey::Mixer(Channel::PULSE2)),
+            ("F3", Hotkey::Mixer(Channel::TRIANGLE)),
+            ("F4", Hotkey::Mixer(Channel::NOISE)),
+            ("F5", Hotkey::Mixer(Channel::DMC)),
+            ("F6", Hotkey::Mixer(Channel::EXPANSION)),
+        ]
+        .iter()
+        .map(|(key, hotkey)| (key_from_name(key).unwrap(), *hotkey))
+        .collect();
+
+        // Like the NES pad, B is on the left and A on the right
+        let pad_buttons = [
+            (Button::B, ControllerButton::A),
+            (Button::A, ControllerButton::B),
+            (Button::Back, ControllerButton::SELECT),
+            (Button::Start, ControllerButton::START),
+        ]
+        .into_iter()
+        .collect();
+        let pad_turbo = [
+            (Button::Y, ControllerButton::A),
+            (Button::X, ControllerButton::B),
+        ]
+        .into_iter()
+        .collect();
+
+        Config {
+            scale: 3,
+            screenshot_scale: 1,
+            palette: SYSTEM_PALLETE,
+            mute: false,
+            volume: 1.0,
+            sample_rate: 48000,
+            latency: 4,
+            bindings,
+            turbo_bindings,
+            hotkeys,
+            macros: HashMap::new(),
+            powerpad,
+            pad_buttons,
+            pad_turbo,
+            deadzone: 0.3,
+            turbo_rate: 2,
+            fast_forward: 4,
+            slow_motion: 2,
+            rewind_interval: 2,
+            rewind_memory: 64,
+        }
+    }
+
+    pub fn default_path() -> Option<PathBuf> {
+        let base = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        };
+        Some(base.join("nexie").join("config.ini"))
+    }
+
+    // A missing file just gives the defaults
+    pub fn load(path: &Path) -> (Config, Vec<String>) {
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                let (config, warnings) = Config::parse(&text);
+                let warnings = warnings
+                    .into_iter()
+                    .map(|warning| format!("{}: {}", path.display(), warning))
+                    .collect();
+                (config, warnings)
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (Config::new(), Vec::new()),
+            Err(err) => (
+                Config::new(),
+                vec![format!("Could not read {}: {}", path.display(), err)],
+            ),
+        }
+    }
+
+    // Warnings about a line start with its number
+    pub fn parse(text: &str) -> (Config, Vec<String>) {
+        let mut config = Config::new();
+        let mut warnings = Vec::new();
+        let mut section = String::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let result =
+                if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                    section = name.trim().to_lowercase();
+                    match section.as_str() {
+                        "video" | "audio" | "speed" | "rewind" | "gamepad" | "input" | "macros"
+                        | "hotkeys" | "powerpad" | "player1" | "player2" | "player3"
+                        | "player4" => Ok(()),
+                        _ => Err(format!("unknown section [{}]", section)),
+                    }
+                } else if let Some((key, value)) = line.split_once('=') {
+                    config.set(&section, &key.trim().to_lowercase(), value.trim())
+                } else {
+                    Err(format!("expected `key = value`, found `{}`", line))
+                };
+            if let Err(warning) = result {
+                warnings.push(format!("line {}: {}", i + 1, warning));
+            }
+        }
+        // A key only does one thing, hotkeys win over macros, then turbo and then buttons
+        let mut shadowed = Vec::new();
+        for (key, hotkey) in config.hotkeys.iter() {
+            let kept = format!("the {:?} hotkey", hotkey);
+            if config.macros.remove(key).is_some() {
+                shadowed.push((*key, "a macro".to_string(), kept.clone()));
+            }
+            if let Some((player, _)) = config.turbo_bindings.remove(key) {
+                shadowed.push((*key, format!("player {} turbo", player + 1), kept.clone()));
+            }
+            if let Some((player, _)) = config.bindings.remove(key) {
+                shadowed.push((*key, format!("player {}", player + 1), kept));
+            }
+        }
+        for key in config.macros.keys() {
+            if let Some((player, _)) = config.turbo_bindings.remove(key) {
+                let lost = format!("player {} turbo", player + 1);
+                shadowed.push((*key, lost, "the macro".to_string()));
+            }
+            if let Some((player, _)) = config.bindings.remove(key) {
+                let lost = format!("player {}", player + 1);
+                shadowed.push((*key, lost, "the macro".to_string()));
+            }
+        }
+        for key in config.turbo_bindings.keys() {
+            if let Some((player, _)) = config.bindings.remove(key) {
+                let lost = format!("player {}", player + 1);
+                shadowed.push((*key, lost, "the turbo button".to_string()));
+            }
+        }
+        // The Power Pad only keeps keys nothing else uses
+        let mut pad_lost = Vec::new();
+        for (key, button) in config.powerpad.iter() {
+            let kept = if let Some(hotkey) = config.hotkeys.get(key) {
+                format!("the {:?} hotkey", hotkey)
+            } else if config.macros.contains_key(key) {
+                "the macro".to_string()
+            } else if let Some((player, _)) = config.turbo_bindings.get(key) {
+                format!("player {} turbo", player + 1)
+            } else if let Some((player, _)) = config.bindings.get(key) {
+                format!("player {}", player + 1)
+            } else {
+                continue;
+            };
+            shadowed.push((*key, format!("Power Pad button {}", button), kept));
+            pad_lost.push(*key);
+        }
+        for key in pad_lost {
+            config.powerpad.remove(&key);
+        }
+        for (key, lost, kept) in shadowed {
+            warnings.push(format!(
+                "{:?} is bound to {} and {}, only {} is kept",
+                key, lost, kept, kept
+            ));
+        }
+        (config, warnings)
+    }
+
+    fn set(&mut self, section: &str, key: &str, value: &str) -> Result<(), String> {
+        match (section, key) {
+            ("video", "scale") => {
+                self.scale = match value.parse() {
+                    Ok(scale) if scale > 0 => scale,
+                    _ => {
+                        return Err(format!(
+                            "scale must be a whole number above 0, not `{}`",
+                            value
+                        ))
+                    }
+                }
+            }
+            ("video", "screenshot_scale") => {
+                self.screenshot_scale = match value.parse() {
+                    Ok(scale) if scale > 0 => scale,
+                    _ => {
+                        return Err(format!(
+                            "screenshot_scale must be a whole number above 0, not `{}`",
+                            value
+                        ))
+                    }
+                }
+            }
+            ("video", "palette") => {
+                self.palette = if value.eq_ignore_ascii_case("default") {
+                    SYSTEM_PALLETE
+                } else {
+                    palette::load(Path::new(value))?
+                }
+            }
+            ("audio", "mute") => self.mute = parse_bool(value)?,
+            ("audio", "volume") => {
+                self.volume = match value.parse::<f32>() {
+                    Ok(volume) if (0.0..=1.0).contains(&volume) => volume,
+                    _ => {
+                        return Err(format!(
+                            "volume must be between 0.0 and 1.0, not `{}`",
+                            value
+                        ))
+                    }
+                }
+            }
+            ("audio", "sample_rate") => {
+                self.sample_rate = match value.parse() {
+                    Ok(rate) if (8000..=192000).contains(&rate) => rate,
+                    _ => return Err(format!("sample_rate must be 8000-192000, not `{}`", value)),
+                }
+            }
+            ("audio", "latency") => {
+                self.latency = match value.parse() {
+                    Ok(latency) if latency > 0 => latency,
+                    _ => return Err(format!("latency must be at least 1 frame, not `{}`", value)),
+                }
+            }
+            ("gamepad", "deadzone") => {
+                self.deadzone = match value.parse::<f32>() {
+                    Ok(deadzone) if (0.0..1.0).contains(&deadzone) => deadzone,
+                    _ => {
+                        return Err(format!(
+                            "deadzone must be from 0.0 up to 1.0, not `{}`",
+                            value
+                        ))
+                    }
+                }
+            }
+            ("speed", "fast_forward") => {
+                self.fast_forward = match value.parse() {
+                    Ok(speed) if speed != 1 => speed,
+                    _ => {
+                        return Err(format!(
+                            "fast_forward must be 0(uncapped) or 2 and up, not `{}`",
+                            value
+                        ))
+                    }
+                }
+            }
+            ("speed", "slow_motion") => {
+                self.slow_motion = match value.parse() {
+                    Ok(speed) if speed > 1 => speed,
+                    _ => return Err(format!("slow_motion must be 2 or more, not `{}`", value)),
+                }
+            }
+            ("rewind", "interval") => {
+                self.rewind_interval = match value.parse() {
+                    Ok(interval) if interval > 0 => interval,
+                    _ => {
+                        return Err(format!(
+                            "rewind interval must be at least 1 frame, not `{}`",
+                            value
+                        ))
+                    }
+                }
+            }
+            ("rewind", "memory") => {
+                self.rewind_memory = value
+                    .parse()
+                    .map_err(|_| format!("rewind memory must be a number of MB, not `{}`", value))?
+            }
+            ("input", "turbo_rate") => {
+                self.turbo_rate = match value.parse() {
+                    Ok(rate) if rate > 0 => rate,
+                    _ => {
+                        return Err(format!(
+                            "turbo_rate must be at least 1 frame, not `{}`",
+                            value
+                        ))
+                    }
+                }
+            }
+            ("macros", _) => {
+                let key = key_from_name(key).ok_or_else(|| format!("unknown key `{}`", key))?;
+                let (player, script) = match value.split_once(':') {
+                    Some((player, script)) => match player.trim().parse::<usize>() {
+                        Ok(player) if (1..=4).contains(&player) => (player - 1, script),
+                        _ => return Err(format!("macro player must be 1-4, not `{}`", player)),
+                    },
+                    None => (0, value),
+                };
+                self.macros.insert(key, (player, Macro::parse(script)?));
+            }
+            ("gamepad", "a" | "b" | "select" | "start" | "turbo_a" | "turbo_b") => {
+                let turbo = key.starts_with("turbo_");
+                let name = key.trim_start_matches("turbo_");
+                let (_, button) = BUTTON_NAMES.iter().find(|(n, _)| *n == name).unwrap();
+                let pad_buttons = value
+                    .split(',')
+                    .map(|name| {
+                        let name = name.trim().to_lowercase();
+                        PAD_BUTTON_NAMES
+                            .iter()
+                            .find(|(pad_name, _)| *pad_name == name)
+                            .map(|(_, pad_button)| *pad_button)
+                            .ok_or_else(|| format!("unknown game controller button `{}`", name))
+                    })
+                    .collect::<Result<Vec<Button>, String>>()?;
+                let (map, other) = if turbo {
+                    (&mut self.pad_turbo, &mut self.pad_buttons)
+                } else {
+                    (&mut self.pad_buttons, &mut self.pad_turbo)
+                };
+                map.retain(|_, b| b != button);
+                for pad_button in pad_buttons {
+                    other.remove(&pad_button);
+                    map.insert(pad_button, *button);
+                }
+            }
+            ("hotkeys", _) => {
+                let (_, hotkey) = HOTKEY_NAMES
+                    .iter()
+                    .find(|(name, _)| *name == key)
+                    .ok_or_else(|| format!("unknown hotkey `{}`", key))?;
+                let keys = parse_keys(value)?;
+                self.hotkeys.retain(|_, bound| bound != hotkey);
+                for key in keys {
+                    self.hotkeys.insert(key, *hotkey);
+                }
+            }
+            ("powerpad", _) => {
+                let button = match key.parse::<usize>() {
+                    Ok(button) if (1..=12).contains(&button) => button,
+                    _ => return Err(format!("Power Pad buttons are 1-12, not `{}`", key)),
+                };
+                let keys = parse_keys(value)?;
+                self.powerpad.retain(|_, bound| *bound != button);
+                for key in keys {
+                    self.powerpad.insert(key, button);
+                }
+            }
+            ("player1" | "player2" | "player3" | "player4", _) => {
+                let player = section["player".len()..].parse::<usize>().unwrap() - 1;
+                let (bindings, name) = match key.strip_prefix("turbo_") {
+                    Some(name) => (&mut self.turbo_bindings, name),
+                    None => (&mut self.bindings, key),
+                };
+                let (_, button) = BUTTON_NAMES
+                    .iter()
+                    .find(|(button_name, _)| *button_name == name)
+                    .ok_or_else(|| format!("unknown button `{}`", key))?;
+                let keys = parse_keys(value)?;
+                bindings.retain(|_, (p, b)| !(*p == player && b == button));
+                for key in keys {
+                    bindings.insert(key, (player, *button));
+                }
+            }
+            ("", _) => return Err(format!("`{}` is outside of a section", key)),
+            _ => return Err(format!("unknown setting `{}` in [{}]", key, section)),
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::new()
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(format!("expected true or false, not `{}`", value)),
+    }
+}
+
+fn parse_keys(value: &str) -> Result<Vec<Keycode>, String> {
+    value
+        .split(',')
+        .map(|name| key_from_name(name).ok_or_else(|| format!("unknown key `{}`", name.trim())))
+        .collect()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let text = "
+            # Comment
+            [video]
+            scale = 2
+
+            [audio]
+            volume = 0.5
+            mute = maybe
+
+            [player1]
+            a = K, Keypad 0
+            turbo = T
+            turbo_b = B
+
+            [input]
+            turbo_rate = 3
+
+            [speed]
+            fast_forward = 0
+
+            [rewind]
+            interval = 0
+            memory = 16
+
+            [macros]
+            M = 2: down*2, a
+            C = start
+
+            [gamepad]
+            a = Y
+            deadzone = 0.5
+
+            [hotkeys]
+            pause = P
+            [net]
+        ";
+        let (config, warnings) = Config::parse(text);
+        assert_eq!(config.scale, 2);
+        assert_eq!(config.volume, 0.5);
+        assert!(!config.mute);
+
+        // Z no longer presses A, K and Keypad 0 do
+        assert!(!config.bindings.contains_key(&Keycode::Z));
+        assert_eq!(config.bindings[&Keycode::K].0, 0);
+        assert_eq!(
+            config.bindings[&Keycode::Kp0].1.bits(),
+            ControllerButton::A.bits()
+        );
+        assert_eq!(config.hotkeys[&Keycode::P], Hotkey::Pause);
+        assert!(!config.hotkeys.contains_key(&Keycode::Pause));
+        assert_eq!(
+            config.pad_buttons[&Button::Y].bits(),
+            ControllerButton::A.bits()
+        );
+        assert!(!config.pad_buttons.contains_key(&Button::B));
+        assert_eq!(config.deadzone, 0.5);
+
+        assert_eq!(config.turbo_rate, 3);
+        assert_eq!(config.fast_forward, 0);
+        assert_eq!(config.rewind_interval, 2);
+        assert_eq!(config.rewind_memory, 16);
+        assert_eq!(config.hotkeys[&Keycode::Backslash], Hotkey::FrameAdvance);
+        assert_eq!(config.turbo_bindings[&Keycode::B], (0, ControllerButton::B));
+        let (player, script) = &config.macros[&Keycode::M];
+        assert_eq!(*player, 1);
+        assert_eq!(script.frames.len(), 3);
+        // C was player 1's turbo A, the macro takes it
+        assert!(config.macros.contains_key(&Keycode::C));
+        assert!(!config.turbo_bindings.contains_key(&Keycode::C));
+
+        // P was player 3's Start
+        assert!(!config.bindings.contains_key(&Keycode::P));
+
+        assert_eq!(warnings.len(), 6);
+        assert!(warnings[0].starts_with("line 8: "));
+        assert!(warnings[1].contains("unknown button `turbo`"));
+        assert!(warnings[2].contains("rewind interval"));
+        assert!(warnings[3].contains("unknown section [net]"));
+        assert!(warnings[4].contains("player 3"));
+        assert!(warnings[5].contains("player 1 turbo"));
+
+        // None of the default Power Pad keys are used elsewhere
+        assert_eq!(config.powerpad.len(), 12);
+        assert_eq!(config.powerpad[&Keycode::Num1], 1);
+        assert_eq!(config.powerpad[&Keycode::LeftBracket], 12);
+    }
+
+    #[test]
+    fn test_parse_powerpad() {
+        let text = "
+            [powerpad]
+            1 = W
+            12 = T, N
+            13 = G
+        ";
+        let (config, warnings) = Config::parse(text);
+
+        // W stays with player 2 and button 1 loses its old key
+        assert_eq!(config.bindings[&Keycode::W].0, 1);
+        assert!(!config.powerpad.contains_key(&Keycode::W));
+        assert!(!config.powerpad.contains_key(&Keycode::Num1));
+        assert_eq!(config.powerpad[&Keycode::T], 12);
+        assert_eq!(config.powerpad[&Keycode::N], 12);
+        assert!(!config.powerpad.contains_key(&Keycode::LeftBracket));
+        assert_eq!(config.powerpad[&Keycode::Num2], 2);
+
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("Power Pad buttons are 1-12"));
+        assert!(warnings[1].contains("Power Pad button 1 and player 2"));
+    }
+}
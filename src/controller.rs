@@ -1,10 +1,13 @@
-// If strobe is off, it will read controller input from highest to lowest bit
-// If strobe is on, it will read the states from the previous inputs and the state of the first button
+// While strobe is on the buttons are reloaded constantly, so every read returns A
+// Once strobe is off the latched buttons are shifted out from A to Right, then the register reads 1s
+// Only D0 is driven here, the bus adds the open bus bits
 
+use crate::input::InputDevice;
+use crate::savestate::{Savestate, StateReader, StateWriter};
 use bitflags::bitflags;
 
 bitflags! {
-    #[derive(Copy, Clone, Debug)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
     pub struct ControllerButton: u8{
         const RIGHT = 0b1000_0000;
         const LEFT = 0b0100_0000;
@@ -19,7 +22,8 @@ bitflags! {
 
 pub struct Controller {
     strobe: bool, // Determines if we are writing input or leaving the read
-    button_index: u8,
+    shift: u8,    // Buttons latched by the strobe
+    reads: u8,    // Bits shifted out since the strobe was released
     button_status: ControllerButton,
 }
 
@@ -27,32 +31,101 @@ impl Controller {
     pub fn new() -> Self {
         Controller {
             strobe: false,
-            button_index: 0,
+            shift: 0,
+            reads: 0,
             button_status: ControllerButton::from_bits_truncate(0b0000_0000),
         }
     }
 
+    fn latch(&mut self) {
+        self.shift = self.button_status.bits();
+        self.reads = 0;
+    }
+
     pub fn write(&mut self, data: u8) {
         self.strobe = data & 1 == 1;
         if self.strobe {
-            // Starts at the first index
-            self.button_index = 0
+            self.latch();
         }
     }
 
     pub fn read(&mut self) -> u8 {
-        if self.button_index > 7 {
-            return 1; // Indicates that there isn't anything left ot read
+        if self.strobe {
+            self.latch();
+        }
+        if self.reads > 7 {
+            return 1; // Official pads shift in 1s once the buttons are out
         }
-        let response = (self.button_status.bits() & (1 << self.button_index)) >> self.button_index;
-        if !self.strobe && self.button_index <= 7 {
-            self.button_index += 1; // Increments the index for the next read
+        let response = self.shift & 1;
+        if !self.strobe {
+            self.shift >>= 1;
+            self.reads += 1;
         }
         response
     }
 
     pub fn set_button_pressed_status(&mut self, button: ControllerButton, input: bool) {
-        println!("Set status to {:?}", button);
         self.button_status.set(button, input);
     }
+
+    pub fn buttons(&self) -> ControllerButton {
+        self.button_status
+    }
+}
+
+impl InputDevice for Controller {
+    fn name(&self) -> &'static str {
+        "controller"
+    }
+
+    fn write(&mut self, data: u8) {
+        Controller::write(self, data);
+    }
+
+    fn read(&mut self, _port: usize) -> u8 {
+        Controller::read(self)
+    }
+
+    fn set_button(&mut self, index: usize, button: ControllerButton, pressed: bool) {
+        if index == 0 {
+            self.set_button_pressed_status(button, pressed);
+        }
+    }
+}
+
+impl Savestate for Controller {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.strobe);
+        w.u8(self.shift);
+        w.u8(self.reads);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.strobe = r.bool();
+        self.shift = r.u8();
+        self.reads = r.u8();
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_controller_reads() {
+        let mut controller = Controller::new();
+        controller.set_button_pressed_status(ControllerButton::A, true);
+        controller.set_button_pressed_status(ControllerButton::RIGHT, true);
+
+        // Strobe held keeps returning A
+        controller.write(1);
+        assert_eq!(controller.read(), 1);
+        assert_eq!(controller.read(), 1);
+
+        controller.write(0);
+        // Presses after the strobe aren't seen until the next one
+        controller.set_button_pressed_status(ControllerButton::B, true);
+        let bits: Vec<u8> = (0..10).map(|_| controller.read()).collect();
+        assert_eq!(bits, vec![1, 0, 0, 0, 0, 0, 0, 1, 1, 1]);
+    }
 }
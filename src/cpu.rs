@@ -1,4 +1,5 @@
 use crate::bus::Bus;
+use crate::savestate::{Savestate, StateReader, StateWriter};
 
 use log::trace;
 
@@ -34,6 +35,7 @@ pub struct CPU<'a> {
     pub bus: Bus<'a>,
     pub halted: bool, // Used for successful exits
     pub cycles: u8, // Stores the number of cycles for one instruction, always restarts to 0 at start of run
+    ticked: u8,     // Cycles of the current instruction the bus has already run
 }
 
 #[derive(Debug)]
@@ -104,10 +106,12 @@ pub trait Mem {
 
 impl Mem for CPU<'_> {
     fn mem_read(&mut self, addr: u16) -> u8 {
+        self.catch_up(addr);
         self.bus.mem_read(addr)
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        self.catch_up(addr);
         self.bus.mem_write(addr, data);
     }
 
@@ -132,6 +136,20 @@ impl<'a> CPU<'a> {
             flags: CpuFlags::from_bits_truncate(0b0010_0100),
             bus: bus,
             cycles: 0, // Starting with 0 clock cycles
+            ticked: 0,
+        }
+    }
+
+    // PPU registers are accessed on the last cycle of an instruction, so the bus runs the cycles
+    // before it first. Otherwise the PPU would see the access up to a whole instruction early,
+    // which matters for the $2002 reads racing the vblank flag
+    // The controller ports catch up too, the Zapper senses light where the beam is
+    fn catch_up(&mut self, addr: u16) {
+        let due = self.cycles.saturating_sub(1);
+        let timed = matches!(addr, 0x2000..=0x3FFF | 0x4016 | 0x4017);
+        if timed && due > self.ticked {
+            self.bus.tick(due - self.ticked);
+            self.ticked = due;
         }
     }
 
@@ -151,6 +169,7 @@ impl<'a> CPU<'a> {
     // This function adds to cycles. This is to avoid any direct augmentation to the cycles(making it more painful to debug)
     fn reset_cycles(&mut self) {
         self.cycles = 0;
+        self.ticked = 0;
     }
 
     fn add_cycles(&mut self, val: u8) {
@@ -213,22 +232,59 @@ impl<'a> CPU<'a> {
         self.pc = self.mem_read_u16(0xFFFA);
     }
 
+    // Maskable interrupt, ignored while the interrupt disable flag is set
+    fn interrupt_irq(&mut self) {
+        self.stack_push_u16(self.pc);
+        let mut flag = self.flags;
+        flag.set(CpuFlags::BREAK, false);
+        flag.set(CpuFlags::BREAK2, true);
+
+        self.stack_push(flag.bits());
+        self.flags.insert(CpuFlags::INTERRUPT_DISABLE);
+
+        self.bus.tick(7);
+        self.pc = self.mem_read_u16(0xFFFE);
+    }
+
+    // Enters a subroutine as if a JSR had been executed, its RTS comes back to return_addr
+    // Used to drive code that isn't a whole program, like the INIT and PLAY routines of NSF tunes
+    pub fn call(&mut self, addr: u16, return_addr: u16) {
+        self.stack_push_u16(return_addr.wrapping_sub(1));
+        self.pc = addr;
+    }
+
     pub fn run(&mut self) {
         self.run_with_callback(|_| {});
     }
 
+    // Runs until the PPU finishes the frame it's on, for tools that step the console a frame
+    // at a time
+    pub fn run_frame(&mut self) {
+        let frame = self.bus.ppu.frame;
+        self.run_with_callback(|cpu| {
+            if cpu.bus.ppu.frame != frame {
+                cpu.halted = true;
+            }
+        });
+        self.halted = false;
+    }
+
     pub fn run_with_callback<F>(&mut self, mut callback: F)
     where
         F: FnMut(&mut CPU),
     {
         loop {
             if let Some(_nmi) = self.bus.poll_nmi_status() {
-                println!("nmi triggered!");
+                debug!("nmi triggered!");
                 self.interrupt_nmi();
+            } else if self.bus.poll_irq_status()
+                && !self.flags.contains(CpuFlags::INTERRUPT_DISABLE)
+            {
+                self.interrupt_irq();
             }
 
             if self.halted {
-                println!("Got EOF signal! Exiting program...");
+                debug!("Halted, leaving the run loop");
                 break;
             }
             // trace!(
@@ -475,7 +531,9 @@ impl<'a> CPU<'a> {
 
             debug!("Calling tick, total number of cycles is {}", self.cycles);
             // Call tick to allow the PPU to catch up
-            self.bus.tick(self.cycles); // self.cycles only contain the number of cycles after the current instruction
+            // self.cycles only contain the number of cycles after the current instruction, some of
+            // them may already have run before a PPU register access
+            self.bus.tick(self.cycles.saturating_sub(self.ticked));
 
             self.pc = self.pc.wrapping_add(1);
 
@@ -1531,3 +1589,70 @@ impl<'a> CPU<'a> {
         }
     }
 }
+
+impl Savestate for CPU<'_> {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.pc);
+        w.u8(self.a);
+        w.u8(self.x);
+        w.u8(self.y);
+        w.u8(self.sp);
+        w.u8(self.flags.bits());
+        w.u8(self.cycles);
+        w.component(&self.bus);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.pc = r.u16();
+        self.a = r.u8();
+        self.x = r.u8();
+        self.y = r.u8();
+        self.sp = r.u8();
+        self.flags = CpuFlags::from_bits_truncate(r.u8());
+        self.cycles = r.u8();
+        r.component(&mut self.bus);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::apu::APU;
+    use crate::input::InputPorts;
+    use crate::mapper;
+    use crate::ppu::PPU;
+    use crate::region::Region;
+    use crate::rom::Mirroring;
+
+    // Runs LDA $2002 with the PPU starting on the given dot of the line before vblank
+    // Returns the value read and whether the NMI was taken straight after
+    fn read_status_from(dot: usize) -> (u8, bool) {
+        let mut prg = vec![0; 0x8000];
+        prg[..6].copy_from_slice(&[0xAD, 0x02, 0x20, 0x4C, 0x03, 0x80]); // LDA $2002, JMP *
+        prg[0x7FFA..].copy_from_slice(&[0x00, 0x90, 0x00, 0x80, 0x00, 0x80]);
+        let bus = Bus::with_mapper(
+            mapper::new(0, prg).unwrap(),
+            vec![0; 0x2000],
+            Mirroring::HORIZONTAL,
+            Region::NTSC,
+            |_: &PPU, _: &mut APU, _: &mut InputPorts| {},
+        );
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu.bus.ppu.write_to_ctrl(0x80);
+        cpu.bus.ppu.scanline = 240;
+        cpu.bus.ppu.cycles = dot;
+        cpu.run_with_callback(|cpu| cpu.halted = true);
+        (cpu.a & 0x80, cpu.pc == 0x9000)
+    }
+
+    #[test]
+    fn test_vblank_read_timing() {
+        // The read lands on the 4th cycle, 9 dots in, the flag is set on dot 1 of line 241
+        assert_eq!(read_status_from(330), (0, true));
+        assert_eq!(read_status_from(332), (0, false)); // One dot before, the flag never sets
+        assert_eq!(read_status_from(333), (0, false)); // Same dot, reads clear
+        assert_eq!(read_status_from(334), (0x80, false));
+        assert_eq!(read_status_from(336), (0x80, true));
+    }
+}
@@ -1,14 +1,23 @@
+use crate::palette::SYSTEM_PALLETE;
+use crate::png;
+use std::io;
+use std::path::Path;
+
 pub struct Frame {
     pub data: Vec<u8>,
+    pub palette: [(u8, u8, u8); 64], // Colours the PPU's palette indices are drawn with
+    pub emphasis: u8,                // PPUMASK emphasis bits(0bBGR) the frame was rendered with
 }
 
 impl Frame {
-    const WIDTH: usize = 256;
-    const HIGHT: usize = 240;
+    pub const WIDTH: usize = 256;
+    pub const HIGHT: usize = 240;
 
     pub fn new() -> Self {
         Frame {
             data: vec![0; (Frame::WIDTH) * (Frame::HIGHT) * 3],
+            palette: SYSTEM_PALLETE,
+            emphasis: 0,
         }
     }
 
@@ -20,4 +29,32 @@ impl Frame {
             self.data[base + 2] = rgb.2;
         }
     }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> Option<(u8, u8, u8)> {
+        if x >= Frame::WIDTH || y >= Frame::HIGHT {
+            return None;
+        }
+        let base = y * 3 * Frame::WIDTH + x * 3;
+        Some((self.data[base], self.data[base + 1], self.data[base + 2]))
+    }
+
+    // Screenshots, scale 1 is the frame's own 256x240
+    pub fn to_png(&self, scale: usize) -> Vec<u8> {
+        let scale = scale.max(1);
+        let rgb = png::scale(Frame::WIDTH, Frame::HIGHT, &self.data, scale);
+        png::encode(Frame::WIDTH * scale, Frame::HIGHT * scale, &rgb)
+    }
+
+    pub fn save_png(&self, path: &Path, scale: usize) -> io::Result<()> {
+        std::fs::write(path, self.to_png(scale))
+    }
+
+    // Sets every pixel to one colour, used for the backdrop
+    pub fn fill(&mut self, rgb: (u8, u8, u8)) {
+        for pixel in self.data.chunks_exact_mut(3) {
+            pixel[0] = rgb.0;
+            pixel[1] = rgb.1;
+            pixel[2] = rgb.2;
+        }
+    }
 }
@@ -0,0 +1,147 @@
+// Game database for the region of dumps whose iNES headers don't say
+// Games are told apart by the CRC32 of their PRG ROM followed by their CHR ROM, the header isn't part of it
+// One game per line, `<crc32 in hex> <ntsc|pal|dendy>` with anything after # ignored
+//
+// The built in list is src/gamedb.txt, $XDG_CONFIG_HOME/nexie/gamedb.txt (or ~/.config/nexie/gamedb.txt)
+// adds to it and wins where both list a game
+// NES 2.0 timing in the header is trusted over the database and --region over both
+use crate::png::crc32;
+use crate::region::Region;
+use crate::rom::Rom;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const BUILTIN: &str = include_str!("gamedb.txt");
+
+pub struct GameDb {
+    regions: HashMap<u32, Region>,
+}
+
+impl GameDb {
+    pub fn builtin() -> Self {
+        GameDb::parse(BUILTIN).0
+    }
+
+    // Builds the database both frontends use
+    pub fn load_default() -> (GameDb, Vec<String>) {
+        match GameDb::default_path() {
+            Some(path) => GameDb::load(&path),
+            None => (GameDb::builtin(), Vec::new()),
+        }
+    }
+
+    pub fn default_path() -> Option<PathBuf> {
+        let base = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        };
+        Some(base.join("nexie").join("gamedb.txt"))
+    }
+
+    // The built in list with the user's file on top, a missing file just gives the built in list
+    pub fn load(path: &Path) -> (GameDb, Vec<String>) {
+        let mut db = GameDb::builtin();
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                let (user, warnings) = GameDb::parse(&text);
+                db.regions.extend(user.regions);
+                let warnings = warnings
+                    .into_iter()
+                    .map(|warning| format!("{}: {}", path.display(), warning))
+                    .collect();
+                (db, warnings)
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (db, Vec::new()),
+            Err(err) => (
+                db,
+                vec![format!("Could not read {}: {}", path.display(), err)],
+            ),
+        }
+    }
+
+    // Warnings about a line start with its number
+    pub fn parse(text: &str) -> (GameDb, Vec<String>) {
+        let mut regions = HashMap::new();
+        let mut warnings = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let crc = fields
+                .next()
+                .and_then(|crc| u32::from_str_radix(crc, 16).ok());
+            let region = fields.next().and_then(Region::from_name);
+            match (crc, region, fields.next()) {
+                (Some(crc), Some(region), None) => {
+                    regions.insert(crc, region);
+                }
+                _ => warnings.push(format!(
+                    "line {}: expected `<crc32> <ntsc|pal|dendy>`, found `{}`",
+                    i + 1,
+                    line
+                )),
+            }
+        }
+        (GameDb { regions }, warnings)
+    }
+
+    pub fn region(&self, prg_rom: &[u8], chr_rom: &[u8]) -> Option<Region> {
+        self.regions.get(&crc32(&[prg_rom, chr_rom])).copied()
+    }
+
+    // Only fills in the region when the header has no NES 2.0 timing
+    pub fn apply(&self, rom: &mut Rom) {
+        if rom.nes2_timing {
+            return;
+        }
+        if let Some(region) = self.region(&rom.prg_rom, &rom.chr_rom) {
+            rom.region = region;
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::rom::Mirroring;
+
+    fn rom(nes2_timing: bool) -> Rom {
+        Rom {
+            prg_rom: vec![0xEA; 0x4000],
+            chr_rom: vec![0; 0x2000],
+            mapper: 0,
+            screen_mirroring: Mirroring::HORIZONTAL,
+            region: Region::NTSC,
+            nes2_timing,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let (db, warnings) =
+            GameDb::parse("# Comment\n1234abcd pal # Title\n\nFFFF0000 Dendy\nzz pal\n1 ntsc x\n");
+        assert_eq!(db.regions.get(&0x1234_ABCD), Some(&Region::PAL));
+        assert_eq!(db.regions.get(&0xFFFF_0000), Some(&Region::DENDY));
+        assert_eq!(db.regions.len(), 2);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("line 5:"));
+
+        assert!(GameDb::parse(BUILTIN).1.is_empty());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut plain = rom(false);
+        let crc = crc32(&[&plain.prg_rom, &plain.chr_rom]);
+        let (db, _) = GameDb::parse(&format!("{:08X} pal", crc));
+        db.apply(&mut plain);
+        assert_eq!(plain.region, Region::PAL);
+
+        // NES 2.0 timing wins over the database
+        let mut nes2 = rom(true);
+        db.apply(&mut nes2);
+        assert_eq!(nes2.region, Region::NTSC);
+    }
+}
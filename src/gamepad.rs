@@ -0,0 +1,272 @@
+use crate::config::Config;
+use crate::controller::ControllerButton;
+use crate::input::InputPorts;
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::event::Event;
+use sdl2::GameControllerSubsystem;
+
+const DIRECTIONS: [ControllerButton; 4] = [
+    ControllerButton::UP,
+    ControllerButton::DOWN,
+    ControllerButton::LEFT,
+    ControllerButton::RIGHT,
+];
+const MAX_PLAYERS: usize = 4;
+
+struct Pad {
+    controller: GameController,
+    player: usize,
+    directions: Directions,
+}
+
+// The d-pad and left stick of a pad, combined so letting go of one doesn't release the other
+struct Directions {
+    dpad: ControllerButton,
+    stick: ControllerButton, // Directions the left stick is pushed past the deadzone
+}
+
+// How far an axis has to move, deadzone is a fraction of its travel
+fn deadzone_threshold(deadzone: f32) -> i32 {
+    (deadzone * i16::MAX as f32) as i32
+}
+
+// Pads take the lowest player no other pad has
+fn lowest_free_player(taken: &[usize]) -> Option<usize> {
+    (0..MAX_PLAYERS).find(|player| !taken.contains(player))
+}
+
+// Game controllers with SDL's standard mapping, opened and closed as they're plugged in
+// SDL also reports the pads connected at start as added, so there's no separate scan
+// Each pad takes the lowest free player, so the first two land on ports 1 and 2
+pub struct Gamepads {
+    subsystem: GameControllerSubsystem,
+    pads: Vec<Pad>,
+}
+
+impl Gamepads {
+    pub fn new(subsystem: GameControllerSubsystem) -> Self {
+        Gamepads {
+            subsystem,
+            pads: Vec::new(),
+        }
+    }
+
+    // Returns true if the event belonged to a game controller
+    pub fn handle_event(&mut self, event: &Event, input: &mut InputPorts, config: &Config) -> bool {
+        match *event {
+            Event::ControllerDeviceAdded { which, .. } => self.add(which),
+            Event::ControllerDeviceRemoved { which, .. } => self.remove(which, input),
+            Event::ControllerButtonDown { which, button, .. } => {
+                self.button(which, button, true, input, config)
+            }
+            Event::ControllerButtonUp { which, button, .. } => {
+                self.button(which, button, false, input, config)
+            }
+            Event::ControllerAxisMotion {
+                which, axis, value, ..
+            } => self.axis(which, axis, value, input, config),
+            _ => return false,
+        }
+        true
+    }
+
+    fn add(&mut self, joystick_index: u32) {
+        let controller = match self.subsystem.open(joystick_index) {
+            Ok(controller) => controller,
+            Err(err) => {
+                println!("Could not open game controller {}: {}", joystick_index, err);
+                return;
+            }
+        };
+        let id = controller.instance_id();
+        if self
+            .pads
+            .iter()
+            .any(|pad| pad.controller.instance_id() == id)
+        {
+            return;
+        }
+        let taken: Vec<usize> = self.pads.iter().map(|pad| pad.player).collect();
+        let Some(player) = lowest_free_player(&taken) else {
+            println!("No free player for {}", controller.name());
+            return;
+        };
+        println!("{} is player {}", controller.name(), player + 1);
+        self.pads.push(Pad {
+            controller,
+            player,
+            directions: Directions::new(),
+        });
+    }
+
+    fn remove(&mut self, id: u32, input: &mut InputPorts) {
+        if let Some(i) = self
+            .pads
+            .iter()
+            .position(|pad| pad.controller.instance_id() == id)
+        {
+            let pad = self.pads.remove(i);
+            // Don't leave anything held down
+            input.set_button(pad.player, ControllerButton::all(), false);
+            input.set_turbo(pad.player, ControllerButton::all(), false);
+            println!(
+                "{} disconnected, player {} is free",
+                pad.controller.name(),
+                pad.player + 1
+            );
+        }
+    }
+
+    fn pad(&mut self, id: u32) -> Option<&mut Pad> {
+        self.pads
+            .iter_mut()
+            .find(|pad| pad.controller.instance_id() == id)
+    }
+
+    fn button(
+        &mut self,
+        id: u32,
+        button: Button,
+        pressed: bool,
+        input: &mut InputPorts,
+        config: &Config,
+    ) {
+        let Some(pad) = self.pad(id) else {
+            return;
+        };
+        let direction = match button {
+            Button::DPadUp => ControllerButton::UP,
+            Button::DPadDown => ControllerButton::DOWN,
+            Button::DPadLeft => ControllerButton::LEFT,
+            Button::DPadRight => ControllerButton::RIGHT,
+            _ => {
+                if let Some(nes_button) = config.pad_buttons.get(&button) {
+                    input.set_button(pad.player, *nes_button, pressed);
+                }
+                if let Some(nes_button) = config.pad_turbo.get(&button) {
+                    input.set_turbo(pad.player, *nes_button, pressed);
+                }
+                return;
+            }
+        };
+        pad.directions
+            .set_dpad(direction, pressed, pad.player, input);
+    }
+
+    fn axis(&mut self, id: u32, axis: Axis, value: i16, input: &mut InputPorts, config: &Config) {
+        let Some(pad) = self.pad(id) else {
+            return;
+        };
+        let axis = match axis {
+            Axis::LeftX => (ControllerButton::LEFT, ControllerButton::RIGHT),
+            Axis::LeftY => (ControllerButton::UP, ControllerButton::DOWN),
+            _ => return,
+        };
+        pad.directions
+            .set_stick(axis, value, config.deadzone, pad.player, input);
+    }
+}
+
+impl Directions {
+    fn new() -> Self {
+        Directions {
+            dpad: ControllerButton::empty(),
+            stick: ControllerButton::empty(),
+        }
+    }
+
+    fn held(&self) -> ControllerButton {
+        self.dpad | self.stick
+    }
+
+    fn set_dpad(
+        &mut self,
+        direction: ControllerButton,
+        pressed: bool,
+        player: usize,
+        input: &mut InputPorts,
+    ) {
+        let before = self.held();
+        self.dpad.set(direction, pressed);
+        self.send_changes(before, player, input);
+    }
+
+    // axis is the directions for the negative and positive ends
+    fn set_stick(
+        &mut self,
+        axis: (ControllerButton, ControllerButton),
+        value: i16,
+        deadzone: f32,
+        player: usize,
+        input: &mut InputPorts,
+    ) {
+        let threshold = deadzone_threshold(deadzone);
+        let before = self.held();
+        self.stick.set(axis.0, (value as i32) < -threshold);
+        self.stick.set(axis.1, value as i32 > threshold);
+        self.send_changes(before, player, input);
+    }
+
+    // Only changes are sent, so stick noise doesn't release directions held on the keyboard
+    fn send_changes(&self, before: ControllerButton, player: usize, input: &mut InputPorts) {
+        let held = self.held();
+        for direction in DIRECTIONS {
+            if held.contains(direction) != before.contains(direction) {
+                input.set_button(player, direction, held.contains(direction));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    const X_AXIS: (ControllerButton, ControllerButton) =
+        (ControllerButton::LEFT, ControllerButton::RIGHT);
+
+    #[test]
+    fn test_deadzone() {
+        let mut input = InputPorts::new();
+        let mut directions = Directions::new();
+        assert_eq!(deadzone_threshold(0.5), 16383);
+        directions.set_stick(X_AXIS, 16383, 0.5, 0, &mut input);
+        assert_eq!(input.state(0), ControllerButton::empty());
+        directions.set_stick(X_AXIS, 16384, 0.5, 0, &mut input);
+        assert_eq!(input.state(0), ControllerButton::RIGHT);
+        directions.set_stick(X_AXIS, -20000, 0.5, 0, &mut input);
+        assert_eq!(input.state(0), ControllerButton::LEFT);
+        directions.set_stick(X_AXIS, i16::MIN, 0.0, 0, &mut input);
+        assert_eq!(input.state(0), ControllerButton::LEFT);
+        directions.set_stick(X_AXIS, 0, 0.0, 0, &mut input);
+        assert_eq!(input.state(0), ControllerButton::empty());
+    }
+
+    #[test]
+    fn test_dpad_and_stick_merge() {
+        let mut input = InputPorts::new();
+        let mut directions = Directions::new();
+        directions.set_dpad(ControllerButton::RIGHT, true, 1, &mut input);
+        directions.set_stick(X_AXIS, i16::MAX, 0.3, 1, &mut input);
+        // Letting go of the stick keeps the d-pad's right held
+        directions.set_stick(X_AXIS, 0, 0.3, 1, &mut input);
+        assert_eq!(input.state(1), ControllerButton::RIGHT);
+        directions.set_dpad(ControllerButton::RIGHT, false, 1, &mut input);
+        assert_eq!(input.state(1), ControllerButton::empty());
+
+        // Stick noise doesn't release a direction held from somewhere else
+        input.set_button(1, ControllerButton::UP, true);
+        directions.set_stick(X_AXIS, 100, 0.3, 1, &mut input);
+        assert_eq!(input.state(1), ControllerButton::UP);
+        assert_eq!(input.state(0), ControllerButton::empty());
+    }
+
+    #[test]
+    fn test_lowest_free_player() {
+        assert_eq!(lowest_free_player(&[]), Some(0));
+        assert_eq!(lowest_free_player(&[0, 1]), Some(2));
+        // A pad unplugged from port 1 leaves it free for the next one
+        assert_eq!(lowest_free_player(&[1, 2]), Some(0));
+        assert_eq!(lowest_free_player(&[3, 0, 2, 1]), None);
+    }
+}
@@ -0,0 +1,399 @@
+pub mod arkanoid;
+pub mod four_score;
+pub mod macros;
+pub mod power_pad;
+pub mod zapper;
+
+use crate::controller::{Controller, ControllerButton};
+use crate::frame::Frame;
+use crate::movie::{Movie, MovieMode, MoviePlayback};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use macros::Macro;
+
+// Anything that plugs into a controller port or the Famicom expansion port
+// Every device sees the strobe written to 0x4016 and is read through 0x4016(port 0) or 0x4017(port 1)
+// NES port devices answer on D0, D3 and D4, expansion port devices on D1-D4
+// The frontend's input is offered to every device, each one only picks up what it has
+// Save states only hold a device's latches, what the frontend is pressing isn't machine state
+pub trait InputDevice: Savestate {
+    fn write(&mut self, data: u8);
+    fn read(&mut self, port: usize) -> u8;
+    // The name device_from_name or expansion_from_name knows it by
+    fn name(&self) -> &'static str;
+
+    // index picks the pad inside the device, a Four Score or Hori adapter holds two
+    fn set_button(&mut self, _index: usize, _button: ControllerButton, _pressed: bool) {}
+    // Screen coordinates of the mouse, used for aiming and the paddle position
+    fn set_pointer(&mut self, _x: i32, _y: i32) {}
+    fn set_trigger(&mut self, _pressed: bool) {}
+    // Power Pad buttons are numbered 1-12
+    fn set_pad_button(&mut self, _button: usize, _pressed: bool) {}
+    // Called with every frame the PPU draws for devices that look at the screen
+    fn update_frame(&mut self, _frame: &Frame) {}
+    // Where the PPU is drawing, given before every read
+    fn set_beam(&mut self, _scanline: u16, _dot: usize) {}
+}
+
+// Devices by name for choosing them from the frontend
+// Port devices: controller, fourscore, zapper, arkanoid, powerpad
+// Expansion devices: hori, arkanoid-fc
+pub fn device_from_name(name: &str, port: usize) -> Option<Box<dyn InputDevice>> {
+    let device: Box<dyn InputDevice> = match name.to_lowercase().as_str() {
+        "controller" => Box::new(Controller::new()),
+        "fourscore" => Box::new(four_score::FourScore::new(port)),
+        "zapper" => Box::new(zapper::Zapper::new()),
+        "arkanoid" => Box::new(arkanoid::ArkanoidPaddle::new(false)),
+        "powerpad" => Box::new(power_pad::PowerPad::new()),
+        _ => return None,
+    };
+    Some(device)
+}
+
+pub fn expansion_from_name(name: &str) -> Option<Box<dyn InputDevice>> {
+    let device: Box<dyn InputDevice> = match name.to_lowercase().as_str() {
+        "hori" => Box::new(four_score::Hori::new()),
+        "arkanoid-fc" => Box::new(arkanoid::ArkanoidPaddle::new(true)),
+        _ => return None,
+    };
+    Some(device)
+}
+
+const PLAYERS: usize = 4;
+const DEFAULT_TURBO_RATE: u32 = 2;
+
+struct MacroPlayback {
+    player: usize,
+    frames: Vec<ControllerButton>,
+    position: usize,
+}
+
+// The pads' state is worked out here once per frame from what the frontend holds, turbo and
+// running macros, and only that is passed on to the devices
+// state() is what the console sees, so that's what movies and netplay should record
+pub struct InputPorts {
+    ports: [Box<dyn InputDevice>; 2],
+    expansion: Option<Box<dyn InputDevice>>,
+    held: [ControllerButton; PLAYERS],
+    turbo: [ControllerButton; PLAYERS],
+    turbo_rate: u32, // Frames per toggle
+    macros: Vec<MacroPlayback>,
+    recording: Option<(usize, Vec<ControllerButton>)>,
+    sent: [ControllerButton; PLAYERS], // What the devices were last given
+    frame: u64,
+    movie: Option<MoviePlayback>,
+}
+
+impl InputPorts {
+    pub fn new() -> Self {
+        InputPorts {
+            ports: [Box::new(Controller::new()), Box::new(Controller::new())],
+            expansion: None,
+            held: [ControllerButton::empty(); PLAYERS],
+            turbo: [ControllerButton::empty(); PLAYERS],
+            turbo_rate: DEFAULT_TURBO_RATE,
+            macros: Vec::new(),
+            recording: None,
+            sent: [ControllerButton::empty(); PLAYERS],
+            frame: 0,
+            movie: None,
+        }
+    }
+
+    pub fn set_port(&mut self, port: usize, device: Box<dyn InputDevice>) {
+        self.ports[port] = device;
+        self.resend();
+    }
+
+    pub fn set_expansion(&mut self, device: Option<Box<dyn InputDevice>>) {
+        self.expansion = device;
+        self.resend();
+    }
+
+    fn devices(&mut self) -> impl Iterator<Item = &mut Box<dyn InputDevice>> {
+        self.ports.iter_mut().chain(self.expansion.iter_mut())
+    }
+
+    // 0x4016 writes strobe every port at once
+    pub fn write(&mut self, data: u8) {
+        for device in self.devices() {
+            device.write(data);
+        }
+    }
+
+    // D0-D4 of 0x4016/0x4017, the bus fills in the open bus bits
+    pub fn read(&mut self, port: usize) -> u8 {
+        let mut data = self.ports[port].read(port) & 0b1_1001;
+        if let Some(expansion) = self.expansion.as_mut() {
+            data |= expansion.read(port) & 0b1_1110;
+        }
+        data
+    }
+
+    pub fn set_button(&mut self, player: usize, button: ControllerButton, pressed: bool) {
+        if player < PLAYERS {
+            self.held[player].set(button, pressed);
+            self.update(player);
+        }
+    }
+
+    // Turbo buttons are pressed for turbo_rate frames and released for the next turbo_rate
+    pub fn set_turbo(&mut self, player: usize, button: ControllerButton, pressed: bool) {
+        if player < PLAYERS {
+            self.turbo[player].set(button, pressed);
+            self.update(player);
+        }
+    }
+
+    pub fn set_turbo_rate(&mut self, frames: u32) {
+        self.turbo_rate = frames.max(1);
+    }
+
+    // The macro's first frame starts straight away, its buttons are added to whatever's held
+    pub fn play_macro(&mut self, player: usize, script: &Macro) {
+        if player < PLAYERS && !script.frames.is_empty() {
+            self.macros.push(MacroPlayback {
+                player,
+                frames: script.frames.clone(),
+                position: 0,
+            });
+            self.update(player);
+        }
+    }
+
+    // Records the player's state every frame until stop_recording
+    pub fn start_recording(&mut self, player: usize) {
+        if player < PLAYERS {
+            self.recording = Some((player, Vec::new()));
+        }
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Macro> {
+        self.recording.take().map(|(_, frames)| Macro { frames })
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    // A movie being played replaces everything else, a recording one starts on this frame
+    pub fn start_movie(&mut self, mut movie: Movie, mode: MovieMode, read_only: bool) {
+        if mode == MovieMode::Recording {
+            movie.four_score = self.ports[0].name() == "fourscore";
+        } else if movie.four_score {
+            for port in 0..2 {
+                self.ports[port] = Box::new(four_score::FourScore::new(port));
+            }
+        }
+        self.movie = Some(MoviePlayback::new(movie, mode, read_only, self.frame));
+        self.resend();
+    }
+
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        let playback = self.movie.take()?;
+        self.resend();
+        Some(playback.movie)
+    }
+
+    pub fn movie(&self) -> Option<&MoviePlayback> {
+        self.movie.as_ref()
+    }
+
+    pub fn movie_mut(&mut self) -> Option<&mut MoviePlayback> {
+        self.movie.as_mut()
+    }
+
+    // The buttons the console sees for this frame
+    pub fn state(&self, player: usize) -> ControllerButton {
+        if let Some(pads) = self
+            .movie
+            .as_ref()
+            .and_then(|movie| movie.input(self.frame))
+        {
+            return pads[player];
+        }
+        let mut state = self.held[player];
+        if (self.frame / self.turbo_rate as u64).is_multiple_of(2) {
+            state |= self.turbo[player];
+        }
+        for playback in self.macros.iter().filter(|m| m.player == player) {
+            state |= playback.frames[playback.position];
+        }
+        state
+    }
+
+    fn load_device(&mut self, r: &mut StateReader, port: Option<usize>) {
+        let name = String::from_utf8_lossy(r.bytes()).to_string();
+        let current = match port {
+            Some(port) => Some(self.ports[port].name()),
+            None => self.expansion.as_ref().map(|device| device.name()),
+        };
+        if current != Some(name.as_str()) {
+            match port {
+                Some(port) => {
+                    if let Some(device) = device_from_name(&name, port) {
+                        self.ports[port] = device;
+                    }
+                }
+                None => self.expansion = expansion_from_name(&name),
+            }
+        }
+        let device = match port {
+            Some(port) => Some(&mut self.ports[port]),
+            None => self.expansion.as_mut(),
+        };
+        if let Some(device) = device {
+            r.component(device.as_mut());
+        }
+    }
+
+    // Called by the bus at the start of every frame
+    pub fn next_frame(&mut self) {
+        if let Some((player, frames)) = self.recording.as_mut() {
+            frames.push(self.sent[*player]);
+        }
+        if let Some(movie) = self.movie.as_mut() {
+            movie.end_frame(self.frame, self.sent);
+        }
+        self.frame += 1;
+        if let Some(movie) = self.movie.as_mut() {
+            movie.next_frame(self.frame);
+        }
+        for playback in self.macros.iter_mut() {
+            playback.position += 1;
+        }
+        self.macros.retain(|m| m.position < m.frames.len());
+        for player in 0..PLAYERS {
+            self.update(player);
+        }
+    }
+
+    // Passes the buttons that changed on to the devices
+    fn update(&mut self, player: usize) {
+        let state = self.state(player);
+        for button in ControllerButton::all().iter() {
+            if state.contains(button) != self.sent[player].contains(button) {
+                self.send_button(player, button, state.contains(button));
+            }
+        }
+        self.sent[player] = state;
+    }
+
+    // A new device starts with nothing pressed
+    fn resend(&mut self) {
+        self.sent = [ControllerButton::empty(); PLAYERS];
+        for player in 0..PLAYERS {
+            self.update(player);
+        }
+    }
+
+    // Players 1 and 2 are the pads in each port, players 3 and 4 are the second pad of
+    // a Four Score in that port or the pads on a Hori adapter
+    fn send_button(&mut self, player: usize, button: ControllerButton, pressed: bool) {
+        match player {
+            0 | 1 => self.ports[player].set_button(0, button, pressed),
+            _ => {
+                self.ports[player - 2].set_button(1, button, pressed);
+                if let Some(expansion) = self.expansion.as_mut() {
+                    expansion.set_button(player - 2, button, pressed);
+                }
+            }
+        }
+    }
+
+    pub fn set_pointer(&mut self, x: i32, y: i32) {
+        for device in self.devices() {
+            device.set_pointer(x, y);
+        }
+    }
+
+    pub fn set_trigger(&mut self, pressed: bool) {
+        for device in self.devices() {
+            device.set_trigger(pressed);
+        }
+    }
+
+    pub fn set_pad_button(&mut self, button: usize, pressed: bool) {
+        for device in self.devices() {
+            device.set_pad_button(button, pressed);
+        }
+    }
+
+    pub fn update_frame(&mut self, frame: &Frame) {
+        for device in self.devices() {
+            device.update_frame(frame);
+        }
+    }
+
+    pub fn set_beam(&mut self, scanline: u16, dot: usize) {
+        for device in self.devices() {
+            device.set_beam(scanline, dot);
+        }
+    }
+}
+
+impl Default for InputPorts {
+    fn default() -> Self {
+        InputPorts::new()
+    }
+}
+
+// The devices that are plugged in and their latches, the frontend's buttons go back to the
+// devices after loading
+impl Savestate for InputPorts {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u64(self.frame);
+        let devices = self.ports.iter().map(Some).chain([self.expansion.as_ref()]);
+        for device in devices {
+            match device {
+                Some(device) => {
+                    w.bytes(device.name().as_bytes());
+                    w.component(device.as_ref());
+                }
+                None => w.bytes(&[]),
+            }
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.frame = r.u64();
+        if let Some(movie) = self.movie.as_mut() {
+            movie.seek(self.frame);
+        }
+        self.load_device(r, Some(0));
+        self.load_device(r, Some(1));
+        self.load_device(r, None);
+        self.resend();
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_turbo_and_macros() {
+        let mut input = InputPorts::new();
+        input.set_turbo_rate(2);
+        input.set_turbo(0, ControllerButton::A, true);
+        let turbo: Vec<bool> = (0..6)
+            .map(|_| {
+                let pressed = input.state(0).contains(ControllerButton::A);
+                input.next_frame();
+                pressed
+            })
+            .collect();
+        assert_eq!(turbo, vec![true, true, false, false, true, true]);
+        input.set_turbo(0, ControllerButton::A, false);
+
+        input.set_button(1, ControllerButton::B, true);
+        input.start_recording(1);
+        input.play_macro(1, &Macro::parse("up*2, a").unwrap());
+        for _ in 0..4 {
+            input.next_frame();
+        }
+        let recorded = input.stop_recording().unwrap();
+        let b = ControllerButton::B;
+        let up = b | ControllerButton::UP;
+        assert_eq!(recorded.frames, vec![up, up, b | ControllerButton::A, b]);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::input::InputDevice;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// Range of the knob's 8-bit reading, the game's paddle covers the whole range
+const MIN_POSITION: u8 = 0x62;
+const MAX_POSITION: u8 = 0xF2;
+
+// Taito Arkanoid Vaus controller, the knob follows the mouse's x position
+// The knob's position is latched on strobe and shifted out inverted, highest bit first
+// NES version: D3 is the serial data, D4 is the button
+// Famicom version(expansion port): 0x4016 D1 is the button, 0x4017 D1 is the serial data
+pub struct ArkanoidPaddle {
+    famicom: bool,
+    position: u8,
+    button: bool,
+    shift: u8,
+}
+
+impl ArkanoidPaddle {
+    pub fn new(famicom: bool) -> Self {
+        ArkanoidPaddle {
+            famicom,
+            position: MIN_POSITION,
+            button: false,
+            shift: 0,
+        }
+    }
+
+    fn next_bit(&mut self) -> u8 {
+        let bit = !self.shift >> 7 & 1;
+        self.shift <<= 1;
+        bit
+    }
+}
+
+impl InputDevice for ArkanoidPaddle {
+    fn name(&self) -> &'static str {
+        if self.famicom {
+            "arkanoid-fc"
+        } else {
+            "arkanoid"
+        }
+    }
+
+    fn write(&mut self, data: u8) {
+        if data & 1 == 1 {
+            self.shift = self.position;
+        }
+    }
+
+    fn read(&mut self, port: usize) -> u8 {
+        let button = self.button as u8;
+        if self.famicom {
+            match port {
+                0 => button << 1,
+                _ => self.next_bit() << 1,
+            }
+        } else {
+            self.next_bit() << 3 | button << 4
+        }
+    }
+
+    fn set_pointer(&mut self, x: i32, _y: i32) {
+        let x = x.clamp(0, 255) as u32;
+        let range = (MAX_POSITION - MIN_POSITION) as u32;
+        self.position = MIN_POSITION + (x * range / 255) as u8;
+    }
+
+    fn set_trigger(&mut self, pressed: bool) {
+        self.button = pressed;
+    }
+}
+
+impl Savestate for ArkanoidPaddle {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.shift);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.shift = r.u8();
+    }
+}
@@ -0,0 +1,193 @@
+use crate::controller::{Controller, ControllerButton};
+use crate::input::InputDevice;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// Four player adapters report their pads back to back followed by a signature byte,
+// all shifted out one bit per read starting from the lowest bit
+struct Multitap {
+    pads: Vec<Controller>,
+    signature: u8,
+    strobe: bool,
+    shift: u32, // Bits latched on the last strobe
+    reads: u8,
+}
+
+impl Multitap {
+    fn new(pads: usize, signature: u8) -> Self {
+        Multitap {
+            pads: (0..pads).map(|_| Controller::new()).collect(),
+            signature,
+            strobe: false,
+            shift: 0,
+            reads: 0,
+        }
+    }
+
+    fn latch(&mut self) {
+        self.shift = 0;
+        for (i, pad) in self.pads.iter().enumerate() {
+            self.shift |= (pad.buttons().bits() as u32) << (i * 8);
+        }
+        self.shift |= (self.signature as u32) << (self.pads.len() * 8);
+        self.reads = 0;
+    }
+
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.strobe {
+            self.latch();
+        }
+        if self.reads as usize >= (self.pads.len() + 1) * 8 {
+            return 1;
+        }
+        let bit = ((self.shift >> self.reads) & 1) as u8;
+        self.reads += 1;
+        bit
+    }
+}
+
+// NES Four Score, one goes in each port(they're two halves of the same adapter)
+// 0x4016 reads player 1, then player 3, then the signature 0b0000_1000
+// 0x4017 reads player 2, then player 4, then the signature 0b0000_0100
+pub struct FourScore {
+    tap: Multitap,
+}
+
+impl FourScore {
+    pub fn new(port: usize) -> Self {
+        let signature = if port == 0 { 0b0000_1000 } else { 0b0000_0100 };
+        FourScore {
+            tap: Multitap::new(2, signature),
+        }
+    }
+}
+
+impl InputDevice for FourScore {
+    fn name(&self) -> &'static str {
+        "fourscore"
+    }
+
+    fn write(&mut self, data: u8) {
+        self.tap.write(data);
+    }
+
+    fn read(&mut self, _port: usize) -> u8 {
+        self.tap.read()
+    }
+
+    fn set_button(&mut self, index: usize, button: ControllerButton, pressed: bool) {
+        if let Some(pad) = self.tap.pads.get_mut(index) {
+            pad.set_button_pressed_status(button, pressed);
+        }
+    }
+}
+
+// Hori 4 Players Adapter on the Famicom expansion port, in 4 player mode
+// 0x4016 D1 reads player 3 then the signature 0b0010_0000
+// 0x4017 D1 reads player 4 then the signature 0b0001_0000
+// Players 1 and 2 stay on the Famicom's own pads in D0
+pub struct Hori {
+    taps: [Multitap; 2],
+}
+
+impl Hori {
+    pub fn new() -> Self {
+        Hori {
+            taps: [Multitap::new(1, 0b0010_0000), Multitap::new(1, 0b0001_0000)],
+        }
+    }
+}
+
+impl Default for Hori {
+    fn default() -> Self {
+        Hori::new()
+    }
+}
+
+impl InputDevice for Hori {
+    fn name(&self) -> &'static str {
+        "hori"
+    }
+
+    fn write(&mut self, data: u8) {
+        for tap in self.taps.iter_mut() {
+            tap.write(data);
+        }
+    }
+
+    fn read(&mut self, port: usize) -> u8 {
+        self.taps[port].read() << 1
+    }
+
+    fn set_button(&mut self, index: usize, button: ControllerButton, pressed: bool) {
+        if let Some(tap) = self.taps.get_mut(index) {
+            tap.pads[0].set_button_pressed_status(button, pressed);
+        }
+    }
+}
+
+// The pads' buttons come from the frontend
+impl Savestate for Multitap {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.strobe);
+        w.u32(self.shift);
+        w.u8(self.reads);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.strobe = r.bool();
+        self.shift = r.u32();
+        self.reads = r.u8();
+    }
+}
+
+impl Savestate for FourScore {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.component(&self.tap);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.component(&mut self.tap);
+    }
+}
+
+impl Savestate for Hori {
+    fn save_state(&self, w: &mut StateWriter) {
+        for tap in self.taps.iter() {
+            w.component(tap);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        for tap in self.taps.iter_mut() {
+            r.component(tap);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_four_score_report() {
+        let mut four_score = FourScore::new(0);
+        four_score.set_button(0, ControllerButton::A, true);
+        four_score.set_button(1, ControllerButton::START, true);
+        four_score.write(1);
+        four_score.write(0);
+        let bits: Vec<u8> = (0..25).map(|_| four_score.read(0)).collect();
+        let mut expected = vec![0; 25];
+        expected[0] = 1; // Player 1 A
+        expected[8 + 3] = 1; // Player 3 Start
+        expected[16 + 3] = 1; // Signature
+        expected[24] = 1;
+        assert_eq!(bits, expected);
+    }
+}
@@ -0,0 +1,59 @@
+use crate::controller::ControllerButton;
+
+// A sequence of pad states, one per frame
+// Scripts are comma separated steps of buttons joined with +, each held for *N frames(1 if left out)
+// eg. "down+b*2, none*3, a*10" holds Down and B for 2 frames, lets go for 3 and then holds A for 10
+#[derive(Debug, Clone, PartialEq)]
+pub struct Macro {
+    pub frames: Vec<ControllerButton>,
+}
+
+impl Macro {
+    pub fn parse(script: &str) -> Result<Macro, String> {
+        let mut frames = Vec::new();
+        for step in script.split(',') {
+            let (buttons, count) = match step.split_once('*') {
+                Some((buttons, count)) => match count.trim().parse::<usize>() {
+                    Ok(count) if count > 0 => (buttons, count),
+                    _ => return Err(format!("bad frame count in `{}`", step.trim())),
+                },
+                None => (step, 1),
+            };
+            let mut state = ControllerButton::empty();
+            for name in buttons.split('+') {
+                let name = name.trim().to_lowercase();
+                state |= match name.as_str() {
+                    "none" => ControllerButton::empty(),
+                    "a" => ControllerButton::A,
+                    "b" => ControllerButton::B,
+                    "select" => ControllerButton::SELECT,
+                    "start" => ControllerButton::START,
+                    "up" => ControllerButton::UP,
+                    "down" => ControllerButton::DOWN,
+                    "left" => ControllerButton::LEFT,
+                    "right" => ControllerButton::RIGHT,
+                    _ => return Err(format!("unknown button `{}` in macro", name)),
+                };
+            }
+            frames.extend(std::iter::repeat_n(state, count));
+        }
+        Ok(Macro { frames })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_macro() {
+        let script = Macro::parse("down+b*2, none, A*3").unwrap();
+        let down_b = ControllerButton::DOWN | ControllerButton::B;
+        let none = ControllerButton::empty();
+        let a = ControllerButton::A;
+        assert_eq!(script.frames, vec![down_b, down_b, none, a, a, a]);
+
+        assert!(Macro::parse("a*0").is_err());
+        assert!(Macro::parse("turbo").is_err());
+    }
+}
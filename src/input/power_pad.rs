@@ -0,0 +1,91 @@
+use crate::input::InputDevice;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// The order the buttons are shifted out in
+const D3_BUTTONS: [usize; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const D4_BUTTONS: [usize; 4] = [4, 3, 12, 8];
+
+// Bandai/Nintendo Power Pad, 12 buttons numbered like side B
+//  1  2  3  4
+//  5  6  7  8
+//  9 10 11 12
+// Two shift registers are latched on strobe, D3 reads 8 buttons and D4 reads the other 4
+// Once they're empty both lines read 1
+pub struct PowerPad {
+    buttons: [bool; 13], // Indexed by button number, 0 is unused
+    strobe: bool,
+    d3: u8,
+    d4: u8,
+}
+
+impl PowerPad {
+    pub fn new() -> Self {
+        PowerPad {
+            buttons: [false; 13],
+            strobe: false,
+            d3: 0,
+            d4: 0,
+        }
+    }
+
+    fn latch(&mut self) {
+        self.d3 = 0;
+        for (i, button) in D3_BUTTONS.iter().enumerate() {
+            self.d3 |= (self.buttons[*button] as u8) << i;
+        }
+        // Filled with 1s after the last button
+        self.d4 = 0xF0;
+        for (i, button) in D4_BUTTONS.iter().enumerate() {
+            self.d4 |= (self.buttons[*button] as u8) << i;
+        }
+    }
+}
+
+impl Default for PowerPad {
+    fn default() -> Self {
+        PowerPad::new()
+    }
+}
+
+impl InputDevice for PowerPad {
+    fn name(&self) -> &'static str {
+        "powerpad"
+    }
+
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self, _port: usize) -> u8 {
+        if self.strobe {
+            self.latch();
+        }
+        let data = (self.d3 & 1) << 3 | (self.d4 & 1) << 4;
+        self.d3 = self.d3 >> 1 | 0x80;
+        self.d4 = self.d4 >> 1 | 0x80;
+        data
+    }
+
+    fn set_pad_button(&mut self, button: usize, pressed: bool) {
+        if (1..=12).contains(&button) {
+            self.buttons[button] = pressed;
+        }
+    }
+}
+
+impl Savestate for PowerPad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.strobe);
+        w.u8(self.d3);
+        w.u8(self.d4);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.strobe = r.bool();
+        self.d3 = r.u8();
+        self.d4 = r.u8();
+    }
+}
@@ -0,0 +1,152 @@
+use crate::frame::Frame;
+use crate::input::InputDevice;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// Brightness the photodiode needs to see, the white targets games flash are well above it
+const LIGHT_THRESHOLD: u32 = 0xC0;
+// Scanlines the photodiode keeps seeing a pixel for after the beam has drawn it
+const LIGHT_LINES: u16 = 20;
+
+// NES Zapper light gun
+// D3 is 0 while the gun sees light, D4 is 1 while the trigger is held
+// The pixel at the mouse position comes from the frame the PPU is drawing, and is only seen once
+// the beam has gone past it and until it fades
+pub struct Zapper {
+    x: i32,
+    y: i32,
+    trigger: bool,
+    bright: bool, // The pixel under the gun this frame
+    scanline: u16,
+    dot: usize,
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Zapper {
+            x: -1,
+            y: -1,
+            trigger: false,
+            bright: false,
+            scanline: 0,
+            dot: 0,
+        }
+    }
+
+    fn sees_light(&self) -> bool {
+        if !self.bright {
+            return false;
+        }
+        // Dot 1 draws the first pixel of a line
+        let (x, y) = (self.x as usize, self.y as u16);
+        match self.scanline.checked_sub(y) {
+            Some(0) => self.dot > x,
+            Some(lines) => lines < LIGHT_LINES,
+            None => false,
+        }
+    }
+}
+
+impl Default for Zapper {
+    fn default() -> Self {
+        Zapper::new()
+    }
+}
+
+impl InputDevice for Zapper {
+    fn name(&self) -> &'static str {
+        "zapper"
+    }
+
+    // The Zapper isn't a shift register, strobing does nothing
+    fn write(&mut self, _data: u8) {}
+
+    fn read(&mut self, _port: usize) -> u8 {
+        let mut data = 0;
+        if !self.sees_light() {
+            data |= 0b0_1000;
+        }
+        if self.trigger {
+            data |= 0b1_0000;
+        }
+        data
+    }
+
+    fn set_pointer(&mut self, x: i32, y: i32) {
+        self.x = x;
+        self.y = y;
+    }
+
+    fn set_trigger(&mut self, pressed: bool) {
+        self.trigger = pressed;
+    }
+
+    fn set_beam(&mut self, scanline: u16, dot: usize) {
+        self.scanline = scanline;
+        self.dot = dot;
+    }
+
+    fn update_frame(&mut self, frame: &Frame) {
+        self.bright = if self.x < 0 || self.y < 0 {
+            false
+        } else {
+            match frame.get_pixel(self.x as usize, self.y as usize) {
+                // Rec. 601 luma
+                Some((r, g, b)) => {
+                    (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000 >= LIGHT_THRESHOLD
+                }
+                None => false,
+            }
+        };
+    }
+}
+
+impl Savestate for Zapper {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.bright);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.bright = r.bool();
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_zapper_light() {
+        let mut zapper = Zapper::new();
+        let mut frame = Frame::new();
+        frame.set_pixel(100, 50, (0xFF, 0xFF, 0xFF));
+        zapper.set_pointer(100, 50);
+        zapper.update_frame(&frame);
+        zapper.set_beam(60, 0);
+        assert_eq!(zapper.read(1), 0);
+
+        zapper.set_pointer(101, 50);
+        zapper.set_trigger(true);
+        zapper.update_frame(&frame);
+        assert_eq!(zapper.read(1), 0b1_1000);
+    }
+
+    #[test]
+    fn test_zapper_beam() {
+        let mut zapper = Zapper::new();
+        let mut frame = Frame::new();
+        frame.set_pixel(100, 50, (0xFF, 0xFF, 0xFF));
+        zapper.set_pointer(100, 50);
+        zapper.update_frame(&frame);
+        let light_at = |zapper: &mut Zapper, scanline, dot| {
+            zapper.set_beam(scanline, dot);
+            zapper.read(1) & 0b0_1000 == 0
+        };
+        // Not drawn yet
+        assert!(!light_at(&mut zapper, 20, 200));
+        assert!(!light_at(&mut zapper, 50, 100));
+        // Drawn, then fading out
+        assert!(light_at(&mut zapper, 50, 101));
+        assert!(light_at(&mut zapper, 69, 0));
+        assert!(!light_at(&mut zapper, 70, 0));
+    }
+}
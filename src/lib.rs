@@ -1,14 +1,30 @@
+pub mod apu;
+pub mod audio;
 pub mod bus;
+pub mod config;
 pub mod controller;
 pub mod cpu;
 pub mod frame;
+pub mod gamedb;
+pub mod gamepad;
+pub mod input;
+pub mod mapper;
+pub mod movie;
+pub mod nsf;
 pub mod op;
 pub mod palette;
+pub mod png;
 pub mod ppu;
 pub mod ppu_reg;
+pub mod region;
 pub mod render;
+pub mod rewind;
 pub mod rom;
+pub mod run_control;
+pub mod savestate;
 pub mod trace;
+pub mod video;
+pub mod wav;
 
 use cpu::*;
 
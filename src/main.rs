@@ -1,37 +1,397 @@
-use nes::controller::{self, ControllerButton};
+use nes::apu::mixer::{Channel, Mixer};
+use nes::apu::APU;
+use nes::config::{Config, Hotkey};
 use nes::cpu::*;
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use nes::frame::Frame;
+use nes::gamedb::GameDb;
+use nes::gamepad::Gamepads;
+use nes::input::macros::Macro;
+use nes::input::{self, InputPorts};
+use nes::movie::{self, Movie, MovieMode};
+use nes::nsf::NSF;
+use nes::png;
 use nes::ppu::PPU;
+use nes::region::Region;
 use nes::render;
+use nes::rewind::Rewind;
 use nes::rom::Rom;
+use nes::run_control::{RunControl, Speed};
+use nes::savestate;
+use nes::trace::trace;
+use nes::video::VideoRecorder;
+use sdl2::audio::AudioSpecDesired;
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::mouse::MouseButton;
 use sdl2::pixels::PixelFormatEnum;
-// use nes::trace::trace;
 
 use nes::bus::Bus;
 
+// Toggles mute on a channel(F1-F6 by default)
+// Shift toggles solo instead and Ctrl steps the channel's volume down by 25%
+fn mixer_hotkey(mixer: &mut Mixer, channel: Channel, keymod: Mod) {
+    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+        if mixer.solo() == Some(channel) {
+            mixer.set_solo(None);
+            println!("Solo off");
+        } else {
+            mixer.set_solo(Some(channel));
+            println!("Solo {:?}", channel);
+        }
+    } else if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) {
+        let mut volume = mixer.volume(channel) - 0.25;
+        if volume < 0.0 {
+            volume = 1.0;
+        }
+        mixer.set_volume(channel, volume);
+        println!("{:?} volume {}%", channel, (volume * 100.0) as u32);
+    } else {
+        let muted = !mixer.is_muted(channel);
+        mixer.set_muted(channel, muted);
+        println!("{:?} {}", channel, if muted { "muted" } else { "unmuted" });
+    }
+}
+
+// Cycles the device in port 2(F7 by default), the mouse aims the Zapper and turns the Arkanoid knob
+const PORT2_DEVICES: [&str; 4] = ["controller", "zapper", "arkanoid", "powerpad"];
+
+fn port2_hotkey(input: &mut InputPorts, device: &mut usize) {
+    *device = (*device + 1) % PORT2_DEVICES.len();
+    input.set_port(
+        1,
+        input::device_from_name(PORT2_DEVICES[*device], 1).unwrap(),
+    );
+    println!("Port 2: {}", PORT2_DEVICES[*device]);
+}
+
+// Cycles between two players, a Four Score(NES) and a Hori adapter(Famicom) for four players
+fn adapter_hotkey(input: &mut InputPorts, adapter: &mut usize) {
+    *adapter = (*adapter + 1) % 3;
+    let (port, expansion) = match *adapter {
+        1 => ("fourscore", None),
+        2 => ("controller", input::expansion_from_name("hori")),
+        _ => ("controller", None),
+    };
+    for i in 0..2 {
+        input.set_port(i, input::device_from_name(port, i).unwrap());
+    }
+    input.set_expansion(expansion);
+    println!(
+        "{}",
+        match *adapter {
+            1 => "Four Score",
+            2 => "Hori 4 Players Adapter",
+            _ => "Two players",
+        }
+    );
+}
+
+// Starts and stops recording(F9 by default) to recording-<unix time>.wav, Shift also writes a file per channel
+fn recording_hotkey(apu: &mut APU, keymod: Mod) {
+    if apu.is_recording() {
+        match apu.stop_recording() {
+            Ok(()) => println!("Recording stopped"),
+            Err(err) => println!("Could not finish recording: {}", err),
+        }
+        return;
+    }
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0);
+    let path = PathBuf::from(format!("recording-{}.wav", secs));
+    let stems = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+    match apu.start_recording(&path, stems) {
+        Ok(()) => println!("Recording to {}", path.display()),
+        Err(err) => println!("Could not record to {}: {}", path.display(), err),
+    }
+}
+
+// Starts and stops video recording to video-<unix time>.avi
+fn video_hotkey(apu: &mut APU, video: &mut Option<VideoRecorder>, region: Region) {
+    if video.is_some() {
+        finish_video(apu, video);
+        return;
+    }
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let path = PathBuf::from(format!("video-{}.avi", secs));
+    match VideoRecorder::start(&path, region) {
+        Ok(recorder) => {
+            apu.start_capture();
+            *video = Some(recorder);
+            println!("Recording video to {}", path.display());
+        }
+        Err(err) => println!("Could not record to {}: {}", path.display(), err),
+    }
+}
+
+fn finish_video(apu: &mut APU, video: &mut Option<VideoRecorder>) {
+    let Some(recorder) = video.take() else {
+        return;
+    };
+    apu.stop_capture();
+    match recorder.finish() {
+        Ok(()) => println!("Video recording stopped"),
+        Err(err) => println!("Could not finish the video: {}", err),
+    }
+}
+
+// Records player 1 until it's pressed again, the new recording replaces the last one
+fn macro_recording_hotkey(input: &mut InputPorts, recorded: &mut Option<Macro>) {
+    match input.stop_recording() {
+        Some(recording) => {
+            println!("Recorded a {} frame macro", recording.frames.len());
+            *recorded = Some(recording);
+        }
+        None => {
+            input.start_recording(0);
+            println!("Recording a macro for player 1");
+        }
+    }
+}
+
+// Writes the movie out if anything was recorded into it
+fn finish_movie(input: &mut InputPorts, path: &Option<PathBuf>) {
+    let recorded = input
+        .movie()
+        .is_some_and(|movie| movie.mode() == MovieMode::Recording);
+    let (Some(movie), Some(path)) = (input.stop_movie(), path) else {
+        return;
+    };
+    if recorded {
+        match std::fs::write(path, movie.to_fm2()) {
+            Ok(()) => println!(
+                "Saved a {} frame movie to {}",
+                movie.frames.len(),
+                path.display()
+            ),
+            Err(err) => println!("Could not write {}: {}", path.display(), err),
+        }
+    } else {
+        println!("Movie stopped");
+    }
+}
+
+fn fast_forward_speed(config: &Config) -> Speed {
+    match config.fast_forward {
+        0 => Speed::Uncapped,
+        n => Speed::Fast(n),
+    }
+}
+
+const STATE_SLOTS: u8 = 10;
+
+// The hotkeys are read in the frame callback, which can't reach the CPU, so saving and loading
+// waits for the CPU's callback at the next instruction, which runs even while paused
+#[derive(Clone, Copy)]
+enum StateRequest {
+    Save(u8),
+    Load(u8),
+    RecordMovie,
+}
+
+fn save_slot(cpu: &CPU, rom: &Path, slot: u8) {
+    let path = savestate::slot_path(rom, slot);
+    match std::fs::write(&path, savestate::save(cpu)) {
+        Ok(()) => println!("Saved slot {}", slot),
+        Err(err) => println!("Could not write {}: {}", path.display(), err),
+    }
+}
+
+fn load_slot(cpu: &mut CPU, rom: &Path, slot: u8) -> Result<(), String> {
+    let path = savestate::slot_path(rom, slot);
+    let data = std::fs::read(&path)
+        .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+    savestate::load(cpu, &data).map_err(|err| format!("Could not load slot {}: {}", slot, err))?;
+    println!("Loaded slot {}", slot);
+    Ok(())
+}
+
+struct Options {
+    rom: String,
+    scale: Option<u32>,
+    region: Option<Region>,
+    fullscreen: bool,
+    mute: bool,
+    paused: bool,
+    frames: Option<usize>,
+    trace: Option<PathBuf>,
+    record_movie: Option<PathBuf>,
+    play_movie: Option<PathBuf>,
+    load_state: Option<u8>,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: nes <rom> [options]");
+    eprintln!("  --scale N          Window scale, overrides the config file");
+    eprintln!("  --region R         Force ntsc, pal or dendy timing");
+    eprintln!("  --fullscreen       Start fullscreen");
+    eprintln!("  --mute             Don't play audio, overrides the config file");
+    eprintln!("  --paused           Start paused, Pause resumes and \\ advances a frame");
+    eprintln!("  --frames N         Quit after N frames");
+    eprintln!("  --trace FILE       Write a nestest style trace of every instruction to FILE");
+    eprintln!("  --record-movie FILE  Record an FM2 movie from power on(or --load-state) to FILE");
+    eprintln!("  --play-movie FILE  Play an FM2 movie, read-only until toggled");
+    eprintln!("  --load-state SLOT  Load save state slot 0-9 on start");
+    exit(2);
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        rom: String::new(),
+        scale: None,
+        region: None,
+        fullscreen: false,
+        mute: false,
+        paused: false,
+        frames: None,
+        trace: None,
+        record_movie: None,
+        play_movie: None,
+        load_state: None,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scale" => {
+                options.scale = match args.next().and_then(|n| n.parse().ok()) {
+                    Some(scale) if scale > 0 => Some(scale),
+                    _ => usage(),
+                }
+            }
+            "--region" => {
+                options.region = match args.next().and_then(|name| Region::from_name(&name)) {
+                    Some(region) => Some(region),
+                    None => usage(),
+                }
+            }
+            "--fullscreen" => options.fullscreen = true,
+            "--mute" => options.mute = true,
+            "--paused" => options.paused = true,
+            "--frames" => {
+                options.frames = match args.next().and_then(|n| n.parse().ok()) {
+                    Some(frames) => Some(frames),
+                    None => usage(),
+                }
+            }
+            "--trace" => options.trace = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "--record-movie" => {
+                options.record_movie = Some(args.next().unwrap_or_else(|| usage()).into())
+            }
+            "--play-movie" => {
+                options.play_movie = Some(args.next().unwrap_or_else(|| usage()).into())
+            }
+            "--load-state" => {
+                options.load_state = match args.next().and_then(|n| n.parse().ok()) {
+                    Some(slot) if slot < STATE_SLOTS => Some(slot),
+                    _ => usage(),
+                }
+            }
+            "--help" | "-h" => usage(),
+            _ if arg.starts_with("--") || !options.rom.is_empty() => usage(),
+            _ => options.rom = arg,
+        }
+    }
+    if options.rom.is_empty() || (options.record_movie.is_some() && options.play_movie.is_some()) {
+        usage();
+    }
+    options
+}
+
+// Reads the config file, with the command line taking priority
+fn load_config(path: &Option<PathBuf>, options: &Options) -> Config {
+    let (mut config, warnings) = match path {
+        Some(path) => Config::load(path),
+        None => (Config::new(), Vec::new()),
+    };
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    if let Some(scale) = options.scale {
+        config.scale = scale;
+    }
+    config.mute |= options.mute;
+    config
+}
+
 fn main() {
+    let options = parse_args();
+    let config_path = Config::default_path();
+    let mut config = load_config(&config_path, &options);
+
+    // Game loading, done first so a bad file fails before a window opens
+    let game_bytes = std::fs::read(&options.rom).unwrap_or_else(|err| {
+        eprintln!("Could not read {}: {}", options.rom, err);
+        exit(1);
+    });
+    if NSF::is_nsf(&game_bytes) {
+        eprintln!(
+            "{} is an NSF tune, play it with `cargo run --bin headless -- {} --wav out.wav`",
+            options.rom, options.rom
+        );
+        exit(1);
+    }
+    let mut rom = Rom::new(&game_bytes).unwrap_or_else(|err| {
+        eprintln!("Could not load {}: {}", options.rom, err);
+        exit(1);
+    });
+    // NES 2.0 timing, then the game database, then --region
+    let (gamedb, warnings) = GameDb::load_default();
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    gamedb.apply(&mut rom);
+    if let Some(region) = options.region {
+        rom.region = region;
+    }
+
     // Setting up screen and scaling
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
-    let window = video_subsystem
-        .window("NEXie", (256.0 * 3.0) as u32, (240.0 * 3.0) as u32)
-        .position_centered()
-        .build()
-        .unwrap();
+    let mut window = video_subsystem.window("NEXie", 256 * config.scale, 240 * config.scale);
+    window.position_centered();
+    if options.fullscreen {
+        window.fullscreen_desktop();
+    }
+    let window = window.build().unwrap();
 
     let mut canvas = window.into_canvas().present_vsync().build().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(3.0, 3.0).unwrap();
+    let mut gamepads = Gamepads::new(sdl_context.game_controller().unwrap());
+    // Scales the frame to the window, letterboxing in fullscreen, mouse positions are scaled back too
+    canvas.set_logical_size(256, 240).unwrap();
 
     let creator = canvas.texture_creator();
     let mut texture = creator
         .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
         .unwrap();
-    canvas.set_scale(3.0, 3.0).unwrap();
+
+    // Setting up audio, samples are queued from the frame callback
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let desired_spec = AudioSpecDesired {
+        freq: Some(config.sample_rate),
+        channels: Some(1),
+        samples: Some(1024),
+    };
+    let audio_queue = audio_subsystem
+        .open_queue::<f32, _>(None, &desired_spec)
+        .unwrap();
+    let audio_rate = audio_queue.spec().freq as u32;
+    // Keep a few frames of audio queued, enough to ride out a slow frame without adding much latency
+    let audio_target =
+        (audio_rate as f64 * config.latency as f64 / rom.region.frame_rate()) as usize;
+    if !config.mute {
+        audio_queue.resume();
+    }
 
     // Note: Reading from the right file, why is it starting on C004?
     // let log_file = std::fs::File::create("debug.log").unwrap();
@@ -40,63 +400,383 @@ fn main() {
     //     .filter_level(log::LevelFilter::Debug)
     //     .init();
 
-    // Setting up controllers
-    let mut input_map = HashMap::new();
-    input_map.insert(Keycode::Down, ControllerButton::DOWN);
-    input_map.insert(Keycode::Up, ControllerButton::UP);
-    input_map.insert(Keycode::Right, ControllerButton::RIGHT);
-    input_map.insert(Keycode::Left, ControllerButton::LEFT);
-    input_map.insert(Keycode::X, ControllerButton::B);
-    input_map.insert(Keycode::Z, ControllerButton::A);
-    input_map.insert(Keycode::Return, ControllerButton::START);
-    input_map.insert(Keycode::Space, ControllerButton::SELECT);
-
-    // Game loading and CPU setup
-    let game_bytes = std::fs::read("PATH GOES HERE").unwrap();
-    let rom = Rom::new(&game_bytes).unwrap();
+    let mut port2_device = 0;
+    let mut adapter = 0;
+    let mut recorded_macro = None;
+    let mut slot = 0;
+    let state_request = Rc::new(Cell::new(None));
+    let request = state_request.clone();
+    let rewinding = Rc::new(Cell::new(false));
+    let rewind_held = rewinding.clone();
+    let redraw = Rc::new(Cell::new(false)); // A state request came in while paused
+    let redraw_pending = redraw.clone();
+
+    // Frames are paced to the console's refresh rate rather than the monitor's
+    let frame_duration = Duration::from_secs_f64(1.0 / rom.region.frame_rate());
+    let mut control = RunControl::new(rom.region.frame_rate());
+    let mut last_present = Instant::now();
+    let mut frame_counter = false;
 
     let mut frame = Frame::new();
+    frame.palette = config.palette;
+    if options.paused {
+        control.pause();
+        println!("Paused, press Pause to resume or \\ to advance a frame");
+    }
 
-    let bus = Bus::new(
+    let trace_path = options.trace.clone();
+    let frames = options.frames;
+    let rom_path = PathBuf::from(&options.rom);
+    let load_state = options.load_state;
+    let mut rewind = (config.rewind_memory > 0)
+        .then(|| Rewind::new(config.rewind_interval, config.rewind_memory * 1024 * 1024));
+    let mut rewind_frame = usize::MAX;
+    let region = rom.region;
+    let rom_name = rom_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let play_movie = options.play_movie.clone();
+    let record_movie = options.record_movie.is_some();
+    let mut movie_path = options.record_movie.clone().or(play_movie.clone());
+    let mut movie_finished = false;
+    let screenshot_name = rom_name.clone();
+    let mut video: Option<VideoRecorder> = None;
+    let mut bus = Bus::new(
         rom,
-        move |ppu: &PPU, controller: &mut controller::Controller| {
+        move |ppu: &PPU, apu: &mut APU, input: &mut InputPorts| {
             render::render(ppu, &mut frame);
+            // Every frame goes into the video, however fast the emulator is running, but not a redraw
+            let redrawn = redraw_pending.replace(false);
+            if let Some(recorder) = video.as_mut().filter(|_| !redrawn) {
+                if let Err(err) = recorder.record(&frame, &apu.take_capture()) {
+                    println!("Could not write the video: {}", err);
+                    finish_video(apu, &mut video);
+                }
+            }
+            input.update_frame(&frame);
+            input.set_turbo_rate(config.turbo_rate);
+            let finished = input
+                .movie()
+                .is_some_and(|movie| movie.mode() == MovieMode::Finished);
+            if finished && !movie_finished {
+                println!("Movie finished");
+            }
+            movie_finished = finished;
+            // The CPU stops at the next instruction
+            if frames.is_some_and(|frames| ppu.frame >= frames) {
+                finish_movie(input, &movie_path);
+                finish_video(apu, &mut video);
+            }
             texture.update(None, &frame.data, 256 * 3).unwrap();
 
-            canvas.copy(&texture, None, None).unwrap();
-
-            canvas.present();
-            for event in event_pump.poll_iter() {
-                match event {
-                    Event::Quit { .. }
-                    | Event::KeyDown {
-                        keycode: Some(Keycode::Escape),
-                        ..
-                    } => std::process::exit(0),
-                    Event::KeyDown { keycode, .. } => {
-                        if let Some(key) = input_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                            // println!("Pressed button!");
-                            controller.set_button_pressed_status(*key, true);
-                        }
+            // present() waits for vsync, so faster than normal only about one frame a refresh is shown
+            let fast = matches!(control.speed(), Speed::Fast(_) | Speed::Uncapped);
+            if !fast || last_present.elapsed() >= frame_duration {
+                canvas.copy(&texture, None, None).unwrap();
+                canvas.present();
+                last_present = Instant::now();
+            }
+            if frame_counter {
+                let title = format!("NEXie - frame {} ({})", ppu.frame, control.speed());
+                let _ = canvas.window_mut().set_title(&title);
+            }
+
+            // Audio is only played at normal speed
+            let mut samples = apu.sink.take_samples();
+            if !config.mute && control.speed() == Speed::Normal && !rewind_held.get() {
+                for sample in samples.iter_mut() {
+                    *sample *= config.volume;
+                }
+                audio_queue.queue(&samples);
+                let queued = audio_queue.size() as usize / std::mem::size_of::<f32>();
+                apu.sink.adjust_for_buffer(queued, audio_target);
+            }
+
+            control.wait();
+
+            // While paused the same frame stays up and only events are handled
+            loop {
+                for event in event_pump.poll_iter() {
+                    if gamepads.handle_event(&event, input, &config) {
+                        continue;
                     }
-                    Event::KeyUp { keycode, .. } => {
-                        if let Some(key) = input_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                            // println!("Released button!");
-                            controller.set_button_pressed_status(*key, false);
+                    match event {
+                        Event::Quit { .. }
+                        | Event::KeyDown {
+                            keycode: Some(Keycode::Escape),
+                            ..
+                        } => {
+                            finish_movie(input, &movie_path);
+                            finish_video(apu, &mut video);
+                            std::process::exit(0)
                         }
-                    }
+                        Event::KeyDown {
+                            keycode,
+                            keymod,
+                            repeat,
+                            ..
+                        } => {
+                            if let Some(button) =
+                                config.powerpad.get(&keycode.unwrap_or(Keycode::Ampersand))
+                            {
+                                input.set_pad_button(*button, true);
+                            }
+                            if let Some((player, key)) =
+                                config.bindings.get(&keycode.unwrap_or(Keycode::Ampersand))
+                            {
+                                // println!("Pressed button!");
+                                input.set_button(*player, *key, true);
+                            }
+                            if let Some((player, key)) = config
+                                .turbo_bindings
+                                .get(&keycode.unwrap_or(Keycode::Ampersand))
+                            {
+                                input.set_turbo(*player, *key, true);
+                            }
+                            if let Some((player, script)) =
+                                config.macros.get(&keycode.unwrap_or(Keycode::Ampersand))
+                            {
+                                if !repeat {
+                                    input.play_macro(*player, script);
+                                }
+                            }
+                            match config.hotkeys.get(&keycode.unwrap_or(Keycode::Ampersand)) {
+                                Some(Hotkey::Pause) => {
+                                    control.toggle_pause();
+                                    let paused = control.is_paused();
+                                    println!("{}", if paused { "Paused" } else { "Resumed" });
+                                }
+                                Some(Hotkey::FrameAdvance) => control.advance_frame(),
+                                Some(Hotkey::FastForward) => {
+                                    control.set_speed(fast_forward_speed(&config))
+                                }
+                                Some(Hotkey::SlowMotion) => {
+                                    let speed = match control.speed() {
+                                        Speed::Slow(_) => Speed::Normal,
+                                        _ => Speed::Slow(config.slow_motion),
+                                    };
+                                    control.set_speed(speed);
+                                    println!("Speed: {}", speed);
+                                }
+                                Some(Hotkey::SaveState) => {
+                                    request.set(Some(StateRequest::Save(slot)))
+                                }
+                                Some(Hotkey::LoadState) => {
+                                    request.set(Some(StateRequest::Load(slot)))
+                                }
+                                Some(Hotkey::NextSlot) => {
+                                    slot = (slot + 1) % STATE_SLOTS;
+                                    println!("Slot {}", slot);
+                                }
+                                Some(Hotkey::PreviousSlot) => {
+                                    slot = (slot + STATE_SLOTS - 1) % STATE_SLOTS;
+                                    println!("Slot {}", slot);
+                                }
+                                Some(Hotkey::Rewind) => rewind_held.set(true),
+                                Some(Hotkey::RecordMovie) => {
+                                    if input.movie().is_some() {
+                                        finish_movie(input, &movie_path);
+                                        movie_path = None;
+                                    } else {
+                                        let secs = SystemTime::now()
+                                            .duration_since(UNIX_EPOCH)
+                                            .map_or(0, |time| time.as_secs());
+                                        let path = PathBuf::from(format!("movie-{}.fm2", secs));
+                                        println!("Recording a movie to {}", path.display());
+                                        movie_path = Some(path);
+                                        request.set(Some(StateRequest::RecordMovie));
+                                    }
+                                }
+                                Some(Hotkey::Screenshot) => {
+                                    let path = png::numbered_path(Path::new("."), &screenshot_name);
+                                    match frame.save_png(&path, config.screenshot_scale) {
+                                        Ok(()) => println!("Saved {}", path.display()),
+                                        Err(err) => {
+                                            println!("Could not write {}: {}", path.display(), err)
+                                        }
+                                    }
+                                }
+                                Some(Hotkey::RecordVideo) => video_hotkey(apu, &mut video, region),
+                                Some(Hotkey::MovieReadOnly) => {
+                                    if let Some(movie) = input.movie_mut() {
+                                        movie.read_only = !movie.read_only;
+                                        let mode = if movie.read_only {
+                                            "read-only"
+                                        } else {
+                                            "read+write"
+                                        };
+                                        println!("Movie is {}", mode);
+                                    }
+                                }
+                                Some(Hotkey::FrameCounter) => {
+                                    frame_counter = !frame_counter;
+                                    if !frame_counter {
+                                        let _ = canvas.window_mut().set_title("NEXie");
+                                    }
+                                }
+                                Some(Hotkey::ReloadConfig) => {
+                                    config = load_config(&config_path, &options);
+                                    let window = canvas.window_mut();
+                                    if !options.fullscreen {
+                                        let _ =
+                                            window.set_size(256 * config.scale, 240 * config.scale);
+                                    }
+                                    frame.palette = config.palette;
+                                    if config.mute {
+                                        audio_queue.pause();
+                                        audio_queue.clear();
+                                    } else {
+                                        audio_queue.resume();
+                                    }
+                                    println!("Reloaded the config");
+                                }
+                                Some(Hotkey::Port2Device) => port2_hotkey(input, &mut port2_device),
+                                Some(Hotkey::Adapter) => {
+                                    adapter_hotkey(input, &mut adapter);
+                                    port2_device = 0;
+                                }
+                                Some(Hotkey::Record) => recording_hotkey(apu, keymod),
+                                Some(Hotkey::RecordMacro) => {
+                                    macro_recording_hotkey(input, &mut recorded_macro)
+                                }
+                                Some(Hotkey::PlayMacro) => match &recorded_macro {
+                                    Some(recording) => input.play_macro(0, recording),
+                                    None => println!("No macro recorded yet"),
+                                },
+                                Some(Hotkey::Mixer(channel)) => {
+                                    mixer_hotkey(&mut apu.mixer, *channel, keymod)
+                                }
+                                None => {}
+                            }
+                        }
+                        Event::KeyUp { keycode, .. } => {
+                            let key = keycode.unwrap_or(Keycode::Ampersand);
+                            match config.hotkeys.get(&key) {
+                                Some(Hotkey::FastForward) => control.set_speed(Speed::Normal),
+                                Some(Hotkey::Rewind) => rewind_held.set(false),
+                                _ => {}
+                            }
+                            if let Some(button) =
+                                config.powerpad.get(&keycode.unwrap_or(Keycode::Ampersand))
+                            {
+                                input.set_pad_button(*button, false);
+                            }
+                            if let Some((player, key)) =
+                                config.bindings.get(&keycode.unwrap_or(Keycode::Ampersand))
+                            {
+                                // println!("Released button!");
+                                input.set_button(*player, *key, false);
+                            }
+                            if let Some((player, key)) = config
+                                .turbo_bindings
+                                .get(&keycode.unwrap_or(Keycode::Ampersand))
+                            {
+                                input.set_turbo(*player, *key, false);
+                            }
+                        }
+                        Event::MouseMotion { x, y, .. } => input.set_pointer(x, y),
+                        Event::MouseButtonDown {
+                            mouse_btn: MouseButton::Left,
+                            ..
+                        } => input.set_trigger(true),
+                        Event::MouseButtonUp {
+                            mouse_btn: MouseButton::Left,
+                            ..
+                        } => input.set_trigger(false),
 
-                    _ => { /* do nothing */ }
+                        _ => { /* do nothing */ }
+                    }
+                }
+                // Rewinding works while paused too
+                if rewind_held.get() || control.run_next() {
+                    break;
                 }
+                // So do save states, the CPU handles them at its next instruction and comes back here
+                if request.get().is_some() {
+                    redraw_pending.set(true);
+                    break;
+                }
+                std::thread::sleep(frame_duration);
             }
         },
-    );
+    )
+    .unwrap_or_else(|err| {
+        eprintln!("Could not load {}: {}", rom_path.display(), err);
+        exit(1);
+    });
+    bus.apu.sink.set_output_rate(audio_rate);
     let mut cpu = CPU::new(bus);
     cpu.reset();
-    // let mut screen_state = [0 as u8; 32 * 3 * 32];
-    // let mut rng = rand::thread_rng();
-    // cpu.run_with_callback(move |cpu| {
-    //     println!("{}", trace(cpu));
-    // });
-    cpu.run();
+    if let Some(slot) = load_state {
+        if let Err(err) = load_slot(&mut cpu, &rom_path, slot) {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    }
+    if let Some(path) = &play_movie {
+        let result = std::fs::read_to_string(path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| Movie::parse(&text))
+            .and_then(|movie| movie::play(&mut cpu, movie, true));
+        if let Err(err) = result {
+            eprintln!("Could not play {}: {}", path.display(), err);
+            exit(1);
+        }
+    }
+    if record_movie {
+        let movie = Movie::new(&rom_name, region);
+        movie::record(&mut cpu, movie, load_state.is_none());
+    }
+
+    let mut trace_file = trace_path.as_ref().map(|path| {
+        let file = std::fs::File::create(path).unwrap_or_else(|err| {
+            eprintln!("Could not create {}: {}", path.display(), err);
+            exit(1);
+        });
+        BufWriter::new(file)
+    });
+    cpu.run_with_callback(move |cpu| {
+        match state_request.take() {
+            Some(StateRequest::Save(slot)) => save_slot(cpu, &rom_path, slot),
+            Some(StateRequest::Load(slot)) => match load_slot(cpu, &rom_path, slot) {
+                Ok(()) => {
+                    if let Some(rewind) = rewind.as_mut() {
+                        rewind.clear();
+                    }
+                }
+                Err(err) => println!("{}", err),
+            },
+            Some(StateRequest::RecordMovie) => {
+                movie::record(cpu, Movie::new(&rom_name, region), false)
+            }
+            None => {}
+        }
+        // Shows the loaded frame and goes back to the pause loop without running anything
+        if redraw.get() {
+            cpu.bus.redraw();
+        }
+        // Snapshots are taken and stepped back through on the first instruction of a frame
+        if let Some(rewind) = rewind.as_mut() {
+            if cpu.bus.ppu.frame != rewind_frame {
+                if rewinding.get() {
+                    // The frame after each snapshot runs to show it, that one is undone as well
+                    rewind.rewind(cpu, rewind.interval() + 1);
+                } else {
+                    rewind.record(cpu);
+                }
+                rewind_frame = cpu.bus.ppu.frame;
+            }
+        }
+        if let Some(file) = trace_file.as_mut() {
+            if let Err(err) = writeln!(file, "{}", trace(cpu)) {
+                eprintln!("Could not write the trace: {}", err);
+                exit(1);
+            }
+        }
+        if frames.is_some_and(|frames| cpu.bus.ppu.frame >= frames) {
+            cpu.halted = true;
+        }
+    });
 }
@@ -0,0 +1,131 @@
+pub mod fme7;
+pub mod mmc5;
+pub mod n163;
+pub mod nrom;
+pub mod nsf;
+pub mod vrc6;
+pub mod vrc7;
+pub mod vrc_irq;
+
+use crate::apu::expansion::ExpansionAudio;
+use crate::rom::Mirroring;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+use fme7::FME7;
+use mmc5::MMC5;
+use n163::N163;
+use nrom::NROM;
+use vrc6::VRC6;
+use vrc7::VRC7;
+
+// Where each 1 KB of the pattern tables(0x0000-0x1FFF) comes from, as 1 KB pages of CHR
+// Sprites and the background only differ on the MMC5 with 8x16 sprites
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ChrBanks {
+    pub background: [usize; 8],
+    pub sprites: [usize; 8],
+}
+
+impl ChrBanks {
+    // 8 KB of CHR as it is, for boards without CHR banking
+    pub const FIXED: ChrBanks = ChrBanks::same([0, 1, 2, 3, 4, 5, 6, 7]);
+
+    pub const fn same(pages: [usize; 8]) -> ChrBanks {
+        ChrBanks {
+            background: pages,
+            sprites: pages,
+        }
+    }
+}
+
+// Cartridge hardware, everything from 0x4020 to 0xFFFF goes through the mapper
+// The PPU reads the pattern tables through the CHR banks, which the bus hands over after every
+// mapper or PPUCTRL write. Nametables still come from the mirroring modes
+// Save states hold the mapper's registers, RAM and sound chip
+pub trait Mapper: Savestate {
+    fn read(&mut self, addr: u16) -> u8;
+
+    fn write(&mut self, addr: u16, data: u8);
+
+    // A read for debuggers that leaves the mapper untouched, only mappers with read side effects override it
+    fn peek(&mut self, addr: u16) -> u8 {
+        self.read(addr)
+    }
+
+    // Mirroring selected through the mapper's registers, None keeps the header's
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+
+    // tall_sprites is PPUCTRL's 8x16 sprite mode, None keeps ChrBanks::FIXED
+    fn chr_banks(&self, _tall_sprites: bool) -> Option<ChrBanks> {
+        None
+    }
+
+    // Level triggered like the APU's IRQ
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // Called every CPU cycle, for IRQ counters
+    fn clock(&mut self) {}
+
+    // Called when the PPU moves on to a new scanline, for counters that follow the rendering
+    fn scanline(&mut self, _scanline: u16, _rendering: bool) {}
+
+    // Sound chip on the cartridge, the bus clocks it and hands its output to the APU
+    fn audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        None
+    }
+}
+
+pub fn new(mapper: u8, prg_rom: Vec<u8>) -> Result<Box<dyn Mapper>, String> {
+    if prg_rom.is_empty() {
+        return Err("the ROM has no PRG ROM".to_string());
+    }
+    Ok(match mapper {
+        0 => Box::new(NROM::new(prg_rom)),
+        5 => Box::new(MMC5::new(prg_rom)),
+        19 => Box::new(N163::new(prg_rom)),
+        24 => Box::new(VRC6::new(prg_rom, false)),
+        26 => Box::new(VRC6::new(prg_rom, true)),
+        69 => Box::new(FME7::new(prg_rom)),
+        85 => Box::new(VRC7::new(prg_rom)),
+        _ => return Err(format!("mapper {} is not supported", mapper)),
+    })
+}
+
+pub const PRG_RAM_SIZE: usize = 0x2000; // 0x6000-0x7FFF
+
+// Number of the last bank_size bank, for banks fixed to the end of the ROM
+pub fn last_bank(prg_rom: &[u8], bank_size: usize) -> usize {
+    (prg_rom.len() / bank_size).saturating_sub(1)
+}
+
+// Reads offset inside a bank of bank_size bytes, bank numbers past the end of the ROM wrap around
+pub fn read_bank(prg_rom: &[u8], bank: usize, bank_size: usize, offset: usize) -> u8 {
+    let banks = (prg_rom.len() / bank_size).max(1);
+    prg_rom[((bank % banks) * bank_size + offset % bank_size) % prg_rom.len()]
+}
+
+// The 2 bit mirroring select used by the Konami and Sunsoft mappers
+pub fn mirroring_from_bits(bits: u8) -> Mirroring {
+    match bits & 0b11 {
+        0 => Mirroring::VERTICAL,
+        1 => Mirroring::HORIZONTAL,
+        2 => Mirroring::SINGLESCREEN_A,
+        _ => Mirroring::SINGLESCREEN_B,
+    }
+}
+
+// Mirroring picked by a mapper in save states, 0 is None
+pub fn save_mirroring(w: &mut StateWriter, mirroring: Option<Mirroring>) {
+    w.u8(mirroring.map_or(0, |mirroring| mirroring as u8 + 1));
+}
+
+pub fn load_mirroring(r: &mut StateReader) -> Option<Mirroring> {
+    match r.u8() {
+        0 => None,
+        index => Some(Mirroring::from_index(index - 1)),
+    }
+}
@@ -0,0 +1,161 @@
+use crate::apu::expansion::sunsoft5b::Sunsoft5BAudio;
+use crate::apu::expansion::ExpansionAudio;
+use crate::mapper::{
+    last_bank, load_mirroring, mirroring_from_bits, read_bank, save_mirroring, ChrBanks, Mapper,
+    PRG_RAM_SIZE,
+};
+use crate::rom::Mirroring;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// Mapper 69, Sunsoft FME-7 and the 5B which adds audio to it
+// 0x8000 Command select
+// 0xA000 Command parameter
+// 0xC000/0xE000 Audio register select and write
+// Commands 0-7 are 1 KB CHR banks
+// Commands 8-B bank 0x6000, 0x8000, 0xA000 and 0xC000, the last 8 KB is fixed at 0xE000
+// Command C is mirroring
+// Command D C--- ---I, counter and IRQ enable, E-F the counter's low and high byte
+// The 16-bit counter counts down every CPU cycle and raises the IRQ when it wraps to 0xFFFF
+pub struct FME7 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    command: u8,
+    prg_banks: [u8; 4],
+    ram_selected: bool, // 0x6000 maps PRG RAM instead of ROM
+    ram_enabled: bool,
+    mirroring: Option<Mirroring>,
+    audio: Sunsoft5BAudio,
+    chr_banks: [u8; 8],
+    irq_counter: u16,
+    irq_enabled: bool,
+    counter_enabled: bool,
+    irq_pending: bool,
+}
+
+impl FME7 {
+    pub fn new(prg_rom: Vec<u8>) -> Self {
+        FME7 {
+            prg_rom,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            command: 0,
+            prg_banks: [0; 4],
+            ram_selected: false,
+            ram_enabled: false,
+            mirroring: None,
+            audio: Sunsoft5BAudio::new(),
+            chr_banks: [0, 1, 2, 3, 4, 5, 6, 7],
+            irq_counter: 0,
+            irq_enabled: false,
+            counter_enabled: false,
+            irq_pending: false,
+        }
+    }
+}
+
+impl Mapper for FME7 {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.ram_selected && self.ram_enabled => {
+                self.prg_ram[(addr - 0x6000) as usize]
+            }
+            0x6000..=0x7FFF if self.ram_selected => 0,
+            0x6000..=0xDFFF => {
+                let bank = self.prg_banks[((addr - 0x6000) / 0x2000) as usize];
+                read_bank(&self.prg_rom, bank as usize, 0x2000, addr as usize)
+            }
+            0xE000..=0xFFFF => {
+                let last = last_bank(&self.prg_rom, 0x2000);
+                read_bank(&self.prg_rom, last, 0x2000, addr as usize)
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.ram_selected && self.ram_enabled => {
+                self.prg_ram[(addr - 0x6000) as usize] = data;
+            }
+            0x8000..=0x9FFF => self.command = data & 0x0F,
+            0xA000..=0xBFFF => match self.command {
+                0x0..=0x7 => self.chr_banks[self.command as usize] = data,
+                // ER-B BBBB for 0x6000
+                0x8 => {
+                    self.prg_banks[0] = data & 0x3F;
+                    self.ram_selected = data & 0b0100_0000 != 0;
+                    self.ram_enabled = data & 0b1000_0000 != 0;
+                }
+                0x9..=0xB => self.prg_banks[(self.command - 0x8) as usize] = data & 0x3F,
+                0xC => self.mirroring = Some(mirroring_from_bits(data)),
+                // Any write acknowledges the IRQ
+                0xD => {
+                    self.irq_enabled = data & 1 != 0;
+                    self.counter_enabled = data & 0b1000_0000 != 0;
+                    self.irq_pending = false;
+                }
+                0xE => self.irq_counter = (self.irq_counter & 0xFF00) | data as u16,
+                0xF => self.irq_counter = (self.irq_counter & 0x00FF) | (data as u16) << 8,
+                _ => {}
+            },
+            0xC000..=0xFFFF => self.audio.write(addr, data),
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        self.mirroring
+    }
+
+    fn chr_banks(&self, _tall_sprites: bool) -> Option<ChrBanks> {
+        Some(ChrBanks::same(self.chr_banks.map(|bank| bank as usize)))
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn clock(&mut self) {
+        if self.counter_enabled {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xFFFF && self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    fn audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+}
+
+impl Savestate for FME7 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.prg_ram);
+        w.u8(self.command);
+        w.bytes(&self.prg_banks);
+        w.bool(self.ram_selected);
+        w.bool(self.ram_enabled);
+        save_mirroring(w, self.mirroring);
+        w.component(&self.audio);
+        w.bytes(&self.chr_banks);
+        w.u16(self.irq_counter);
+        w.bool(self.irq_enabled);
+        w.bool(self.counter_enabled);
+        w.bool(self.irq_pending);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes_into(&mut self.prg_ram);
+        self.command = r.u8();
+        r.bytes_into(&mut self.prg_banks);
+        self.ram_selected = r.bool();
+        self.ram_enabled = r.bool();
+        self.mirroring = load_mirroring(r);
+        r.component(&mut self.audio);
+        r.bytes_into(&mut self.chr_banks);
+        self.irq_counter = r.u16();
+        self.irq_enabled = r.bool();
+        self.counter_enabled = r.bool();
+        self.irq_pending = r.bool();
+    }
+}
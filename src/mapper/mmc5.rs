@@ -0,0 +1,309 @@
+use crate::apu::expansion::mmc5::MMC5Audio;
+use crate::apu::expansion::ExpansionAudio;
+use crate::mapper::{load_mirroring, read_bank, save_mirroring, ChrBanks, Mapper};
+use crate::rom::Mirroring;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const PRG_RAM_SIZE: usize = 0x10000; // Up to 64 KB, banked 8 KB at a time
+
+// Mapper 5, Nintendo MMC5
+// 0x5000-0x5015 Audio
+// 0x5100 PRG mode, 32/16/16+8/8 KB banks
+// 0x5101 CHR mode, 8/4/2/1 KB banks
+// 0x5102-0x5103 PRG RAM write protect
+// 0x5105 Nametable mapping
+// 0x5113-0x5117 PRG banks, bit 7 of 0x5114-0x5116 picks ROM over RAM
+// 0x5120-0x5127 CHR banks for sprites, 0x5128-0x512B for the background with 8x16 sprites.
+// With 8x8 sprites whichever set was written last is used for both
+// 0x5130 Upper CHR bank bits
+// 0x5203 Scanline the IRQ is raised on
+// 0x5204 Write E---- ----, IRQ enable. Read PI-- ----, IRQ pending and in frame, acknowledges
+// 0x5205-0x5206 8x8 multiplier
+// 0x5C00-0x5FFF Expansion RAM, used as plain RAM
+// Split screen, extended attributes and fill mode are not handled
+pub struct MMC5 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    exram: [u8; 0x400],
+    prg_mode: u8,
+    prg_banks: [u8; 5], // 0x5113-0x5117
+    ram_protect: [u8; 2],
+    multiplicand: u8,
+    multiplier: u8,
+    mirroring: Option<Mirroring>,
+    audio: MMC5Audio,
+    chr_mode: u8,
+    chr_sprites: [u16; 8],    // 0x5120-0x5127
+    chr_background: [u16; 4], // 0x5128-0x512B
+    chr_upper: u8,
+    background_last: bool, // 0x5128-0x512B were written after 0x5120-0x5127
+    irq_scanline: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    in_frame: bool,
+    scanline: u8, // Counted from the first rendered scanline
+}
+
+impl MMC5 {
+    pub fn new(prg_rom: Vec<u8>) -> Self {
+        MMC5 {
+            prg_rom,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            exram: [0; 0x400],
+            prg_mode: 3,
+            prg_banks: [0, 0, 0, 0, 0xFF],
+            ram_protect: [0; 2],
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            mirroring: None,
+            audio: MMC5Audio::new(),
+            chr_mode: 3,
+            chr_sprites: [0, 1, 2, 3, 4, 5, 6, 7],
+            chr_background: [0, 1, 2, 3],
+            chr_upper: 0,
+            background_last: false,
+            irq_scanline: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            in_frame: false,
+            scanline: 0,
+        }
+    }
+
+    // 1 KB pages for each slot of the pattern tables from eight bank registers
+    fn chr_pages(&self, banks: [u16; 8]) -> [usize; 8] {
+        std::array::from_fn(|slot| match self.chr_mode {
+            0 => banks[7] as usize * 8 + slot,
+            1 => banks[slot | 3] as usize * 4 + (slot & 3),
+            2 => banks[slot | 1] as usize * 2 + (slot & 1),
+            _ => banks[slot] as usize,
+        })
+    }
+
+    // Returns the bank register and size in bytes of the bank covering addr
+    fn prg_window(&self, addr: u16) -> (u8, usize) {
+        match (self.prg_mode, addr) {
+            (0, _) => (self.prg_banks[4] & !0b11, 0x8000),
+            (1, 0x8000..=0xBFFF) => (self.prg_banks[2] & !1, 0x4000),
+            (1, _) => (self.prg_banks[4] & !1, 0x4000),
+            (2, 0x8000..=0xBFFF) => (self.prg_banks[2] & !1, 0x4000),
+            (2, 0xC000..=0xDFFF) => (self.prg_banks[3], 0x2000),
+            (2, _) => (self.prg_banks[4], 0x2000),
+            (_, _) => (
+                self.prg_banks[1 + ((addr - 0x8000) / 0x2000) as usize],
+                0x2000,
+            ),
+        }
+    }
+
+    fn ram_writable(&self) -> bool {
+        self.ram_protect == [0b10, 0b01]
+    }
+
+    fn ram_index(bank: u8, size: usize, offset: usize) -> usize {
+        ((bank as usize & 0b111) * 0x2000 + offset % size) % PRG_RAM_SIZE
+    }
+
+    // 0x5204 without acknowledging the IRQ
+    fn irq_status(&self) -> u8 {
+        (self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6
+    }
+
+    fn read_prg(&self, addr: u16) -> u8 {
+        let (bank, size) = self.prg_window(addr);
+        // The last bank is always ROM, in mode 3 the others pick with bit 7
+        if addr < 0xE000 && bank & 0x80 == 0 && self.prg_mode != 0 {
+            self.prg_ram[MMC5::ram_index(bank, size, addr as usize)]
+        } else {
+            read_bank(&self.prg_rom, (bank & 0x7F) as usize, size, addr as usize)
+        }
+    }
+
+    // Nametable slots are 2 bits each, only the layouts matching a standard mirroring are used
+    fn nametable_mirroring(data: u8) -> Option<Mirroring> {
+        match data {
+            0x44 => Some(Mirroring::VERTICAL),
+            0x50 => Some(Mirroring::HORIZONTAL),
+            0x00 => Some(Mirroring::SINGLESCREEN_A),
+            0x55 => Some(Mirroring::SINGLESCREEN_B),
+            _ => None,
+        }
+    }
+}
+
+impl Mapper for MMC5 {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x5010 | 0x5015 => self.audio.read(addr).unwrap_or(0),
+            0x5204 => {
+                let status = self.irq_status();
+                self.irq_pending = false;
+                status
+            }
+            0x5205 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+            0x5206 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+            0x5C00..=0x5FFF => self.exram[(addr - 0x5C00) as usize],
+            0x6000..=0x7FFF => {
+                self.prg_ram[MMC5::ram_index(self.prg_banks[0], 0x2000, (addr - 0x6000) as usize)]
+            }
+            0x8000..=0xFFFF => {
+                let data = self.read_prg(addr);
+                if addr < 0xC000 {
+                    self.audio.pcm_read(data);
+                }
+                data
+            }
+            _ => 0,
+        }
+    }
+
+    // Reading 0x5010 and 0x5204 acknowledges IRQs and PRG reads feed the PCM channel
+    fn peek(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x5010 => self.audio.status(),
+            0x5204 => self.irq_status(),
+            0x8000..=0xFFFF => self.read_prg(addr),
+            _ => self.read(addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5015 => self.audio.write(addr, data),
+            0x5100 => self.prg_mode = data & 0b11,
+            0x5101 => self.chr_mode = data & 0b11,
+            0x5102 => self.ram_protect[0] = data & 0b11,
+            0x5103 => self.ram_protect[1] = data & 0b11,
+            0x5105 => {
+                if let Some(mirroring) = MMC5::nametable_mirroring(data) {
+                    self.mirroring = Some(mirroring);
+                }
+            }
+            0x5113..=0x5117 => self.prg_banks[(addr - 0x5113) as usize] = data,
+            0x5120..=0x5127 => {
+                self.chr_sprites[(addr - 0x5120) as usize] =
+                    (self.chr_upper as u16) << 8 | data as u16;
+                self.background_last = false;
+            }
+            0x5128..=0x512B => {
+                self.chr_background[(addr - 0x5128) as usize] =
+                    (self.chr_upper as u16) << 8 | data as u16;
+                self.background_last = true;
+            }
+            0x5130 => self.chr_upper = data & 0b11,
+            0x5203 => self.irq_scanline = data,
+            0x5204 => self.irq_enabled = data & 0b1000_0000 != 0,
+            0x5205 => self.multiplicand = data,
+            0x5206 => self.multiplier = data,
+            0x5C00..=0x5FFF => self.exram[(addr - 0x5C00) as usize] = data,
+            0x6000..=0x7FFF if self.ram_writable() => {
+                let index = MMC5::ram_index(self.prg_banks[0], 0x2000, (addr - 0x6000) as usize);
+                self.prg_ram[index] = data;
+            }
+            0x8000..=0xDFFF => {
+                let (bank, size) = self.prg_window(addr);
+                if self.ram_writable() && bank & 0x80 == 0 && self.prg_mode != 0 {
+                    self.prg_ram[MMC5::ram_index(bank, size, addr as usize)] = data;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        self.mirroring
+    }
+
+    fn chr_banks(&self, tall_sprites: bool) -> Option<ChrBanks> {
+        let sprites = self.chr_pages(self.chr_sprites);
+        let [a, b, c, d] = self.chr_background;
+        let background = self.chr_pages([a, b, c, d, a, b, c, d]);
+        Some(if tall_sprites {
+            ChrBanks {
+                background,
+                sprites,
+            }
+        } else if self.background_last {
+            ChrBanks::same(background)
+        } else {
+            ChrBanks::same(sprites)
+        })
+    }
+
+    fn irq(&self) -> bool {
+        self.audio.irq() || (self.irq_pending && self.irq_enabled)
+    }
+
+    // The first rendered scanline starts the frame, the IRQ is raised on the start of scanline
+    // irq_scanline. Vblank or turning rendering off ends the frame
+    fn scanline(&mut self, scanline: u16, rendering: bool) {
+        if !rendering || scanline >= 240 {
+            self.in_frame = false;
+        } else if !self.in_frame {
+            self.in_frame = true;
+            self.scanline = 0;
+            self.irq_pending = false;
+        } else {
+            self.scanline = self.scanline.wrapping_add(1);
+            if self.scanline == self.irq_scanline {
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    fn audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+}
+
+impl Savestate for MMC5 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.prg_ram);
+        w.bytes(&self.exram);
+        w.u8(self.prg_mode);
+        w.bytes(&self.prg_banks);
+        w.bytes(&self.ram_protect);
+        w.u8(self.multiplicand);
+        w.u8(self.multiplier);
+        save_mirroring(w, self.mirroring);
+        w.component(&self.audio);
+        w.u8(self.chr_mode);
+        for bank in self.chr_sprites.iter().chain(self.chr_background.iter()) {
+            w.u16(*bank);
+        }
+        w.u8(self.chr_upper);
+        w.bool(self.background_last);
+        w.u8(self.irq_scanline);
+        w.bool(self.irq_enabled);
+        w.bool(self.irq_pending);
+        w.bool(self.in_frame);
+        w.u8(self.scanline);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes_into(&mut self.prg_ram);
+        r.bytes_into(&mut self.exram);
+        self.prg_mode = r.u8();
+        r.bytes_into(&mut self.prg_banks);
+        r.bytes_into(&mut self.ram_protect);
+        self.multiplicand = r.u8();
+        self.multiplier = r.u8();
+        self.mirroring = load_mirroring(r);
+        r.component(&mut self.audio);
+        self.chr_mode = r.u8();
+        for bank in self
+            .chr_sprites
+            .iter_mut()
+            .chain(self.chr_background.iter_mut())
+        {
+            *bank = r.u16();
+        }
+        self.chr_upper = r.u8();
+        self.background_last = r.bool();
+        self.irq_scanline = r.u8();
+        self.irq_enabled = r.bool();
+        self.irq_pending = r.bool();
+        self.in_frame = r.bool();
+        self.scanline = r.u8();
+    }
+}
@@ -0,0 +1,123 @@
+use crate::apu::expansion::n163::N163Audio;
+use crate::apu::expansion::ExpansionAudio;
+use crate::mapper::{last_bank, read_bank, ChrBanks, Mapper, PRG_RAM_SIZE};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// Mapper 19, Namco 163
+// 0x4800 Audio RAM data
+// 0x5000/0x5800 IRQ counter low byte and EHHH HHHH, IRQ enable and high bits. The 15-bit counter
+// counts up every CPU cycle and holds the IRQ while it sits at 0x7FFF, until it's written to
+// 0x8000-0xB800 1 KB CHR banks, the nametable RAM banks at 0xC000-0xD800 are not used
+// 0xE000 -SPP PPPP, audio disable and 8 KB PRG bank at 0x8000
+// 0xE800 8 KB PRG bank at 0xA000
+// 0xF000 8 KB PRG bank at 0xC000, the last 8 KB is fixed at 0xE000
+// 0xF800 Audio RAM address
+pub struct N163 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    prg_banks: [u8; 3],
+    audio: N163Audio,
+    chr_banks: [u8; 8],
+    irq_counter: u16,
+    irq_enabled: bool,
+}
+
+impl N163 {
+    pub fn new(prg_rom: Vec<u8>) -> Self {
+        N163 {
+            prg_rom,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            prg_banks: [0; 3],
+            audio: N163Audio::new(),
+            chr_banks: [0, 1, 2, 3, 4, 5, 6, 7],
+            irq_counter: 0,
+            irq_enabled: false,
+        }
+    }
+}
+
+impl Mapper for N163 {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4800..=0x4FFF => self.audio.read(addr).unwrap_or(0),
+            0x5000..=0x57FF => self.irq_counter as u8,
+            0x5800..=0x5FFF => (self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7,
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xDFFF => {
+                let bank = self.prg_banks[((addr - 0x8000) / 0x2000) as usize];
+                read_bank(&self.prg_rom, bank as usize, 0x2000, addr as usize)
+            }
+            0xE000..=0xFFFF => {
+                let last = last_bank(&self.prg_rom, 0x2000);
+                read_bank(&self.prg_rom, last, 0x2000, addr as usize)
+            }
+            _ => 0,
+        }
+    }
+
+    // The sound RAM data port moves its address on when read
+    fn peek(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4800..=0x4FFF => self.audio.peek(),
+            _ => self.read(addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4800..=0x4FFF | 0xF800..=0xFFFF => self.audio.write(addr, data),
+            0x5000..=0x57FF => self.irq_counter = (self.irq_counter & 0x7F00) | data as u16,
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | (data as u16 & 0x7F) << 8;
+                self.irq_enabled = data & 0b1000_0000 != 0;
+            }
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize] = data,
+            0x8000..=0xBFFF => self.chr_banks[((addr - 0x8000) / 0x800) as usize] = data,
+            0xE000..=0xE7FF => {
+                self.prg_banks[0] = data & 0x3F;
+                self.audio.disabled = data & 0b0100_0000 != 0;
+            }
+            0xE800..=0xEFFF => self.prg_banks[1] = data & 0x3F,
+            0xF000..=0xF7FF => self.prg_banks[2] = data & 0x3F,
+            _ => {}
+        }
+    }
+
+    fn chr_banks(&self, _tall_sprites: bool) -> Option<ChrBanks> {
+        Some(ChrBanks::same(self.chr_banks.map(|bank| bank as usize)))
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_enabled && self.irq_counter == 0x7FFF
+    }
+
+    fn clock(&mut self) {
+        if self.irq_enabled && self.irq_counter < 0x7FFF {
+            self.irq_counter += 1;
+        }
+    }
+
+    fn audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+}
+
+impl Savestate for N163 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.prg_ram);
+        w.bytes(&self.prg_banks);
+        w.component(&self.audio);
+        w.bytes(&self.chr_banks);
+        w.u16(self.irq_counter);
+        w.bool(self.irq_enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes_into(&mut self.prg_ram);
+        r.bytes_into(&mut self.prg_banks);
+        r.component(&mut self.audio);
+        r.bytes_into(&mut self.chr_banks);
+        self.irq_counter = r.u16();
+        self.irq_enabled = r.bool();
+    }
+}
@@ -0,0 +1,59 @@
+use crate::mapper::{Mapper, PRG_RAM_SIZE};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use log::debug;
+
+// Mapper 0, 16 or 32 KB of PRG ROM with no banking
+pub struct NROM {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+}
+
+impl NROM {
+    pub fn new(prg_rom: Vec<u8>) -> Self {
+        NROM {
+            prg_rom,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+        }
+    }
+}
+
+impl Mapper for NROM {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xFFFF => {
+                let mut addr = addr - 0x8000;
+                if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
+                    //mirror 16 kb for addressible space
+                    addr %= 0x4000;
+                }
+                self.prg_rom[addr as usize]
+            }
+            _ => {
+                debug!("Ignoring mem access at 0x{:4X}", addr);
+                0
+            }
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize] = data,
+            // No registers, games write to ROM anyway and nothing happens
+            0x8000..=0xFFFF => {}
+            _ => {
+                debug!("Ignoring mem write-access at 0x{:4X}", addr);
+            }
+        }
+    }
+}
+
+impl Savestate for NROM {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.prg_ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes_into(&mut self.prg_ram);
+    }
+}
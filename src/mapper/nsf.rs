@@ -0,0 +1,293 @@
+use crate::apu::expansion::fds::FDSAudio;
+use crate::apu::expansion::mmc5::MMC5Audio;
+use crate::apu::expansion::n163::N163Audio;
+use crate::apu::expansion::sunsoft5b::Sunsoft5BAudio;
+use crate::apu::expansion::vrc6::VRC6Audio;
+use crate::apu::expansion::vrc7::VRC7Audio;
+use crate::apu::expansion::ExpansionAudio;
+use crate::mapper::Mapper;
+use crate::nsf::{ExpansionChips, IDLE_ADDR, NSF};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const BANK_SIZE: usize = 0x1000;
+const MEMORY_START: u16 = 0x6000; // RAM at 0x6000-0x7FFF and the tune at 0x8000-0xFFFF
+
+// Memory map for NSF tunes
+// 0x5FF6-0x5FF7 4 KB banks at 0x6000 and 0x7000(FDS only)
+// 0x5FF8-0x5FFF 4 KB banks at 0x8000-0xF000
+// Switching a bank copies it into place, so the FDS can treat 0x6000-0xDFFF as RAM
+pub struct NSFMapper {
+    data: Vec<u8>, // Padded so that bank 0 starts at a 4 KB boundary
+    memory: Vec<u8>,
+    fds: bool,
+    audio: NSFAudio,
+}
+
+impl NSFMapper {
+    pub fn new(nsf: &NSF) -> Self {
+        let mut memory = vec![0; 0x10000 - MEMORY_START as usize];
+        let mut data = Vec::new();
+        if nsf.bankswitch.is_some() {
+            data.resize((nsf.load_addr & 0x0FFF) as usize, 0);
+            data.extend_from_slice(&nsf.data);
+            data.resize(data.len().div_ceil(BANK_SIZE) * BANK_SIZE, 0);
+        } else {
+            let start = nsf.load_addr.max(MEMORY_START) as usize - MEMORY_START as usize;
+            let length = nsf.data.len().min(memory.len() - start);
+            memory[start..start + length].copy_from_slice(&nsf.data[..length]);
+        }
+        NSFMapper {
+            data,
+            memory,
+            fds: nsf.expansion.contains(ExpansionChips::FDS),
+            audio: NSFAudio::new(nsf.expansion),
+        }
+    }
+
+    // slot is the 4 KB page counting from 0x6000
+    fn switch_bank(&mut self, slot: usize, bank: u8) {
+        if self.data.is_empty() {
+            return;
+        }
+        let banks = self.data.len() / BANK_SIZE;
+        let src = (bank as usize % banks) * BANK_SIZE;
+        let dst = slot * BANK_SIZE;
+        self.memory[dst..dst + BANK_SIZE].copy_from_slice(&self.data[src..src + BANK_SIZE]);
+    }
+}
+
+impl Mapper for NSFMapper {
+    fn read(&mut self, addr: u16) -> u8 {
+        if let Some(data) = self.audio.read(addr) {
+            return data;
+        }
+        match addr {
+            // JMP IDLE_ADDR
+            IDLE_ADDR => 0x4C,
+            0x4101 => IDLE_ADDR as u8,
+            0x4102 => (IDLE_ADDR >> 8) as u8,
+            MEMORY_START..=0xFFFF => self.memory[(addr - MEMORY_START) as usize],
+            _ => 0,
+        }
+    }
+
+    // The MMC5 PCM status and N163 data port change when read
+    fn peek(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4800 => self.audio.n163.as_ref().map_or(0, |n163| n163.peek()),
+            0x5010 => self.audio.mmc5.as_ref().map_or(0, |mmc5| mmc5.status()),
+            _ => self.read(addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5FF6..=0x5FF7 if self.fds => self.switch_bank((addr - 0x5FF6) as usize, data),
+            0x5FF8..=0x5FFF => self.switch_bank((addr - 0x5FF8) as usize + 2, data),
+            0x6000..=0x7FFF => self.memory[(addr - MEMORY_START) as usize] = data,
+            0x8000..=0xDFFF if self.fds => self.memory[(addr - MEMORY_START) as usize] = data,
+            _ => {}
+        }
+        self.audio.write(addr, data);
+    }
+
+    fn audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        if self.audio.is_empty() {
+            None
+        } else {
+            Some(&mut self.audio)
+        }
+    }
+}
+
+// Tunes can use any combination of sound chips, each one only sees the writes to its own registers
+pub struct NSFAudio {
+    vrc6: Option<VRC6Audio>,
+    vrc7: Option<VRC7Audio>,
+    fds: Option<FDSAudio>,
+    mmc5: Option<MMC5Audio>,
+    n163: Option<N163Audio>,
+    sunsoft5b: Option<Sunsoft5BAudio>,
+    mmc5_exram: [u8; 0x400], // MMC5 tunes can use its expansion RAM and multiplier
+    multiplicand: u8,
+    multiplier: u8,
+}
+
+impl NSFAudio {
+    fn new(chips: ExpansionChips) -> Self {
+        NSFAudio {
+            vrc6: chips.contains(ExpansionChips::VRC6).then(VRC6Audio::new),
+            vrc7: chips.contains(ExpansionChips::VRC7).then(VRC7Audio::new),
+            fds: chips.contains(ExpansionChips::FDS).then(FDSAudio::new),
+            mmc5: chips.contains(ExpansionChips::MMC5).then(MMC5Audio::new),
+            n163: chips.contains(ExpansionChips::N163).then(N163Audio::new),
+            sunsoft5b: chips
+                .contains(ExpansionChips::SUNSOFT5B)
+                .then(Sunsoft5BAudio::new),
+            mmc5_exram: [0; 0x400],
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.chips().next().is_none()
+    }
+
+    fn chips(&self) -> impl Iterator<Item = &dyn ExpansionAudio> {
+        let chips: [Option<&dyn ExpansionAudio>; 6] = [
+            self.vrc6.as_ref().map(|chip| chip as &dyn ExpansionAudio),
+            self.vrc7.as_ref().map(|chip| chip as &dyn ExpansionAudio),
+            self.fds.as_ref().map(|chip| chip as &dyn ExpansionAudio),
+            self.mmc5.as_ref().map(|chip| chip as &dyn ExpansionAudio),
+            self.n163.as_ref().map(|chip| chip as &dyn ExpansionAudio),
+            self.sunsoft5b
+                .as_ref()
+                .map(|chip| chip as &dyn ExpansionAudio),
+        ];
+        chips.into_iter().flatten()
+    }
+
+    fn chips_mut(&mut self) -> impl Iterator<Item = &mut dyn ExpansionAudio> {
+        let chips: [Option<&mut dyn ExpansionAudio>; 6] = [
+            self.vrc6
+                .as_mut()
+                .map(|chip| chip as &mut dyn ExpansionAudio),
+            self.vrc7
+                .as_mut()
+                .map(|chip| chip as &mut dyn ExpansionAudio),
+            self.fds
+                .as_mut()
+                .map(|chip| chip as &mut dyn ExpansionAudio),
+            self.mmc5
+                .as_mut()
+                .map(|chip| chip as &mut dyn ExpansionAudio),
+            self.n163
+                .as_mut()
+                .map(|chip| chip as &mut dyn ExpansionAudio),
+            self.sunsoft5b
+                .as_mut()
+                .map(|chip| chip as &mut dyn ExpansionAudio),
+        ];
+        chips.into_iter().flatten()
+    }
+}
+
+impl Savestate for NSFMapper {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.memory);
+        w.component(&self.audio);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes_into(&mut self.memory);
+        r.component(&mut self.audio);
+    }
+}
+
+// The chips a tune uses come from its header, so only those are in the state
+impl Savestate for NSFAudio {
+    fn save_state(&self, w: &mut StateWriter) {
+        for chip in self.chips() {
+            w.component(chip);
+        }
+        w.bytes(&self.mmc5_exram);
+        w.u8(self.multiplicand);
+        w.u8(self.multiplier);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        for chip in self.chips_mut() {
+            r.component(chip);
+        }
+        r.bytes_into(&mut self.mmc5_exram);
+        self.multiplicand = r.u8();
+        self.multiplier = r.u8();
+    }
+}
+
+impl ExpansionAudio for NSFAudio {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x9000..=0x9003 | 0xA000..=0xA002 | 0xB000..=0xB002 => {
+                if let Some(vrc6) = self.vrc6.as_mut() {
+                    vrc6.write(addr, data);
+                }
+            }
+            0x9010 | 0x9030 => {
+                if let Some(vrc7) = self.vrc7.as_mut() {
+                    vrc7.write(addr, data);
+                }
+            }
+            0x4040..=0x408A => {
+                if let Some(fds) = self.fds.as_mut() {
+                    fds.write(addr, data);
+                }
+            }
+            0x5000..=0x5015 => {
+                if let Some(mmc5) = self.mmc5.as_mut() {
+                    mmc5.write(addr, data);
+                }
+            }
+            0x5205 if self.mmc5.is_some() => self.multiplicand = data,
+            0x5206 if self.mmc5.is_some() => self.multiplier = data,
+            0x5C00..=0x5FF5 if self.mmc5.is_some() => {
+                self.mmc5_exram[(addr - 0x5C00) as usize] = data;
+            }
+            0x4800 | 0xF800 => {
+                if let Some(n163) = self.n163.as_mut() {
+                    n163.write(addr, data);
+                }
+            }
+            0xC000 | 0xE000 => {
+                if let Some(sunsoft5b) = self.sunsoft5b.as_mut() {
+                    sunsoft5b.write(addr, data);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4040..=0x4092 => self.fds.as_mut().and_then(|fds| fds.read(addr)),
+            0x5010 | 0x5015 => self.mmc5.as_mut().and_then(|mmc5| mmc5.read(addr)),
+            0x5205 if self.mmc5.is_some() => {
+                Some((self.multiplicand as u16 * self.multiplier as u16) as u8)
+            }
+            0x5206 if self.mmc5.is_some() => {
+                Some(((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8)
+            }
+            0x5C00..=0x5FF5 if self.mmc5.is_some() => {
+                Some(self.mmc5_exram[(addr - 0x5C00) as usize])
+            }
+            0x4800 => self.n163.as_mut().and_then(|n163| n163.read(addr)),
+            _ => None,
+        }
+    }
+
+    fn clock(&mut self) {
+        if let Some(vrc6) = self.vrc6.as_mut() {
+            vrc6.clock();
+        }
+        if let Some(vrc7) = self.vrc7.as_mut() {
+            vrc7.clock();
+        }
+        if let Some(fds) = self.fds.as_mut() {
+            fds.clock();
+        }
+        if let Some(mmc5) = self.mmc5.as_mut() {
+            mmc5.clock();
+        }
+        if let Some(n163) = self.n163.as_mut() {
+            n163.clock();
+        }
+        if let Some(sunsoft5b) = self.sunsoft5b.as_mut() {
+            sunsoft5b.clock();
+        }
+    }
+
+    fn output(&self) -> f32 {
+        self.chips().map(|chip| chip.output()).sum()
+    }
+}
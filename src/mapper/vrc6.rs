@@ -0,0 +1,145 @@
+use crate::apu::expansion::vrc6::VRC6Audio;
+use crate::apu::expansion::ExpansionAudio;
+use crate::mapper::vrc_irq::VrcIrq;
+use crate::mapper::{
+    last_bank, load_mirroring, mirroring_from_bits, read_bank, save_mirroring, ChrBanks, Mapper,
+    PRG_RAM_SIZE,
+};
+use crate::rom::Mirroring;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// Mappers 24(VRC6a) and 26(VRC6b), the only difference is that VRC6b swaps address lines A0 and A1
+// 0x8000-0x8003 16 KB PRG bank at 0x8000
+// 0xC000-0xC003 8 KB PRG bank at 0xC000, the last 8 KB is fixed at 0xE000
+// 0xB003 PRG RAM enable and mirroring, only the 1 KB CHR banking mode is used
+// 0x9000-0xB002 Audio
+// 0xD000-0xE003 1 KB CHR banks
+// 0xF000-0xF002 IRQ latch, control and acknowledge
+pub struct VRC6 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    swapped: bool,
+    prg_16k: u8,
+    prg_8k: u8,
+    ram_enabled: bool,
+    mirroring: Option<Mirroring>,
+    audio: VRC6Audio,
+    chr_banks: [u8; 8],
+    irq: VrcIrq,
+}
+
+impl VRC6 {
+    pub fn new(prg_rom: Vec<u8>, swapped: bool) -> Self {
+        VRC6 {
+            prg_rom,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            swapped,
+            prg_16k: 0,
+            prg_8k: 0,
+            ram_enabled: false,
+            mirroring: None,
+            audio: VRC6Audio::new(),
+            chr_banks: [0, 1, 2, 3, 4, 5, 6, 7],
+            irq: VrcIrq::new(),
+        }
+    }
+}
+
+impl Mapper for VRC6 {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.ram_enabled => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xBFFF => read_bank(
+                &self.prg_rom,
+                self.prg_16k as usize,
+                0x4000,
+                (addr - 0x8000) as usize,
+            ),
+            0xC000..=0xDFFF => read_bank(
+                &self.prg_rom,
+                self.prg_8k as usize,
+                0x2000,
+                (addr - 0xC000) as usize,
+            ),
+            0xE000..=0xFFFF => {
+                let last = last_bank(&self.prg_rom, 0x2000);
+                read_bank(&self.prg_rom, last, 0x2000, (addr - 0xE000) as usize)
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if (0x6000..=0x7FFF).contains(&addr) {
+            if self.ram_enabled {
+                self.prg_ram[(addr - 0x6000) as usize] = data;
+            }
+            return;
+        }
+        let addr = if self.swapped {
+            (addr & 0xFFFC) | ((addr & 1) << 1) | ((addr >> 1) & 1)
+        } else {
+            addr
+        };
+        match addr & 0xF003 {
+            0x8000..=0x8003 => self.prg_16k = data & 0x0F,
+            0xC000..=0xC003 => self.prg_8k = data & 0x1F,
+            // R--- MM--
+            0xB003 => {
+                self.ram_enabled = data & 0b1000_0000 != 0;
+                self.mirroring = Some(mirroring_from_bits(data >> 2));
+            }
+            0x9000..=0xB002 => self.audio.write(addr & 0xF003, data),
+            0xD000..=0xD003 => self.chr_banks[(addr & 3) as usize] = data,
+            0xE000..=0xE003 => self.chr_banks[4 + (addr & 3) as usize] = data,
+            0xF000 => self.irq.write_latch(data),
+            0xF001 => self.irq.write_control(data),
+            0xF002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        self.mirroring
+    }
+
+    fn chr_banks(&self, _tall_sprites: bool) -> Option<ChrBanks> {
+        Some(ChrBanks::same(self.chr_banks.map(|bank| bank as usize)))
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn clock(&mut self) {
+        self.irq.clock();
+    }
+
+    fn audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+}
+
+impl Savestate for VRC6 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.prg_ram);
+        w.u8(self.prg_16k);
+        w.u8(self.prg_8k);
+        w.bool(self.ram_enabled);
+        save_mirroring(w, self.mirroring);
+        w.component(&self.audio);
+        w.bytes(&self.chr_banks);
+        w.component(&self.irq);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes_into(&mut self.prg_ram);
+        self.prg_16k = r.u8();
+        self.prg_8k = r.u8();
+        self.ram_enabled = r.bool();
+        self.mirroring = load_mirroring(r);
+        r.component(&mut self.audio);
+        r.bytes_into(&mut self.chr_banks);
+        r.component(&mut self.irq);
+    }
+}
@@ -0,0 +1,137 @@
+use crate::apu::expansion::vrc7::VRC7Audio;
+use crate::apu::expansion::ExpansionAudio;
+use crate::mapper::vrc_irq::VrcIrq;
+use crate::mapper::{
+    last_bank, load_mirroring, mirroring_from_bits, read_bank, save_mirroring, ChrBanks, Mapper,
+    PRG_RAM_SIZE,
+};
+use crate::rom::Mirroring;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// Mapper 85, VRC7a selects registers with A4 and VRC7b with A3, so both are accepted
+// 0x8000 8 KB PRG bank at 0x8000
+// 0x8008/0x8010 8 KB PRG bank at 0xA000
+// 0x9000 8 KB PRG bank at 0xC000, the last 8 KB is fixed at 0xE000
+// 0x9010/0x9030 Audio register select and write
+// 0xA000-0xD008 1 KB CHR banks, two to each 0x1000
+// 0xE000 RS-- --MM, PRG RAM enable, audio silence/reset and mirroring
+// 0xE008-0xF008 IRQ latch, control and acknowledge
+pub struct VRC7 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    prg_banks: [u8; 3],
+    ram_enabled: bool,
+    mirroring: Option<Mirroring>,
+    audio: VRC7Audio,
+    chr_banks: [u8; 8],
+    irq: VrcIrq,
+}
+
+impl VRC7 {
+    pub fn new(prg_rom: Vec<u8>) -> Self {
+        VRC7 {
+            prg_rom,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            prg_banks: [0; 3],
+            ram_enabled: false,
+            mirroring: None,
+            audio: VRC7Audio::new(),
+            chr_banks: [0, 1, 2, 3, 4, 5, 6, 7],
+            irq: VrcIrq::new(),
+        }
+    }
+}
+
+impl Mapper for VRC7 {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.ram_enabled => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xDFFF => {
+                let bank = self.prg_banks[((addr - 0x8000) / 0x2000) as usize];
+                read_bank(&self.prg_rom, bank as usize, 0x2000, addr as usize)
+            }
+            0xE000..=0xFFFF => {
+                let last = last_bank(&self.prg_rom, 0x2000);
+                read_bank(&self.prg_rom, last, 0x2000, addr as usize)
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if (0x6000..=0x7FFF).contains(&addr) {
+            if self.ram_enabled {
+                self.prg_ram[(addr - 0x6000) as usize] = data;
+            }
+            return;
+        }
+        match addr & 0xF038 {
+            0x8000 => self.prg_banks[0] = data & 0x3F,
+            0x8008 | 0x8010 => self.prg_banks[1] = data & 0x3F,
+            0x9000 => self.prg_banks[2] = data & 0x3F,
+            0x9010 | 0x9030 => self.audio.write(addr & 0xF030, data),
+            // A3 or A4 picks the second bank of each pair
+            0xA000 | 0xA008 | 0xA010 | 0xB000 | 0xB008 | 0xB010 | 0xC000 | 0xC008 | 0xC010
+            | 0xD000 | 0xD008 | 0xD010 => {
+                let second = addr & 0x18 != 0;
+                let bank = ((addr - 0xA000) >> 12) as usize * 2 + second as usize;
+                self.chr_banks[bank] = data;
+            }
+            0xE008 | 0xE010 => self.irq.write_latch(data),
+            0xF000 => self.irq.write_control(data),
+            0xF008 | 0xF010 => self.irq.acknowledge(),
+            0xE000 => {
+                self.ram_enabled = data & 0b1000_0000 != 0;
+                let silenced = data & 0b0100_0000 != 0;
+                if silenced {
+                    self.audio.reset();
+                }
+                self.audio.silenced = silenced;
+                self.mirroring = Some(mirroring_from_bits(data));
+            }
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        self.mirroring
+    }
+
+    fn chr_banks(&self, _tall_sprites: bool) -> Option<ChrBanks> {
+        Some(ChrBanks::same(self.chr_banks.map(|bank| bank as usize)))
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn clock(&mut self) {
+        self.irq.clock();
+    }
+
+    fn audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+}
+
+impl Savestate for VRC7 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.prg_ram);
+        w.bytes(&self.prg_banks);
+        w.bool(self.ram_enabled);
+        save_mirroring(w, self.mirroring);
+        w.component(&self.audio);
+        w.bytes(&self.chr_banks);
+        w.component(&self.irq);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes_into(&mut self.prg_ram);
+        r.bytes_into(&mut self.prg_banks);
+        self.ram_enabled = r.bool();
+        self.mirroring = load_mirroring(r);
+        r.component(&mut self.audio);
+        r.bytes_into(&mut self.chr_banks);
+        r.component(&mut self.irq);
+    }
+}
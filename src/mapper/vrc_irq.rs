@@ -0,0 +1,147 @@
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const PRESCALER: i16 = 341; // Scanline mode counts every 113.667 CPU cycles, 341 / 3
+
+// The IRQ counter shared by the Konami VRC4, VRC6 and VRC7
+// An 8-bit counter counting up from the latch, the IRQ is raised when it overflows
+// Control bits: 0 enable after acknowledge, 1 enable, 2 count CPU cycles instead of scanlines
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl VrcIrq {
+    pub fn new() -> Self {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            prescaler: PRESCALER,
+            enabled: false,
+            enable_after_ack: false,
+            cycle_mode: false,
+            pending: false,
+        }
+    }
+
+    pub fn write_latch(&mut self, data: u8) {
+        self.latch = data;
+    }
+
+    pub fn write_control(&mut self, data: u8) {
+        self.enable_after_ack = data & 0b001 != 0;
+        self.enabled = data & 0b010 != 0;
+        self.cycle_mode = data & 0b100 != 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = PRESCALER;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if !self.cycle_mode {
+            self.prescaler -= 3;
+            if self.prescaler > 0 {
+                return;
+            }
+            self.prescaler += PRESCALER;
+        }
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+}
+
+impl Default for VrcIrq {
+    fn default() -> Self {
+        VrcIrq::new()
+    }
+}
+
+impl Savestate for VrcIrq {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.latch);
+        w.u8(self.counter);
+        w.u16(self.prescaler as u16);
+        w.bool(self.enabled);
+        w.bool(self.enable_after_ack);
+        w.bool(self.cycle_mode);
+        w.bool(self.pending);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.latch = r.u8();
+        self.counter = r.u8();
+        self.prescaler = r.u16() as i16;
+        self.enabled = r.bool();
+        self.enable_after_ack = r.bool();
+        self.cycle_mode = r.bool();
+        self.pending = r.bool();
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_cycle_mode() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xFD);
+        irq.write_control(0b111);
+        irq.clock();
+        irq.clock();
+        assert!(!irq.pending());
+        irq.clock();
+        assert!(irq.pending());
+
+        // Acknowledging keeps it counting from the latch since bit 0 was set
+        irq.acknowledge();
+        assert!(!irq.pending());
+        for _ in 0..3 {
+            irq.clock();
+        }
+        assert!(irq.pending());
+    }
+
+    #[test]
+    fn test_scanline_mode() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xFE);
+        irq.write_control(0b010);
+        // Two scanlines, 341 dots each at 3 dots a cycle
+        for _ in 0..227 {
+            irq.clock();
+        }
+        assert!(!irq.pending());
+        irq.clock();
+        assert!(irq.pending());
+
+        // Without bit 0 acknowledging stops the counter
+        irq.acknowledge();
+        for _ in 0..1000 {
+            irq.clock();
+        }
+        assert!(!irq.pending());
+    }
+}
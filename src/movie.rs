@@ -0,0 +1,461 @@
+// Input movies in FCEUX's FM2 text format
+// A movie is the controller state for every frame from power on, or from a save state it
+// carries. Frames count from the first frame the movie was started on, the input ports play
+// them back or record into them as the console runs
+//
+// A header of `key value` lines is followed by one line per frame, eg.
+// |0|R..U...A|........||
+// holding the commands(1 is a soft reset, 2 a power cycle) and the pads in RLDUTSBA order, with
+// four pads when a Four Score is used. The expansion port field is always empty
+// The console can't be reset from a movie, so movies that reset or power cycle it part way through
+// are refused rather than played out of sync
+// romChecksum(an MD5 of the ROM) isn't written and save states are in this emulator's format, so
+// only movies from power on play in FCEUX
+use crate::controller::ControllerButton;
+use crate::cpu::CPU;
+use crate::region::Region;
+use crate::savestate;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MovieFrame {
+    pub commands: u8,
+    pub pads: [ControllerButton; 4],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+    pub rerecords: u32,
+    pub pal: bool,
+    pub rom_name: String,
+    pub guid: String,
+    pub comments: Vec<String>,
+    pub four_score: bool,
+    pub savestate: Option<Vec<u8>>, // Where the movie starts, None is power on
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn new(rom_name: &str, region: Region) -> Self {
+        Movie {
+            rerecords: 0,
+            pal: region == Region::PAL,
+            rom_name: rom_name.to_string(),
+            guid: new_guid(),
+            comments: Vec::new(),
+            four_score: false,
+            savestate: None,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, String> {
+        let mut movie = Movie::new("", Region::NTSC);
+        let mut ports = [1, 1];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.starts_with('|') {
+                let frame = parse_frame(line, movie.four_score, ports)
+                    .map_err(|err| format!("line {}: {}", i + 1, err))?;
+                movie.frames.push(frame);
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "" => {}
+                "version" if value != "3" => {
+                    return Err(format!("FM2 version {} isn't supported", value))
+                }
+                "binary" if value == "1" => {
+                    return Err("binary input logs aren't supported".to_string())
+                }
+                "rerecordCount" => movie.rerecords = value.parse().unwrap_or(0),
+                "palFlag" => movie.pal = value == "1",
+                "romFilename" => movie.rom_name = value.to_string(),
+                "guid" => movie.guid = value.to_string(),
+                "comment" => movie.comments.push(value.to_string()),
+                "fourscore" => movie.four_score = value == "1",
+                "port0" | "port1" => {
+                    let port = (key == "port1") as usize;
+                    ports[port] = match value {
+                        "0" | "1" => value.parse().unwrap(),
+                        _ => return Err("only movies with controllers are supported".to_string()),
+                    };
+                }
+                "savestate" => {
+                    let data = value
+                        .strip_prefix("base64:")
+                        .ok_or("the save state isn't base64")?;
+                    movie.savestate = Some(base64_decode(data)?);
+                }
+                _ => {}
+            }
+        }
+        Ok(movie)
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut text = String::new();
+        text.push_str("version 3\n");
+        text.push_str(&format!("rerecordCount {}\n", self.rerecords));
+        text.push_str(&format!("palFlag {}\n", self.pal as u8));
+        text.push_str(&format!("romFilename {}\n", self.rom_name));
+        text.push_str(&format!("guid {}\n", self.guid));
+        text.push_str(&format!("fourscore {}\n", self.four_score as u8));
+        text.push_str("microphone 0\n");
+        text.push_str("port0 1\nport1 1\nport2 0\n");
+        text.push_str("FDS 0\nNewPPU 0\n");
+        for comment in &self.comments {
+            text.push_str(&format!("comment {}\n", comment));
+        }
+        if let Some(state) = &self.savestate {
+            text.push_str(&format!("savestate base64:{}\n", base64_encode(state)));
+        }
+        let pads = if self.four_score { 4 } else { 2 };
+        for frame in &self.frames {
+            text.push_str(&format!("|{}|", frame.commands));
+            for pad in &frame.pads[..pads] {
+                text.push_str(&pad_field(*pad));
+                text.push('|');
+            }
+            text.push_str("|\n");
+        }
+        text
+    }
+}
+
+fn parse_frame(line: &str, four_score: bool, ports: [u8; 2]) -> Result<MovieFrame, String> {
+    let mut fields = line.split('|').skip(1);
+    let commands = fields.next().unwrap_or("").trim();
+    let mut frame = MovieFrame {
+        commands: commands
+            .parse()
+            .map_err(|_| format!("bad commands `{}`", commands))?,
+        pads: [ControllerButton::empty(); 4],
+    };
+    let players = if four_score { 4 } else { 2 };
+    for (player, pad) in frame.pads.iter_mut().enumerate().take(players) {
+        let field = fields.next().unwrap_or("");
+        // Nothing is logged for an empty port
+        if !four_score && ports[player] == 0 {
+            continue;
+        }
+        if field.chars().count() != 8 {
+            return Err(format!("bad controller `{}`", field));
+        }
+        for (i, c) in field.chars().enumerate() {
+            if c != '.' && c != ' ' {
+                *pad |= ControllerButton::from_bits_truncate(0x80 >> i);
+            }
+        }
+    }
+    Ok(frame)
+}
+
+fn pad_field(pad: ControllerButton) -> String {
+    "RLDUTSBA"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if pad.bits() & (0x80 >> i) != 0 {
+                c
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn base64_decode(text: &str) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    let mut n = 0u32;
+    let mut bits = 0;
+    for c in text.trim().bytes().filter(|c| *c != b'=') {
+        let value = BASE64
+            .iter()
+            .position(|b| *b == c)
+            .ok_or("bad base64 in the save state")?;
+        n = n << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            data.push((n >> bits) as u8);
+            n &= (1 << bits) - 1;
+        }
+    }
+    Ok(data)
+}
+
+// Only has to tell movies apart, FCEUX pairs save states with movies through it
+fn new_guid() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos());
+    let hash = |seed: u64| {
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    };
+    let a = hash(nanos as u64);
+    let b = hash(a ^ (nanos >> 64) as u64);
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:04X}-{:012X}",
+        a >> 32,
+        (a >> 16) & 0xFFFF,
+        a & 0xFFFF,
+        b >> 48,
+        b & 0xFFFF_FFFF_FFFF
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovieMode {
+    Recording,
+    Playing,
+    Finished, // Played to the end, the frontend's input is back
+}
+
+// A movie the input ports are playing or recording
+// Loading a save state moves to the state's frame. Read only, the movie keeps playing from there,
+// otherwise everything after that frame is dropped and recording carries on(a rerecord)
+pub struct MoviePlayback {
+    pub movie: Movie,
+    pub read_only: bool,
+    mode: MovieMode,
+    start: u64, // Input frame the movie's first frame ran on
+}
+
+impl MoviePlayback {
+    pub(crate) fn new(movie: Movie, mode: MovieMode, read_only: bool, start: u64) -> Self {
+        MoviePlayback {
+            movie,
+            read_only,
+            mode,
+            start,
+        }
+    }
+
+    pub fn mode(&self) -> MovieMode {
+        self.mode
+    }
+
+    // Frames into the movie
+    pub fn position(&self, frame: u64) -> usize {
+        frame.saturating_sub(self.start) as usize
+    }
+
+    pub(crate) fn input(&self, frame: u64) -> Option<[ControllerButton; 4]> {
+        match self.mode {
+            MovieMode::Playing => self.movie.frames.get(self.position(frame)).map(|f| f.pads),
+            _ => None,
+        }
+    }
+
+    // Called with what the console saw once a frame is over
+    pub(crate) fn end_frame(&mut self, frame: u64, pads: [ControllerButton; 4]) {
+        if self.mode == MovieMode::Recording {
+            let position = self.position(frame);
+            self.movie.frames.truncate(position);
+            self.movie.frames.push(MovieFrame { commands: 0, pads });
+        }
+    }
+
+    pub(crate) fn next_frame(&mut self, frame: u64) {
+        if self.mode == MovieMode::Playing && self.position(frame) >= self.movie.frames.len() {
+            self.mode = MovieMode::Finished;
+        }
+    }
+
+    pub(crate) fn seek(&mut self, frame: u64) {
+        let position = self.position(frame);
+        if self.read_only {
+            self.mode = if position < self.movie.frames.len() {
+                MovieMode::Playing
+            } else {
+                MovieMode::Finished
+            };
+        } else {
+            self.movie.frames.truncate(position);
+            self.movie.rerecords += 1;
+            self.mode = MovieMode::Recording;
+        }
+    }
+}
+
+// Starts recording the console from here, a movie that isn't from power on gets a save state
+pub fn record(cpu: &mut CPU, mut movie: Movie, power_on: bool) {
+    movie.savestate = (!power_on).then(|| savestate::save(cpu));
+    movie.frames.clear();
+    cpu.bus
+        .input()
+        .start_movie(movie, MovieMode::Recording, false);
+}
+
+// Plays the movie from its save state, or from here for a console that was just switched on
+pub fn play(cpu: &mut CPU, movie: Movie, read_only: bool) -> Result<(), String> {
+    if movie.pal != (cpu.bus.ppu.region == Region::PAL) {
+        let region = if movie.pal { "PAL" } else { "NTSC" };
+        return Err(format!("the movie was recorded on a {} console", region));
+    }
+    // A power cycle on the first frame is where a movie from power on starts anyway
+    let reset = movie
+        .frames
+        .iter()
+        .enumerate()
+        .position(|(i, frame)| frame.commands & 0b11 != 0 && !(i == 0 && frame.commands == 2));
+    if let Some(frame) = reset {
+        return Err(format!(
+            "the movie resets the console on frame {}, resets can't be played back",
+            frame
+        ));
+    }
+    if let Some(state) = &movie.savestate {
+        savestate::load(cpu, state)?;
+    }
+    cpu.bus
+        .input()
+        .start_movie(movie, MovieMode::Playing, read_only);
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::input::InputPorts;
+    use crate::savestate::{StateReader, StateWriter};
+
+    const FM2: &str = "version 3
+emuVersion 22020
+rerecordCount 7
+palFlag 0
+romFilename Game
+guid 01234567-89AB-CDEF-0123-456789ABCDEF
+fourscore 0
+port0 1
+port1 1
+port2 0
+comment author Someone
+|0|R......A|........||
+|0|...U....|.L....B.||
+";
+
+    #[test]
+    fn test_fm2() {
+        let movie = Movie::parse(FM2).unwrap();
+        assert_eq!(movie.rerecords, 7);
+        assert_eq!(movie.rom_name, "Game");
+        assert_eq!(movie.comments, vec!["author Someone"]);
+        assert_eq!(movie.frames.len(), 2);
+        let pads = movie.frames[1].pads;
+        assert_eq!(pads[0], ControllerButton::UP);
+        assert_eq!(pads[1], ControllerButton::LEFT | ControllerButton::B);
+        assert_eq!(
+            movie.frames[0].pads[0],
+            ControllerButton::RIGHT | ControllerButton::A
+        );
+
+        // Written back out and with a save state and four players
+        let mut movie = movie;
+        movie.four_score = true;
+        movie.frames[1].pads[3] = ControllerButton::START;
+        movie.savestate = Some(vec![0, 1, 2, 3, 254, 255, 7]);
+        let text = movie.to_fm2();
+        assert!(text.contains("|0|...U....|.L....B.|........|....T...||"));
+        assert_eq!(Movie::parse(&text).unwrap(), movie);
+
+        assert!(Movie::parse("version 2").is_err());
+        assert!(Movie::parse("|0|R......|........||").is_err());
+    }
+
+    #[test]
+    fn test_play_refuses_resets() {
+        let mut cpu = crate::savestate::test::test_cpu();
+        let mut movie = Movie::parse(FM2).unwrap();
+        movie.frames[0].commands = 2; // Power on
+        play(&mut cpu, movie.clone(), true).unwrap();
+
+        movie.frames[1].commands = 1;
+        let err = play(&mut cpu, movie, true).unwrap_err();
+        assert!(err.contains("frame 1"));
+    }
+
+    fn run(input: &mut InputPorts, frames: &[ControllerButton]) {
+        for buttons in frames {
+            input.set_button(0, ControllerButton::all(), false);
+            input.set_button(0, *buttons, true);
+            input.next_frame();
+        }
+    }
+
+    fn save(input: &InputPorts) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.component(input);
+        w.into_bytes()
+    }
+
+    fn load(input: &mut InputPorts, state: &[u8]) {
+        StateReader::new(state).component(input);
+    }
+
+    #[test]
+    fn test_record_and_rerecord() {
+        let (a, b, up) = (
+            ControllerButton::A,
+            ControllerButton::B,
+            ControllerButton::UP,
+        );
+        let mut input = InputPorts::new();
+        input.start_movie(Movie::new("", Region::NTSC), MovieMode::Recording, false);
+        run(&mut input, &[a, b]);
+        let state = save(&input);
+        run(&mut input, &[a, a]);
+
+        // Loading goes back and records over what came after
+        load(&mut input, &state);
+        run(&mut input, &[up]);
+        let movie = input.stop_movie().unwrap();
+        let pads: Vec<ControllerButton> = movie.frames.iter().map(|f| f.pads[0]).collect();
+        assert_eq!(pads, vec![a, b, up]);
+        assert_eq!(movie.rerecords, 1);
+
+        // Playback ignores what's held until the movie is over
+        let mut input = InputPorts::new();
+        input.start_movie(movie, MovieMode::Playing, true);
+        let played: Vec<ControllerButton> = (0..4)
+            .map(|_| {
+                input.set_button(0, ControllerButton::SELECT, true);
+                let state = input.state(0);
+                input.next_frame();
+                state
+            })
+            .collect();
+        assert_eq!(played, vec![a, b, up, ControllerButton::SELECT]);
+        assert_eq!(input.movie().unwrap().mode(), MovieMode::Finished);
+
+        // Read only, loading keeps playing
+        load(&mut input, &state);
+        assert_eq!(input.movie().unwrap().mode(), MovieMode::Playing);
+        assert_eq!(input.state(0), up);
+    }
+}
@@ -0,0 +1,477 @@
+// NSF and NSFe music rips
+// Both formats hold the 6502 code and data of a game's sound engine plus an INIT routine that sets up a track
+// and a PLAY routine that is called at a fixed rate(usually every frame). There is no PPU program, so instead of
+// running from the reset vector the CPU is driven by NSFDriver
+use crate::cpu::{CpuFlags, Mem, CPU};
+use crate::mapper::nsf::NSFMapper;
+use crate::mapper::Mapper;
+use crate::region::Region;
+use bitflags::bitflags;
+use std::time::Duration;
+
+const NSF_TAG: [u8; 5] = [0x4E, 0x45, 0x53, 0x4D, 0x1A]; // NESM
+const NSFE_TAG: [u8; 4] = [0x4E, 0x53, 0x46, 0x45]; // NSFE
+const HEADER_SIZE: usize = 0x80;
+const DEFAULT_NTSC_SPEED: u16 = 16639; // Microseconds between PLAY calls, about 60.1Hz
+const DEFAULT_PAL_SPEED: u16 = 19997;
+pub const DEFAULT_TRACK_LENGTH: Duration = Duration::from_secs(150); // For tracks without a time from NSFe
+
+// The CPU waits in a JMP to itself here between calls, the mapper supplies the instruction
+pub const IDLE_ADDR: u16 = 0x4100;
+
+bitflags! {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ExpansionChips: u8 {
+        const VRC6      = 0b00000001;
+        const VRC7      = 0b00000010;
+        const FDS       = 0b00000100;
+        const MMC5      = 0b00001000;
+        const N163      = 0b00010000;
+        const SUNSOFT5B = 0b00100000;
+    }
+}
+
+// Per track metadata, only NSFe files have it
+#[derive(Debug, Clone, Default)]
+pub struct TrackInfo {
+    pub name: Option<String>,
+    pub duration: Option<Duration>,
+    pub fade: Option<Duration>,
+}
+
+pub struct NSF {
+    pub total_tracks: u8,
+    pub starting_track: u8, // 0 based
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ripper: String,
+    pub ntsc_speed: u16, // Microseconds between PLAY calls
+    pub pal_speed: u16,
+    pub bankswitch: Option<[u8; 8]>, // Initial 4 KB banks for 0x8000-0xFFFF, None loads the data flat
+    pub region: Region,
+    pub expansion: ExpansionChips,
+    pub tracks: Vec<TrackInfo>,
+    pub data: Vec<u8>,
+}
+
+impl NSF {
+    pub fn is_nsf(raw: &[u8]) -> bool {
+        raw.starts_with(&NSF_TAG) || raw.starts_with(&NSFE_TAG)
+    }
+
+    pub fn new(raw: &[u8]) -> Result<NSF, String> {
+        if raw.starts_with(&NSF_TAG) {
+            NSF::parse_nsf(raw)
+        } else if raw.starts_with(&NSFE_TAG) {
+            NSF::parse_nsfe(raw)
+        } else {
+            Err("File is not in NSF or NSFe format".to_string())
+        }
+    }
+
+    fn empty() -> NSF {
+        NSF {
+            total_tracks: 1,
+            starting_track: 0,
+            load_addr: 0x8000,
+            init_addr: 0x8000,
+            play_addr: 0x8000,
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            ripper: String::new(),
+            ntsc_speed: DEFAULT_NTSC_SPEED,
+            pal_speed: DEFAULT_PAL_SPEED,
+            bankswitch: None,
+            region: Region::NTSC,
+            expansion: ExpansionChips::empty(),
+            tracks: Vec::new(),
+            data: Vec::new(),
+        }
+    }
+
+    fn parse_nsf(raw: &[u8]) -> Result<NSF, String> {
+        if raw.len() < HEADER_SIZE {
+            return Err("NSF header is truncated".to_string());
+        }
+        let mut nsf = NSF::empty();
+        nsf.total_tracks = raw[0x06].max(1);
+        nsf.starting_track = raw[0x07].saturating_sub(1).min(nsf.total_tracks - 1);
+        nsf.load_addr = u16::from_le_bytes([raw[0x08], raw[0x09]]);
+        nsf.init_addr = u16::from_le_bytes([raw[0x0A], raw[0x0B]]);
+        nsf.play_addr = u16::from_le_bytes([raw[0x0C], raw[0x0D]]);
+        nsf.title = NSF::read_string(&raw[0x0E..0x2E]);
+        nsf.artist = NSF::read_string(&raw[0x2E..0x4E]);
+        nsf.copyright = NSF::read_string(&raw[0x4E..0x6E]);
+        nsf.ntsc_speed = NSF::speed_or_default(&raw[0x6E..0x70], DEFAULT_NTSC_SPEED);
+        nsf.set_bankswitch(&raw[0x70..0x78]);
+        nsf.pal_speed = NSF::speed_or_default(&raw[0x78..0x7A], DEFAULT_PAL_SPEED);
+        // Bit 0 is PAL, bit 1 is a tune that plays on both, which runs as NTSC
+        if raw[0x7A] & 0b11 == 0b01 {
+            nsf.region = Region::PAL;
+        }
+        nsf.expansion = ExpansionChips::from_bits_truncate(raw[0x7B]);
+
+        // NSF2 can follow the program data with metadata, its length is in 0x7D-0x7F
+        let length = u32::from_le_bytes([raw[0x7D], raw[0x7E], raw[0x7F], 0]) as usize;
+        let end = if raw[0x05] >= 2 && length > 0 {
+            (HEADER_SIZE + length).min(raw.len())
+        } else {
+            raw.len()
+        };
+        nsf.data = raw[HEADER_SIZE..end].to_vec();
+        nsf.tracks = vec![TrackInfo::default(); nsf.total_tracks as usize];
+        Ok(nsf)
+    }
+
+    // NSFe is a list of chunks, each a 4 byte length and 4 byte id followed by the data
+    // Chunks starting with an upper case letter are required to play the file correctly
+    fn parse_nsfe(raw: &[u8]) -> Result<NSF, String> {
+        let mut nsf = NSF::empty();
+        let mut has_info = false;
+        let mut has_data = false;
+        let mut names = Vec::new();
+        let mut times = Vec::new();
+        let mut fades = Vec::new();
+
+        let mut pos = 4;
+        loop {
+            if pos + 8 > raw.len() {
+                return Err("NSFe is missing its NEND chunk".to_string());
+            }
+            let length = u32::from_le_bytes([raw[pos], raw[pos + 1], raw[pos + 2], raw[pos + 3]]);
+            let id = &raw[pos + 4..pos + 8];
+            let start = pos + 8;
+            let end = start + length as usize;
+            if end > raw.len() {
+                return Err(format!(
+                    "NSFe chunk {} is truncated",
+                    String::from_utf8_lossy(id)
+                ));
+            }
+            let chunk = &raw[start..end];
+            pos = end;
+
+            match id {
+                b"INFO" => {
+                    if chunk.len() < 8 {
+                        return Err("NSFe INFO chunk is too short".to_string());
+                    }
+                    nsf.load_addr = u16::from_le_bytes([chunk[0], chunk[1]]);
+                    nsf.init_addr = u16::from_le_bytes([chunk[2], chunk[3]]);
+                    nsf.play_addr = u16::from_le_bytes([chunk[4], chunk[5]]);
+                    if chunk[6] & 0b11 == 0b01 {
+                        nsf.region = Region::PAL;
+                    }
+                    nsf.expansion = ExpansionChips::from_bits_truncate(chunk[7]);
+                    nsf.total_tracks = chunk.get(8).copied().unwrap_or(1).max(1);
+                    nsf.starting_track =
+                        chunk.get(9).copied().unwrap_or(0).min(nsf.total_tracks - 1);
+                    has_info = true;
+                }
+                b"DATA" => {
+                    nsf.data = chunk.to_vec();
+                    has_data = true;
+                }
+                b"BANK" => {
+                    let mut banks = [0; 8];
+                    banks[..chunk.len().min(8)].copy_from_slice(&chunk[..chunk.len().min(8)]);
+                    nsf.set_bankswitch(&banks);
+                }
+                b"RATE" => {
+                    if chunk.len() >= 2 {
+                        nsf.ntsc_speed = NSF::speed_or_default(&chunk[0..2], DEFAULT_NTSC_SPEED);
+                    }
+                    if chunk.len() >= 4 {
+                        nsf.pal_speed = NSF::speed_or_default(&chunk[2..4], DEFAULT_PAL_SPEED);
+                    }
+                }
+                b"auth" => {
+                    let mut fields = chunk.split(|&b| b == 0).map(NSF::read_string);
+                    nsf.title = fields.next().unwrap_or_default();
+                    nsf.artist = fields.next().unwrap_or_default();
+                    nsf.copyright = fields.next().unwrap_or_default();
+                    nsf.ripper = fields.next().unwrap_or_default();
+                }
+                b"tlbl" => {
+                    names = chunk.split(|&b| b == 0).map(NSF::read_string).collect();
+                }
+                b"time" => times = NSF::read_durations(chunk),
+                b"fade" => fades = NSF::read_durations(chunk),
+                b"NEND" => break,
+                _ => {
+                    if id[0].is_ascii_uppercase() {
+                        return Err(format!(
+                            "NSFe chunk {} is not supported",
+                            String::from_utf8_lossy(id)
+                        ));
+                    }
+                }
+            }
+        }
+
+        if !has_info || !has_data {
+            return Err("NSFe is missing its INFO or DATA chunk".to_string());
+        }
+        nsf.tracks = (0..nsf.total_tracks as usize)
+            .map(|i| TrackInfo {
+                name: names.get(i).filter(|name| !name.is_empty()).cloned(),
+                duration: times.get(i).copied().flatten(),
+                fade: fades.get(i).copied().flatten(),
+            })
+            .collect();
+        Ok(nsf)
+    }
+
+    // Null terminated, usually ASCII but some rips use other encodings
+    fn read_string(bytes: &[u8]) -> String {
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+    }
+
+    fn speed_or_default(bytes: &[u8], default: u16) -> u16 {
+        match u16::from_le_bytes([bytes[0], bytes[1]]) {
+            0 => default,
+            speed => speed,
+        }
+    }
+
+    // Signed 32-bit milliseconds, negative means unknown
+    fn read_durations(chunk: &[u8]) -> Vec<Option<Duration>> {
+        chunk
+            .chunks_exact(4)
+            .map(|ms| {
+                let ms = i32::from_le_bytes([ms[0], ms[1], ms[2], ms[3]]);
+                if ms < 0 {
+                    None
+                } else {
+                    Some(Duration::from_millis(ms as u64))
+                }
+            })
+            .collect()
+    }
+
+    // All zeroes means the tune isn't bankswitched
+    fn set_bankswitch(&mut self, banks: &[u8]) {
+        if banks.iter().any(|&bank| bank != 0) {
+            let mut init = [0; 8];
+            init.copy_from_slice(&banks[..8]);
+            self.bankswitch = Some(init);
+        } else {
+            self.bankswitch = None;
+        }
+    }
+
+    pub fn track_name(&self, track: u8) -> String {
+        match self
+            .tracks
+            .get(track as usize)
+            .and_then(|info| info.name.clone())
+        {
+            Some(name) => name,
+            None => format!("Track {}", track + 1),
+        }
+    }
+
+    pub fn track_duration(&self, track: u8) -> Duration {
+        self.tracks
+            .get(track as usize)
+            .and_then(|info| info.duration)
+            .unwrap_or(DEFAULT_TRACK_LENGTH)
+    }
+
+    pub fn track_fade(&self, track: u8) -> Duration {
+        self.tracks
+            .get(track as usize)
+            .and_then(|info| info.fade)
+            .unwrap_or_default()
+    }
+
+    pub fn mapper(&self) -> Box<dyn Mapper> {
+        Box::new(NSFMapper::new(self))
+    }
+}
+
+// Calls INIT when a track starts and PLAY at the tune's rate, waiting at IDLE_ADDR in between
+pub struct NSFDriver {
+    init_addr: u16,
+    play_addr: u16,
+    load_addr: u16,
+    bankswitch: Option<[u8; 8]>,
+    fds: bool,
+    pal: bool,
+    play_period: f64, // CPU cycles
+    next_play: f64,
+}
+
+impl NSFDriver {
+    pub fn new(nsf: &NSF, region: Region) -> Self {
+        let speed = if region == Region::NTSC {
+            nsf.ntsc_speed
+        } else {
+            nsf.pal_speed
+        };
+        NSFDriver {
+            init_addr: nsf.init_addr,
+            play_addr: nsf.play_addr,
+            load_addr: nsf.load_addr,
+            bankswitch: nsf.bankswitch,
+            fds: nsf.expansion.contains(ExpansionChips::FDS),
+            pal: region != Region::NTSC,
+            play_period: speed as f64 * region.cpu_clock_hz() / 1_000_000.0,
+            next_play: 0.0,
+        }
+    }
+
+    // Resets memory and the APU, then calls INIT with the track in A and the region in X
+    pub fn start_track(&mut self, cpu: &mut CPU, track: u8) {
+        for addr in 0x0000..0x0800 {
+            cpu.mem_write(addr, 0);
+        }
+        // Tunes loaded below 0x8000 keep their data in the cartridge RAM
+        if self.load_addr >= 0x8000 {
+            for addr in 0x6000..0x8000 {
+                cpu.mem_write(addr, 0);
+            }
+        }
+        for addr in 0x4000..=0x4013 {
+            cpu.mem_write(addr, 0);
+        }
+        cpu.mem_write(0x4015, 0x00);
+        cpu.mem_write(0x4015, 0x0F);
+        cpu.mem_write(0x4017, 0x40);
+        if let Some(banks) = self.bankswitch {
+            for (i, bank) in banks.iter().enumerate() {
+                cpu.mem_write(0x5FF8 + i as u16, *bank);
+            }
+            // The FDS has RAM at 0x6000-0x7FFF which takes the last two banks
+            if self.fds {
+                cpu.mem_write(0x5FF6, banks[6]);
+                cpu.mem_write(0x5FF7, banks[7]);
+            }
+        }
+
+        cpu.a = track;
+        cpu.x = self.pal as u8;
+        cpu.y = 0;
+        cpu.sp = 0xFD;
+        cpu.flags = CpuFlags::from_bits_truncate(0b0010_0100);
+        cpu.call(self.init_addr, IDLE_ADDR);
+        self.next_play = cpu.bus.cycles as f64 + self.play_period;
+    }
+
+    // Run from the CPU callback, PLAY is only called once the last routine has returned
+    pub fn step(&mut self, cpu: &mut CPU) {
+        if cpu.pc == IDLE_ADDR && cpu.bus.cycles as f64 >= self.next_play {
+            self.next_play += self.play_period;
+            cpu.call(self.play_addr, IDLE_ADDR);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::apu::APU;
+    use crate::bus::Bus;
+    use crate::input::InputPorts;
+    use crate::ppu::PPU;
+    use crate::rom::Mirroring;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_le_bytes().to_vec();
+        chunk.extend_from_slice(id);
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    #[test]
+    fn test_nsf_header() {
+        let mut raw = vec![0; HEADER_SIZE];
+        raw[..5].copy_from_slice(&NSF_TAG);
+        raw[0x06] = 3;
+        raw[0x07] = 2;
+        raw[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x03, 0x80, 0x06, 0x80]);
+        raw[0x0E..0x13].copy_from_slice(b"Tune\0");
+        raw[0x72] = 1;
+        raw[0x7B] = 0b0000_0101;
+        raw.extend_from_slice(&[0xEA; 16]);
+
+        let nsf = NSF::new(&raw).unwrap();
+        assert_eq!(nsf.total_tracks, 3);
+        assert_eq!(nsf.starting_track, 1);
+        assert_eq!(nsf.init_addr, 0x8003);
+        assert_eq!(nsf.title, "Tune");
+        assert_eq!(nsf.ntsc_speed, DEFAULT_NTSC_SPEED);
+        assert_eq!(nsf.bankswitch, Some([0, 0, 1, 0, 0, 0, 0, 0]));
+        assert_eq!(nsf.expansion, ExpansionChips::VRC6 | ExpansionChips::FDS);
+        assert_eq!(nsf.data.len(), 16);
+        assert_eq!(nsf.track_name(2), "Track 3");
+    }
+
+    #[test]
+    fn test_nsfe_chunks() {
+        let mut raw = NSFE_TAG.to_vec();
+        raw.extend(chunk(
+            b"INFO",
+            &[0x00, 0x80, 0x00, 0x80, 0x03, 0x80, 0, 0, 2, 0],
+        ));
+        raw.extend(chunk(b"DATA", &[0x60, 0x60, 0x60, 0x60]));
+        raw.extend(chunk(b"auth", b"Title\0Artist\0Copyright\0Ripper\0"));
+        raw.extend(chunk(b"tlbl", b"Intro\0Boss\0"));
+        let mut times = 90_000i32.to_le_bytes().to_vec();
+        times.extend((-1i32).to_le_bytes());
+        raw.extend(chunk(b"time", &times));
+        raw.extend(chunk(b"xtra", &[1, 2, 3]));
+        raw.extend(chunk(b"NEND", &[]));
+
+        let nsf = NSF::new(&raw).unwrap();
+        assert_eq!(nsf.total_tracks, 2);
+        assert_eq!(nsf.play_addr, 0x8003);
+        assert_eq!(nsf.artist, "Artist");
+        assert_eq!(nsf.ripper, "Ripper");
+        assert_eq!(nsf.track_name(1), "Boss");
+        assert_eq!(nsf.track_duration(0), Duration::from_secs(90));
+        assert_eq!(nsf.track_duration(1), DEFAULT_TRACK_LENGTH);
+
+        // Unknown required chunks can't be skipped
+        let mut bad = NSFE_TAG.to_vec();
+        bad.extend(chunk(b"ZZZZ", &[]));
+        assert!(NSF::new(&bad).is_err());
+    }
+
+    #[test]
+    fn test_driver_calls_init_and_play() {
+        let mut nsf = NSF::empty();
+        nsf.play_addr = 0x8003;
+        // INIT: STA $00, RTS   PLAY: INC $01, RTS
+        nsf.data = vec![0x85, 0x00, 0x60, 0xE6, 0x01, 0x60];
+        nsf.total_tracks = 4;
+
+        let bus = Bus::with_mapper(
+            nsf.mapper(),
+            vec![0; 0x2000],
+            Mirroring::HORIZONTAL,
+            Region::NTSC,
+            |_: &PPU, _: &mut APU, _: &mut InputPorts| {},
+        );
+        let mut cpu = CPU::new(bus);
+        let mut driver = NSFDriver::new(&nsf, Region::NTSC);
+        driver.start_track(&mut cpu, 2);
+        let period = driver.play_period;
+        let end = cpu.bus.cycles as f64 + period * 10.5;
+        cpu.run_with_callback(|cpu| {
+            driver.step(cpu);
+            if cpu.bus.cycles as f64 >= end {
+                cpu.halted = true;
+            }
+        });
+        assert_eq!(cpu.mem_read(0x00), 2);
+        assert_eq!(cpu.mem_read(0x01), 10);
+        assert_eq!(cpu.pc, IDLE_ADDR);
+    }
+}
@@ -1,3 +1,5 @@
+use std::path::Path;
+
 #[rustfmt::skip]
 
 pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
@@ -15,3 +17,51 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
    (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
    (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
 ];
+
+// Reads a .pal file, 64 RGB triples(files with the emphasis variants after them are fine too)
+pub fn load(path: &Path) -> Result<[(u8, u8, u8); 64], String> {
+    let data = std::fs::read(path)
+        .map_err(|err| format!("could not read palette {}: {}", path.display(), err))?;
+    if data.len() < 64 * 3 {
+        return Err(format!(
+            "palette {} has {} bytes, expected at least 192",
+            path.display(),
+            data.len()
+        ));
+    }
+    let mut palette = [(0, 0, 0); 64];
+    for (colour, rgb) in palette.iter_mut().zip(data.chunks_exact(3)) {
+        *colour = (rgb[0], rgb[1], rgb[2]);
+    }
+    Ok(palette)
+}
+
+// Emphasis attenuation factor for the colour channels that are not emphasized (roughly 0.816 on NTSC)
+const EMPHASIS_ATTENUATION: (u16, u16) = (209, 256);
+
+// Greyscale forces the palette index into the grey column ($x0)
+pub fn greyscale(palette_idx: u8) -> u8 {
+    palette_idx & 0x30
+}
+
+// Applies PPUMASK emphasis bits (0bBGR) to a colour
+// Columns $xE/$xF are black and are unaffected
+pub fn apply_emphasis(palette_idx: u8, rgb: (u8, u8, u8), emphasis: u8) -> (u8, u8, u8) {
+    if emphasis == 0 || palette_idx & 0x0E == 0x0E {
+        return rgb;
+    }
+    // Channels without their emphasis bit are darkened, all three bits darken everything
+    let all = emphasis & 0b111 == 0b111;
+    let attenuate = |channel: u8, bit: u8| -> u8 {
+        if emphasis & bit != 0 && !all {
+            channel
+        } else {
+            (channel as u16 * EMPHASIS_ATTENUATION.0 / EMPHASIS_ATTENUATION.1) as u8
+        }
+    };
+    (
+        attenuate(rgb.0, 0b001),
+        attenuate(rgb.1, 0b010),
+        attenuate(rgb.2, 0b100),
+    )
+}
@@ -0,0 +1,250 @@
+// PNG writing for screenshots, 8 bit RGB only
+// The image data is deflated with the fixed Huffman codes and a simple LZ77 search, which is
+// plenty for the large flat areas NES frames have
+use std::path::{Path, PathBuf};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+const WINDOW: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: usize = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// Deflate's bits go in starting from the lowest bit of each byte
+struct BitWriter {
+    data: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.data.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes are stored starting from their highest bit
+    fn write_code(&mut self, code: u32, count: u32) {
+        self.write(code.reverse_bits() >> (32 - count), count);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.data.push(self.bits as u8);
+        }
+        self.data
+    }
+}
+
+fn write_literal(w: &mut BitWriter, symbol: u16) {
+    let symbol = symbol as u32;
+    match symbol {
+        0..=143 => w.write_code(0x30 + symbol, 8),
+        144..=255 => w.write_code(0x190 + symbol - 144, 9),
+        256..=279 => w.write_code(symbol - 256, 7),
+        _ => w.write_code(0xC0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(w: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE
+        .iter()
+        .rposition(|base| *base as usize <= length)
+        .unwrap();
+    write_literal(w, 257 + code as u16);
+    w.write(
+        (length - LENGTH_BASE[code] as usize) as u32,
+        LENGTH_EXTRA[code] as u32,
+    );
+    let code = DISTANCE_BASE
+        .iter()
+        .rposition(|base| *base as usize <= distance)
+        .unwrap();
+    w.write_code(code as u32, 5);
+    w.write(
+        (distance - DISTANCE_BASE[code] as usize) as u32,
+        DISTANCE_EXTRA[code] as u32,
+    );
+}
+
+fn hash(data: &[u8]) -> usize {
+    let n = (data[0] as usize) << 16 | (data[1] as usize) << 8 | data[2] as usize;
+    (n.wrapping_mul(0x9E37_79B1) >> 8) & ((1 << HASH_BITS) - 1)
+}
+
+// One final block with the fixed codes, only the last place each 3 bytes were seen is tried
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter {
+        data: Vec::new(),
+        bits: 0,
+        count: 0,
+    };
+    w.write(1, 1); // Final block
+    w.write(1, 2); // Fixed Huffman codes
+    let mut last_seen = vec![usize::MAX; 1 << HASH_BITS];
+    let mut i = 0;
+    while i < data.len() {
+        let mut length = 0;
+        if i + MIN_MATCH <= data.len() {
+            let h = hash(&data[i..]);
+            let candidate = last_seen[h];
+            last_seen[h] = i;
+            if candidate != usize::MAX && i - candidate <= WINDOW {
+                let max = MAX_MATCH.min(data.len() - i);
+                while length < max && data[candidate + length] == data[i + length] {
+                    length += 1;
+                }
+            }
+            if length >= MIN_MATCH {
+                write_match(&mut w, length, i - candidate);
+                // The matched bytes can be matched against later on too
+                for j in i + 1..(i + length).min(data.len() - MIN_MATCH + 1) {
+                    last_seen[hash(&data[j..])] = j;
+                }
+                i += length;
+                continue;
+            }
+        }
+        write_literal(&mut w, data[i] as u16);
+        i += 1;
+    }
+    write_literal(&mut w, 256); // End of block
+    w.finish()
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+pub fn crc32(data: &[&[u8]]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data.iter().flat_map(|bytes| bytes.iter()) {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    png.extend_from_slice(&crc32(&[kind, data]).to_be_bytes());
+}
+
+// rgb holds width * height pixels, 3 bytes each
+pub fn encode(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    // Every row starts with its filter type, 0 leaves the row as it is
+    let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+    for row in rgb.chunks_exact(width * 3).take(height) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    let mut zlib = vec![0x78, 0x01];
+    zlib.extend(deflate(&raw));
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bit RGB, no interlacing
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib);
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+// Every pixel becomes a scale x scale square
+pub fn scale(width: usize, height: usize, rgb: &[u8], scale: usize) -> Vec<u8> {
+    let mut scaled = Vec::with_capacity(rgb.len() * scale * scale);
+    for row in rgb.chunks_exact(width * 3).take(height) {
+        let mut line = Vec::with_capacity(row.len() * scale);
+        for pixel in row.chunks_exact(3) {
+            for _ in 0..scale {
+                line.extend_from_slice(pixel);
+            }
+        }
+        for _ in 0..scale {
+            scaled.extend_from_slice(&line);
+        }
+    }
+    scaled
+}
+
+// The first of name-001.png, name-002.png... in dir that isn't taken
+pub fn numbered_path(dir: &Path, name: &str) -> PathBuf {
+    (1..)
+        .map(|n| dir.join(format!("{}-{:03}.png", name, n)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(&[b"IEND"]), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn test_encode() {
+        // Two colours in stripes compress to a fraction of their size
+        let rgb: Vec<u8> = (0..64 * 64)
+            .flat_map(|i| {
+                if i % 64 < 32 {
+                    [255, 0, 0]
+                } else {
+                    [0, 0, 255]
+                }
+            })
+            .collect();
+        let png = encode(64, 64, &rgb);
+        assert_eq!(png[..8], SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(png[16..24], [0, 0, 0, 64, 0, 0, 0, 64]);
+        assert_eq!(
+            png[png.len() - 12..],
+            [0, 0, 0, 0, 73, 69, 78, 68, 174, 66, 96, 130]
+        );
+        assert!(png.len() < rgb.len() / 10);
+
+        let scaled = scale(2, 1, &[1, 2, 3, 4, 5, 6], 2);
+        assert_eq!(scaled, [1, 2, 3, 1, 2, 3, 4, 5, 6, 4, 5, 6].repeat(2));
+    }
+}
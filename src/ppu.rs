@@ -1,12 +1,21 @@
+use crate::mapper::ChrBanks;
+use crate::ppu_reg::openbus::OpenBus;
 use crate::ppu_reg::scrollreg::ScrollRegister;
 use crate::ppu_reg::statusreg::StatusRegister;
 use crate::ppu_reg::{addrreg::AddrRegister, controlreg::ControlRegister, maskreg::MaskRegister};
+use crate::region::Region;
 use crate::rom::Mirroring;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use log::debug;
+
+const DOTS_PER_SCANLINE: usize = 341;
 
 pub struct PPU {
     pub chr_rom: Vec<u8>,
+    pub chr_banks: ChrBanks, // Set by the bus from the mapper
     pub palette_table: [u8; 32],
     pub vram: [u8; 2048],
+    pub cart_vram: Vec<u8>, // Extra nametable RAM provided by four-screen cartridges
     pub oam_data: [u8; 256],
     pub oam_addr: u8,
     pub internal_data_buf: u8,
@@ -16,13 +25,17 @@ pub struct PPU {
     pub ctrl: ControlRegister,
     pub mask: MaskRegister,
     pub scroll: ScrollRegister,
+    pub open_bus: OpenBus,
 
     pub mirroring: Mirroring,
+    pub region: Region,
 
     pub scanline: u16, // Which scanline should be drawn
     pub cycles: usize, // Location of current cycle
+    pub frame: usize,  // Number of frames completed since power on
 
     pub nmi_interrupt: Option<u8>,
+    vblank_suppressed: bool, // PPUSTATUS was read right before vblank would have started
 }
 
 impl PPU {
@@ -33,8 +46,10 @@ impl PPU {
     pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
         PPU {
             chr_rom: chr_rom,
+            chr_banks: ChrBanks::FIXED,
             palette_table: [0; 32],
             vram: [0; 2048],
+            cart_vram: PPU::cart_vram_for(mirroring),
             oam_data: [0; 256],
             oam_addr: 0,
             internal_data_buf: 0, // Emulating internal data buffer
@@ -43,11 +58,55 @@ impl PPU {
             ctrl: ControlRegister::new(),
             mask: MaskRegister::new(),
             scroll: ScrollRegister::new(),
+            open_bus: OpenBus::new(),
             mirroring: mirroring,
+            region: Region::NTSC,
             scanline: 0,
             cycles: 21, // PPU starts with 3 times the cycles of CPU(which is 7)
+            frame: 0,
             nmi_interrupt: None,
+            vblank_suppressed: false,
+        }
+    }
+
+    // Four-screen boards supply the other two nametables themselves
+    fn cart_vram_for(mirroring: Mirroring) -> Vec<u8> {
+        match mirroring {
+            Mirroring::FOURSCREEN => vec![0; 2048],
+            _ => Vec::new(),
+        }
+    }
+
+    // Used by mappers that switch mirroring at runtime
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        if mirroring == Mirroring::FOURSCREEN && self.cart_vram.is_empty() {
+            self.cart_vram = PPU::cart_vram_for(mirroring);
+        }
+        self.mirroring = mirroring;
+    }
+
+    // Pattern table byte through the mapper's CHR banks, pages past the end of CHR wrap around
+    pub fn read_chr(&self, addr: u16, sprite: bool) -> u8 {
+        let pages = self.chr_rom.len() / 0x400;
+        if pages == 0 {
+            return self.chr_rom.get(addr as usize).copied().unwrap_or(0);
         }
+        let banks = if sprite {
+            &self.chr_banks.sprites
+        } else {
+            &self.chr_banks.background
+        };
+        let page = banks[(addr as usize >> 10) & 7] % pages;
+        self.chr_rom[page * 0x400 + (addr as usize & 0x3FF)]
+    }
+
+    // The 16 bytes of the tile at addr, a tile never crosses a 1 KB page
+    pub fn chr_tile(&self, addr: u16, sprite: bool) -> [u8; 16] {
+        let mut tile = [0; 16];
+        for (i, byte) in tile.iter_mut().enumerate() {
+            *byte = self.read_chr(addr + i as u16, sprite);
+        }
+        tile
     }
 
     fn is_sprite_zero_hit(&self, cycle: usize) -> bool {
@@ -57,43 +116,91 @@ impl PPU {
         (y == self.scanline as usize) && x <= cycle && self.mask.show_sprites()
     }
 
+    // Advances the PPU one dot at a time, returns true once a frame has been completed
     pub fn tick(&mut self, cycles: u8) -> bool {
-        self.cycles += cycles as usize;
-        if self.cycles >= 341 {
+        let mut new_frame = false;
+        for _ in 0..cycles {
+            new_frame |= self.step();
+        }
+        new_frame
+    }
+
+    fn step(&mut self) -> bool {
+        let mut new_frame = false;
+        self.cycles += 1;
+
+        let vblank_scanline = self.region.vblank_scanline();
+        let pre_render_scanline = self.region.pre_render_scanline();
+
+        // Odd frames skip the last dot of the pre-render line when rendering is enabled(NTSC only)
+        if self.scanline == pre_render_scanline
+            && self.cycles == DOTS_PER_SCANLINE - 1
+            && self.frame % 2 == 1
+            && self.mask.is_rendering()
+            && self.region.skips_odd_frame_dot()
+        {
+            self.cycles = DOTS_PER_SCANLINE;
+        }
+
+        if self.cycles >= DOTS_PER_SCANLINE {
             if self.is_sprite_zero_hit(self.cycles) {
                 self.status.set_sprite_zero_hit(true);
             }
 
-            self.cycles = self.cycles - 341;
+            self.cycles -= DOTS_PER_SCANLINE;
             self.scanline += 1;
 
-            if self.scanline == 241 {
+            if self.scanline > pre_render_scanline {
+                self.scanline = 0;
+                self.frame += 1;
+                new_frame = true;
+            }
+        }
+
+        if self.cycles == 1 && self.scanline == vblank_scanline {
+            // A PPUSTATUS read on the dot before suppresses both the flag and the NMI for this frame
+            if !self.vblank_suppressed {
                 self.status.set_vblank_status(true);
-                self.status.set_sprite_zero_hit(false);
                 if self.ctrl.generate_vblank_nmi() {
-                    self.nmi_interrupt = Some(1);
+                    self.nmi_interrupt = Some(0);
                 }
             }
+            self.vblank_suppressed = false;
+        } else if self.cycles == 1 && self.scanline == pre_render_scanline {
+            self.nmi_interrupt = None;
+            self.status.set_sprite_zero_hit(false);
+            self.status.set_sprite_overflow(false);
+            self.status.reset_vblank_status();
+        }
+        new_frame
+    }
 
-            if self.scanline >= 262 {
-                self.scanline = 0;
-                self.nmi_interrupt = None;
-                self.status.set_sprite_zero_hit(false);
-                self.status.reset_vblank_status();
-                return true;
+    // Polled by the CPU before every instruction
+    // The value held in nmi_interrupt is the number of instructions left before the NMI is taken
+    pub fn poll_nmi(&mut self) -> Option<u8> {
+        match self.nmi_interrupt {
+            Some(0) => self.nmi_interrupt.take(),
+            Some(delay) => {
+                self.nmi_interrupt = Some(delay - 1);
+                None
             }
+            None => None,
         }
-        return false;
     }
 
     // 0x2000 write, PPUCTRL(Flags)
     // If PPU is in VBlank state and Generate NMI bit in control register is updated from 0 to 1
+    // the NMI fires after the next instruction
     pub fn write_to_ctrl(&mut self, value: u8) {
         let before_nmi_status = self.ctrl.generate_vblank_nmi();
         self.ctrl.update(value);
         if !before_nmi_status && self.ctrl.generate_vblank_nmi() && self.status.is_in_vblank() {
             self.nmi_interrupt = Some(1);
         }
+        // Disabling NMI generation drops an NMI that has not been taken yet
+        if !self.ctrl.generate_vblank_nmi() {
+            self.nmi_interrupt = None;
+        }
     }
 
     // 0x2001 write, PPUMASK
@@ -101,10 +208,36 @@ impl PPU {
         self.mask.update(val);
     }
 
+    // Every write to 0x2000-0x2007 fills the I/O latch
+    pub fn write_open_bus(&mut self, val: u8) {
+        self.open_bus.write(val, self.frame);
+    }
+
+    // Reads of write-only registers return the I/O latch
+    pub fn read_open_bus(&mut self) -> u8 {
+        self.open_bus.read(self.frame)
+    }
+
     // 0x2002 read, PPUSTATUS
     pub fn read_status(&mut self) -> u8 {
         // Flags are read, Vblank and w register should be cleared after read
-        let ret = self.status.get_status();
+        // Only the upper 3 bits are driven, the rest come from the I/O latch
+        let mut ret = (self.status.get_status() & 0xE0) | (self.read_open_bus() & 0x1F);
+        if self.scanline == self.region.vblank_scanline() {
+            match self.cycles {
+                // Read one dot before the flag is set, the flag and NMI never happen this frame
+                0 => self.vblank_suppressed = true,
+                // Read on the same dot the flag is set, it reads as clear and the NMI is suppressed
+                1 => {
+                    ret &= !0x80;
+                    self.nmi_interrupt = None;
+                }
+                // Read shortly after, the flag is seen but the NMI is still suppressed
+                2 | 3 => self.nmi_interrupt = None,
+                _ => {}
+            }
+        }
+        self.open_bus.refresh(ret, 0xE0, self.frame);
         self.status.clear_vblank();
         self.addr.reset_latch();
         self.scroll.reset_latch();
@@ -117,8 +250,10 @@ impl PPU {
     }
 
     // 0x2004 read, OAMDATA
-    pub fn read_oam_data(&self) -> u8 {
-        self.oam_data[self.oam_addr as usize]
+    pub fn read_oam_data(&mut self) -> u8 {
+        let ret = self.oam_data[self.oam_addr as usize];
+        self.open_bus.write(ret, self.frame);
+        ret
     }
 
     pub fn write_to_oam_data(&mut self, val: u8) {
@@ -133,34 +268,28 @@ impl PPU {
 
     // 0x2006 write, PPUADDR
     pub fn write_to_ppu_addr(&mut self, val: u8) {
-        println!("Wrote {:x} to ppu address!", val);
+        debug!("Wrote {:x} to ppu address!", val);
         self.addr.update(val);
     }
 
     // 0x2007 read/write, PPUDATA(VRAM read/write data register)
     // Writes to data
     pub fn write_to_data(&mut self, val: u8) {
-        println!("writing data");
         let addr = self.addr.get();
-        println!("Writing to address {:x} with value {:x}", addr, val);
+        debug!("Writing to address {:x} with value {:x}", addr, val);
         match addr {
-            0..=0x1fff => println!(
+            0..=0x1fff => debug!(
                 "Attempt to write to character rom space, writing to {:4X}!",
                 addr
             ),
-            0x2000..=0x2FFF => {
-                // Name tables
-                self.vram[self.mirror_vram_addr(addr) as usize] = val;
-            }
-            0x3000..=0x3EFF => unimplemented!("Unused ppu memory attempted to write {}", addr),
-            // Scales down to palette RAM
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize] = val;
+            0x2000..=0x3EFF => {
+                // Name tables, 0x3000-0x3eff mirrors 0x2000-0x2eff
+                let index = self.mirror_vram_addr(addr) as usize;
+                self.write_nametable_ram(index, val);
             }
             0x3F00..=0x3FFF => {
-                println!("Writing to palette table {:x}", addr);
-                self.palette_table[(addr - 0x3f00) as usize] = val
+                let index = PPU::palette_index(addr);
+                self.palette_table[index] = val
             }
             _ => panic!("Unknown write access to mirrored space {}", addr),
         }
@@ -169,12 +298,10 @@ impl PPU {
     }
 
     fn increment_vram_addr(&mut self) {
-        println!("incremented addr!");
         self.addr.increment(self.ctrl.vram_addr_increment());
     }
 
     pub fn read_data(&mut self) -> u8 {
-        println!("reading data");
         let addr = self.addr.get();
         self.increment_vram_addr();
 
@@ -182,40 +309,87 @@ impl PPU {
             0..=0x1fff => {
                 // Pattern tables 0 and 1
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[addr as usize];
+                self.internal_data_buf = self.read_chr(addr, false);
+                self.open_bus.write(result, self.frame);
                 result
             }
-            0x2000..=0x2fff => {
-                // Nametables 0-3
+            0x2000..=0x3eff => {
+                // Nametables 0-3, 0x3000-0x3eff mirrors 0x2000-0x2eff
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                self.internal_data_buf =
+                    self.read_nametable_ram(self.mirror_vram_addr(addr) as usize);
+                self.open_bus.write(result, self.frame);
                 result
             }
-            0x3000..=0x3eff => panic!(
-                // Unused
-                "0x3000 to 0x3eff is not expected to be used, the requested address is {}",
-                addr
-            ), //Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize]
+            0x3f00..=0x3fff => {
+                // Palette reads are not buffered, but the buffer is still filled with the nametable byte "underneath"
+                self.internal_data_buf =
+                    self.read_nametable_ram(self.mirror_vram_addr(addr - 0x1000) as usize);
+                let mut color = self.palette_table[PPU::palette_index(addr)];
+                if self.mask.is_greyscale() {
+                    color &= 0x30;
+                }
+                // Only the lower 6 bits are driven, upper 2 bits are open bus
+                let result = (self.read_open_bus() & 0xC0) | (color & 0x3F);
+                self.open_bus.refresh(result, 0x3F, self.frame);
+                result
             }
-            0x3f00..=0x3fff => self.palette_table[(addr - 0x3f00) as usize], // Palette RAM, 0x3F20-0x3FFF are mirrors of 0x3F00-0x3F1F
             _ => panic!("unexpected access to mirrored space {}", addr),
         }
     }
 
+    // Palette RAM, 0x3F20-0x3FFF are mirrors of 0x3F00-0x3F1F
+    // Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
+    fn palette_index(addr: u16) -> usize {
+        let index = (addr & 0x1F) as usize;
+        match index {
+            0x10 | 0x14 | 0x18 | 0x1C => index - 0x10,
+            _ => index,
+        }
+    }
+
     // Mirrors vram
-    fn mirror_vram_addr(&mut self, addr: u16) -> u16 {
+    // Returns an index into nametable RAM, 0x000..0x7ff is the console VRAM and 0x800..0xfff is cartridge VRAM
+    fn mirror_vram_addr(&self, addr: u16) -> u16 {
         let mirrored_vram = addr & !0x1000; // Bring down 0x3000.. 0x3eff to 0x2000.. 0x2eff by subtracting 0x1000(If it's 0x2000 or 0x2fff, nothing changes)
         let vram_index = mirrored_vram - 0x2000; // Bring down to 0x0.. 0x0eff(to vram vector)
         let name_table = vram_index / 0x400; // Determines what nametable to access
-        match (&self.mirroring, name_table) {
-            (Mirroring::VERTICAL, 2) | (Mirroring::VERTICAL, 3) => vram_index - 0x800,
-            (Mirroring::HORIZONTAL, 2) => vram_index - 0x400,
-            (Mirroring::HORIZONTAL, 1) => vram_index - 0x400,
-            (Mirroring::HORIZONTAL, 3) => vram_index - 0x800,
-            _ => vram_index,
+        let offset = vram_index % 0x400;
+        let physical_table = match (&self.mirroring, name_table) {
+            (Mirroring::VERTICAL, 0) | (Mirroring::VERTICAL, 2) => 0,
+            (Mirroring::VERTICAL, _) => 1,
+            (Mirroring::HORIZONTAL, 0) | (Mirroring::HORIZONTAL, 1) => 0,
+            (Mirroring::HORIZONTAL, _) => 1,
+            (Mirroring::SINGLESCREEN_A, _) => 0,
+            (Mirroring::SINGLESCREEN_B, _) => 1,
+            (Mirroring::FOURSCREEN, _) => name_table,
+        };
+        physical_table * 0x400 + offset
+    }
+
+    fn read_nametable_ram(&self, index: usize) -> u8 {
+        if index < 0x800 {
+            self.vram[index]
+        } else {
+            self.cart_vram[index - 0x800]
+        }
+    }
+
+    fn write_nametable_ram(&mut self, index: usize, val: u8) {
+        if index < 0x800 {
+            self.vram[index] = val;
+        } else {
+            self.cart_vram[index - 0x800] = val;
+        }
+    }
+
+    // Returns the 1 KB of RAM backing logical nametable 0-3 after mirroring
+    pub fn name_table(&self, table: u16) -> &[u8] {
+        let start = self.mirror_vram_addr(0x2000 + (table & 0b11) * 0x400) as usize;
+        if start < 0x800 {
+            &self.vram[start..start + 0x400]
+        } else {
+            &self.cart_vram[start - 0x800..start - 0x800 + 0x400]
         }
     }
 
@@ -227,6 +401,54 @@ impl PPU {
     }
 }
 
+// The CHR banks come from the mapper and the region is checked by the state's header
+impl Savestate for PPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.palette_table);
+        w.bytes(&self.vram);
+        w.bytes(&self.cart_vram);
+        w.bytes(&self.oam_data);
+        w.u8(self.oam_addr);
+        w.u8(self.internal_data_buf);
+        w.component(&self.addr);
+        w.u8(self.status.bits());
+        w.u8(self.ctrl.bits());
+        w.u8(self.mask.bits());
+        w.component(&self.scroll);
+        w.component(&self.open_bus);
+        w.u8(self.mirroring as u8);
+        w.u16(self.scanline);
+        w.usize(self.cycles);
+        w.usize(self.frame);
+        w.bool(self.nmi_interrupt.is_some());
+        w.u8(self.nmi_interrupt.unwrap_or(0));
+        w.bool(self.vblank_suppressed);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes_into(&mut self.palette_table);
+        r.bytes_into(&mut self.vram);
+        self.cart_vram = r.bytes().to_vec();
+        r.bytes_into(&mut self.oam_data);
+        self.oam_addr = r.u8();
+        self.internal_data_buf = r.u8();
+        r.component(&mut self.addr);
+        self.status = StatusRegister::from_bits_truncate(r.u8());
+        self.ctrl = ControlRegister::from_bits_truncate(r.u8());
+        self.mask = MaskRegister::from_bits_truncate(r.u8());
+        r.component(&mut self.scroll);
+        r.component(&mut self.open_bus);
+        self.mirroring = Mirroring::from_index(r.u8());
+        self.scanline = r.u16();
+        self.cycles = r.usize();
+        self.frame = r.usize();
+        let nmi = r.bool();
+        let delay = r.u8();
+        self.nmi_interrupt = nmi.then_some(delay);
+        self.vblank_suppressed = r.bool();
+    }
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
@@ -346,6 +568,48 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x77); //read from B
     }
 
+    // Four-screen: every nametable is backed by its own RAM
+    //   [0x2000 A ] [0x2400 B ]
+    //   [0x2800 C ] [0x2C00 D ]
+    #[test]
+    fn test_vram_four_screen() {
+        let mut ppu = PPU::new(vec![0; 2048], Mirroring::FOURSCREEN);
+
+        ppu.write_to_ppu_addr(0x2C);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66); //write to D
+
+        ppu.write_to_ppu_addr(0x24);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.read_data(), 0); //B is untouched
+
+        ppu.write_to_ppu_addr(0x2C);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.read_data(), 0x66);
+        assert_eq!(ppu.cart_vram[0x0405], 0x66);
+        assert_eq!(ppu.name_table(3)[0x05], 0x66);
+    }
+
+    #[test]
+    fn test_vram_single_screen_switch() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.set_mirroring(Mirroring::SINGLESCREEN_B);
+
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+        assert_eq!(ppu.vram[0x0405], 0x66);
+
+        ppu.set_mirroring(Mirroring::SINGLESCREEN_A);
+        ppu.write_to_ppu_addr(0x2C);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x77);
+        assert_eq!(ppu.vram[0x0005], 0x77);
+        assert_eq!(ppu.name_table(1)[0x05], 0x77);
+    }
+
     #[test]
     fn test_read_status_resets_latch() {
         let mut ppu = PPU::new_empty_rom();
@@ -381,6 +645,63 @@ pub mod test {
         // assert_eq!(ppu.addr.read(), 0x0306)
     }
 
+    #[test]
+    fn test_ppu_3000_mirror() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x33);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+        assert_eq!(ppu.vram[0x0305], 0x66);
+
+        ppu.write_to_ppu_addr(0x33);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.read_data(), 0x66);
+    }
+
+    #[test]
+    fn test_palette_mirrors() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x30); // Mirror of 0x3f10, which mirrors 0x3f00
+        ppu.write_to_data(0x2c);
+        assert_eq!(ppu.palette_table[0], 0x2c);
+
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0xe0);
+        assert_eq!(ppu.read_data(), 0x2c); // Palette reads are not buffered
+    }
+
+    #[test]
+    fn test_palette_read_fills_buffer() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.vram[0x0705] = 0x66; // 0x2f05 with horizontal mirroring
+
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data();
+        assert_eq!(ppu.internal_data_buf, 0x66);
+
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x00);
+        assert_eq!(ppu.read_data(), 0x66);
+    }
+
+    #[test]
+    fn test_open_bus_decay() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_open_bus(0x5a);
+        assert_eq!(ppu.read_open_bus(), 0x5a);
+
+        // Status only drives the upper 3 bits
+        ppu.status.set_vblank_status(true);
+        assert_eq!(ppu.read_status(), 0x9a);
+        assert_eq!(ppu.read_open_bus(), 0x9a);
+
+        ppu.frame += 60;
+        assert_eq!(ppu.read_open_bus(), 0);
+    }
+
     #[test]
     fn test_read_status_resets_vblank() {
         let mut ppu = PPU::new_empty_rom();
@@ -392,6 +713,100 @@ pub mod test {
         assert_eq!(ppu.status.snapshot() >> 7, 0);
     }
 
+    // Ticks the PPU to the given scanline and dot
+    fn tick_to(ppu: &mut PPU, scanline: u16, dot: usize) {
+        while ppu.scanline != scanline || ppu.cycles != dot {
+            ppu.tick(1);
+        }
+    }
+
+    #[test]
+    fn test_vblank_set_and_clear_dots() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_ctrl(0b1000_0000);
+        tick_to(&mut ppu, 241, 0);
+        assert!(!ppu.status.is_in_vblank());
+        ppu.tick(1);
+        assert!(ppu.status.is_in_vblank());
+        assert_eq!(ppu.poll_nmi(), Some(0));
+
+        tick_to(&mut ppu, 261, 0);
+        assert!(ppu.status.is_in_vblank());
+        ppu.tick(1);
+        assert!(!ppu.status.is_in_vblank());
+    }
+
+    #[test]
+    fn test_status_read_suppresses_vblank() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_ctrl(0b1000_0000);
+        tick_to(&mut ppu, 241, 0);
+        assert_eq!(ppu.read_status() >> 7, 0);
+        ppu.tick(1);
+        assert!(!ppu.status.is_in_vblank());
+        assert_eq!(ppu.poll_nmi(), None);
+
+        // Reading on the dot the flag is set also hides it
+        tick_to(&mut ppu, 0, 0);
+        tick_to(&mut ppu, 241, 1);
+        assert_eq!(ppu.read_status() >> 7, 0);
+        assert_eq!(ppu.poll_nmi(), None);
+
+        // Reading two dots late sees the flag but still cancels the NMI
+        tick_to(&mut ppu, 0, 0);
+        tick_to(&mut ppu, 241, 2);
+        assert_eq!(ppu.read_status() >> 7, 1);
+        assert_eq!(ppu.poll_nmi(), None);
+    }
+
+    #[test]
+    fn test_nmi_enabled_during_vblank_is_delayed() {
+        let mut ppu = PPU::new_empty_rom();
+        tick_to(&mut ppu, 245, 0);
+        ppu.write_to_ctrl(0b1000_0000);
+        assert_eq!(ppu.poll_nmi(), None);
+        assert!(ppu.poll_nmi().is_some());
+    }
+
+    #[test]
+    fn test_odd_frame_skips_dot() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_mask(0b0000_1000);
+        tick_to(&mut ppu, 0, 0); // End of frame 0
+        let mut dots = 0;
+        while !ppu.tick(1) {
+            dots += 1;
+        }
+        assert_eq!(dots + 1, 341 * 262 - 1); // Frame 1 is odd
+
+        dots = 0;
+        while !ppu.tick(1) {
+            dots += 1;
+        }
+        assert_eq!(dots + 1, 341 * 262);
+    }
+
+    #[test]
+    fn test_pal_frame_timing() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.region = Region::PAL;
+        ppu.write_to_mask(0b0000_1000);
+        tick_to(&mut ppu, 0, 0);
+        for _ in 0..2 {
+            let mut dots = 1;
+            while !ppu.tick(1) {
+                dots += 1;
+            }
+            assert_eq!(dots, 341 * 312); // No odd frame skip on PAL
+        }
+
+        tick_to(&mut ppu, 241, 0);
+        ppu.tick(1);
+        assert!(ppu.status.is_in_vblank());
+        tick_to(&mut ppu, 311, 1);
+        assert!(!ppu.status.is_in_vblank());
+    }
+
     #[test]
     fn test_oam_read_write() {
         let mut ppu = PPU::new_empty_rom();
@@ -1,5 +1,6 @@
 pub mod addrreg;
 pub mod controlreg; // 2000 PPUCTRL
 pub mod maskreg; // 2001 PPUMASK
+pub mod openbus; // I/O latch shared by 0x2000-0x2007
 pub mod scrollreg; // 2005 PPUSCROLL
 pub mod statusreg; // 2002 PPUSTATUS // 2007 PPUADDR
@@ -1,3 +1,5 @@
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
 pub struct AddrRegister {
     value: (u8, u8),
     hi_ptr: bool,
@@ -49,3 +51,15 @@ impl AddrRegister {
         }
     }
 }
+
+impl Savestate for AddrRegister {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.get());
+        w.bool(self.hi_ptr);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.set(r.u16());
+        self.hi_ptr = r.bool();
+    }
+}
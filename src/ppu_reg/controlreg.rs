@@ -74,6 +74,10 @@ impl ControlRegister {
         }
     }
 
+    pub fn tall_sprites(&self) -> bool {
+        self.contains(ControlRegister::SPRITE_SIZE)
+    }
+
     pub fn generate_vblank_nmi(&self) -> bool {
         self.contains(ControlRegister::GENERATE_NMI)
     }
@@ -37,4 +37,30 @@ impl MaskRegister {
     pub fn show_sprites(&self) -> bool {
         self.contains(MaskRegister::SPRITE)
     }
+
+    pub fn show_background(&self) -> bool {
+        self.contains(MaskRegister::BACKGROUND)
+    }
+
+    pub fn show_left_background(&self) -> bool {
+        self.contains(MaskRegister::LEFTBG)
+    }
+
+    pub fn show_left_sprites(&self) -> bool {
+        self.contains(MaskRegister::LEFTSPRITE)
+    }
+
+    pub fn is_greyscale(&self) -> bool {
+        self.contains(MaskRegister::GREY)
+    }
+
+    // Either background or sprites are being drawn
+    pub fn is_rendering(&self) -> bool {
+        self.show_background() || self.show_sprites()
+    }
+
+    // Upper three bits (BGR) of the register, 0bBGR
+    pub fn emphasis(&self) -> u8 {
+        self.bits() >> 5
+    }
 }
@@ -0,0 +1,65 @@
+// The PPU I/O latch(open bus)
+// Any write to 0x2000-0x2007 fills the latch, reads of write-only registers return it
+// Each bit decays back to 0 if it is not refreshed for roughly 600ms
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const DECAY_FRAMES: usize = 36;
+
+pub struct OpenBus {
+    value: u8,
+    refreshed: [usize; 8], // Frame each bit was last refreshed at
+}
+
+impl OpenBus {
+    pub fn new() -> Self {
+        OpenBus {
+            value: 0,
+            refreshed: [0; 8],
+        }
+    }
+
+    // Refreshes the bits set in mask with the bits from data
+    pub fn refresh(&mut self, data: u8, mask: u8, frame: usize) {
+        self.value = (self.value & !mask) | (data & mask);
+        for bit in 0..8 {
+            if mask & (1 << bit) != 0 {
+                self.refreshed[bit] = frame;
+            }
+        }
+    }
+
+    pub fn write(&mut self, data: u8, frame: usize) {
+        self.refresh(data, 0xFF, frame);
+    }
+
+    pub fn read(&mut self, frame: usize) -> u8 {
+        for bit in 0..8 {
+            if frame.saturating_sub(self.refreshed[bit]) >= DECAY_FRAMES {
+                self.value &= !(1 << bit);
+            }
+        }
+        self.value
+    }
+}
+
+impl Default for OpenBus {
+    fn default() -> Self {
+        OpenBus::new()
+    }
+}
+
+impl Savestate for OpenBus {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.value);
+        for frame in self.refreshed {
+            w.usize(frame);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.value = r.u8();
+        for frame in self.refreshed.iter_mut() {
+            *frame = r.usize();
+        }
+    }
+}
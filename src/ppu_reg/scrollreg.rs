@@ -1,3 +1,5 @@
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
 pub struct ScrollRegister {
     pub scroll_x: u8,
     pub scroll_y: u8,
@@ -26,3 +28,17 @@ impl ScrollRegister {
         self.latch = false;
     }
 }
+
+impl Savestate for ScrollRegister {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.scroll_x);
+        w.u8(self.scroll_y);
+        w.bool(self.latch);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.scroll_x = r.u8();
+        self.scroll_y = r.u8();
+        self.latch = r.bool();
+    }
+}
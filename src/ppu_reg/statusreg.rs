@@ -44,6 +44,10 @@ impl StatusRegister {
         self.set(StatusRegister::SPRITE_ZERO_HIT, status);
     }
 
+    pub fn set_sprite_overflow(&mut self, status: bool) {
+        self.set(StatusRegister::OVERFLOW, status);
+    }
+
     pub fn is_in_vblank(&mut self) -> bool {
         self.contains(StatusRegister::VBLANK)
     }
@@ -0,0 +1,101 @@
+// Console timing region
+// NTSC: 2C02 PPU, 3 PPU dots per CPU cycle, 262 scanlines
+// PAL: 2C07 PPU, 3.2 PPU dots per CPU cycle, 312 scanlines with a long vblank
+// Dendy: PAL-like famiclone, 3 PPU dots per CPU cycle, 312 scanlines but vblank starts late like NTSC
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Region {
+    NTSC,
+    PAL,
+    DENDY,
+}
+
+impl Region {
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name.to_lowercase().as_str() {
+            "ntsc" => Some(Region::NTSC),
+            "pal" => Some(Region::PAL),
+            "dendy" => Some(Region::DENDY),
+            _ => None,
+        }
+    }
+
+    // CPU clock in Hz
+    pub fn cpu_clock_hz(&self) -> f64 {
+        match self {
+            Region::NTSC => 1_789_773.0,
+            Region::PAL => 1_662_607.0,
+            Region::DENDY => 1_773_448.0,
+        }
+    }
+
+    // PPU dots for every 5 CPU cycles, PAL runs at 3.2 dots per cycle
+    pub fn ppu_dots_per_5_cycles(&self) -> u16 {
+        match self {
+            Region::NTSC | Region::DENDY => 15,
+            Region::PAL => 16,
+        }
+    }
+
+    pub fn scanlines_per_frame(&self) -> u16 {
+        match self {
+            Region::NTSC => 262,
+            Region::PAL | Region::DENDY => 312,
+        }
+    }
+
+    // Scanline where the vblank flag is raised
+    pub fn vblank_scanline(&self) -> u16 {
+        match self {
+            Region::NTSC | Region::PAL => 241,
+            Region::DENDY => 291,
+        }
+    }
+
+    // Last scanline of the frame, vblank is cleared here
+    pub fn pre_render_scanline(&self) -> u16 {
+        self.scanlines_per_frame() - 1
+    }
+
+    // Only the NTSC PPU skips a dot on odd frames
+    pub fn skips_odd_frame_dot(&self) -> bool {
+        *self == Region::NTSC
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        let dots_per_frame = 341.0 * self.scanlines_per_frame() as f64;
+        let ppu_clock = self.cpu_clock_hz() * self.ppu_dots_per_5_cycles() as f64 / 5.0;
+        match self {
+            // Odd frames are one dot shorter when rendering
+            Region::NTSC => ppu_clock / (dots_per_frame - 0.5),
+            _ => ppu_clock / dots_per_frame,
+        }
+    }
+
+    // frame_rate() as a fraction of whole numbers, for video files
+    pub fn frame_rate_fraction(&self) -> (u32, u32) {
+        let dots_per_frame = 341 * self.scanlines_per_frame() as u64;
+        let ppu_clock_x5 = self.cpu_clock_hz() as u64 * self.ppu_dots_per_5_cycles() as u64;
+        let (mut rate, mut scale) = match self {
+            // Doubled so NTSC's half dot is a whole one
+            Region::NTSC => (ppu_clock_x5 * 2, (dots_per_frame * 2 - 1) * 5),
+            _ => (ppu_clock_x5, dots_per_frame * 5),
+        };
+        let (mut a, mut b) = (rate, scale);
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        rate /= a;
+        scale /= a;
+        (rate as u32, scale as u32)
+    }
+
+    // The 2C07 swaps the red and green emphasis bits(0bBGR)
+    pub fn emphasis(&self, emphasis: u8) -> u8 {
+        match self {
+            Region::NTSC => emphasis,
+            Region::PAL | Region::DENDY => {
+                (emphasis & 0b100) | ((emphasis & 0b001) << 1) | ((emphasis & 0b010) >> 1)
+            }
+        }
+    }
+}
@@ -3,7 +3,6 @@ use core::panic;
 use crate::frame::Frame;
 use crate::palette;
 use crate::ppu::PPU;
-use crate::rom::Mirroring;
 
 // This file is responsible for rendering the background and sprites
 
@@ -36,8 +35,7 @@ fn render_name_table(
         let tile_column = i % 32;
         let tile_row = i / 32;
         let tile_idx = name_table[i] as u16;
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        let tile = ppu.chr_tile(bank + tile_idx * 16, false);
         let palette = bg_palette(ppu, attribute_table, tile_column, tile_row);
 
         for y in 0..=7 {
@@ -48,13 +46,6 @@ fn render_name_table(
                 let value = (1 & lower) << 1 | (1 & upper);
                 upper = upper >> 1;
                 lower = lower >> 1;
-                let rgb = match value {
-                    0 => palette::SYSTEM_PALLETE[ppu.palette_table[0] as usize],
-                    1 => palette::SYSTEM_PALLETE[palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[palette[3] as usize],
-                    _ => panic!("can't be"),
-                };
                 let pixel_x = tile_column * 8 + x;
                 let pixel_y = tile_row * 8 + y;
 
@@ -62,18 +53,38 @@ fn render_name_table(
                     && pixel_x < view_port.x2
                     && pixel_y >= view_port.y1
                     && pixel_y < view_port.y2
-                {                    
-                    frame.set_pixel(
-                        (shift_x + pixel_x as isize) as usize,
-                        (shift_y + pixel_y as isize) as usize,
-                        rgb,
-                    );
+                {
+                    let screen_x = (shift_x + pixel_x as isize) as usize;
+                    // Leftmost 8 pixels show the backdrop when background clipping is on
+                    let rgb = if screen_x < 8 && !ppu.mask.show_left_background() {
+                        color(ppu, &frame.palette, ppu.palette_table[0])
+                    } else {
+                        match value {
+                            0 => color(ppu, &frame.palette, ppu.palette_table[0]),
+                            1 => color(ppu, &frame.palette, palette[1]),
+                            2 => color(ppu, &frame.palette, palette[2]),
+                            3 => color(ppu, &frame.palette, palette[3]),
+                            _ => panic!("can't be"),
+                        }
+                    };
+                    frame.set_pixel(screen_x, (shift_y + pixel_y as isize) as usize, rgb);
                 }
             }
         }
     }
 }
 
+// Converts a palette RAM entry into the displayed colour, applying PPUMASK greyscale and emphasis
+fn color(ppu: &PPU, colours: &[(u8, u8, u8); 64], palette_idx: u8) -> (u8, u8, u8) {
+    let idx = if ppu.mask.is_greyscale() {
+        palette::greyscale(palette_idx)
+    } else {
+        palette_idx & 0x3F
+    };
+    let emphasis = ppu.region.emphasis(ppu.mask.emphasis());
+    palette::apply_emphasis(idx, colours[idx as usize], emphasis)
+}
+
 // Renders the palette for a background tile
 fn bg_palette(ppu: &PPU, attribute_table: &[u8], tile_col: usize, tile_row: usize) -> [u8; 4] {
     let attr_table_idx = tile_row / 4 * 8 + tile_col / 4; // 8 columns in attribute table to get index
@@ -109,20 +120,31 @@ fn sprite_palette(ppu: &PPU, pallete_idx: u8) -> [u8; 4] {
 }
 
 pub fn render(ppu: &PPU, frame: &mut Frame) {
-    // Rendering Background
+    frame.emphasis = ppu.region.emphasis(ppu.mask.emphasis());
+
+    // Background disabled(or rendering off entirely), only the backdrop colour is shown
+    if !ppu.mask.show_background() {
+        frame.fill(color(ppu, &frame.palette, ppu.palette_table[0]));
+    } else {
+        render_background(ppu, frame);
+    }
+
+    if ppu.mask.show_sprites() {
+        render_sprites(ppu, frame);
+    }
+}
+
+fn render_background(ppu: &PPU, frame: &mut Frame) {
     let scroll_x = (ppu.scroll.scroll_x) as usize;
     let scroll_y = (ppu.scroll.scroll_y) as usize;
 
-    let (main_nametable, second_nametable) = match (&ppu.mirroring, ppu.ctrl.nametable_addr()) {
-        (Mirroring::VERTICAL, 0x2000) | (Mirroring::VERTICAL, 0x2800) | (Mirroring::HORIZONTAL, 0x2000) | (Mirroring::HORIZONTAL, 0x2400) => {
-            (&ppu.vram[0..0x400], &ppu.vram[0x400..0x800])
-        }
-        (Mirroring::VERTICAL, 0x2400) | (Mirroring::VERTICAL, 0x2C00) | (Mirroring::HORIZONTAL, 0x2800) | (Mirroring::HORIZONTAL, 0x2C00) => {
-            ( &ppu.vram[0x400..0x800], &ppu.vram[0..0x400])
-        }
-        (_,_) => {
-            panic!("Not supported mirroring type {:?}", ppu.mirroring);
-        }
+    // Logical nametable selected by PPUCTRL, its right neighbour is table ^ 1 and the one below is table ^ 2
+    let main_table = (ppu.ctrl.nametable_addr() - 0x2000) / 0x400;
+    let main_nametable = ppu.name_table(main_table);
+    let second_nametable = if scroll_x > 0 {
+        ppu.name_table(main_table ^ 1)
+    } else {
+        ppu.name_table(main_table ^ 2)
     };
 
     render_name_table(
@@ -134,21 +156,28 @@ pub fn render(ppu: &PPU, frame: &mut Frame) {
         -(scroll_y as isize),
     );
 
-  if scroll_x > 0 {
-        render_name_table(ppu, frame, 
-            second_nametable, 
+    if scroll_x > 0 {
+        render_name_table(
+            ppu,
+            frame,
+            second_nametable,
             Rect::new(0, 0, scroll_x, 240),
-            (256 - scroll_x) as isize, 0
+            (256 - scroll_x) as isize,
+            0,
         );
     } else if scroll_y > 0 {
-        render_name_table(ppu, frame, 
-            second_nametable, 
+        render_name_table(
+            ppu,
+            frame,
+            second_nametable,
             Rect::new(0, 0, 256, scroll_y),
-            0, (240 - scroll_y) as isize
+            0,
+            (240 - scroll_y) as isize,
         );
     }
+}
 
-    // Sprites
+fn render_sprites(ppu: &PPU, frame: &mut Frame) {
     // Iterates through the OAM to list up to 64 bytes
     for i in (0..ppu.oam_data.len()).step_by(4).rev() {
         let tile_idx = ppu.oam_data[i + 1] as u16; // Byte 1
@@ -170,8 +199,7 @@ pub fn render(ppu: &PPU, frame: &mut Frame) {
 
         let bank: u16 = ppu.ctrl.sprt_pattern_addr();
 
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        let tile = ppu.chr_tile(bank + tile_idx * 16, true);
 
         for y in 0..=7 {
             let mut upper = tile[y];
@@ -182,18 +210,109 @@ pub fn render(ppu: &PPU, frame: &mut Frame) {
                 lower = lower >> 1;
                 let rgb = match value {
                     0 => continue 'ololo, // skip coloring the pixel
-                    1 => palette::SYSTEM_PALLETE[sprite_palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[sprite_palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[sprite_palette[3] as usize],
+                    1 => color(ppu, &frame.palette, sprite_palette[1]),
+                    2 => color(ppu, &frame.palette, sprite_palette[2]),
+                    3 => color(ppu, &frame.palette, sprite_palette[3]),
                     _ => panic!("can't be"),
                 };
-                match (flip_horizontal, flip_vertical) {
-                    (false, false) => frame.set_pixel(tile_x + x, tile_y + y, rgb),
-                    (true, false) => frame.set_pixel(tile_x + 7 - x, tile_y + y, rgb),
-                    (false, true) => frame.set_pixel(tile_x + x, tile_y + 7 - y, rgb),
-                    (true, true) => frame.set_pixel(tile_x + 7 - x, tile_y + 7 - y, rgb),
+                let (pixel_x, pixel_y) = match (flip_horizontal, flip_vertical) {
+                    (false, false) => (tile_x + x, tile_y + y),
+                    (true, false) => (tile_x + 7 - x, tile_y + y),
+                    (false, true) => (tile_x + x, tile_y + 7 - y),
+                    (true, true) => (tile_x + 7 - x, tile_y + 7 - y),
+                };
+                // Sprites are clipped in the leftmost 8 pixels and never wrap onto the next line
+                if (pixel_x < 8 && !ppu.mask.show_left_sprites()) || pixel_x >= 256 {
+                    continue 'ololo;
                 }
+                frame.set_pixel(pixel_x, pixel_y, rgb);
             }
         }
     }
 }
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::palette::SYSTEM_PALLETE;
+    use crate::rom::Mirroring;
+
+    // Nametable 0 is covered in tile 1, which is colour 1 everywhere, sprite 0 is tile 1 at (0, 100)
+    fn test_ppu(mask: u8) -> PPU {
+        let mut chr = vec![0; 0x2000];
+        chr[0x10..0x18].copy_from_slice(&[0xFF; 8]);
+        let mut ppu = PPU::new(chr, Mirroring::HORIZONTAL);
+        ppu.vram[..0x3C0].fill(1);
+        ppu.palette_table[0] = 0x0F; // Backdrop
+        ppu.palette_table[1] = 0x30; // Background colour 1
+        ppu.palette_table[0x11] = 0x2A; // Sprite colour 1
+        ppu.oam_data[0..4].copy_from_slice(&[100, 1, 0, 0]);
+        ppu.write_to_mask(mask);
+        ppu
+    }
+
+    fn render_pixel(mask: u8, x: usize, y: usize) -> (u8, u8, u8) {
+        let mut frame = Frame::new();
+        render(&test_ppu(mask), &mut frame);
+        frame.get_pixel(x, y).unwrap()
+    }
+
+    #[test]
+    fn test_greyscale() {
+        // Greyscale keeps only the brightness row of the palette, 0x16 red becomes 0x10 grey
+        let mut ppu = test_ppu(0b0000_1010);
+        ppu.palette_table[1] = 0x16;
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(20, 20), Some(SYSTEM_PALLETE[0x16]));
+
+        ppu.write_to_mask(0b0000_1011);
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(20, 20), Some(SYSTEM_PALLETE[0x10]));
+    }
+
+    #[test]
+    fn test_emphasis() {
+        let white = SYSTEM_PALLETE[0x30];
+        let mut frame = Frame::new();
+        render(&test_ppu(0b0010_1010), &mut frame); // Red emphasis
+        let (r, g, b) = frame.get_pixel(20, 20).unwrap();
+        assert_eq!(frame.emphasis, 0b001);
+        assert_eq!(r, white.0);
+        assert!(g < white.1 && b < white.2);
+
+        // All three bits darken every channel
+        let (r, g, b) = render_pixel(0b1110_1010, 20, 20);
+        assert!(r < white.0 && g < white.1 && b < white.2);
+    }
+
+    #[test]
+    fn test_left_clipping() {
+        let backdrop = SYSTEM_PALLETE[0x0F];
+        let background = SYSTEM_PALLETE[0x30];
+        let sprite = SYSTEM_PALLETE[0x2A];
+        // Both clipped
+        assert_eq!(render_pixel(0b0001_1000, 3, 20), backdrop);
+        assert_eq!(render_pixel(0b0001_1000, 8, 20), background);
+        assert_eq!(render_pixel(0b0001_1000, 3, 100), backdrop);
+        // Background shown, sprites clipped
+        assert_eq!(render_pixel(0b0001_1010, 3, 20), background);
+        assert_eq!(render_pixel(0b0001_1010, 3, 100), background);
+        // Both shown
+        assert_eq!(render_pixel(0b0001_1110, 3, 100), sprite);
+    }
+
+    #[test]
+    fn test_backdrop_when_rendering_off() {
+        let mut frame = Frame::new();
+        render(&test_ppu(0b0000_0110), &mut frame);
+        assert!(frame
+            .data
+            .chunks_exact(3)
+            .all(|pixel| (pixel[0], pixel[1], pixel[2]) == SYSTEM_PALLETE[0x0F]));
+
+        // Sprites still draw over the backdrop with the background off
+        assert_eq!(render_pixel(0b0001_0110, 3, 100), SYSTEM_PALLETE[0x2A]);
+        assert_eq!(render_pixel(0b0001_0110, 20, 20), SYSTEM_PALLETE[0x0F]);
+    }
+}
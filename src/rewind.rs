@@ -0,0 +1,243 @@
+// Rewinding through recent save states
+// A snapshot is taken every `interval` frames. Only the newest one is kept whole, each older one
+// is stored as its difference to the snapshot after it: the XOR of the two, which is mostly
+// zeros, run length encoded. Stepping back undoes one difference at a time and the oldest
+// snapshots are dropped once the budget is used up
+use crate::cpu::CPU;
+use crate::savestate;
+use std::collections::VecDeque;
+
+const MIN_ZERO_RUN: usize = 4; // Shorter runs of zeros are cheaper left inside the copied bytes
+
+struct Delta {
+    frame: usize,
+    data: Vec<u8>,
+}
+
+pub struct Rewind {
+    interval: usize,         // Frames between snapshots
+    budget: usize,           // Bytes the snapshots can take up
+    latest: Vec<u8>,         // Newest snapshot, empty until the first one is taken
+    latest_frame: usize,     // PPU frame it was taken on
+    deltas: VecDeque<Delta>, // Older snapshots, oldest first
+    used: usize,             // Bytes held by latest and deltas
+}
+
+impl Rewind {
+    pub fn new(interval: usize, budget: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            latest: Vec::new(),
+            latest_frame: 0,
+            deltas: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    pub fn interval(&self) -> usize {
+        self.interval
+    }
+
+    // How far back the oldest snapshot is from the newest
+    pub fn frames(&self) -> usize {
+        self.deltas
+            .front()
+            .map_or(0, |delta| self.latest_frame - delta.frame)
+    }
+
+    pub fn clear(&mut self) {
+        self.latest.clear();
+        self.deltas.clear();
+        self.used = 0;
+    }
+
+    // Called between instructions, takes a snapshot when one is due
+    pub fn record(&mut self, cpu: &CPU) {
+        let frame = cpu.bus.ppu.frame;
+        if !self.latest.is_empty() {
+            if frame < self.latest_frame {
+                // A state from earlier on was loaded, what came after it is gone
+                self.clear();
+            } else if frame < self.latest_frame + self.interval {
+                return;
+            }
+        }
+
+        let state = savestate::save(cpu);
+        if !self.latest.is_empty() {
+            let data = delta(&state, &self.latest);
+            self.used += data.len();
+            self.deltas.push_back(Delta {
+                frame: self.latest_frame,
+                data,
+            });
+        }
+        self.used += state.len();
+        self.used -= self.latest.len();
+        self.latest = state;
+        self.latest_frame = frame;
+
+        while self.used > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.used -= delta.data.len(),
+                None => break,
+            }
+        }
+    }
+
+    // Goes back to the newest snapshot at least `frames` frames ago, or the oldest one left,
+    // and returns how many frames were undone
+    // Lands exactly when `frames` is a multiple of the interval and the snapshots line up
+    pub fn rewind(&mut self, cpu: &mut CPU, frames: usize) -> usize {
+        if self.latest.is_empty() {
+            return 0;
+        }
+        let frame = cpu.bus.ppu.frame;
+        let target = frame.saturating_sub(frames);
+        while self.latest_frame > target {
+            let Some(delta) = self.deltas.pop_back() else {
+                break;
+            };
+            self.used -= self.latest.len() + delta.data.len();
+            self.latest = apply(&self.latest, &delta.data);
+            self.used += self.latest.len();
+            self.latest_frame = delta.frame;
+        }
+        // The snapshots were all taken from this game and region
+        savestate::load(cpu, &self.latest).unwrap();
+        frame.saturating_sub(self.latest_frame)
+    }
+}
+
+fn write_len(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 0x80 {
+        out.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    out.push(len as u8);
+}
+
+fn read_len(data: &[u8], pos: &mut usize) -> usize {
+    let mut len = 0;
+    let mut shift = 0;
+    while let Some(&byte) = data.get(*pos) {
+        *pos += 1;
+        len |= ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    len
+}
+
+// Turns base into target: target's length, then pairs of a run of unchanged bytes and a run of
+// bytes to XOR in
+fn delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let xor: Vec<u8> = target
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ base.get(i).copied().unwrap_or(0))
+        .collect();
+    let mut out = Vec::new();
+    write_len(&mut out, target.len());
+    let mut i = 0;
+    while i < xor.len() {
+        let start = i;
+        while i < xor.len() && xor[i] == 0 {
+            i += 1;
+        }
+        write_len(&mut out, i - start);
+
+        let start = i;
+        while i < xor.len()
+            && xor[i..xor.len().min(i + MIN_ZERO_RUN)]
+                .iter()
+                .any(|b| *b != 0)
+        {
+            i += 1;
+        }
+        write_len(&mut out, i - start);
+        out.extend_from_slice(&xor[start..i]);
+    }
+    out
+}
+
+fn apply(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_len(delta, &mut pos);
+    let mut target: Vec<u8> = (0..len)
+        .map(|i| base.get(i).copied().unwrap_or(0))
+        .collect();
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_len(delta, &mut pos);
+        let changed = read_len(delta, &mut pos);
+        for byte in &delta[pos..pos + changed] {
+            target[i] ^= byte;
+            i += 1;
+        }
+        pos += changed;
+    }
+    target
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::savestate::test::test_cpu;
+
+    #[test]
+    fn test_delta() {
+        let base = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let target = [1, 2, 0, 4, 5, 6, 7, 8, 9, 10, 12, 12, 13, 0];
+        let encoded = delta(&base, &target);
+        assert!(encoded.len() < target.len());
+        assert_eq!(apply(&base, &encoded), target);
+        assert_eq!(apply(&target, &delta(&target, &base)), base);
+        assert_eq!(apply(&base, &delta(&base, &[])), []);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut cpu = test_cpu();
+        let mut rewind = Rewind::new(2, usize::MAX);
+        let mut states = Vec::new();
+        for _ in 0..10 {
+            rewind.record(&cpu);
+            states.push((cpu.bus.ppu.frame, savestate::save(&cpu)));
+            cpu.run_frame();
+        }
+        assert_eq!(rewind.frames(), 8);
+
+        // 5 frames back is between snapshots, the one before is used
+        assert_eq!(rewind.rewind(&mut cpu, 5), 6);
+        let (frame, state) = &states[4];
+        assert_eq!(cpu.bus.ppu.frame, *frame);
+        assert_eq!(savestate::save(&cpu), *state);
+
+        // Past the start stops at the first snapshot
+        assert_eq!(rewind.rewind(&mut cpu, 100), 4);
+        assert_eq!(savestate::save(&cpu), states[0].1);
+
+        // Recording carries on from there
+        cpu.run_frame();
+        cpu.run_frame();
+        rewind.record(&cpu);
+        assert_eq!(rewind.frames(), 2);
+    }
+
+    #[test]
+    fn test_budget_drops_oldest() {
+        let mut cpu = test_cpu();
+        let state_len = savestate::save(&cpu).len();
+        let mut rewind = Rewind::new(1, state_len + 200);
+        for _ in 0..60 {
+            rewind.record(&cpu);
+            cpu.run_frame();
+        }
+        assert!(rewind.used <= state_len + 200);
+        assert!(rewind.frames() > 0 && rewind.frames() < 59);
+    }
+}
@@ -1,35 +1,74 @@
-#[derive(Debug, PartialEq)]
+use crate::region::Region;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[allow(non_camel_case_types)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
-    FOURSCREEN,
+    FOURSCREEN,     // Uses 2 KB of extra nametable RAM on the cartridge
+    SINGLESCREEN_A, // Every nametable maps to the first 1 KB of VRAM, only selectable by mappers
+    SINGLESCREEN_B, // Every nametable maps to the second 1 KB of VRAM, only selectable by mappers
 }
+impl Mirroring {
+    // Back from `as u8`, for save states
+    pub fn from_index(index: u8) -> Mirroring {
+        match index {
+            1 => Mirroring::HORIZONTAL,
+            2 => Mirroring::FOURSCREEN,
+            3 => Mirroring::SINGLESCREEN_A,
+            4 => Mirroring::SINGLESCREEN_B,
+            _ => Mirroring::VERTICAL,
+        }
+    }
+}
+
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
     pub mapper: u8,
     pub screen_mirroring: Mirroring,
+    pub region: Region, // Taken from the header, can be overridden before the bus is created
+    pub nes2_timing: bool, // The region came from NES 2.0 timing rather than a guess
 }
 
 const NES_TAG: [u8; 4] = [0x4e, 0x45, 0x53, 0x1a];
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
 
 impl Rom {
     pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
         // Checks first 4 bytes to recognize NES file
-        if &raw[0..4] != NES_TAG {
+        if raw.len() < 4 || raw[0..4] != NES_TAG {
             return Err("File is not in iNES file format".to_string());
         }
+        if raw.len() < HEADER_SIZE {
+            return Err("The iNES header is cut short".to_string());
+        }
         // Control byte 2 first 4 upper bits mapper
         // Control byte 1 first 4 lower bits for mapper
         let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
 
         let ines_ver = (raw[7] >> 2) & 0b11;
-        if ines_ver == 0b10 {
-            return Err("NES2.0 format is not supported".to_string());
+        let nes2 = ines_ver == 0b10;
+        if nes2 && (raw[9] & 0x0F == 0x0F || raw[9] >> 4 == 0x0F) {
+            return Err("NES2.0 exponent ROM sizes are not supported".to_string());
         }
 
+        // NES 2.0 stores the CPU/PPU timing in byte 12, iNES only has a rarely used PAL bit in byte 9
+        let region = if nes2 {
+            match raw[12] & 0b11 {
+                1 => Region::PAL,
+                3 => Region::DENDY,
+                _ => Region::NTSC, // 2 is multi-region, which runs fine as NTSC
+            }
+        } else if raw[9] & 1 == 1 {
+            Region::PAL
+        } else {
+            Region::NTSC
+        };
+
         let four_screen = raw[6] & 0b1000 != 0;
         let vertical_mirroring = raw[6] & 0b1 != 0;
         let screen_mirroring = match (four_screen, vertical_mirroring) {
@@ -38,19 +77,79 @@ impl Rom {
             (false, false) => Mirroring::HORIZONTAL,
         };
 
-        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        // NES 2.0 keeps the upper bits of the page counts in byte 9
+        let (prg_pages, chr_pages) = if nes2 {
+            (
+                ((raw[9] as usize & 0x0F) << 8) | raw[4] as usize,
+                ((raw[9] as usize >> 4) << 8) | raw[5] as usize,
+            )
+        } else {
+            (raw[4] as usize, raw[5] as usize)
+        };
+        let prg_rom_size = prg_pages * PRG_ROM_PAGE_SIZE;
+        let chr_rom_size = chr_pages * CHR_ROM_PAGE_SIZE;
 
         let skip_trainer = raw[6] & 0b100 != 0;
 
-        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
+        let prg_rom_start = HEADER_SIZE + if skip_trainer { TRAINER_SIZE } else { 0 };
         let chr_rom_start = prg_rom_start + prg_rom_size;
+        if raw.len() < chr_rom_start + chr_rom_size {
+            return Err(format!(
+                "The file is truncated, the header needs {} bytes but there are {}",
+                chr_rom_start + chr_rom_size,
+                raw.len()
+            ));
+        }
 
         Ok(Rom {
             prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
             chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
-            mapper: mapper,
-            screen_mirroring: screen_mirroring,
+            mapper,
+            screen_mirroring,
+            region,
+            nes2_timing: nes2,
         })
     }
 }
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn header(prg_pages: u8, chr_pages: u8, flags6: u8) -> Vec<u8> {
+        let mut raw = vec![0; HEADER_SIZE];
+        raw[0..4].copy_from_slice(&NES_TAG);
+        raw[4] = prg_pages;
+        raw[5] = chr_pages;
+        raw[6] = flags6;
+        raw
+    }
+
+    #[test]
+    fn test_sizes() {
+        let mut raw = header(2, 1, 0b100); // With a trainer
+        raw.resize(HEADER_SIZE + TRAINER_SIZE + 2 * PRG_ROM_PAGE_SIZE, 1);
+        raw.resize(raw.len() + CHR_ROM_PAGE_SIZE, 2);
+        let rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.prg_rom.len(), 2 * PRG_ROM_PAGE_SIZE);
+        assert!(rom.prg_rom.iter().all(|byte| *byte == 1));
+        assert_eq!(rom.chr_rom, vec![2; CHR_ROM_PAGE_SIZE]);
+    }
+
+    #[test]
+    fn test_short_files() {
+        assert!(Rom::new(&vec![]).is_err());
+        assert!(Rom::new(&NES_TAG[..3].to_vec()).is_err());
+        assert!(Rom::new(&vec![0x4e, 0x45, 0x53, 0x1a, 1, 0, 0, 0]).is_err());
+
+        let mut raw = header(1, 1, 0);
+        raw.resize(HEADER_SIZE + PRG_ROM_PAGE_SIZE + CHR_ROM_PAGE_SIZE - 1, 0);
+        assert!(Rom::new(&raw).is_err());
+        raw.push(0);
+        assert!(Rom::new(&raw).is_ok());
+
+        // The trainer counts too
+        raw[6] = 0b100;
+        assert!(Rom::new(&raw).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    Normal,
+    Fast(u32), // N times the console's speed
+    Uncapped,  // As fast as the host can go
+    Slow(u32), // 1/N of the console's speed
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Speed::Normal => write!(f, "1x"),
+            Speed::Fast(n) => write!(f, "{}x", n),
+            Speed::Uncapped => write!(f, "uncapped"),
+            Speed::Slow(n) => write!(f, "1/{}x", n),
+        }
+    }
+}
+
+// Pausing, frame advance and pacing for running the console in real time
+// After each frame wait() sleeps until the next one is due, then run_next() says whether to
+// emulate it, while it's false the frontend keeps handling events with the same frame up
+pub struct RunControl {
+    paused: bool,
+    advance: u32, // Frames left to run while paused
+    speed: Speed,
+    frame_duration: Duration, // At normal speed
+    next_frame: Instant,
+}
+
+impl RunControl {
+    pub fn new(frame_rate: f64) -> Self {
+        let frame_duration = Duration::from_secs_f64(1.0 / frame_rate);
+        RunControl {
+            paused: false,
+            advance: 0,
+            speed: Speed::Normal,
+            frame_duration,
+            next_frame: Instant::now() + frame_duration,
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.advance = 0;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        if self.paused {
+            self.resume();
+        } else {
+            self.pause();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Runs one more frame and stays paused, pausing first if it was running
+    pub fn advance_frame(&mut self) {
+        if self.paused {
+            self.advance += 1;
+        } else {
+            self.paused = true;
+        }
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = match speed {
+            Speed::Fast(n) | Speed::Slow(n) if n <= 1 => Speed::Normal,
+            _ => speed,
+        };
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    // None when uncapped
+    pub fn frame_duration(&self) -> Option<Duration> {
+        match self.speed {
+            Speed::Normal => Some(self.frame_duration),
+            Speed::Fast(n) => Some(self.frame_duration / n),
+            Speed::Uncapped => None,
+            Speed::Slow(n) => Some(self.frame_duration * n),
+        }
+    }
+
+    // Sleeps until the next frame is due, when running behind it doesn't try to catch up
+    pub fn wait(&mut self) {
+        let Some(duration) = self.frame_duration() else {
+            self.next_frame = Instant::now();
+            return;
+        };
+        let now = Instant::now();
+        if now < self.next_frame {
+            std::thread::sleep(self.next_frame - now);
+            self.next_frame += duration;
+        } else {
+            self.next_frame = now + duration;
+        }
+    }
+
+    // Whether the next frame should be emulated, uses up a frame advance while paused
+    pub fn run_next(&mut self) -> bool {
+        if self.paused {
+            if self.advance == 0 {
+                return false;
+            }
+            self.advance -= 1;
+        }
+        // Nothing was emulated while paused
+        self.next_frame = self.next_frame.max(Instant::now());
+        true
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_pause_and_advance() {
+        let mut control = RunControl::new(50.0);
+        assert!(control.run_next());
+
+        control.advance_frame();
+        assert!(control.is_paused());
+        assert!(!control.run_next());
+        control.advance_frame();
+        control.advance_frame();
+        assert!(control.run_next());
+        assert!(control.run_next());
+        assert!(!control.run_next());
+
+        control.toggle_pause();
+        assert!(control.run_next());
+
+        control.set_speed(Speed::Fast(4));
+        assert_eq!(control.frame_duration(), Some(Duration::from_millis(5)));
+        control.set_speed(Speed::Slow(2));
+        assert_eq!(control.frame_duration(), Some(Duration::from_millis(40)));
+        control.set_speed(Speed::Uncapped);
+        assert_eq!(control.frame_duration(), None);
+        control.set_speed(Speed::Fast(1));
+        assert_eq!(control.speed(), Speed::Normal);
+    }
+}
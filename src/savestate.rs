@@ -0,0 +1,370 @@
+// Save states of the whole machine
+// A state is the magic "NXST", the format version(u16), a checksum of the game and then the CPU,
+// which nests everything it owns
+// Every component is written in its own length prefixed block with its fields in a fixed order
+// New fields only ever go on the end of a block: reading past the end of a block gives 0s, so
+// older states load with the new fields zeroed and older versions skip what they don't know
+// The version is only bumped when a field changes meaning, for code that has to convert old states
+// Settings that aren't machine state(mixer volumes, audio resampling) and what the frontend is
+// holding down are left as they are
+use crate::cpu::CPU;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"NXST";
+pub const VERSION: u16 = 1;
+
+pub trait Savestate {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader);
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.u32(value.to_bits());
+    }
+
+    pub fn f64(&mut self, value: f64) {
+        self.u64(value.to_bits());
+    }
+
+    // Length prefixed
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn component(&mut self, component: &dyn Savestate) {
+        let start = self.data.len();
+        self.u32(0);
+        component.save_state(self);
+        let len = (self.data.len() - start - 4) as u32;
+        self.data[start..start + 4].copy_from_slice(&len.to_le_bytes());
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> &'a [u8] {
+        let start = self.pos.min(self.data.len());
+        let end = (self.pos + len).min(self.data.len());
+        self.pos += len;
+        &self.data[start..end]
+    }
+
+    // Missing bytes read as 0
+    fn array<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        let data = self.take(N);
+        bytes[..data.len()].copy_from_slice(data);
+        bytes
+    }
+
+    pub fn u8(&mut self) -> u8 {
+        self.array::<1>()[0]
+    }
+
+    pub fn bool(&mut self) -> bool {
+        self.u8() != 0
+    }
+
+    pub fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.array())
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.array())
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.array())
+    }
+
+    pub fn usize(&mut self) -> usize {
+        self.u64() as usize
+    }
+
+    pub fn f32(&mut self) -> f32 {
+        f32::from_bits(self.u32())
+    }
+
+    pub fn f64(&mut self) -> f64 {
+        f64::from_bits(self.u64())
+    }
+
+    pub fn bytes(&mut self) -> &'a [u8] {
+        let len = self.u32() as usize;
+        self.take(len)
+    }
+
+    // For fixed size memory, anything missing is zeroed
+    pub fn bytes_into(&mut self, dest: &mut [u8]) {
+        let bytes = self.bytes();
+        let len = bytes.len().min(dest.len());
+        dest[..len].copy_from_slice(&bytes[..len]);
+        dest[len..].fill(0);
+    }
+
+    // Whether the next block is all there and nothing follows it
+    fn rest_is_one_block(&self) -> bool {
+        let rest = &self.data[self.pos.min(self.data.len())..];
+        rest.len() >= 4
+            && u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize == rest.len() - 4
+    }
+
+    pub fn component(&mut self, component: &mut dyn Savestate) {
+        let len = self.u32() as usize;
+        let mut block = StateReader::new(self.take(len));
+        component.load_state(&mut block);
+    }
+}
+
+// FNV-1a, only used to tell games apart
+pub fn checksum(data: &[&[u8]]) -> u32 {
+    let mut hash: u32 = 0x811C_9DC5;
+    for byte in data.iter().flat_map(|bytes| bytes.iter()) {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+pub fn save(cpu: &CPU) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    w.data.extend_from_slice(MAGIC);
+    w.u16(VERSION);
+    w.u32(cpu.bus.checksum);
+    w.u8(cpu.bus.ppu.region as u8);
+    w.component(cpu);
+    w.into_bytes()
+}
+
+// Nothing is changed if the state can't be used
+pub fn load(cpu: &mut CPU, data: &[u8]) -> Result<(), String> {
+    if data.len() < 11 || &data[0..4] != MAGIC {
+        return Err("not a save state".to_string());
+    }
+    let mut r = StateReader::new(&data[4..]);
+    let version = r.u16();
+    if version == 0 || version > VERSION {
+        return Err(format!("unknown save state version {}", version));
+    }
+    if r.u32() != cpu.bus.checksum {
+        return Err("the save state is for a different game".to_string());
+    }
+    if r.u8() != cpu.bus.ppu.region as u8 {
+        return Err("the save state is for a different region".to_string());
+    }
+    // Missing bytes would otherwise load as 0s
+    if !r.rest_is_one_block() {
+        return Err("the save state is truncated or corrupt".to_string());
+    }
+    r.component(cpu);
+    Ok(())
+}
+
+// game.nes keeps its slots in game.state0 to game.state9
+pub fn slot_path(rom: &Path, slot: u8) -> PathBuf {
+    rom.with_extension(format!("state{}", slot))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::apu::APU;
+    use crate::bus::Bus;
+    use crate::input::InputPorts;
+    use crate::mapper;
+    use crate::ppu::PPU;
+    use crate::region::Region;
+    use crate::rom::Mirroring;
+
+    struct Old {
+        a: u8,
+    }
+
+    struct New {
+        a: u8,
+        b: u16,
+    }
+
+    impl Savestate for Old {
+        fn save_state(&self, w: &mut StateWriter) {
+            w.u8(self.a);
+        }
+
+        fn load_state(&mut self, r: &mut StateReader) {
+            self.a = r.u8();
+        }
+    }
+
+    impl Savestate for New {
+        fn save_state(&self, w: &mut StateWriter) {
+            w.u8(self.a);
+            w.u16(self.b);
+        }
+
+        fn load_state(&mut self, r: &mut StateReader) {
+            self.a = r.u8();
+            self.b = r.u16();
+        }
+    }
+
+    #[test]
+    fn test_blocks_are_forward_compatible() {
+        // An older state is missing b, it comes back as 0
+        let mut w = StateWriter::new();
+        w.component(&Old { a: 1 });
+        w.u8(9);
+        let data = w.into_bytes();
+        let mut r = StateReader::new(&data);
+        let mut new = New { a: 0, b: 7 };
+        r.component(&mut new);
+        assert_eq!((new.a, new.b), (1, 0));
+        assert_eq!(r.u8(), 9);
+
+        // A newer state has b, it's skipped
+        let mut w = StateWriter::new();
+        w.component(&New { a: 2, b: 0x1234 });
+        w.u8(9);
+        let data = w.into_bytes();
+        let mut r = StateReader::new(&data);
+        let mut old = Old { a: 0 };
+        r.component(&mut old);
+        assert_eq!(old.a, 2);
+        assert_eq!(r.u8(), 9);
+    }
+
+    // Writes a counter to RAM and the backdrop colour every loop with rendering on
+    pub fn test_cpu() -> CPU<'static> {
+        let program = [
+            0xA9, 0x1E, 0x8D, 0x01, 0x20, // LDA #$1E, STA $2001
+            0xE8, 0x86, 0x10, // loop: INX, STX $10
+            0xA9, 0x3F, 0x8D, 0x06, 0x20, // LDA #$3F, STA $2006
+            0xA9, 0x00, 0x8D, 0x06, 0x20, // LDA #$00, STA $2006
+            0x8E, 0x07, 0x20, // STX $2007
+            0x4C, 0x05, 0x80, // JMP loop
+        ];
+        let mut prg = vec![0; 0x8000];
+        prg[..program.len()].copy_from_slice(&program);
+        prg[0x7FFC] = 0x00;
+        prg[0x7FFD] = 0x80;
+        let bus = Bus::with_mapper(
+            mapper::new(0, prg).unwrap(),
+            vec![0; 0x2000],
+            Mirroring::HORIZONTAL,
+            Region::NTSC,
+            |_: &PPU, _: &mut APU, _: &mut InputPorts| {},
+        );
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu
+    }
+
+    fn machine(cpu: &CPU) -> (u16, u8, u8, usize, usize, u16, usize, [u8; 32]) {
+        (
+            cpu.pc,
+            cpu.a,
+            cpu.x,
+            cpu.bus.cycles,
+            cpu.bus.ppu.cycles,
+            cpu.bus.ppu.scanline,
+            cpu.bus.apu.cycles,
+            cpu.bus.ppu.palette_table,
+        )
+    }
+
+    #[test]
+    fn test_load_repeats_frames() {
+        let mut cpu = test_cpu();
+        for _ in 0..3 {
+            cpu.run_frame();
+        }
+        let state = save(&cpu);
+        let saved = machine(&cpu);
+        for _ in 0..2 {
+            cpu.run_frame();
+        }
+        let expected = machine(&cpu);
+        assert_ne!(saved, expected);
+
+        // A fresh machine carries on the same way
+        let mut loaded = test_cpu();
+        load(&mut loaded, &state).unwrap();
+        for _ in 0..2 {
+            loaded.run_frame();
+        }
+        assert_eq!(machine(&loaded), expected);
+
+        assert!(load(&mut loaded, b"not a state").is_err());
+        let mut other = state.clone();
+        other[6] ^= 1; // Checksum
+        assert!(load(&mut loaded, &other).is_err());
+    }
+
+    #[test]
+    fn test_load_rejects_bad_states() {
+        let mut cpu = test_cpu();
+        cpu.run_frame();
+        let state = save(&cpu);
+        let mut loaded = test_cpu();
+        let fresh = machine(&loaded);
+
+        let mut newer = state.clone();
+        newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(load(&mut loaded, &newer).is_err());
+        assert!(load(&mut loaded, &state[..state.len() - 1]).is_err());
+        assert!(load(&mut loaded, &state[..13]).is_err());
+        let mut longer = state.clone();
+        longer.push(0);
+        assert!(load(&mut loaded, &longer).is_err());
+        assert_eq!(machine(&loaded), fresh);
+
+        load(&mut loaded, &state).unwrap();
+        assert_eq!(machine(&loaded), machine(&cpu));
+    }
+}
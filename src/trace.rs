@@ -1,16 +1,25 @@
 use crate::cpu::AddressingMode;
-use crate::cpu::Mem;
 use crate::cpu::CPU;
 use crate::op::OPCODES_MAP;
 
 // use log::{debug, info, warn};
 
+// Reading the PPU, APU and controller registers has side effects(clearing vblank, clocking the controllers)
+// and so do some mapper registers, so the trace only peeks at memory
+fn peek(cpu: &mut CPU, addr: u16) -> u8 {
+    cpu.bus.peek(addr)
+}
+
+fn peek_u16(cpu: &mut CPU, addr: u16) -> u16 {
+    u16::from_le_bytes([peek(cpu, addr), peek(cpu, addr.wrapping_add(1))])
+}
+
 // This will return the current state of the cpu based on its parameters in trace
 pub fn trace(cpu: &mut CPU) -> String {
     // Extract the PC
     let op_map = &OPCODES_MAP;
     let pc = cpu.pc;
-    let instr = cpu.mem_read(pc);
+    let instr = peek(cpu, pc);
     let op = op_map
         .get(&instr)
         .unwrap_or_else(|| panic!("Trace: Unknown opcode: {:#04X}", instr));
@@ -22,7 +31,7 @@ pub fn trace(cpu: &mut CPU) -> String {
     let times = op.len;
     let mut raw_ar = Vec::new();
     for n in 0..times {
-        raw_ar.push(format!("{:02X}", cpu.mem_read(pc + n as u16)));
+        raw_ar.push(format!("{:02X}", peek(cpu, pc + n as u16)));
     }
     let ret_raw = raw_ar.join(" ");
 
@@ -43,14 +52,14 @@ pub fn trace(cpu: &mut CPU) -> String {
     // Format the address based on what mode it is
     let addr_format: String = match op.mode {
         AddressingMode::Accumulator => "A".to_string(),
-        AddressingMode::Immediate => format!("#${:02X}", cpu.mem_read(addr)),
-        AddressingMode::ZeroPage => format!("${:02X} = {:02X}", addr, cpu.mem_read(addr)),
+        AddressingMode::Immediate => format!("#${:02X}", peek(cpu, addr)),
+        AddressingMode::ZeroPage => format!("${:02X} = {:02X}", addr, peek(cpu, addr)),
         // TODO Add the hard coded values for STX(what is the content of the previous value)
         AddressingMode::Absolute => {
             match op.code {
                 // JMP Absolute
                 0x4C | 0x20 => format!("${:04X}", addr),
-                _ => format!("${:04X} = {:02X}", addr, cpu.mem_read(addr)),
+                _ => format!("${:04X} = {:02X}", addr, peek(cpu, addr)),
             }
         }
         // First number is the address we are looking at
@@ -58,56 +67,56 @@ pub fn trace(cpu: &mut CPU) -> String {
         // Final number is the content of the value fetched
         AddressingMode::ZeroPage_X => format!(
             "${:02X},X @ {:02X} = {:02X}",
-            cpu.mem_read(pc + 1),
+            peek(cpu, pc + 1),
             addr,
-            cpu.mem_read(addr)
+            peek(cpu, addr)
         ),
         AddressingMode::ZeroPage_Y => format!(
             "${:02X},Y @ {:02X} = {:02X}",
-            cpu.mem_read(pc + 1),
+            peek(cpu, pc + 1),
             addr,
-            cpu.mem_read(addr)
+            peek(cpu, addr)
         ),
         AddressingMode::Absolute_X => format!(
             "${:04X},X @ {:04X} = {:02X}",
-            cpu.mem_read_u16(pc + 1),
+            peek_u16(cpu, pc + 1),
             addr,
-            cpu.mem_read(addr)
+            peek(cpu, addr)
         ),
         // BUG Should be mem_read_u16 not mem_read
         AddressingMode::Absolute_Y => format!(
             "${:04X},Y @ {:04X} = {:02X}",
-            cpu.mem_read_u16(pc + 1),
+            peek_u16(cpu, pc + 1),
             addr,
-            cpu.mem_read(addr)
+            peek(cpu, addr)
         ),
         AddressingMode::Indirect => {
             match op.code {
                 // JMP Indirect
-                0x6C => format!("(${:04X}) = {:04X}", cpu.mem_read_u16(pc + 1), addr),
-                _ => format!("({:04X} = {:04X})", cpu.mem_read_u16(pc), addr),
+                0x6C => format!("(${:04X}) = {:04X}", peek_u16(cpu, pc + 1), addr),
+                _ => format!("({:04X} = {:04X})", peek_u16(cpu, pc), addr),
             }
         }
         AddressingMode::Indirect_X => format!(
             "(${:02X},X) @ {:02X} = {:04X} = {:02X}",
-            cpu.mem_read(pc + 1),
-            cpu.mem_read(pc + 1).wrapping_add(cpu.x),
+            peek(cpu, pc + 1),
+            peek(cpu, pc + 1).wrapping_add(cpu.x),
             addr,
-            cpu.mem_read(addr)
+            peek(cpu, addr)
         ),
         // NOTE: Second value is initial dereferenced value
         AddressingMode::Indirect_Y => {
             format!(
                 "(${:02X}),Y = {:04X} @ {:04X} = {:02X}",
-                cpu.mem_read(pc + 1),
+                peek(cpu, pc + 1),
                 addr.wrapping_sub(cpu.y as u16),
                 addr,
-                cpu.mem_read(addr)
+                peek(cpu, addr)
             )
         }
         AddressingMode::Relative => {
             format!("${:4X}", {
-                let branch_offset = (cpu.mem_read(pc.wrapping_add(1)) as i8).wrapping_add(2);
+                let branch_offset = (peek(cpu, pc.wrapping_add(1)) as i8).wrapping_add(2);
                 pc.wrapping_add(branch_offset as u16)
             })
         }
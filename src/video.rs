@@ -0,0 +1,297 @@
+// Lossless video recording to uncompressed AVI
+// Every emulated frame is written as 24-bit RGB alongside the APU's output as 16-bit PCM, with the
+// frame rate stored as the region's exact fraction. The audio comes from the APU at a fixed rate
+// and the frames from the PPU, so a recording only depends on the emulation, not on how fast the
+// host ran it
+// Plain AVI files can't go past 4 GB and many players stop at 1 GB, so long recordings carry on in
+// name_2.avi, name_3.avi...
+use crate::frame::Frame;
+use crate::region::Region;
+use crate::wav::{pcm16, RECORDING_RATE};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const FRAME_BYTES: u32 = (Frame::WIDTH * Frame::HIGHT * 3) as u32;
+const HEADER_BYTES: u32 = 326; // Everything before the first chunk in the movi list
+const SEGMENT_LIMIT: u32 = 1 << 30;
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+pub struct AviWriter {
+    file: BufWriter<File>,
+    rate: u32, // The frame rate is rate / scale
+    scale: u32,
+    frames: u32,
+    samples: u32,
+    movi_bytes: u32, // Chunks written to the movi list so far
+    index: Vec<u8>,  // idx1 entries, written after the chunks
+}
+
+fn chunk_header(out: &mut Vec<u8>, id: &[u8; 4], size: u32) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&size.to_le_bytes());
+}
+
+fn u16s(out: &mut Vec<u8>, values: &[u16]) {
+    for value in values {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn u32s(out: &mut Vec<u8>, values: &[u32]) {
+    for value in values {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+impl AviWriter {
+    pub fn create(path: &Path, region: Region) -> io::Result<AviWriter> {
+        let (rate, scale) = region.frame_rate_fraction();
+        let mut writer = AviWriter {
+            file: BufWriter::new(File::create(path)?),
+            rate,
+            scale,
+            frames: 0,
+            samples: 0,
+            movi_bytes: 0,
+            index: Vec::new(),
+        };
+        let header = writer.header();
+        writer.file.write_all(&header)?;
+        Ok(writer)
+    }
+
+    // Sizes and lengths are 0 until finish() goes back and fills them in
+    fn header(&self) -> Vec<u8> {
+        let width = Frame::WIDTH as u32;
+        let height = Frame::HIGHT as u32;
+        let audio_bytes = self.samples * 2;
+        let file_bytes = HEADER_BYTES + self.movi_bytes + 8 + self.index.len() as u32;
+        let micros_per_frame = (1_000_000 * self.scale as u64 / self.rate as u64) as u32;
+        let bytes_per_second =
+            (FRAME_BYTES as u64 * self.rate as u64 / self.scale as u64) as u32 + RECORDING_RATE * 2;
+
+        let mut out = Vec::with_capacity(HEADER_BYTES as usize);
+        chunk_header(&mut out, b"RIFF", file_bytes - 8);
+        out.extend_from_slice(b"AVI ");
+        chunk_header(&mut out, b"LIST", 302 - 8);
+        out.extend_from_slice(b"hdrl");
+        chunk_header(&mut out, b"avih", 56);
+        #[rustfmt::skip]
+        u32s(&mut out, &[
+            micros_per_frame, bytes_per_second, 0, AVIF_HASINDEX, self.frames,
+            0, 2, FRAME_BYTES, width, height, 0, 0, 0, 0,
+        ]);
+
+        // Video stream, bottom up BGR like a Windows bitmap
+        chunk_header(&mut out, b"LIST", 124 - 8);
+        out.extend_from_slice(b"strl");
+        chunk_header(&mut out, b"strh", 56);
+        out.extend_from_slice(b"vids");
+        out.extend_from_slice(b"DIB ");
+        #[rustfmt::skip]
+        u32s(&mut out, &[
+            0, 0, 0, self.scale, self.rate, 0, self.frames, FRAME_BYTES, u32::MAX, 0,
+        ]);
+        u16s(&mut out, &[0, 0, width as u16, height as u16]);
+        chunk_header(&mut out, b"strf", 40);
+        u32s(&mut out, &[40, width, height]);
+        u16s(&mut out, &[1, 24]); // Planes, bits per pixel
+        u32s(&mut out, &[0, FRAME_BYTES, 0, 0, 0, 0]);
+
+        // Audio stream, mono 16-bit PCM
+        chunk_header(&mut out, b"LIST", 102 - 8);
+        out.extend_from_slice(b"strl");
+        chunk_header(&mut out, b"strh", 56);
+        out.extend_from_slice(b"auds");
+        out.extend_from_slice(&[0; 4]);
+        #[rustfmt::skip]
+        u32s(&mut out, &[
+            0, 0, 0, 2, RECORDING_RATE * 2, 0, audio_bytes / 2, RECORDING_RATE * 2, u32::MAX, 2,
+        ]);
+        u16s(&mut out, &[0, 0, 0, 0]);
+        chunk_header(&mut out, b"strf", 18);
+        u16s(&mut out, &[1, 1]); // PCM, channels
+        u32s(&mut out, &[RECORDING_RATE, RECORDING_RATE * 2]);
+        u16s(&mut out, &[2, 16, 0]); // Block align, bits per sample, no extra bytes
+
+        chunk_header(&mut out, b"LIST", 4 + self.movi_bytes);
+        out.extend_from_slice(b"movi");
+        out
+    }
+
+    // Bytes the file would have if it was finished now
+    pub fn len(&self) -> u64 {
+        HEADER_BYTES as u64 + self.movi_bytes as u64 + 8 + self.index.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames == 0 && self.samples == 0
+    }
+
+    fn write_chunk(&mut self, id: &[u8; 4], data: &[u8]) -> io::Result<()> {
+        // Offsets in the index count from the movi fourcc
+        self.index.extend_from_slice(id);
+        u32s(
+            &mut self.index,
+            &[AVIIF_KEYFRAME, 4 + self.movi_bytes, data.len() as u32],
+        );
+        self.file.write_all(id)?;
+        self.file.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.file.write_all(data)?;
+        self.movi_bytes += 8 + data.len() as u32;
+        Ok(())
+    }
+
+    pub fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let mut bgr = Vec::with_capacity(FRAME_BYTES as usize);
+        for row in frame.data.chunks_exact(Frame::WIDTH * 3).rev() {
+            for pixel in row.chunks_exact(3) {
+                bgr.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            }
+        }
+        self.write_chunk(b"00db", &bgr)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    // Mono samples at RECORDING_RATE, in the range -1.0 to 1.0
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        let pcm: Vec<u8> = samples
+            .iter()
+            .flat_map(|sample| pcm16(*sample).to_le_bytes())
+            .collect();
+        self.write_chunk(b"01wb", &pcm)?;
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.write_all(b"idx1")?;
+        self.file
+            .write_all(&(self.index.len() as u32).to_le_bytes())?;
+        self.file.write_all(&self.index)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        let header = self.header();
+        self.file.write_all(&header)?;
+        self.file.flush()
+    }
+}
+
+pub struct VideoRecorder {
+    path: PathBuf,
+    region: Region,
+    writer: AviWriter,
+    segment: usize,
+}
+
+impl VideoRecorder {
+    pub fn start(path: &Path, region: Region) -> io::Result<VideoRecorder> {
+        Ok(VideoRecorder {
+            path: path.to_path_buf(),
+            region,
+            writer: AviWriter::create(path, region)?,
+            segment: 1,
+        })
+    }
+
+    // video.avi carries on in video_2.avi, video_3.avi...
+    pub fn segment_path(path: &Path, segment: usize) -> PathBuf {
+        if segment == 1 {
+            return path.to_path_buf();
+        }
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        path.with_file_name(format!("{}_{}.avi", stem, segment))
+    }
+
+    // One frame and the audio that played during it
+    pub fn record(&mut self, frame: &Frame, samples: &[f32]) -> io::Result<()> {
+        let needed = FRAME_BYTES as u64 + samples.len() as u64 * 2 + 48;
+        if !self.writer.is_empty() && self.writer.len() + needed > SEGMENT_LIMIT as u64 {
+            self.segment += 1;
+            let path = VideoRecorder::segment_path(&self.path, self.segment);
+            let next = AviWriter::create(&path, self.region)?;
+            std::mem::replace(&mut self.writer, next).finish()?;
+        }
+        self.writer.write_frame(frame)?;
+        self.writer.write_samples(samples)
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.writer.finish()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_frame_rate_fraction() {
+        for region in [Region::NTSC, Region::PAL, Region::DENDY] {
+            let (rate, scale) = region.frame_rate_fraction();
+            assert!((rate as f64 / scale as f64 - region.frame_rate()).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_avi_layout() {
+        let path = std::env::temp_dir().join("nes_test_avi_layout.avi");
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 0, (1, 2, 3));
+        let mut writer = AviWriter::create(&path, Region::NTSC).unwrap();
+        writer.write_frame(&frame).unwrap();
+        writer.write_samples(&[1.0, -1.0, 0.0]).unwrap();
+        writer.write_frame(&frame).unwrap();
+        let len = writer.len();
+        writer.finish().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bytes.len() as u64, len);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[8..12], b"AVI ");
+        assert_eq!(u32_at(&bytes, 48), 2); // Frames
+        assert_eq!(
+            &bytes[HEADER_BYTES as usize - 4..HEADER_BYTES as usize],
+            b"movi"
+        );
+
+        // The top left pixel ends up at the start of the last row, as BGR
+        let first = HEADER_BYTES as usize;
+        assert_eq!(&bytes[first..first + 4], b"00db");
+        let last_row = first + 8 + (Frame::HIGHT - 1) * Frame::WIDTH * 3;
+        assert_eq!(bytes[last_row..last_row + 3], [3, 2, 1]);
+
+        let audio = first + 8 + FRAME_BYTES as usize;
+        assert_eq!(&bytes[audio..audio + 4], b"01wb");
+        assert_eq!(u32_at(&bytes, audio + 4), 6);
+
+        // Three chunks in the index, the second one pointing at the audio
+        let index = bytes.len() - 8 - 3 * 16;
+        assert_eq!(&bytes[index..index + 4], b"idx1");
+        assert_eq!(
+            u32_at(&bytes, index + 8 + 16 + 8) as usize,
+            audio - (first - 4)
+        );
+    }
+
+    #[test]
+    fn test_segment_path() {
+        let path = Path::new("out/video.avi");
+        assert_eq!(VideoRecorder::segment_path(path, 1), path);
+        assert_eq!(
+            VideoRecorder::segment_path(path, 3),
+            Path::new("out/video_3.avi")
+        );
+    }
+}
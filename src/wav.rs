@@ -0,0 +1,187 @@
+// 16-bit PCM WAV recording of the APU's output
+// The mix is recorded through its own AudioSink at a fixed rate, so recordings are not affected by the
+// frontend's dynamic rate control and are identical between runs
+use crate::apu::mixer::Channel;
+use crate::audio::AudioSink;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const RECORDING_RATE: u32 = 44100;
+const FLUSH_INTERVAL: usize = 4096; // CPU cycles between moving resampled audio into the files
+
+// -1.0 to 1.0 as a 16-bit sample, anything outside is clamped
+pub fn pcm16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+pub struct WavWriter {
+    file: BufWriter<File>,
+    sample_rate: u32,
+    channels: u16,
+    data_bytes: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32, channels: u16) -> io::Result<WavWriter> {
+        let mut writer = WavWriter {
+            file: BufWriter::new(File::create(path)?),
+            sample_rate,
+            channels,
+            data_bytes: 0,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    // Sizes are 0 until finish() goes back and fills them in
+    fn write_header(&mut self) -> io::Result<()> {
+        let block_align = self.channels * 2;
+        let byte_rate = self.sample_rate * block_align as u32;
+        self.file.write_all(b"RIFF")?;
+        self.file.write_all(&(36 + self.data_bytes).to_le_bytes())?;
+        self.file.write_all(b"WAVE")?;
+        self.file.write_all(b"fmt ")?;
+        self.file.write_all(&16u32.to_le_bytes())?; // fmt chunk size
+        self.file.write_all(&1u16.to_le_bytes())?; // PCM
+        self.file.write_all(&self.channels.to_le_bytes())?;
+        self.file.write_all(&self.sample_rate.to_le_bytes())?;
+        self.file.write_all(&byte_rate.to_le_bytes())?;
+        self.file.write_all(&block_align.to_le_bytes())?;
+        self.file.write_all(&16u16.to_le_bytes())?; // Bits per sample
+        self.file.write_all(b"data")?;
+        self.file.write_all(&self.data_bytes.to_le_bytes())?;
+        Ok(())
+    }
+
+    // Samples are interleaved when there is more than one channel, in the range -1.0 to 1.0
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            self.file.write_all(&pcm16(*sample).to_le_bytes())?;
+        }
+        self.data_bytes += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.flush()
+    }
+}
+
+// A resampler and file for the mix or one channel
+struct Track {
+    sink: AudioSink,
+    writer: WavWriter,
+}
+
+impl Track {
+    fn new(path: &Path, cpu_clock_hz: f64) -> io::Result<Track> {
+        let mut sink = AudioSink::new(cpu_clock_hz);
+        sink.set_output_rate(RECORDING_RATE);
+        Ok(Track {
+            sink,
+            writer: WavWriter::create(path, RECORDING_RATE, 1)?,
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.write_samples(&self.sink.take_samples())
+    }
+}
+
+pub struct AudioRecorder {
+    mix: Track,
+    stems: Vec<(Channel, Track)>,
+    cycles: usize,
+}
+
+impl AudioRecorder {
+    // Stems are written next to the mix, eg. music.wav, music_pulse1.wav, music_triangle.wav...
+    pub fn start(path: &Path, cpu_clock_hz: f64, stems: bool) -> io::Result<AudioRecorder> {
+        let mut recorder = AudioRecorder {
+            mix: Track::new(path, cpu_clock_hz)?,
+            stems: Vec::new(),
+            cycles: 0,
+        };
+        if stems {
+            for channel in Channel::ALL {
+                let stem_path = AudioRecorder::stem_path(path, channel);
+                recorder
+                    .stems
+                    .push((channel, Track::new(&stem_path, cpu_clock_hz)?));
+            }
+        }
+        Ok(recorder)
+    }
+
+    pub fn stem_path(path: &Path, channel: Channel) -> PathBuf {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = format!("{}_{}.wav", stem, format!("{:?}", channel).to_lowercase());
+        path.with_file_name(name)
+    }
+
+    pub fn has_stems(&self) -> bool {
+        !self.stems.is_empty()
+    }
+
+    // Called every CPU cycle with the mixed output and each channel's output on its own
+    pub fn push(&mut self, mix: f32, channels: &[f32; 6]) -> io::Result<()> {
+        self.mix.sink.push(mix);
+        for (channel, track) in self.stems.iter_mut() {
+            track.sink.push(channels[*channel as usize]);
+        }
+        self.cycles += 1;
+        if self.cycles == FLUSH_INTERVAL {
+            self.cycles = 0;
+            self.mix.flush()?;
+            for (_, track) in self.stems.iter_mut() {
+                track.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stop(mut self) -> io::Result<()> {
+        self.mix.flush()?;
+        self.mix.writer.finish()?;
+        for (_, mut track) in self.stems.drain(..) {
+            track.flush()?;
+            track.writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_wav_header_sizes() {
+        let path = std::env::temp_dir().join("nes_test_wav_header.wav");
+        let mut writer = WavWriter::create(&path, RECORDING_RATE, 1).unwrap();
+        writer.write_samples(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+        writer.finish().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 36 + 8);
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 8);
+        assert_eq!(i16::from_le_bytes([bytes[46], bytes[47]]), i16::MAX);
+        // Out of range samples are clamped
+        assert_eq!(i16::from_le_bytes([bytes[50], bytes[51]]), i16::MAX);
+    }
+
+    #[test]
+    fn test_stem_path() {
+        let path = Path::new("out/music.wav");
+        assert_eq!(
+            AudioRecorder::stem_path(path, Channel::TRIANGLE),
+            Path::new("out/music_triangle.wav")
+        );
+    }
+}